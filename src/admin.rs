@@ -0,0 +1,352 @@
+use std::{
+    convert::Infallible,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+
+use bytes::Bytes;
+use http::{header, Method, StatusCode};
+use http_body_util::Full;
+use hyper::{body::Incoming, server::conn::http1, service::service_fn, Request, Response};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+use crate::{
+    metrics::metrics,
+    server::{http::fail_closed::Readiness, tls::ServerTls},
+    shutdown::Shutdown,
+};
+
+/// Listener for operators, kept apart from the proxied traffic
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct AdminConfig {
+    /// Only reachable from the host itself by default
+    #[serde(default = "AdminConfig::default_ip")]
+    pub(crate) ip: IpAddr,
+    pub(crate) port: u16,
+}
+
+impl AdminConfig {
+    fn default_ip() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    }
+}
+
+/// Version of the running build, `git_hash` is `unknown` when it wasn't built from a checkout
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const GIT_HASH: &str = env!("BIFROST_GIT_HASH");
+
+/// Serves admin endpoints until `shutdown` completes, `started` is when the process started,
+/// `certificates` are the TLS settings of the listeners that terminate TLS and `readiness` is
+/// shared with the servers that fail closed
+pub(crate) async fn run(
+    config: AdminConfig,
+    started: Instant,
+    certificates: Vec<ServerTls>,
+    readiness: Readiness,
+    shutdown: Shutdown,
+) -> Result<(), io::Error> {
+    let certificates = Arc::new(certificates);
+
+    let addr = SocketAddr::new(config.ip, config.port);
+    let listener = TcpListener::bind(addr).await?;
+
+    tracing::info!(address = %addr, "Admin listener started");
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => accepted?.0,
+            _ = shutdown.clone() => return Ok(()),
+        };
+
+        let certificates = certificates.clone();
+        let readiness = readiness.clone();
+        let service = service_fn(move |req: Request<Incoming>| {
+            let certificates = certificates.clone();
+            let readiness = readiness.clone();
+
+            async move {
+                Ok::<_, Infallible>(route(
+                    req.method(),
+                    req.uri().path(),
+                    started,
+                    &readiness,
+                    &certificates,
+                ))
+            }
+        });
+
+        tokio::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::warn!(error = ?err, "Error serving admin connection");
+            }
+        });
+    }
+}
+
+fn route(
+    method: &Method,
+    path: &str,
+    started: Instant,
+    readiness: &Readiness,
+    certificates: &[ServerTls],
+) -> Response<Full<Bytes>> {
+    match (method, path) {
+        (&Method::GET, "/info") => json(&info(started)),
+        (&Method::GET, "/ready") => ready(readiness),
+        (&Method::GET, "/metrics") => prometheus(),
+        (&Method::POST, "/-/reload-certs") => reload_certificates(certificates),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::new(Bytes::from_static(b"Not found")))
+            // FIX: expect
+            .expect("Failed to build response"),
+    }
+}
+
+/// Build and uptime of the running process
+#[derive(Serialize)]
+struct Info {
+    version: &'static str,
+    git_hash: &'static str,
+    uptime_seconds: u64,
+}
+
+fn info(started: Instant) -> Info {
+    Info {
+        version: VERSION,
+        git_hash: GIT_HASH,
+        uptime_seconds: started.elapsed().as_secs(),
+    }
+}
+
+/// How many listeners got their certificates reloaded
+#[derive(Serialize)]
+struct Reloaded {
+    reloaded: usize,
+}
+
+/// Metrics in the Prometheus text format, for scrapers that can't use the control plane
+fn prometheus() -> Response<Full<Bytes>> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Full::new(Bytes::from(metrics().render())))
+        .expect("Failed to build response")
+}
+
+/// Not ready while a server failed closed, so load balancers in front can tell along with the
+/// closed listener
+fn ready(readiness: &Readiness) -> Response<Full<Bytes>> {
+    let (status, body) = if readiness.is_ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    };
+
+    Response::builder()
+        .status(status)
+        .body(Full::new(Bytes::from_static(body.as_bytes())))
+        // FIX: expect
+        .expect("Failed to build response")
+}
+
+/// Reads the certificates of all TLS listeners again without touching anything else. Listeners
+/// whose certificate fails to load keep the old one, the rest are reloaded anyway.
+fn reload_certificates(certificates: &[ServerTls]) -> Response<Full<Bytes>> {
+    let errors: Vec<String> = certificates
+        .iter()
+        .filter_map(|tls| tls.reload().err())
+        .collect();
+
+    if errors.is_empty() {
+        tracing::info!(
+            certificates = certificates.len(),
+            "Reloaded TLS certificates"
+        );
+
+        return json(&Reloaded {
+            reloaded: certificates.len(),
+        });
+    }
+
+    for err in &errors {
+        tracing::warn!(error = %err, "Failed to reload a TLS certificate");
+    }
+
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(Full::new(Bytes::from(errors.join("\n"))))
+        // FIX: expect
+        .expect("Failed to build response")
+}
+
+fn json(body: &impl Serialize) -> Response<Full<Bytes>> {
+    // FIX: expect
+    let body = serde_json::to_vec(body).expect("Failed to serialize response");
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)))
+        // FIX: expect
+        .expect("Failed to build response")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http_body_util::BodyExt;
+
+    use super::*;
+    use crate::server::http::fail_closed::FailClosed;
+    use crate::test_helpers::{closed_port, get, http_server, slow_backend};
+
+    #[tokio::test]
+    async fn info_reports_build_and_uptime() {
+        let started = Instant::now() - Duration::from_secs(90);
+
+        let response = route(&Method::GET, "/info", started, &Readiness::default(), &[]);
+
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        assert_eq!(
+            body,
+            format!(
+                r#"{{"version":"{}","git_hash":"{}","uptime_seconds":90}}"#,
+                env!("CARGO_PKG_VERSION"),
+                env!("BIFROST_GIT_HASH")
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_are_scraped() {
+        let scrape = || async {
+            let response = route(
+                &Method::GET,
+                "/metrics",
+                Instant::now(),
+                &Readiness::default(),
+                &[],
+            );
+
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "text/plain; version=0.0.4"
+            );
+
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+        let requests = |scraped: &str| {
+            scraped
+                .lines()
+                .find_map(|line| {
+                    line.strip_prefix(
+                        r#"bifrost_http_requests_total{listener="metrics-scraped",route="metrics-scraped",status="2xx"} "#,
+                    )
+                })
+                .map_or(0, |count| count.parse::<u64>().unwrap())
+        };
+
+        let port = closed_port().await;
+        let server = http_server("metrics-scraped", port, slow_backend().await, None);
+
+        tokio::spawn(server.run(std::future::pending()));
+        // Lets the server bind its listener, the test runtime has a single thread
+        tokio::task::yield_now().await;
+
+        assert_eq!(requests(&scrape().await), 0);
+
+        assert!(get(([127, 0, 0, 1], port).into())
+            .await
+            .starts_with("HTTP/1.1 200 OK"));
+
+        assert_eq!(requests(&scrape().await), 1);
+    }
+
+    #[test]
+    fn not_ready_while_a_server_failed_closed() {
+        let readiness = Readiness::default();
+        let ready = || route(&Method::GET, "/ready", Instant::now(), &readiness, &[]).status();
+
+        assert_eq!(ready(), StatusCode::OK);
+
+        let mut closed = FailClosed::new(vec![], readiness.clone());
+        closed.set_closed(true);
+
+        assert_eq!(ready(), StatusCode::SERVICE_UNAVAILABLE);
+
+        drop(closed);
+
+        assert_eq!(ready(), StatusCode::OK);
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        assert_eq!(
+            route(
+                &Method::GET,
+                "/nope",
+                Instant::now(),
+                &Readiness::default(),
+                &[]
+            )
+            .status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            route(
+                &Method::POST,
+                "/info",
+                Instant::now(),
+                &Readiness::default(),
+                &[]
+            )
+            .status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn certificates_are_reloaded() {
+        let (cert, key) = crate::server::tls::tests::write_certificate();
+        let certificates: [ServerTls; 1] = [serde_yaml::from_str(&format!(
+            "{{ cert: {}, key: {} }}",
+            cert.display(),
+            key.display()
+        ))
+        .unwrap()];
+
+        let reload = || {
+            route(
+                &Method::POST,
+                "/-/reload-certs",
+                Instant::now(),
+                &Readiness::default(),
+                &certificates,
+            )
+        };
+
+        let body = reload().into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, r#"{"reloaded":1}"#);
+
+        std::fs::remove_dir_all(cert.parent().unwrap()).unwrap();
+
+        let response = reload();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.starts_with(b"Failed to read"), "{:?}", body);
+    }
+}
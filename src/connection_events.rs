@@ -0,0 +1,324 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, LazyLock,
+    },
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::broadcast,
+};
+
+use crate::metrics::RelayCounters;
+
+/// Events a subscriber can fall behind by, it skips the ones it missed after that
+const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ConnectionProtocol {
+    Http,
+    Tcp,
+    /// A session of datagrams from the same client
+    Udp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ConnectionEventKind {
+    Opened {
+        protocol: ConnectionProtocol,
+        client: SocketAddr,
+    },
+    /// Once per connection for TCP and UDP, for every request that reached a backend for HTTP
+    BackendSelected { backend: String },
+    Closed {
+        client_to_upstream: u64,
+        upstream_to_client: u64,
+    },
+}
+
+/// Something that happened to a connection, events of the same connection share its id
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionEvent {
+    pub(crate) time: SystemTime,
+    pub(crate) connection: u64,
+    pub(crate) listener: String,
+    pub(crate) kind: ConnectionEventKind,
+}
+
+/// Lifecycle events of the connections of every listener, for the control plane. Connections
+/// are only tracked while someone is subscribed, otherwise it costs the data path a single
+/// atomic load per connection. Events are dropped for subscribers that can't keep up, sending
+/// them never waits.
+pub(crate) struct ConnectionEvents {
+    sender: broadcast::Sender<ConnectionEvent>,
+    subscribers: AtomicUsize,
+    next_id: AtomicU64,
+}
+
+static EVENTS: LazyLock<ConnectionEvents> = LazyLock::new(ConnectionEvents::new);
+
+pub(crate) fn events() -> &'static ConnectionEvents {
+    &EVENTS
+}
+
+/// Receiver of the events, connections stop being tracked once every subscription is dropped
+pub(crate) struct Subscription {
+    pub(crate) receiver: broadcast::Receiver<ConnectionEvent>,
+    events: &'static ConnectionEvents,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.events.subscribers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConnectionEvents {
+    fn new() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            subscribers: AtomicUsize::new(0),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    pub(crate) fn subscribe(&'static self) -> Subscription {
+        self.subscribers.fetch_add(1, Ordering::Relaxed);
+
+        Subscription {
+            receiver: self.sender.subscribe(),
+            events: self,
+        }
+    }
+
+    /// Starts tracking a connection, `None` when nobody is subscribed. Connections opened
+    /// before a subscription aren't tracked.
+    pub(crate) fn opened(
+        &'static self,
+        listener: &str,
+        protocol: ConnectionProtocol,
+        client: SocketAddr,
+    ) -> Option<TrackedConnection> {
+        if self.subscribers.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+
+        let connection = TrackedConnection {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            listener: listener.to_owned(),
+            client_to_upstream: Arc::default(),
+            upstream_to_client: Arc::default(),
+            events: self,
+        };
+
+        connection.send(ConnectionEventKind::Opened { protocol, client });
+
+        Some(connection)
+    }
+}
+
+/// Connection that's reported to the subscribers, it's reported closed when dropped
+pub(crate) struct TrackedConnection {
+    id: u64,
+    listener: String,
+    client_to_upstream: Arc<AtomicU64>,
+    upstream_to_client: Arc<AtomicU64>,
+    events: &'static ConnectionEvents,
+}
+
+impl TrackedConnection {
+    fn send(&self, kind: ConnectionEventKind) {
+        // Fails only when the last subscriber just left
+        let _ = self.events.sender.send(ConnectionEvent {
+            time: SystemTime::now(),
+            connection: self.id,
+            listener: self.listener.clone(),
+            kind,
+        });
+    }
+
+    pub(crate) fn backend_selected(&self, backend: &str) {
+        self.send(ConnectionEventKind::BackendSelected {
+            backend: backend.to_owned(),
+        });
+    }
+
+    /// Same counters that also count the bytes of the connection
+    pub(crate) fn relay_counters(&self, counters: RelayCounters) -> RelayCounters {
+        RelayCounters {
+            client_to_upstream: counters
+                .client_to_upstream
+                .with_connection(self.client_to_upstream.clone()),
+            upstream_to_client: counters
+                .upstream_to_client
+                .with_connection(self.upstream_to_client.clone()),
+        }
+    }
+
+    /// Counts what's read from the client and written to it, for connections that aren't
+    /// relayed with `RelayCounters`
+    pub(crate) fn count<S>(&self, stream: S) -> CountedStream<S> {
+        CountedStream {
+            stream,
+            read: self.client_to_upstream.clone(),
+            written: self.upstream_to_client.clone(),
+        }
+    }
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        self.send(ConnectionEventKind::Closed {
+            client_to_upstream: self.client_to_upstream.load(Ordering::Relaxed),
+            upstream_to_client: self.upstream_to_client.load(Ordering::Relaxed),
+        });
+    }
+}
+
+/// Client stream of a tracked connection
+pub(crate) struct CountedStream<S> {
+    stream: S,
+    read: Arc<AtomicU64>,
+    written: Arc<AtomicU64>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.stream).poll_read(cx, buf);
+
+        self.read
+            .fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+
+        polled
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut self.stream).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(written)) = polled {
+            self.written.fetch_add(written as u64, Ordering::Relaxed);
+        }
+
+        polled
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn events() -> &'static ConnectionEvents {
+        // Separate from the global one, other tests open connections concurrently
+        Box::leak(Box::new(ConnectionEvents::new()))
+    }
+
+    #[test]
+    fn connections_are_only_tracked_with_subscribers() {
+        let events = events();
+        let client = "127.0.0.1:5000".parse().unwrap();
+
+        assert!(events
+            .opened("tcp", ConnectionProtocol::Tcp, client)
+            .is_none());
+
+        let subscription = events.subscribe();
+
+        assert!(events
+            .opened("tcp", ConnectionProtocol::Tcp, client)
+            .is_some());
+
+        drop(subscription);
+
+        assert!(events
+            .opened("tcp", ConnectionProtocol::Tcp, client)
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn lifecycle_is_reported_with_bytes() {
+        let events = events();
+        let mut subscription = events.subscribe();
+        let client = "127.0.0.1:5000".parse().unwrap();
+
+        let connection = events
+            .opened("http", ConnectionProtocol::Http, client)
+            .unwrap();
+
+        connection.backend_selected("127.0.0.1:3000");
+
+        let (near, far) = tokio::io::duplex(64);
+        let mut counted = connection.count(near);
+        let mut far = far;
+
+        far.write_all(b"request").await.unwrap();
+        counted.read_exact(&mut [0; 7]).await.unwrap();
+        counted.write_all(b"response").await.unwrap();
+
+        drop(connection);
+
+        let kinds: Vec<_> = (0..3)
+            .map(|_| subscription.receiver.try_recv().unwrap().kind)
+            .collect();
+
+        assert_eq!(
+            kinds,
+            [
+                ConnectionEventKind::Opened {
+                    protocol: ConnectionProtocol::Http,
+                    client,
+                },
+                ConnectionEventKind::BackendSelected {
+                    backend: "127.0.0.1:3000".to_owned(),
+                },
+                ConnectionEventKind::Closed {
+                    client_to_upstream: 7,
+                    upstream_to_client: 8,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn slow_subscribers_skip_events() {
+        let events = events();
+        let mut subscription = events.subscribe();
+        let client = "127.0.0.1:5000".parse().unwrap();
+
+        // Opened and closed for each, more than the channel holds
+        for _ in 0..CAPACITY {
+            events.opened("udp", ConnectionProtocol::Udp, client);
+        }
+
+        assert!(matches!(
+            subscription.receiver.try_recv(),
+            Err(broadcast::error::TryRecvError::Lagged(_))
+        ));
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::BTreeSet;
+
+use serde_yaml::{Mapping, Value};
+
+use super::plane::control::{config_change::Kind, ConfigChange};
+
+/// Blocks that hold sections of named entries, those are compared entry by entry
+const BLOCKS: &[&str] = &["stream", "http"];
+
+/// Compares two configs serialized into YAML values.
+///
+/// Servers, routes and services are matched by name so that reordering them isn't reported,
+/// everything else is compared as a whole.
+pub(crate) fn diff(running: &Value, candidate: &Value) -> Vec<ConfigChange> {
+    let mut changes = vec![];
+
+    for key in keys(running, candidate) {
+        let (running, candidate) = (get(running, &key), get(candidate, &key));
+
+        if BLOCKS.contains(&key.as_str()) {
+            for section in keys(running, candidate) {
+                let (running, candidate) = (get(running, &section), get(candidate, &section));
+                let section = format!("{}.{}", key, section);
+
+                match (named(running), named(candidate)) {
+                    (Some(running), Some(candidate)) => {
+                        compare_named(&mut changes, &section, &running, &candidate)
+                    }
+                    _ => compare(&mut changes, &key, &section, running, candidate),
+                }
+            }
+        } else {
+            compare(&mut changes, "", &key, running, candidate);
+        }
+    }
+
+    changes
+}
+
+fn compare(
+    changes: &mut Vec<ConfigChange>,
+    section: &str,
+    name: &str,
+    running: &Value,
+    candidate: &Value,
+) {
+    let kind = match (running.is_null(), candidate.is_null()) {
+        (true, true) => return,
+        (true, false) => Kind::Added,
+        (false, true) => Kind::Removed,
+        (false, false) if running == candidate => return,
+        (false, false) => Kind::Changed,
+    };
+
+    changes.push(ConfigChange {
+        kind: kind as i32,
+        section: section.to_owned(),
+        name: name.to_owned(),
+    });
+}
+
+fn compare_named(
+    changes: &mut Vec<ConfigChange>,
+    section: &str,
+    running: &Mapping,
+    candidate: &Mapping,
+) {
+    let names: BTreeSet<&str> = running
+        .keys()
+        .chain(candidate.keys())
+        .filter_map(Value::as_str)
+        .collect();
+
+    for name in names {
+        compare(
+            changes,
+            section,
+            name,
+            running.get(name).unwrap_or(&Value::Null),
+            candidate.get(name).unwrap_or(&Value::Null),
+        );
+    }
+}
+
+/// Entries of a section by name, lists are keyed by the `name` field of their items
+fn named(section: &Value) -> Option<Mapping> {
+    match section {
+        Value::Null => Some(Mapping::new()),
+        Value::Mapping(mapping) => Some(mapping.clone()),
+        Value::Sequence(items) => items
+            .iter()
+            .map(|item| Some((item.get("name")?.clone(), item.clone())))
+            .collect(),
+        _ => None,
+    }
+}
+
+fn keys(first: &Value, second: &Value) -> BTreeSet<String> {
+    [first, second]
+        .into_iter()
+        .filter_map(Value::as_mapping)
+        .flat_map(Mapping::keys)
+        .filter_map(Value::as_str)
+        .map(str::to_owned)
+        .collect()
+}
+
+fn get<'a>(value: &'a Value, key: &str) -> &'a Value {
+    value.get(key).unwrap_or(&Value::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(kind: Kind, section: &str, name: &str) -> ConfigChange {
+        ConfigChange {
+            kind: kind as i32,
+            section: section.to_owned(),
+            name: name.to_owned(),
+        }
+    }
+
+    fn yaml(contents: &str) -> Value {
+        serde_yaml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn same_config_has_no_changes() {
+        let config = yaml(include_str!("../../fixtures/combined.yaml"));
+
+        assert!(diff(&config, &config).is_empty());
+    }
+
+    #[test]
+    fn named_entries_are_matched_by_name() {
+        let running = yaml(
+            "
+            http:
+              servers:
+                - { name: a, port: 80 }
+                - { name: b, port: 81 }
+              services:
+                one: { backends: [] }
+            ",
+        );
+        let candidate = yaml(
+            "
+            http:
+              servers:
+                - { name: b, port: 82 }
+                - { name: c, port: 83 }
+              services:
+                one: { backends: [] }
+                two: { backends: [] }
+            tracing:
+              otlp-endpoint: http://localhost:4317
+            ",
+        );
+
+        assert_eq!(
+            diff(&running, &candidate),
+            vec![
+                change(Kind::Removed, "http.servers", "a"),
+                change(Kind::Changed, "http.servers", "b"),
+                change(Kind::Added, "http.servers", "c"),
+                change(Kind::Added, "http.services", "two"),
+                change(Kind::Added, "", "tracing"),
+            ]
+        );
+    }
+
+    #[test]
+    fn reordering_is_not_a_change() {
+        let running = yaml("stream: { servers: [{ name: a }, { name: b }] }");
+        let candidate = yaml("stream: { servers: [{ name: b }, { name: a }] }");
+
+        assert!(diff(&running, &candidate).is_empty());
+    }
+
+    #[test]
+    fn removed_block_removes_its_entries() {
+        let running = yaml("stream: { servers: [{ name: a }], services: { s: {} } }");
+        let candidate = yaml("stream: null");
+
+        assert_eq!(
+            diff(&running, &candidate),
+            vec![
+                change(Kind::Removed, "stream.servers", "a"),
+                change(Kind::Removed, "stream.services", "s"),
+            ]
+        );
+    }
+}
@@ -1,16 +1,23 @@
+pub(crate) mod diff;
 pub(crate) mod plane;
 
 use plane::control::control_server::ControlServer;
 use plane::MyControl;
 use tonic::transport::Server;
 
-pub(crate) async fn run_grpc() -> Result<(), Box<dyn std::error::Error>> {
+use crate::shutdown::Shutdown;
+
+/// `running_config` is the config the proxy was started with, serialized back to YAML
+pub(crate) async fn run_grpc(
+    running_config: serde_yaml::Value,
+    shutdown: Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
     let addr = "[::1]:50005".parse()?;
-    let greeter = MyControl;
+    let greeter = MyControl::new(running_config);
 
     Server::builder()
         .add_service(ControlServer::new(greeter))
-        .serve(addr)
+        .serve_with_shutdown(addr, shutdown)
         .await?;
 
     Ok(())
@@ -1,28 +1,214 @@
+use std::time::UNIX_EPOCH;
+
 use control::{
-    control_server::{Control, ControlServer},
-    GetConfigReply, GetConfigRequest,
+    connection_event::{self, BackendSelected, Closed, Opened},
+    control_server::Control,
+    ConnectionEvent as ConnectionEventEntry, DiffConfigReply, DiffConfigRequest, GetConfigReply,
+    GetConfigRequest, GetMetricsReply, GetMetricsRequest, RequestLogEntry, TailRequestsRequest,
+    WatchConnectionsRequest,
+};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
 };
+use tokio::sync::broadcast::{error::RecvError, Receiver};
 use tonic::{Request, Response, Status};
 
+use crate::{
+    connection_events::{
+        events, ConnectionEvent, ConnectionEventKind, ConnectionProtocol, Subscription,
+    },
+    metrics::metrics,
+    request_log::{request_log, RequestRecord},
+    server,
+};
+
+use super::diff::diff;
+
 pub mod control {
     tonic::include_proto!("control");
 }
 
-#[derive(Debug, Default)]
-pub struct MyControl;
+#[derive(Debug)]
+pub struct MyControl {
+    running_config: serde_yaml::Value,
+}
+
+impl MyControl {
+    pub(crate) fn new(running_config: serde_yaml::Value) -> Self {
+        Self { running_config }
+    }
+}
+
+impl From<RequestRecord> for RequestLogEntry {
+    fn from(record: RequestRecord) -> Self {
+        Self {
+            timestamp_ms: record
+                .time
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            listener: record.listener,
+            method: record.method.to_string(),
+            path: record.path,
+            route: record.route.unwrap_or_default(),
+            backend: record.backend.unwrap_or_default(),
+            status: record.status.as_u16().into(),
+            latency_ms: record.latency.as_millis() as u64,
+        }
+    }
+}
+
+impl From<ConnectionEvent> for ConnectionEventEntry {
+    fn from(event: ConnectionEvent) -> Self {
+        let kind = match event.kind {
+            ConnectionEventKind::Opened { protocol, client } => {
+                let protocol = match protocol {
+                    ConnectionProtocol::Http => connection_event::Protocol::Http,
+                    ConnectionProtocol::Tcp => connection_event::Protocol::Tcp,
+                    ConnectionProtocol::Udp => connection_event::Protocol::Udp,
+                };
+
+                connection_event::Kind::Opened(Opened {
+                    protocol: protocol.into(),
+                    client: client.to_string(),
+                })
+            }
+            ConnectionEventKind::BackendSelected { backend } => {
+                connection_event::Kind::BackendSelected(BackendSelected { backend })
+            }
+            ConnectionEventKind::Closed {
+                client_to_upstream,
+                upstream_to_client,
+            } => connection_event::Kind::Closed(Closed {
+                client_to_upstream_bytes: client_to_upstream,
+                upstream_to_client_bytes: upstream_to_client,
+            }),
+        };
+
+        Self {
+            timestamp_ms: event
+                .time
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            connection_id: event.connection,
+            listener: event.listener,
+            kind: Some(kind),
+        }
+    }
+}
+
+/// Events as they happen, connections stop being tracked once the watcher hangs up and the
+/// stream along with the subscription is dropped
+fn live_events(subscription: Subscription) -> BoxStream<'static, ConnectionEvent> {
+    stream::unfold(subscription, |mut subscription| async move {
+        loop {
+            match subscription.receiver.recv().await {
+                Ok(event) => return Some((event, subscription)),
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Connection watcher fell behind, skipping events");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Requests as they're recorded, until the log goes away. A follower too slow to keep up
+/// skips what it missed.
+fn live_requests(receiver: Receiver<RequestRecord>) -> BoxStream<'static, RequestRecord> {
+    stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(record) => return Some((record, receiver)),
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!(
+                        skipped,
+                        "Request log follower fell behind, skipping requests"
+                    );
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .boxed()
+}
 
 #[tonic::async_trait]
 impl Control for MyControl {
+    type TailRequestsStream = BoxStream<'static, Result<RequestLogEntry, Status>>;
+    type WatchConnectionsStream = BoxStream<'static, Result<ConnectionEventEntry, Status>>;
+
     async fn get_config(
         &self,
         request: Request<GetConfigRequest>,
     ) -> Result<Response<GetConfigReply>, Status> {
-        println!("Got a request: {:?}", request);
+        tracing::debug!(request = ?request, "Got a config request");
+
+        let contents = serde_yaml::to_string(&self.running_config)
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(GetConfigReply { contents }))
+    }
+
+    async fn diff_config(
+        &self,
+        request: Request<DiffConfigRequest>,
+    ) -> Result<Response<DiffConfigReply>, Status> {
+        // Going through the config types both validates the candidate and normalizes it the same
+        // way the running config was
+        let candidate: server::Config = serde_yaml::from_str(&request.into_inner().contents)
+            .map_err(|err| Status::invalid_argument(format!("Invalid config: {}", err)))?;
+
+        candidate
+            .validate()
+            .map_err(|err| Status::invalid_argument(format!("Invalid config: {}", err)))?;
+
+        let candidate =
+            serde_yaml::to_value(&candidate).map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(DiffConfigReply {
+            changes: diff(&self.running_config, &candidate),
+        }))
+    }
 
-        let config = GetConfigReply {
-            contents: "No config yet, amateur".to_owned(),
+    async fn get_metrics(
+        &self,
+        _request: Request<GetMetricsRequest>,
+    ) -> Result<Response<GetMetricsReply>, Status> {
+        Ok(Response::new(GetMetricsReply {
+            contents: metrics().render(),
+        }))
+    }
+
+    async fn tail_requests(
+        &self,
+        request: Request<TailRequestsRequest>,
+    ) -> Result<Response<Self::TailRequestsStream>, Status> {
+        let log = request_log()
+            .ok_or_else(|| Status::failed_precondition("Request log is not enabled"))?;
+
+        let (recent, live) = log.follow();
+
+        let records = if request.into_inner().follow {
+            stream::iter(recent).chain(live_requests(live)).boxed()
+        } else {
+            stream::iter(recent).boxed()
         };
 
-        Ok(Response::new(config))
+        Ok(Response::new(
+            records.map(RequestLogEntry::from).map(Ok).boxed(),
+        ))
+    }
+
+    async fn watch_connections(
+        &self,
+        _request: Request<WatchConnectionsRequest>,
+    ) -> Result<Response<Self::WatchConnectionsStream>, Status> {
+        let events = live_events(events().subscribe());
+
+        Ok(Response::new(
+            events.map(ConnectionEventEntry::from).map(Ok).boxed(),
+        ))
     }
 }
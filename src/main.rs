@@ -1,19 +1,32 @@
 // TODO: break this file down
 pub(crate) mod cli;
 
+mod admin;
+mod connection_events;
 mod control;
+mod metrics;
 mod protocol;
+mod request_log;
 mod server;
 mod service;
+mod shutdown;
+mod telemetry;
+#[cfg(test)]
+mod test_helpers;
 
 use clap::Parser;
 use cli::Args;
 use futures::{future::OptionFuture, join};
-use server::{http::cluster::HttpServerCluster, stream::cluster::StreamServerCluster};
+use std::time::{Duration, Instant};
+
+use server::{
+    http::{cluster::HttpServerCluster, fail_closed::Readiness},
+    stream::cluster::StreamServerCluster,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+    let started = Instant::now();
 
     let args = Args::parse();
 
@@ -23,22 +36,79 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config: server::Config =
         serde_yaml::from_str(&config_contents).expect("Failed to parse config file");
 
-    println!("{:#?}", config);
+    config.validate().expect("Invalid config");
+
+    telemetry::init(config.tracing.as_ref())?;
+    request_log::init(config.request_log.as_ref());
+    service::resolver::init(config.dns.as_ref());
+
+    tracing::debug!(config = ?config, "Loaded config");
+
+    let running_config = serde_yaml::to_value(&config)?;
 
-    let server::Config { stream, http } = config;
+    // Shared with the listeners, so reloading them through the admin listener reaches both
+    let certificates = config
+        .http
+        .iter()
+        .flat_map(|http| &http.servers)
+        .filter_map(|server| server.tls.clone())
+        .collect();
+
+    let server::Config {
+        stream,
+        http,
+        admin,
+        shutdown: shutdown_config,
+        ..
+    } = config;
+
+    let grace_period: Duration = shutdown_config.unwrap_or_default().grace_period.into();
+    let shutdown = shutdown::on_signal();
 
     let stream_cluster: OptionFuture<_> = stream
         .map(StreamServerCluster::from_config)
-        .map(StreamServerCluster::run_all)
+        .map(|cluster| cluster.run_all(shutdown.clone()))
         .into();
+    // Servers that fail closed report it to the admin listener
+    let readiness = Readiness::default();
+
     let http_cluster: OptionFuture<_> = http
-        .map(HttpServerCluster::from_config)
-        .map(HttpServerCluster::run_all)
+        .map(|http| HttpServerCluster::from_config(http, &readiness))
+        .transpose()?
+        .map(|cluster| cluster.run_all(shutdown.clone()))
         .into();
 
-    let control_server = control::run_grpc();
+    let admin_server: OptionFuture<_> = admin
+        .map(|admin| admin::run(admin, started, certificates, readiness, shutdown.clone()))
+        .into();
+
+    let control_server = control::run_grpc(running_config, shutdown.clone());
+
+    let servers = async { join!(stream_cluster, http_cluster, admin_server, control_server) };
+
+    // The listeners stop accepting on shutdown, the connections left after the grace period
+    // are dropped along with the process
+    let grace_period_over = async {
+        shutdown.await;
+        tokio::time::sleep(grace_period).await;
+    };
+
+    let control_server = tokio::select! {
+        (_, _, admin_server, control_server) = servers => {
+            if let Some(Err(err)) = admin_server {
+                tracing::error!(error = %err, "Admin listener failed");
+            }
+
+            control_server
+        }
+        _ = grace_period_over => {
+            tracing::warn!("Grace period is over, dropping the connections left");
+
+            Ok(())
+        }
+    };
 
-    join!(stream_cluster, http_cluster, control_server);
+    telemetry::shutdown();
 
-    Ok(())
+    control_server
 }
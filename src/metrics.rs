@@ -0,0 +1,461 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock,
+    },
+    time::Duration,
+};
+
+use http::StatusCode;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+/// Route label for requests that didn't match any route
+const NO_ROUTE: &str = "none";
+
+/// Prometheus metrics of the whole proxy.
+///
+/// Label values only ever come from the config (listener, route and service names, backend
+/// addresses) and never from requests, so the number of series stays bounded no matter what
+/// clients send. Status codes are reduced to their class for the same reason.
+pub(crate) struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    route_match_duration: HistogramVec,
+    route_rules_evaluated: IntCounterVec,
+    rule_match_duration: HistogramVec,
+    backend_connections: IntCounterVec,
+    backend_responses: IntCounterVec,
+    backend_response_duration: HistogramVec,
+    stream_connections: IntCounterVec,
+    stream_backend_connections: IntCounterVec,
+    relay_bytes: IntCounterVec,
+    udp_backend_errors: IntCounterVec,
+}
+
+/// Byte counters of a single listener, cloned into every relay it runs
+#[derive(Clone)]
+pub(crate) struct RelayCounters {
+    pub(crate) client_to_upstream: ByteCounter,
+    pub(crate) upstream_to_client: ByteCounter,
+}
+
+/// Bytes relayed in one direction by a listener, and by a single connection when its events
+/// are followed
+#[derive(Clone)]
+pub(crate) struct ByteCounter {
+    listener: IntCounter,
+    connection: Option<Arc<AtomicU64>>,
+}
+
+impl ByteCounter {
+    pub(crate) fn inc_by(&self, bytes: u64) {
+        self.listener.inc_by(bytes);
+
+        if let Some(connection) = &self.connection {
+            connection.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Bytes of the whole listener
+    #[cfg(test)]
+    pub(crate) fn get(&self) -> u64 {
+        self.listener.get()
+    }
+
+    pub(crate) fn with_connection(self, connection: Arc<AtomicU64>) -> Self {
+        Self {
+            connection: Some(connection),
+            ..self
+        }
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub(crate) fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("bifrost".to_owned()), None)
+            .expect("Failed to create metrics registry");
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled"),
+            &["listener", "route", "status"],
+        )
+        .expect("Invalid metric");
+
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time from receiving an HTTP request to having the response headers",
+            ),
+            &["listener", "route"],
+        )
+        .expect("Invalid metric");
+
+        // Matching takes micro- rather than milliseconds, the default buckets start at 5ms
+        let match_buckets =
+            prometheus::exponential_buckets(0.000_001, 4.0, 10).expect("Invalid buckets");
+
+        let route_match_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "route_match_duration_seconds",
+                "Time spent finding the route and rule of an HTTP request",
+            )
+            .buckets(match_buckets.clone()),
+            &["listener", "route"],
+        )
+        .expect("Invalid metric");
+
+        let route_rules_evaluated = IntCounterVec::new(
+            Opts::new(
+                "route_rules_evaluated_total",
+                "Rules evaluated while looking for the one matching a request",
+            ),
+            &["listener", "route"],
+        )
+        .expect("Invalid metric");
+
+        let rule_match_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "rule_match_duration_seconds",
+                "Time spent evaluating the matchers of a single rule",
+            )
+            .buckets(match_buckets),
+            &["listener", "route", "rule"],
+        )
+        .expect("Invalid metric");
+
+        let backend_connections = IntCounterVec::new(
+            Opts::new(
+                "backend_connections_total",
+                "Connection attempts to backends",
+            ),
+            &["service", "backend", "result"],
+        )
+        .expect("Invalid metric");
+
+        let backend_responses = IntCounterVec::new(
+            Opts::new(
+                "backend_responses_total",
+                "Responses from backends, failed and timed out requests count as 502 and 504",
+            ),
+            &["service", "backend", "status"],
+        )
+        .expect("Invalid metric");
+
+        let backend_response_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "backend_response_duration_seconds",
+                "Time from sending a request to a backend to having its response headers",
+            ),
+            &["service", "backend"],
+        )
+        .expect("Invalid metric");
+
+        let stream_connections = IntCounterVec::new(
+            Opts::new(
+                "stream_connections_total",
+                "TCP connections and UDP sessions accepted by stream listeners",
+            ),
+            &["listener", "service"],
+        )
+        .expect("Invalid metric");
+
+        let stream_backend_connections = IntCounterVec::new(
+            Opts::new(
+                "stream_backend_connections_total",
+                "TCP connections and UDP sessions relayed to each stream backend",
+            ),
+            &["service", "backend"],
+        )
+        .expect("Invalid metric");
+
+        let relay_bytes = IntCounterVec::new(
+            Opts::new(
+                "relay_bytes_total",
+                "Bytes relayed by TCP and UDP listeners",
+            ),
+            &["listener", "direction"],
+        )
+        .expect("Invalid metric");
+
+        let udp_backend_errors = IntCounterVec::new(
+            Opts::new(
+                "udp_backend_errors_total",
+                "Errors sending to or receiving from UDP backends, e.g. ICMP port unreachable",
+            ),
+            &["service", "backend"],
+        )
+        .expect("Invalid metric");
+
+        for collector in [
+            Box::new(http_requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(http_request_duration.clone()),
+            Box::new(route_match_duration.clone()),
+            Box::new(route_rules_evaluated.clone()),
+            Box::new(rule_match_duration.clone()),
+            Box::new(backend_connections.clone()),
+            Box::new(backend_responses.clone()),
+            Box::new(backend_response_duration.clone()),
+            Box::new(stream_connections.clone()),
+            Box::new(stream_backend_connections.clone()),
+            Box::new(relay_bytes.clone()),
+            Box::new(udp_backend_errors.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("Metric registered twice");
+        }
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            route_match_duration,
+            route_rules_evaluated,
+            rule_match_duration,
+            backend_connections,
+            backend_responses,
+            backend_response_duration,
+            stream_connections,
+            stream_backend_connections,
+            relay_bytes,
+            udp_backend_errors,
+        }
+    }
+
+    pub(crate) fn http_request(
+        &self,
+        listener: &str,
+        route: Option<&str>,
+        status: StatusCode,
+        elapsed: Duration,
+    ) {
+        let route = route.unwrap_or(NO_ROUTE);
+
+        self.http_requests
+            .with_label_values(&[listener, route, status_class(status)])
+            .inc();
+        self.http_request_duration
+            .with_label_values(&[listener, route])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn route_match(
+        &self,
+        listener: &str,
+        route: Option<&str>,
+        rules_evaluated: usize,
+        elapsed: Duration,
+    ) {
+        let route = route.unwrap_or(NO_ROUTE);
+
+        self.route_match_duration
+            .with_label_values(&[listener, route])
+            .observe(elapsed.as_secs_f64());
+        self.route_rules_evaluated
+            .with_label_values(&[listener, route])
+            .inc_by(rules_evaluated as u64);
+    }
+
+    pub(crate) fn rule_match(&self, listener: &str, route: &str, rule: &str, elapsed: Duration) {
+        self.rule_match_duration
+            .with_label_values(&[listener, route, rule])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn backend_connection(&self, service: &str, backend: &str, succeeded: bool) {
+        let result = if succeeded { "ok" } else { "error" };
+
+        self.backend_connections
+            .with_label_values(&[service, backend, result])
+            .inc();
+    }
+
+    pub(crate) fn backend_response(&self, service: &str, backend: &str, status: StatusCode) {
+        self.backend_responses
+            .with_label_values(&[service, backend, status_class(status)])
+            .inc();
+    }
+
+    /// Only for responses the backend sent, failures are counted by `backend_response` alone
+    pub(crate) fn backend_response_time(&self, service: &str, backend: &str, elapsed: Duration) {
+        self.backend_response_duration
+            .with_label_values(&[service, backend])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Requests a backend got so far and how many of them failed, either with a 5xx or by not
+    /// accepting the connection at all
+    pub(crate) fn backend_outcomes(&self, service: &str, backend: &str) -> (u64, u64) {
+        let responses = |status| {
+            self.backend_responses
+                .with_label_values(&[service, backend, status])
+                .get()
+        };
+        let refused = self
+            .backend_connections
+            .with_label_values(&[service, backend, "error"])
+            .get();
+
+        let succeeded: u64 = ["1xx", "2xx", "3xx", "4xx"]
+            .into_iter()
+            .map(responses)
+            .sum();
+        let failed = responses("5xx") + refused;
+
+        (succeeded + failed, failed)
+    }
+
+    pub(crate) fn stream_connection(&self, listener: &str, service: &str, backend: &str) {
+        self.stream_connections
+            .with_label_values(&[listener, service])
+            .inc();
+        self.stream_backend_connections
+            .with_label_values(&[service, backend])
+            .inc();
+    }
+
+    pub(crate) fn relay_counters(&self, listener: &str) -> RelayCounters {
+        let counter = |direction| ByteCounter {
+            listener: self.relay_bytes.with_label_values(&[listener, direction]),
+            connection: None,
+        };
+
+        RelayCounters {
+            client_to_upstream: counter("client-to-upstream"),
+            upstream_to_client: counter("upstream-to-client"),
+        }
+    }
+
+    pub(crate) fn udp_backend_errors(&self, service: &str, backend: &str) -> IntCounter {
+        self.udp_backend_errors
+            .with_label_values(&[service, backend])
+    }
+
+    /// All metrics in the Prometheus text format
+    pub(crate) fn render(&self) -> String {
+        let mut buffer = vec![];
+
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Failed to encode metrics");
+
+        String::from_utf8(buffer).expect("Metrics are always UTF-8")
+    }
+}
+
+fn status_class(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_labeled_by_listener_and_route() {
+        let metrics = Metrics::new();
+
+        metrics.http_request(
+            "http-1",
+            Some("api"),
+            StatusCode::CREATED,
+            Duration::from_millis(5),
+        );
+        metrics.http_request(
+            "http-1",
+            None,
+            StatusCode::NOT_FOUND,
+            Duration::from_millis(1),
+        );
+        metrics.backend_connection("api-service", "127.0.0.1:3000", false);
+
+        let rendered = metrics.render();
+
+        assert!(rendered.contains(
+            r#"bifrost_http_requests_total{listener="http-1",route="api",status="2xx"} 1"#
+        ));
+        assert!(rendered.contains(
+            r#"bifrost_http_requests_total{listener="http-1",route="none",status="4xx"} 1"#
+        ));
+        assert!(rendered.contains(
+            r#"bifrost_backend_connections_total{backend="127.0.0.1:3000",result="error",service="api-service"} 1"#
+        ));
+    }
+
+    #[test]
+    fn route_matching_counts_evaluated_rules() {
+        let metrics = Metrics::new();
+
+        metrics.route_match("http-1", Some("api"), 3, Duration::from_micros(20));
+        metrics.route_match("http-1", Some("api"), 1, Duration::from_micros(5));
+        metrics.route_match("http-1", None, 0, Duration::from_micros(1));
+
+        let rendered = metrics.render();
+
+        assert!(rendered
+            .contains(r#"bifrost_route_rules_evaluated_total{listener="http-1",route="api"} 4"#));
+        assert!(rendered.contains(
+            r#"bifrost_route_match_duration_seconds_count{listener="http-1",route="none"} 1"#
+        ));
+        assert!(rendered.contains(
+            r#"bifrost_route_match_duration_seconds_bucket{listener="http-1",route="api",le="0.000016"} 1"#
+        ));
+    }
+
+    #[test]
+    fn backend_outcomes_count_refused_connections_as_errors() {
+        let metrics = Metrics::new();
+
+        metrics.backend_response("api", "127.0.0.1:3000", StatusCode::OK);
+        metrics.backend_response("api", "127.0.0.1:3000", StatusCode::NOT_FOUND);
+        metrics.backend_response("api", "127.0.0.1:3000", StatusCode::BAD_GATEWAY);
+        metrics.backend_connection("api", "127.0.0.1:3000", false);
+        metrics.backend_response("api", "127.0.0.1:3001", StatusCode::OK);
+
+        assert_eq!(metrics.backend_outcomes("api", "127.0.0.1:3000"), (4, 2));
+    }
+
+    #[test]
+    fn stream_connections_are_counted_per_backend() {
+        let metrics = Metrics::new();
+
+        metrics.stream_connection("tcp-1", "db", "10.0.0.1:5432");
+        metrics.stream_connection("tcp-2", "db", "10.0.0.1:5432");
+        metrics.stream_connection("tcp-1", "db", "10.0.0.2:5432");
+
+        let rendered = metrics.render();
+
+        assert!(rendered
+            .contains(r#"bifrost_stream_connections_total{listener="tcp-1",service="db"} 2"#));
+        assert!(rendered.contains(
+            r#"bifrost_stream_backend_connections_total{backend="10.0.0.1:5432",service="db"} 2"#
+        ));
+    }
+
+    #[test]
+    fn relay_counters_share_series_per_listener() {
+        let metrics = Metrics::new();
+
+        metrics.relay_counters("tcp").client_to_upstream.inc_by(10);
+        metrics.relay_counters("tcp").client_to_upstream.inc_by(5);
+
+        assert!(metrics.render().contains(
+            r#"bifrost_relay_bytes_total{direction="client-to-upstream",listener="tcp"} 15"#
+        ));
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use http::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+/// Most requests the log can be configured to keep
+pub(crate) const MAX_CAPACITY: usize = 100_000;
+
+/// Opt-in log of the most recent HTTP requests, operators read it through the control plane
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RequestLogConfig {
+    /// How many requests to keep, older ones are dropped. At most 100000
+    #[serde(default = "RequestLogConfig::default_capacity")]
+    pub(crate) capacity: usize,
+}
+
+impl RequestLogConfig {
+    fn default_capacity() -> usize {
+        1000
+    }
+}
+
+/// A request handled by an HTTP server
+#[derive(Debug, Clone)]
+pub(crate) struct RequestRecord {
+    pub(crate) time: SystemTime,
+    pub(crate) listener: String,
+    pub(crate) method: Method,
+    pub(crate) path: String,
+    pub(crate) route: Option<String>,
+    /// `host:port` of the backend, `None` when the request didn't reach one
+    pub(crate) backend: Option<String>,
+    pub(crate) status: StatusCode,
+    pub(crate) latency: Duration,
+}
+
+/// Ring buffer of the recent requests, followers get new ones as they're recorded
+pub(crate) struct RequestLog {
+    capacity: usize,
+    recent: Mutex<VecDeque<RequestRecord>>,
+    live: broadcast::Sender<RequestRecord>,
+}
+
+static REQUEST_LOG: OnceLock<RequestLog> = OnceLock::new();
+
+/// Enables the log when it's configured, has to be called before any server starts
+pub(crate) fn init(config: Option<&RequestLogConfig>) {
+    if let Some(config) = config {
+        REQUEST_LOG.get_or_init(|| RequestLog::new(config.capacity));
+    }
+}
+
+/// `None` when the log isn't enabled
+pub(crate) fn request_log() -> Option<&'static RequestLog> {
+    REQUEST_LOG.get()
+}
+
+impl RequestLog {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            recent: Mutex::new(VecDeque::with_capacity(capacity)),
+            // Followers that fall this far behind skip what they missed
+            live: broadcast::channel(capacity.max(1)).0,
+        }
+    }
+
+    pub(crate) fn record(&self, record: RequestRecord) {
+        let mut recent = self.recent.lock().expect("Request log lock poisoned");
+
+        if recent.len() == self.capacity {
+            recent.pop_front();
+        }
+
+        recent.push_back(record.clone());
+
+        // Fails only when nobody is following
+        let _ = self.live.send(record);
+    }
+
+    /// Recent requests, oldest first, and a receiver of the ones recorded after them
+    pub(crate) fn follow(&self) -> (Vec<RequestRecord>, broadcast::Receiver<RequestRecord>) {
+        let recent = self.recent.lock().expect("Request log lock poisoned");
+
+        // Subscribing under the lock so no request is missed or seen twice
+        (recent.iter().cloned().collect(), self.live.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(path: &str) -> RequestRecord {
+        RequestRecord {
+            time: SystemTime::now(),
+            listener: "http-1".to_owned(),
+            method: Method::GET,
+            path: path.to_owned(),
+            route: Some("api".to_owned()),
+            backend: Some("127.0.0.1:3000".to_owned()),
+            status: StatusCode::OK,
+            latency: Duration::from_millis(3),
+        }
+    }
+
+    fn paths(records: &[RequestRecord]) -> Vec<&str> {
+        records.iter().map(|record| record.path.as_str()).collect()
+    }
+
+    #[test]
+    fn keeps_only_the_most_recent_requests() {
+        let log = RequestLog::new(2);
+
+        for path in ["/1", "/2", "/3"] {
+            log.record(record(path));
+        }
+
+        assert_eq!(paths(&log.follow().0), ["/2", "/3"]);
+    }
+
+    #[tokio::test]
+    async fn followers_get_new_requests() {
+        let log = RequestLog::new(2);
+
+        log.record(record("/old"));
+
+        let (recent, mut live) = log.follow();
+
+        log.record(record("/new"));
+
+        assert_eq!(paths(&recent), ["/old"]);
+        assert_eq!(live.recv().await.unwrap().path, "/new");
+    }
+}
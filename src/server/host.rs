@@ -1,5 +1,6 @@
 use std::net::IpAddr;
 use std::str::FromStr;
+use std::sync::LazyLock;
 
 use regex::Regex;
 use serde::de::{Deserializer, Visitor};
@@ -7,6 +8,11 @@ use serde::{Deserialize, Serialize, Serializer};
 
 use derive_more::Display;
 
+/// Compiled once, hostnames are parsed for every route and may be parsed per request
+static HOST_LABEL: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^[a-z0-9]([a-z0-9-]*[a-z0-9])?$").expect("Invalid host label regex")
+});
+
 #[derive(Debug, Display)]
 #[display(fmt = "{} {:?}", wildcard, labels)]
 pub(crate) struct HostSpec {
@@ -46,8 +52,6 @@ impl FromStr for HostSpec {
             return Err(HostSpecParseError::UnexpectedIp);
         }
 
-        let host_label_regex: Regex = Regex::new(r"^[a-z0-9]([a-z0-9-]*[a-z0-9])?$").unwrap();
-
         if value.is_empty() {
             return Err(HostSpecParseError::EmptyStr);
         }
@@ -68,7 +72,7 @@ impl FromStr for HostSpec {
             if label == "*" {
                 wildcard = true;
             } else {
-                if !host_label_regex.is_match(label) {
+                if !HOST_LABEL.is_match(label) {
                     return Err(HostSpecParseError::InvalidLabel);
                 }
 
@@ -173,7 +177,7 @@ impl From<HostSpecParseError> for HostnameParseError {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct Hostname {
     labels: Vec<String>,
 }
@@ -194,6 +198,58 @@ impl FromStr for Hostname {
     }
 }
 
+impl std::fmt::Display for Hostname {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.stringify())
+    }
+}
+
+impl Hostname {
+    fn stringify(&self) -> String {
+        self.labels
+            .iter()
+            .rev()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+}
+
+struct HostnameVisitor;
+
+impl<'de> Visitor<'de> for HostnameVisitor {
+    type Value = Hostname;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a valid RFC 1123 hostname without wildcards")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Hostname::from_str(value).map_err(|err| serde::de::Error::custom(format!("{:?}", err)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Hostname {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_string(HostnameVisitor)
+    }
+}
+
+impl Serialize for Hostname {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.stringify())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -377,4 +433,45 @@ mod tests {
 
         assert!(!host_spec.matches(&hostname))
     }
+
+    /// Compares the shared regex against compiling it for every hostname, the way it was done
+    /// before, run with `cargo test --release host_spec_benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn host_spec_benchmark() {
+        const HOSTNAMES: usize = 10_000;
+
+        let hostnames: Vec<_> = (0..HOSTNAMES)
+            .map(|index| match index % 2 {
+                0 => format!("service-{}.api.example.com", index),
+                _ => format!("*.tenant-{}.example.com", index),
+            })
+            .collect();
+
+        let started = std::time::Instant::now();
+        for hostname in &hostnames {
+            let host_label = Regex::new(HOST_LABEL.as_str()).unwrap();
+
+            std::hint::black_box(
+                hostname
+                    .split('.')
+                    .filter(|label| *label != "*")
+                    .all(|label| host_label.is_match(label)),
+            );
+        }
+        let compiled_per_hostname = started.elapsed();
+
+        let started = std::time::Instant::now();
+        for hostname in &hostnames {
+            std::hint::black_box(HostSpec::from_str(hostname).unwrap());
+        }
+        let shared = started.elapsed();
+
+        println!(
+            "{} hostnames: regex compiled per hostname {:?}, shared {:?}",
+            HOSTNAMES, compiled_per_hostname, shared
+        );
+
+        assert!(shared < compiled_per_hostname);
+    }
 }
@@ -0,0 +1,171 @@
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use tokio::task::AbortHandle;
+
+use super::{backend_set::InFlightRequest, pool::Http1Connection};
+
+/// Body of a backend response, streamed to the client as it arrives.
+///
+/// When the client goes away mid-response hyper drops the body before its end. The backend
+/// connection is closed then, so the rest of the response isn't pulled for nobody and the
+/// connection, which is in the middle of a response, is never reused.
+pub(crate) struct BackendBody {
+    body: Incoming,
+    /// Task driving the backend connection, not set for streams of a shared HTTP/2 connection,
+    /// which are reset on their own when they're dropped
+    connection: Option<AbortHandle>,
+    finished: bool,
+    /// Released when the body ends, not when hyper gets around to dropping it
+    in_flight: Option<InFlightRequest>,
+    /// Goes back to its pool when the body ends
+    pooled: Option<Http1Connection>,
+}
+
+impl BackendBody {
+    pub(crate) fn new(body: Incoming, connection: Option<AbortHandle>) -> Self {
+        Self {
+            body,
+            connection,
+            finished: false,
+            in_flight: None,
+            pooled: None,
+        }
+    }
+
+    /// Keeps the request counted as in flight to the backend until the body ends
+    pub(crate) fn holding(mut self, in_flight: InFlightRequest) -> Self {
+        self.in_flight = Some(in_flight);
+        self
+    }
+
+    /// Releases the connection the response came on once the body ends
+    pub(crate) fn returning(mut self, connection: Http1Connection) -> Self {
+        self.pooled = Some(connection);
+        self
+    }
+
+    fn finish(&mut self) {
+        self.finished = true;
+        self.in_flight = None;
+
+        if let Some(connection) = self.pooled.take() {
+            connection.release();
+        }
+    }
+}
+
+impl Body for BackendBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.body).poll_frame(cx));
+
+        if frame.is_none() {
+            self.finish();
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Backend connection of a request still waiting for its response. It's closed when the wait is
+/// given up, e.g. on a timeout, so the backend stops working on a request nobody waits for and
+/// the connection, which is in the middle of a request, is never reused.
+pub(crate) struct PendingResponse {
+    /// Task driving the backend connection
+    connection: Option<AbortHandle>,
+    received: bool,
+}
+
+impl PendingResponse {
+    pub(crate) fn new(connection: AbortHandle) -> Self {
+        Self {
+            connection: Some(connection),
+            received: false,
+        }
+    }
+
+    /// Stream of a shared HTTP/2 connection, giving up on it only resets the stream and the
+    /// connection carries on with the others
+    pub(crate) fn stream() -> Self {
+        Self {
+            connection: None,
+            received: false,
+        }
+    }
+
+    /// The connection is up to the body from now on
+    pub(crate) fn received(mut self, body: Incoming) -> BackendBody {
+        self.received = true;
+
+        BackendBody::new(body, self.connection.clone())
+    }
+}
+
+impl Drop for PendingResponse {
+    fn drop(&mut self) {
+        if self.received {
+            return;
+        }
+
+        if let Some(connection) = &self.connection {
+            tracing::debug!("Response wasn't waited for, closing the backend connection");
+
+            connection.abort();
+        }
+    }
+}
+
+impl Drop for BackendBody {
+    fn drop(&mut self) {
+        if self.finished || self.body.is_end_stream() {
+            self.finish();
+
+            return;
+        }
+
+        if let Some(connection) = &self.connection {
+            tracing::debug!("Response dropped before its end, closing the backend connection");
+
+            connection.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn connection_closes_when_the_response_isnt_waited_for() {
+        // Stands in for a connection hyper would keep open
+        let connection = tokio::spawn(std::future::pending::<()>());
+
+        drop(PendingResponse::new(connection.abort_handle()));
+
+        let closed = tokio::time::timeout(Duration::from_secs(1), connection)
+            .await
+            .expect("Backend connection wasn't closed");
+
+        assert!(closed.unwrap_err().is_cancelled());
+    }
+}
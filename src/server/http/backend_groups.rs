@@ -0,0 +1,152 @@
+use std::ops::{Deref, DerefMut, Range};
+
+use serde::{Deserialize, Serialize};
+
+use crate::service::config::BackendDefinition;
+
+/// Backends that take traffic together. Only the group with the lowest `priority` that has
+/// a backend in rotation gets traffic, the others are standby for when all of its backends fail
+/// their health checks.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct BackendGroup {
+    /// Groups with the same priority are tried in the order they're listed
+    #[serde(default)]
+    pub(crate) priority: u32,
+    pub(crate) backends: Vec<BackendDefinition>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+struct BackendsConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    backends: Vec<BackendDefinition>,
+    #[serde(
+        default,
+        rename = "backend-groups",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    backend_groups: Vec<BackendGroup>,
+}
+
+/// Backends of a service, configured as a single list under `backends` or as prioritized
+/// `backend-groups`. Either way they're all in one list, which derefs to it, and a list is
+/// a single group.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(try_from = "BackendsConfig", into = "BackendsConfig")]
+pub(crate) struct Backends {
+    /// Ordered by the priority of their groups
+    all: Vec<BackendDefinition>,
+    /// Ranges of `all` the groups take, from the highest priority
+    tiers: Vec<Range<usize>>,
+    config: BackendsConfig,
+}
+
+impl Backends {
+    pub(crate) fn tiers(&self) -> &[Range<usize>] {
+        &self.tiers
+    }
+}
+
+impl TryFrom<BackendsConfig> for Backends {
+    type Error = &'static str;
+
+    fn try_from(config: BackendsConfig) -> Result<Self, Self::Error> {
+        if !config.backends.is_empty() && !config.backend_groups.is_empty() {
+            return Err("backends and backend-groups can't be used together");
+        }
+
+        let mut groups: Vec<&BackendGroup> = config.backend_groups.iter().collect();
+
+        groups.sort_by_key(|group| group.priority);
+
+        let mut all = config.backends.clone();
+        let mut tiers = vec![];
+
+        if !all.is_empty() {
+            tiers.push(0..all.len());
+        }
+
+        for group in groups
+            .into_iter()
+            .filter(|group| !group.backends.is_empty())
+        {
+            let start = all.len();
+
+            all.extend(group.backends.iter().cloned());
+            tiers.push(start..all.len());
+        }
+
+        Ok(Self { all, tiers, config })
+    }
+}
+
+impl From<Backends> for BackendsConfig {
+    fn from(backends: Backends) -> Self {
+        backends.config
+    }
+}
+
+impl Deref for Backends {
+    type Target = [BackendDefinition];
+
+    fn deref(&self) -> &Self::Target {
+        &self.all
+    }
+}
+
+impl DerefMut for Backends {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.all
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Service {
+        #[serde(flatten)]
+        backends: Backends,
+    }
+
+    fn parse(yaml: &str) -> Result<Backends, serde_yaml::Error> {
+        serde_yaml::from_str::<Service>(yaml).map(|service| service.backends)
+    }
+
+    #[test]
+    fn groups_are_ordered_by_priority() {
+        let backends = parse(
+            "
+            backend-groups:
+            - priority: 1
+              backends: [{ ip: 10.0.1.1, port: 80 }]
+            - priority: 0
+              backends: [{ ip: 10.0.0.1, port: 80 }, { ip: 10.0.0.2, port: 80 }]
+            ",
+        )
+        .unwrap();
+
+        let addresses: Vec<String> = backends.iter().map(BackendDefinition::address).collect();
+
+        assert_eq!(addresses, ["10.0.0.1:80", "10.0.0.2:80", "10.0.1.1:80"]);
+        assert_eq!(backends.tiers(), [0..2, 2..3]);
+    }
+
+    #[test]
+    fn list_is_a_single_group() {
+        let backends = parse("backends: [{ ip: 10.0.0.1, port: 80 }]").unwrap();
+
+        assert_eq!(backends.tiers(), vec![0..1]);
+    }
+
+    #[test]
+    fn list_and_groups_are_exclusive() {
+        assert!(parse(
+            "
+            backends: [{ ip: 10.0.0.1, port: 80 }]
+            backend-groups: [{ backends: [{ ip: 10.0.1.1, port: 80 }] }]
+            ",
+        )
+        .is_err());
+    }
+}
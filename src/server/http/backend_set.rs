@@ -0,0 +1,227 @@
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::backend_groups::Backends;
+
+/// What's known about a backend, kept for as long as it stays in the set of its service
+#[derive(Debug)]
+pub(crate) struct BackendState {
+    /// Requests in flight to it, until their response bodies end
+    in_flight: AtomicUsize,
+    /// Cleared while it fails its health checks, every backend starts in rotation
+    up: AtomicBool,
+}
+
+impl Default for BackendState {
+    fn default() -> Self {
+        Self {
+            in_flight: AtomicUsize::new(0),
+            up: AtomicBool::new(true),
+        }
+    }
+}
+
+impl BackendState {
+    pub(crate) fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_up(&self, up: bool) {
+        self.up.store(up, Ordering::Relaxed);
+    }
+}
+
+/// Counts a request as in flight to its backend until dropped
+#[derive(Debug)]
+pub(crate) struct InFlightRequest(Arc<BackendState>);
+
+impl InFlightRequest {
+    pub(crate) fn new(state: Arc<BackendState>) -> Self {
+        state.in_flight.fetch_add(1, Ordering::Relaxed);
+
+        Self(state)
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Backends of a service at one point in time along with their state, which derefs to them
+#[derive(Debug)]
+pub(crate) struct BackendSnapshot {
+    backends: Backends,
+    states: Vec<Arc<BackendState>>,
+}
+
+impl BackendSnapshot {
+    pub(crate) fn state(&self, index: usize) -> &Arc<BackendState> {
+        &self.states[index]
+    }
+
+    /// The index every backend had in `previous`, `None` for the ones that weren't there
+    pub(crate) fn previous_indexes(&self, previous: &BackendSnapshot) -> Vec<Option<usize>> {
+        self.states
+            .iter()
+            .map(|state| {
+                previous
+                    .states
+                    .iter()
+                    .position(|previous| Arc::ptr_eq(previous, state))
+            })
+            .collect()
+    }
+
+    /// Backends with the address of one in `previous` take over its state, in order when the
+    /// same address is there more than once
+    fn carried_over(previous: &BackendSnapshot, backends: Backends) -> Self {
+        let mut kept: HashMap<String, Vec<Arc<BackendState>>> = HashMap::new();
+
+        for (backend, state) in previous.backends.iter().zip(&previous.states).rev() {
+            kept.entry(backend.address())
+                .or_default()
+                .push(state.clone());
+        }
+
+        let states = backends
+            .iter()
+            .map(|backend| {
+                kept.get_mut(&backend.address())
+                    .and_then(Vec::pop)
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        Self { backends, states }
+    }
+}
+
+impl From<Backends> for BackendSnapshot {
+    fn from(backends: Backends) -> Self {
+        let states = backends.iter().map(|_| Arc::default()).collect();
+
+        Self { backends, states }
+    }
+}
+
+impl Deref for BackendSnapshot {
+    type Target = Backends;
+
+    fn deref(&self) -> &Self::Target {
+        &self.backends
+    }
+}
+
+/// Backends of a service that can be replaced while it serves requests, e.g. when they're
+/// discovered instead of configured. Readers load the current set without locking and requests
+/// in flight keep the set they were sent with. Backends that stay keep their state.
+#[derive(Debug, Clone)]
+pub(crate) struct BackendSet(Arc<ArcSwap<BackendSnapshot>>);
+
+impl BackendSet {
+    pub(crate) fn new(backends: Backends) -> Self {
+        Self(Arc::new(ArcSwap::from_pointee(backends.into())))
+    }
+
+    pub(crate) fn load(&self) -> Arc<BackendSnapshot> {
+        self.0.load_full()
+    }
+
+    /// Swaps in the backends `update` makes of the current ones. It's called again when
+    /// another update gets in first.
+    pub(crate) fn update(&self, update: impl Fn(&Backends) -> Backends) {
+        self.0
+            .rcu(|current| BackendSnapshot::carried_over(current, update(current)));
+    }
+}
+
+impl<'de> Deserialize<'de> for BackendSet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Backends::deserialize(deserializer).map(Self::new)
+    }
+}
+
+impl Serialize for BackendSet {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.load().backends.serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::config::BackendDefinition;
+
+    #[derive(Deserialize)]
+    struct Service {
+        #[serde(flatten)]
+        backends: Backends,
+    }
+
+    fn backends(ports: &[u16]) -> Backends {
+        let backends = ports
+            .iter()
+            .map(|port| format!("{{ ip: 127.0.0.1, port: {} }}", port))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        serde_yaml::from_str::<Service>(&format!("backends: [{}]", backends))
+            .unwrap()
+            .backends
+    }
+
+    #[test]
+    fn state_stays_with_the_address() {
+        let set = BackendSet::new(backends(&[3000, 3001, 3002]));
+        let previous = set.load();
+
+        previous.state(1).set_up(false);
+        let in_flight = InFlightRequest::new(previous.state(2).clone());
+
+        set.update(|_| backends(&[3002, 3003, 3001]));
+
+        let current = set.load();
+        let addresses: Vec<String> = current.iter().map(BackendDefinition::address).collect();
+
+        assert_eq!(
+            addresses,
+            ["127.0.0.1:3002", "127.0.0.1:3003", "127.0.0.1:3001"]
+        );
+        assert_eq!(
+            current.previous_indexes(&previous),
+            [Some(2), None, Some(1)]
+        );
+        assert_eq!(current.state(0).in_flight(), 1);
+        assert!(current.state(1).is_up());
+        assert!(!current.state(2).is_up());
+
+        // The request was counted on the state the new set has too
+        drop(in_flight);
+        assert_eq!(current.state(0).in_flight(), 0);
+
+        // Readers of the old set aren't affected
+        assert_eq!(previous.len(), 3);
+        assert_eq!(previous[0].address(), "127.0.0.1:3000");
+    }
+}
@@ -0,0 +1,214 @@
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use http::header;
+use http_body_util::{combinators::BoxBody, BodyExt, BodyStream, StreamBody};
+use hyper::{body::Frame, Request};
+use serde::{Deserialize, Serialize};
+use serde_json_path::JsonPath;
+
+use super::server::full;
+
+/// Most a route can be configured to buffer, bodies are held in memory until they're matched
+pub(crate) const MAX_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// What happens to requests with a body over the buffer limit of their route
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum WhenTooLarge {
+    /// Body matchers don't match them, other rules still can
+    #[default]
+    NoMatch,
+    /// Respond with `413`
+    Reject,
+}
+
+/// How much of the request body a route with body matchers reads before picking a rule
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BodyBufferConfig {
+    /// Bytes, at most 1MiB
+    #[serde(default = "BodyBufferConfig::default_max_size")]
+    pub(crate) max_size: usize,
+    #[serde(default)]
+    pub(crate) when_too_large: WhenTooLarge,
+}
+
+impl BodyBufferConfig {
+    fn default_max_size() -> usize {
+        64 * 1024
+    }
+}
+
+impl Default for BodyBufferConfig {
+    fn default() -> Self {
+        Self {
+            max_size: Self::default_max_size(),
+            when_too_large: WhenTooLarge::default(),
+        }
+    }
+}
+
+/// Whole body of a request, in its extensions once it's been buffered for matching
+#[derive(Debug, Clone)]
+pub(crate) struct BufferedBody(pub(crate) Bytes);
+
+/// Matches the content of the request body, which the route buffers for it
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type")]
+pub(crate) enum BodyMatch {
+    Contains {
+        value: String,
+    },
+    /// Matches JSON bodies the path selects a node of, that node has to equal `value` when
+    /// it's set
+    JsonPath {
+        path: JsonPath,
+        value: Option<serde_json::Value>,
+    },
+}
+
+impl BodyMatch {
+    pub(crate) fn matches(&self, body: Option<&BufferedBody>) -> bool {
+        let Some(BufferedBody(body)) = body else {
+            return false;
+        };
+
+        match self {
+            Self::Contains { value } => body
+                .windows(value.len().max(1))
+                .any(|window| window == value.as_bytes()),
+            Self::JsonPath { path, value } => {
+                let Ok(json) = serde_json::from_slice::<serde_json::Value>(body) else {
+                    return false;
+                };
+
+                path.query(&json)
+                    .iter()
+                    .any(|node| value.as_ref().is_none_or(|value| *node == value))
+            }
+        }
+    }
+}
+
+/// Reads the body of `req` for matching, up to `max_size` bytes. Returns the request with
+/// its body intact, buffered in the extensions when it fit.
+pub(crate) async fn buffer(
+    req: Request<BoxBody<Bytes, hyper::Error>>,
+    max_size: usize,
+) -> Result<(Request<BoxBody<Bytes, hyper::Error>>, bool), hyper::Error> {
+    let too_large = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<usize>().ok())
+        .is_some_and(|length| length > max_size);
+
+    // Not worth reading when it's known not to fit
+    if too_large {
+        return Ok((req, false));
+    }
+
+    let (mut parts, body) = req.into_parts();
+    let mut frames = BodyStream::new(body);
+    let mut read = vec![];
+    let mut size = 0;
+
+    while let Some(frame) = frames.next().await {
+        let frame = frame?;
+
+        size += frame.data_ref().map_or(0, Bytes::len);
+        read.push(frame);
+
+        if size > max_size {
+            // What was read goes first, followed by the rest as it comes
+            let body = StreamBody::new(stream::iter(read.into_iter().map(Ok)).chain(frames));
+
+            return Ok((Request::from_parts(parts, BodyExt::boxed(body)), false));
+        }
+    }
+
+    let mut body = Vec::with_capacity(size);
+    let mut trailers = None;
+
+    for frame in read {
+        match frame.into_data() {
+            Ok(data) => body.extend_from_slice(&data),
+            Err(frame) => trailers = frame.into_trailers().ok(),
+        }
+    }
+
+    let body = Bytes::from(body);
+
+    parts.extensions.insert(BufferedBody(body.clone()));
+
+    let body = match trailers {
+        Some(trailers) => BodyExt::boxed(StreamBody::new(stream::iter([
+            Ok(Frame::data(body)),
+            Ok(Frame::trailers(trailers)),
+        ]))),
+        None => full(body),
+    };
+
+    Ok((Request::from_parts(parts, body), true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body_match(yaml: &str) -> BodyMatch {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn buffered(body: &str) -> BufferedBody {
+        BufferedBody(Bytes::copy_from_slice(body.as_bytes()))
+    }
+
+    #[test]
+    fn json_path_matches_selected_value() {
+        let tier = body_match("{ type: JsonPath, path: '$.user.tier', value: gold }");
+        let any_tier = body_match("{ type: JsonPath, path: '$.user.tier' }");
+
+        assert!(tier.matches(Some(&buffered(r#"{ "user": { "tier": "gold" } }"#))));
+        assert!(!tier.matches(Some(&buffered(r#"{ "user": { "tier": "free" } }"#))));
+        assert!(any_tier.matches(Some(&buffered(r#"{ "user": { "tier": "free" } }"#))));
+        assert!(!any_tier.matches(Some(&buffered(r#"{ "user": {} }"#))));
+        assert!(!any_tier.matches(Some(&buffered("not json"))));
+    }
+
+    #[test]
+    fn unbuffered_body_doesnt_match() {
+        let contains = body_match("{ type: Contains, value: urgent }");
+
+        assert!(contains.matches(Some(&buffered("this is urgent!"))));
+        assert!(!contains.matches(None));
+    }
+
+    #[tokio::test]
+    async fn buffering_keeps_the_body() {
+        let (req, buffered) = buffer(Request::new(full("0123456789")), 10).await.unwrap();
+
+        assert!(buffered);
+        assert_eq!(
+            req.extensions().get::<BufferedBody>().unwrap().0,
+            "0123456789"
+        );
+        assert_eq!(
+            req.into_body().collect().await.unwrap().to_bytes(),
+            "0123456789"
+        );
+
+        // Over the limit, it's still all passed on
+        let chunks = stream::iter(["01234", "56789", "abcde"])
+            .map(|chunk| Ok::<_, hyper::Error>(Frame::data(Bytes::from(chunk))));
+        let req = Request::new(BodyExt::boxed(StreamBody::new(chunks)));
+
+        let (req, buffered) = buffer(req, 8).await.unwrap();
+
+        assert!(!buffered);
+        assert!(req.extensions().get::<BufferedBody>().is_none());
+        assert_eq!(
+            req.into_body().collect().await.unwrap().to_bytes(),
+            "0123456789abcde"
+        );
+    }
+}
@@ -0,0 +1,558 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use duration_string::DurationString;
+use http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use super::server::{bad_gateway, full};
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ResponseCacheConfig {
+    /// Total size of cached bodies in bytes, least recently used responses are evicted first
+    pub(crate) max_size: usize,
+    /// Upper bound for how long a response is kept, `max-age` from the backend can only make it
+    /// shorter
+    pub(crate) ttl: DurationString,
+}
+
+/// Method is not a part of the key as only `GET` requests are ever cached
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct CacheKey {
+    host: String,
+    path_and_query: String,
+}
+
+#[derive(Debug)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// Request header values the response was selected by, as listed in its `Vary` header
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    stored_at: Instant,
+    ttl: Duration,
+}
+
+impl CachedResponse {
+    fn is_fresh(&self, now: Instant) -> bool {
+        now.duration_since(self.stored_at) < self.ttl
+    }
+
+    fn varies_from(&self, request_headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .any(|(name, value)| request_headers.get(name) != value.as_ref())
+    }
+
+    fn to_response(&self, now: Instant) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut response = Response::new(full(self.body.clone()));
+
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+
+        response.headers_mut().insert(
+            header::AGE,
+            HeaderValue::from(now.duration_since(self.stored_at).as_secs()),
+        );
+
+        response
+    }
+}
+
+#[derive(Debug)]
+struct Entries {
+    lru: LruCache<CacheKey, CachedResponse>,
+    size: usize,
+}
+
+#[derive(Debug)]
+pub(crate) struct ResponseCache {
+    max_size: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+    /// Keys a request is already on its way to the backend for. Waiters are woken up when the
+    /// sender is dropped
+    in_flight: Mutex<HashMap<CacheKey, watch::Sender<()>>>,
+}
+
+pub(crate) enum Lookup<'a> {
+    Hit(Response<BoxBody<Bytes, hyper::Error>>),
+    /// The request has to go to the backend, requests for the same key wait for it as long as
+    /// the flight is held
+    Miss(Option<Flight<'a>>),
+}
+
+/// Request leading the way to the backend for a key, released on drop
+pub(crate) struct Flight<'a> {
+    cache: &'a ResponseCache,
+    key: CacheKey,
+}
+
+impl Drop for Flight<'_> {
+    fn drop(&mut self) {
+        self.cache.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Parsed `Cache-Control` directives the cache cares about
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<Duration>,
+    shared_max_age: Option<Duration>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut cache_control = Self::default();
+
+        let directives = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','));
+
+        for directive in directives {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name.trim(), Some(argument.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+
+            let seconds = argument
+                .and_then(|argument| argument.parse().ok())
+                .map(Duration::from_secs);
+
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => cache_control.no_store = true,
+                "no-cache" => cache_control.no_cache = true,
+                "private" => cache_control.private = true,
+                "max-age" => cache_control.max_age = seconds,
+                "s-maxage" => cache_control.shared_max_age = seconds,
+                _ => {}
+            }
+        }
+
+        cache_control
+    }
+}
+
+impl ResponseCache {
+    pub(crate) fn new(config: ResponseCacheConfig) -> Self {
+        Self {
+            max_size: config.max_size,
+            ttl: config.ttl.into(),
+            entries: Mutex::new(Entries {
+                lru: LruCache::unbounded(),
+                size: 0,
+            }),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Key of the request if it can be served from the cache at all
+    pub(crate) fn key<B>(req: &Request<B>) -> Option<CacheKey> {
+        if req.method() != Method::GET || req.headers().contains_key(header::AUTHORIZATION) {
+            return None;
+        }
+
+        let cache_control = CacheControl::parse(req.headers());
+
+        if cache_control.no_store || cache_control.no_cache {
+            return None;
+        }
+
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| req.uri().authority().map(|authority| authority.as_str()))?;
+
+        Some(CacheKey {
+            host: host.to_ascii_lowercase(),
+            path_and_query: req
+                .uri()
+                .path_and_query()
+                .map_or("/", |path_and_query| path_and_query.as_str())
+                .to_owned(),
+        })
+    }
+
+    pub(crate) fn get(
+        &self,
+        key: &CacheKey,
+        request_headers: &HeaderMap,
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        self.get_at(key, request_headers, Instant::now())
+    }
+
+    /// Looks the key up, coalescing concurrent misses so only one of them goes to the backend.
+    ///
+    /// The rest wait for it and check the cache again. When the response turned out not to be
+    /// cacheable, or differs by `Vary`, they go to the backend on their own.
+    pub(crate) async fn lookup(&self, key: &CacheKey, request_headers: &HeaderMap) -> Lookup<'_> {
+        if let Some(response) = self.get(key, request_headers) {
+            return Lookup::Hit(response);
+        }
+
+        let mut landed = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+
+            match in_flight.get(key) {
+                Some(sender) => sender.subscribe(),
+                None => {
+                    in_flight.insert(key.clone(), watch::channel(()).0);
+
+                    return Lookup::Miss(Some(Flight {
+                        cache: self,
+                        key: key.clone(),
+                    }));
+                }
+            }
+        };
+
+        // Only ever fails, once the flight is dropped along with the sender
+        let _ = landed.changed().await;
+
+        match self.get(key, request_headers) {
+            Some(response) => Lookup::Hit(response),
+            None => Lookup::Miss(None),
+        }
+    }
+
+    fn get_at(
+        &self,
+        key: &CacheKey,
+        request_headers: &HeaderMap,
+        now: Instant,
+    ) -> Option<Response<BoxBody<Bytes, hyper::Error>>> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let cached = entries.lru.get(key)?;
+
+        if cached.is_fresh(now) {
+            return (!cached.varies_from(request_headers)).then(|| cached.to_response(now));
+        }
+
+        if let Some(stale) = entries.lru.pop(key) {
+            entries.size -= stale.body.len();
+        }
+
+        None
+    }
+
+    /// How long a response may be cached, `None` when it must not be cached
+    fn cacheable_for(&self, response: &Response<BoxBody<Bytes, hyper::Error>>) -> Option<Duration> {
+        if response.status() != StatusCode::OK
+            || response.headers().contains_key(header::SET_COOKIE)
+        {
+            return None;
+        }
+
+        let cache_control = CacheControl::parse(response.headers());
+
+        if cache_control.no_store || cache_control.no_cache || cache_control.private {
+            return None;
+        }
+
+        let ttl = cache_control
+            .shared_max_age
+            .or(cache_control.max_age)
+            .map_or(self.ttl, |max_age| max_age.min(self.ttl));
+
+        (!ttl.is_zero()).then_some(ttl)
+    }
+
+    /// Stores a response if it's cacheable and gives it back either way
+    pub(crate) async fn store(
+        &self,
+        key: CacheKey,
+        request_headers: &HeaderMap,
+        response: Response<BoxBody<Bytes, hyper::Error>>,
+    ) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let Some(ttl) = self.cacheable_for(&response) else {
+            return response;
+        };
+
+        let vary: Option<Vec<HeaderName>> = response
+            .headers()
+            .get_all(header::VARY)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .map(|name| match name {
+                // The response depends on something other than headers so it can't be reused
+                "*" => None,
+                name => HeaderName::from_bytes(name.as_bytes()).ok(),
+            })
+            .collect();
+
+        let Some(vary) = vary else {
+            return response;
+        };
+
+        // Only buffer bodies known to fit, anything else is streamed through untouched
+        let fits = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<usize>().ok())
+            .is_some_and(|length| length <= self.max_size);
+
+        if !fits {
+            return response;
+        }
+
+        let (parts, body) = response.into_parts();
+
+        let body = match body.collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(err) => {
+                tracing::warn!(error = ?err, "Failed to read a response to cache");
+
+                return bad_gateway();
+            }
+        };
+
+        let cached = CachedResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+            vary: vary
+                .into_iter()
+                .map(|name| {
+                    let value = request_headers.get(&name).cloned();
+
+                    (name, value)
+                })
+                .collect(),
+            stored_at: Instant::now(),
+            ttl,
+        };
+
+        self.insert(key, cached);
+
+        Response::from_parts(parts, full(body))
+    }
+
+    fn insert(&self, key: CacheKey, cached: CachedResponse) {
+        let mut entries = self.entries.lock().unwrap();
+
+        entries.size += cached.body.len();
+
+        if let Some(replaced) = entries.lru.put(key, cached) {
+            entries.size -= replaced.body.len();
+        }
+
+        while entries.size > self.max_size {
+            match entries.lru.pop_lru() {
+                Some((_, evicted)) => entries.size -= evicted.body.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(max_size: usize) -> ResponseCache {
+        ResponseCache::new(ResponseCacheConfig {
+            max_size,
+            ttl: Duration::from_secs(60).into(),
+        })
+    }
+
+    fn request(path: &str) -> Request<()> {
+        Request::builder()
+            .uri(path)
+            .header("host", "test.com")
+            .body(())
+            .unwrap()
+    }
+
+    fn response(
+        body: &'static str,
+        headers: &[(&str, &str)],
+    ) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut builder = Response::builder().header(header::CONTENT_LENGTH, body.len());
+
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+
+        builder.body(full(body)).unwrap()
+    }
+
+    async fn body(response: Response<BoxBody<Bytes, hyper::Error>>) -> Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn serves_stored_response() {
+        let cache = cache(1024);
+        let req = request("/cached?page=1");
+        let key = ResponseCache::key(&req).unwrap();
+
+        let stored = cache
+            .store(
+                key.clone(),
+                req.headers(),
+                response("hello", &[("cache-control", "max-age=30")]),
+            )
+            .await;
+
+        assert_eq!(body(stored).await, "hello");
+
+        let hit = cache.get(&key, req.headers()).unwrap();
+
+        assert!(hit.headers().contains_key(header::AGE));
+        assert_eq!(body(hit).await, "hello");
+    }
+
+    #[tokio::test]
+    async fn respects_response_cache_control() {
+        let cache = cache(1024);
+        let req = request("/");
+        let key = ResponseCache::key(&req).unwrap();
+
+        for directive in ["no-store", "private", "no-cache", "max-age=0"] {
+            cache
+                .store(
+                    key.clone(),
+                    req.headers(),
+                    response("hello", &[("cache-control", directive)]),
+                )
+                .await;
+
+            assert!(cache.get(&key, req.headers()).is_none(), "{directive}");
+        }
+    }
+
+    #[test]
+    fn only_plain_get_requests_have_keys() {
+        let post = Request::builder()
+            .method(Method::POST)
+            .uri("/")
+            .header("host", "test.com")
+            .body(())
+            .unwrap();
+
+        assert!(ResponseCache::key(&post).is_none());
+
+        let no_cache = Request::builder()
+            .uri("/")
+            .header("host", "test.com")
+            .header("cache-control", "no-cache")
+            .body(())
+            .unwrap();
+
+        assert!(ResponseCache::key(&no_cache).is_none());
+    }
+
+    #[tokio::test]
+    async fn expires_after_max_age() {
+        let cache = cache(1024);
+        let req = request("/");
+        let key = ResponseCache::key(&req).unwrap();
+
+        cache
+            .store(
+                key.clone(),
+                req.headers(),
+                response("hello", &[("cache-control", "max-age=5")]),
+            )
+            .await;
+
+        let later = Instant::now() + Duration::from_secs(6);
+
+        assert!(cache.get_at(&key, req.headers(), later).is_none());
+        assert_eq!(cache.entries.lock().unwrap().size, 0);
+    }
+
+    #[tokio::test]
+    async fn vary_headers_are_part_of_the_match() {
+        let cache = cache(1024);
+        let mut req = request("/");
+        req.headers_mut()
+            .insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let key = ResponseCache::key(&req).unwrap();
+
+        cache
+            .store(
+                key.clone(),
+                req.headers(),
+                response("hello", &[("vary", "Accept-Encoding")]),
+            )
+            .await;
+
+        assert!(cache.get(&key, req.headers()).is_some());
+        assert!(cache.get(&key, request("/").headers()).is_none());
+
+        let other_key = ResponseCache::key(&request("/wildcard")).unwrap();
+
+        cache
+            .store(
+                other_key.clone(),
+                req.headers(),
+                response("hello", &[("vary", "*")]),
+            )
+            .await;
+
+        assert!(cache.get(&other_key, req.headers()).is_none());
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let cache = cache(10);
+        let headers = HeaderMap::new();
+
+        let first = ResponseCache::key(&request("/first")).unwrap();
+        let second = ResponseCache::key(&request("/second")).unwrap();
+        let third = ResponseCache::key(&request("/third")).unwrap();
+
+        cache
+            .store(first.clone(), &headers, response("01234", &[]))
+            .await;
+        cache
+            .store(second.clone(), &headers, response("56789", &[]))
+            .await;
+
+        // Touch the first one so the second one is the least recently used
+        assert!(cache.get(&first, &headers).is_some());
+
+        cache
+            .store(third.clone(), &headers, response("abcde", &[]))
+            .await;
+
+        assert!(cache.get(&first, &headers).is_some());
+        assert!(cache.get(&second, &headers).is_none());
+        assert!(cache.get(&third, &headers).is_some());
+    }
+
+    #[tokio::test]
+    async fn bodies_over_the_limit_are_not_stored() {
+        let cache = cache(4);
+        let req = request("/");
+        let key = ResponseCache::key(&req).unwrap();
+
+        let passed_through = cache
+            .store(key.clone(), req.headers(), response("hello", &[]))
+            .await;
+
+        assert_eq!(body(passed_through).await, "hello");
+        assert!(cache.get(&key, req.headers()).is_none());
+    }
+}
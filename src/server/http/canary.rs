@@ -0,0 +1,223 @@
+use std::{sync::Arc, time::Duration};
+
+use duration_string::DurationString;
+use serde::{Deserialize, Serialize};
+
+use crate::{metrics::metrics, shutdown::Shutdown};
+
+use super::service::HttpService;
+
+/// Progressive delivery of a single backend of a weighted service. The backend starts with
+/// `step` percent of the traffic, which grows by `step` every `interval` its error rate stays
+/// at or below `error-threshold`, until it gets all of it. Going above the threshold at any
+/// point takes all traffic away from it and stops the rollout.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct CanaryConfig {
+    /// `host:port` of one of the service's backends
+    pub(crate) backend: String,
+    /// Highest tolerated share of failed requests, from 0 to 1
+    pub(crate) error_threshold: f64,
+    /// Percent of the traffic added on every promotion, from 1 to 100
+    pub(crate) step: u32,
+    /// How long the error rate has to stay below the threshold before the next promotion
+    pub(crate) interval: DurationString,
+}
+
+#[derive(Debug, PartialEq)]
+enum Decision {
+    /// Nothing reached the canary, there's nothing to judge it by
+    Hold,
+    Promote(u32),
+    RollBack,
+}
+
+fn decide(config: &CanaryConfig, share: u32, requests: u64, errors: u64) -> Decision {
+    if requests == 0 {
+        return Decision::Hold;
+    }
+
+    if errors as f64 / requests as f64 > config.error_threshold {
+        return Decision::RollBack;
+    }
+
+    Decision::Promote((share + config.step).min(100))
+}
+
+pub(crate) struct CanaryController {
+    pub(crate) service_name: String,
+    pub(crate) service: Arc<HttpService>,
+    pub(crate) config: CanaryConfig,
+}
+
+impl CanaryController {
+    /// Adjusts the weights until the canary gets all the traffic, gets rolled back or `shutdown`
+    /// completes
+    pub(crate) async fn run(self, shutdown: Shutdown) {
+        let Self {
+            service_name,
+            service,
+            config,
+        } = self;
+
+        let mut share = config.step.min(100);
+        service.split_traffic(&config.backend, share);
+
+        tracing::info!(
+            service = %service_name,
+            backend = %config.backend,
+            share,
+            "Canary starts with its share of the traffic"
+        );
+
+        let period: Duration = config.interval.into();
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+
+        // Only what happened during the current interval is judged
+        let mut previous = metrics().backend_outcomes(&service_name, &config.backend);
+
+        while share < 100 {
+            tokio::select! {
+                _ = ticks.tick() => {},
+                _ = shutdown.clone() => return,
+            }
+
+            let current = metrics().backend_outcomes(&service_name, &config.backend);
+            let (requests, errors) = (current.0 - previous.0, current.1 - previous.1);
+            previous = current;
+
+            match decide(&config, share, requests, errors) {
+                Decision::Hold => {}
+                Decision::Promote(promoted) => {
+                    share = promoted;
+                    service.split_traffic(&config.backend, share);
+
+                    tracing::info!(
+                        service = %service_name,
+                        backend = %config.backend,
+                        share,
+                        "Canary promoted"
+                    );
+                }
+                Decision::RollBack => {
+                    service.split_traffic(&config.backend, 0);
+
+                    tracing::warn!(
+                        service = %service_name,
+                        backend = %config.backend,
+                        errors,
+                        requests,
+                        "Canary rolled back"
+                    );
+
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+    use http::StatusCode;
+
+    use super::*;
+    use crate::service::config::BackendDefinition;
+
+    fn config() -> CanaryConfig {
+        serde_yaml::from_str(
+            "
+            backend: 127.0.0.1:3001
+            error-threshold: 0.05
+            step: 30
+            interval: 1m
+            ",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn promotes_in_steps_up_to_everything() {
+        assert_eq!(decide(&config(), 30, 100, 5), Decision::Promote(60));
+        assert_eq!(decide(&config(), 90, 100, 0), Decision::Promote(100));
+    }
+
+    #[test]
+    fn rolls_back_above_threshold() {
+        assert_eq!(decide(&config(), 60, 100, 6), Decision::RollBack);
+    }
+
+    #[test]
+    fn holds_without_traffic() {
+        assert_eq!(decide(&config(), 30, 0, 0), Decision::Hold);
+    }
+
+    /// Runs a canary of the second of two backends while it responds with `status`, until the
+    /// rollout is over. Returns the weights it leaves behind.
+    async fn roll_out(service_name: &str, status: StatusCode) -> Vec<u32> {
+        let service: HttpService = serde_yaml::from_str(
+            "
+            load_balancing_algorithm: weighted-random
+            backends:
+            - { ip: 127.0.0.1, port: 3000 }
+            - { ip: 127.0.0.1, port: 3001 }
+            ",
+        )
+        .unwrap();
+        let service = Arc::new(service);
+
+        let controller = CanaryController {
+            service_name: service_name.to_owned(),
+            service: service.clone(),
+            config: serde_yaml::from_str(
+                "
+                backend: 127.0.0.1:3001
+                error-threshold: 0.5
+                step: 40
+                interval: 20ms
+                ",
+            )
+            .unwrap(),
+        };
+
+        let mut rollout = tokio::spawn(controller.run(std::future::pending().boxed().shared()));
+
+        let finished = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                tokio::select! {
+                    finished = &mut rollout => return finished.unwrap(),
+                    _ = tokio::time::sleep(Duration::from_millis(5)) => {
+                        metrics().backend_response(service_name, "127.0.0.1:3001", status);
+                    }
+                }
+            }
+        });
+
+        finished.await.expect("Rollout didn't finish");
+
+        service
+            .backend_set()
+            .unwrap()
+            .load()
+            .iter()
+            .map(BackendDefinition::weight)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn healthy_canary_is_promoted_to_all_traffic() {
+        let weights = roll_out("canary-promoted", StatusCode::OK).await;
+
+        assert_eq!(weights[0], 0);
+        assert!(weights[1] > 0);
+    }
+
+    #[tokio::test]
+    async fn failing_canary_is_rolled_back() {
+        let weights = roll_out("canary-rolled-back", StatusCode::BAD_GATEWAY).await;
+
+        assert!(weights[0] > 0);
+        assert_eq!(weights[1], 0);
+    }
+}
@@ -5,49 +5,139 @@ use std::{
 };
 
 use futures::future::join_all;
-use tokio::sync::Mutex;
+use itertools::Itertools;
+
+use crate::shutdown::Shutdown;
 
 use super::{
+    cache::ResponseCache,
+    canary::CanaryController,
+    dns_refresh::DnsRefresher,
+    error_pages::ErrorPages,
+    fail_closed::Readiness,
+    health::HealthChecker,
+    mirror::{Mirror, Mirrors},
+    path_index::PathIndex,
+    pool::ConnectionReaper,
     route::{HttpRoute, HttpRule},
     HttpConfig, HttpServer,
 };
 
 pub(crate) struct HttpServerCluster {
     servers: Vec<HttpServer>,
+    canaries: Vec<CanaryController>,
+    health_checkers: Vec<HealthChecker>,
+    dns_refreshers: Vec<DnsRefresher>,
+    connection_reapers: Vec<ConnectionReaper>,
 }
 
 impl HttpServerCluster {
-    pub(crate) fn from_config(config: HttpConfig) -> Self {
+    /// Fails when an error page can't be read. Servers that fail closed report it to `readiness`.
+    pub(crate) fn from_config(config: HttpConfig, readiness: &Readiness) -> io::Result<Self> {
         let HttpConfig {
             servers,
             routes,
             services,
+            timeouts,
+            error_pages,
+            route_limits,
         } = config;
 
+        for server in &servers {
+            let server_routes = routes.iter().filter(|route| route.server == server.name);
+
+            route_limits.check(&server.name, server_routes);
+        }
+
+        let service_timeouts = services
+            .iter()
+            .map(|(name, service)| (name.clone(), service.timeouts().or(timeouts)))
+            .collect::<HashMap<_, _>>();
+
+        let mut health_checkers = vec![];
+        let mut dns_refreshers = vec![];
+        let mut connection_reapers = vec![];
+
+        let backend_sets = services
+            .iter()
+            .filter_map(|(name, service)| Some((name.clone(), service.backend_set()?)))
+            .collect::<HashMap<_, _>>();
+
         let services_map = services
             .into_iter()
-            .map(|(name, backend)| (name, Arc::new(Mutex::new(backend))))
+            .map(|(name, mut backend)| {
+                health_checkers.extend(backend.health_checker(&name));
+                dns_refreshers.extend(backend.dns_refresher());
+                connection_reapers.extend(backend.connection_reaper());
+
+                (name, Arc::new(backend))
+            })
             .collect::<HashMap<_, _>>();
 
         let mut route_map = HashMap::<String, Vec<HttpRoute>>::new();
+        let mut canaries = vec![];
 
         for route in routes {
             let server_name = route.server;
+            let name = route.name;
 
             let hostnames = route.hostnames;
-            let rules = route
+            let rules: Vec<HttpRule> = route
                 .rules
                 .into_iter()
-                .map(|rule| {
+                .enumerate()
+                .map(|(index, rule)| {
                     let backend = services_map.get(&rule.backend).unwrap().clone();
 
-                    HttpRule::new(rule.matches, backend)
+                    let mirrors: Vec<Mirror> = rule
+                        .mirror_services()
+                        .map(|name| Mirror {
+                            name: name.clone(),
+                            service: services_map[name].clone(),
+                            timeouts: service_timeouts[name].into(),
+                        })
+                        .collect();
+                    let mirrors = (!mirrors.is_empty()).then(|| Mirrors::new(mirrors));
+
+                    let rule_name = rule.name.unwrap_or_else(|| format!("{}/{}", name, index));
+
+                    let timeouts = rule.timeouts.or(service_timeouts[&rule.backend]).into();
+
+                    if let Some(config) = rule.canary {
+                        canaries.push(CanaryController {
+                            service_name: rule.backend.clone(),
+                            service: backend.clone(),
+                            config,
+                        });
+                    }
+
+                    HttpRule::new(
+                        rule_name,
+                        rule.matches,
+                        rule.backend,
+                        backend,
+                        timeouts,
+                        mirrors,
+                        route.rewrite_location.clone(),
+                        rule.filters,
+                    )
                 })
                 .collect();
 
+            let matches_body = rules
+                .iter()
+                .flat_map(|rule| &rule.matchers)
+                .any(|matcher| matcher.body.is_some());
+
             let route = HttpRoute {
+                name,
                 hostnames: hostnames.unwrap_or_default(),
+                path_index: PathIndex::new(&rules),
                 rules,
+                cache: route.cache.map(ResponseCache::new),
+                grpc_web: route.grpc_web,
+                body_buffer: matches_body.then(|| route.body_buffer.unwrap_or_default()),
+                synthesize: route.synthesize,
             };
 
             match route_map.entry(server_name) {
@@ -60,19 +150,207 @@ impl HttpServerCluster {
             }
         }
 
-        Self {
+        Ok(Self {
             servers: servers
                 .into_iter()
                 .map(|config| {
                     let routes = route_map.remove(&config.name).unwrap_or_default();
+                    let error_pages = ErrorPages::load(&error_pages, &config.error_pages)?;
+
+                    // The services the routes send requests to, mirrors don't answer clients
+                    let backends = routes
+                        .iter()
+                        .flat_map(|route| &route.rules)
+                        .map(|rule| &rule.service)
+                        .unique()
+                        .filter_map(|service| backend_sets.get(service).cloned())
+                        .collect();
+                    let fails_closed = config.fail_closed.is_some();
+                    let server = HttpServer::new(config, routes, error_pages);
 
-                    HttpServer::new(config, routes)
+                    Ok(if fails_closed {
+                        server.failing_closed(backends, readiness.clone())
+                    } else {
+                        server
+                    })
                 })
-                .collect(),
+                .collect::<io::Result<_>>()?,
+            canaries,
+            health_checkers,
+            dns_refreshers,
+            connection_reapers,
+        })
+    }
+
+    pub(crate) async fn run_all(self, shutdown: Shutdown) -> Vec<Result<(), io::Error>> {
+        for canary in self.canaries {
+            tokio::spawn(canary.run(shutdown.clone()));
+        }
+
+        for checker in self.health_checkers {
+            tokio::spawn(checker.run(shutdown.clone()));
+        }
+
+        for refresher in self.dns_refreshers {
+            tokio::spawn(refresher.run(shutdown.clone()));
+        }
+
+        for reaper in self.connection_reapers {
+            tokio::spawn(reaper.run(shutdown.clone()));
+        }
+
+        join_all(
+            self.servers
+                .into_iter()
+                .map(|server| server.run(shutdown.clone())),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use super::*;
+
+    /// Backend that reports the request it gets and answers with `response`
+    async fn backend(response: &'static [u8], requests: mpsc::UnboundedSender<String>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut request = vec![0; 1024];
+            let read = stream.read(&mut request).await.unwrap();
+
+            requests
+                .send(String::from_utf8_lossy(&request[..read]).into_owned())
+                .unwrap();
+
+            stream.write_all(response).await.unwrap();
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn mirror_gets_a_copy_of_the_request() {
+        let (primary_requests, mut primary_received) = mpsc::unbounded_channel();
+        let (shadow_requests, mut shadow_received) = mpsc::unbounded_channel();
+
+        let primary = backend(
+            b"HTTP/1.1 200 OK\r\ncontent-length: 7\r\n\r\nprimary",
+            primary_requests,
+        )
+        .await;
+        let shadow = backend(
+            b"HTTP/1.1 500 Internal Server Error\r\ncontent-length: 6\r\n\r\nshadow",
+            shadow_requests,
+        )
+        .await;
+
+        let config: HttpConfig = serde_yaml::from_str(&format!(
+            "
+            servers: [{{ port: 0, name: mirror-test }}]
+            routes:
+            - name: orders
+              hostnames: [test.com]
+              server: mirror-test
+              rules:
+              - backend: orders
+                matches: []
+                filters: [{{ type: RequestMirror, service: orders-candidate }}]
+            services:
+              orders:
+                backends: [{{ ip: 127.0.0.1, port: {} }}]
+              orders-candidate:
+                backends: [{{ ip: 127.0.0.1, port: {} }}]
+            ",
+            primary, shadow
+        ))
+        .unwrap();
+
+        let mut cluster = HttpServerCluster::from_config(config, &Readiness::default()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        tokio::spawn(
+            cluster
+                .servers
+                .remove(0)
+                .serve(vec![listener], std::future::pending()),
+        );
+
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client
+            .write_all(
+                b"PUT /orders/7 HTTP/1.1\r\nhost: test.com\r\ncontent-length: 4\r\n\
+                  connection: close\r\n\r\npaid",
+            )
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+
+        // The primary answers, whatever the mirror does
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nprimary"), "{}", response);
+
+        for received in [&mut primary_received, &mut shadow_received] {
+            let request = received.recv().await.unwrap();
+
+            assert!(
+                request.starts_with("PUT /orders/7 HTTP/1.1\r\n"),
+                "{}",
+                request
+            );
+            assert!(request.ends_with("\r\n\r\npaid"), "{}", request);
         }
     }
 
-    pub(crate) async fn run_all(self) -> Vec<Result<(), io::Error>> {
-        join_all(self.servers.into_iter().map(HttpServer::run)).await
+    #[tokio::test]
+    async fn names_are_refreshed_into_the_backends() {
+        let config: HttpConfig = serde_yaml::from_str(
+            "
+            servers: [{ port: 0, name: dns-refresh-test }]
+            routes: []
+            services:
+              api:
+                backends: [{ host: localhost, port: 3000 }]
+                dns-refresh-interval: 50ms
+            ",
+        )
+        .unwrap();
+
+        let cluster = HttpServerCluster::from_config(config, &Readiness::default()).unwrap();
+        let backends = cluster.dns_refreshers[0].backends.clone();
+
+        assert!(backends.load()[0].addresses.is_empty());
+
+        tokio::spawn(cluster.run_all(std::future::pending().boxed().shared()));
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while backends.load()[0].addresses.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Backend addresses weren't refreshed");
+
+        assert!(backends.load()[0]
+            .addresses
+            .iter()
+            .all(|address| address.is_loopback()));
     }
 }
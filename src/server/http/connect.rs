@@ -0,0 +1,265 @@
+use bytes::Bytes;
+use http::{Method, StatusCode};
+use http_body_util::combinators::BoxBody;
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tokio::net::TcpStream;
+
+use crate::{
+    metrics::metrics,
+    server::{
+        host::{HostSpec, Hostname},
+        stream::tcp::{relay, DEFAULT_BUFFER_SIZE},
+    },
+};
+
+use super::server::{bad_gateway, bad_request, full, generated};
+
+/// Destination clients are allowed to open a `CONNECT` tunnel to
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ConnectDestination {
+    /// Only hostnames can be allowed, IP literals are always rejected
+    pub(crate) host: HostSpec,
+    /// Any port is allowed when not set
+    pub(crate) ports: Option<Vec<u16>>,
+}
+
+impl ConnectDestination {
+    fn allows(&self, host: &Hostname, port: u16) -> bool {
+        self.host.matches(host)
+            && self
+                .ports
+                .as_ref()
+                .is_none_or(|ports| ports.contains(&port))
+    }
+}
+
+pub(crate) fn is_connect<B>(req: &Request<B>) -> bool {
+    req.method() == Method::CONNECT
+}
+
+/// Opens a tunnel to the authority of a `CONNECT` request and relays bytes both ways once the
+/// client connection is upgraded.
+///
+/// Without an allowlist every `CONNECT` is rejected, so a server can't become an open proxy by
+/// accident.
+pub(crate) async fn tunnel<B>(
+    req: Request<B>,
+    allowlist: Option<&[ConnectDestination]>,
+    listener: &str,
+) -> Response<BoxBody<Bytes, hyper::Error>>
+where
+    B: Send + 'static,
+{
+    let Some(allowlist) = allowlist else {
+        tracing::debug!("CONNECT is not allowed on this server");
+
+        return generated(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    };
+
+    let Some((host, port)) = req
+        .uri()
+        .authority()
+        .and_then(|authority| Some((authority.host(), authority.port_u16()?)))
+    else {
+        return bad_request();
+    };
+
+    let allowed = Hostname::from_str(host)
+        .is_ok_and(|hostname| allowlist.iter().any(|dest| dest.allows(&hostname, port)));
+
+    if !allowed {
+        tracing::debug!(host = %host, port, "CONNECT to the destination is not allowed");
+
+        return generated(StatusCode::FORBIDDEN, "Forbidden");
+    }
+
+    let mut upstream = match TcpStream::connect((host, port)).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            tracing::warn!(host = %host, port, error = %err, "Failed to open a tunnel");
+
+            return bad_gateway();
+        }
+    };
+
+    tracing::info!(host = %host, port, "Opened a tunnel");
+
+    let counters = metrics().relay_counters(listener);
+
+    tokio::spawn(async move {
+        let upgraded = match hyper::upgrade::on(req).await {
+            Ok(upgraded) => upgraded,
+            Err(err) => {
+                tracing::warn!(error = %err, "Failed to upgrade CONNECT request");
+                return;
+            }
+        };
+
+        let mut client = TokioIo::new(upgraded);
+
+        if let Err(err) = relay(
+            &mut client,
+            &mut upstream,
+            DEFAULT_BUFFER_SIZE,
+            DEFAULT_BUFFER_SIZE,
+            &counters,
+        )
+        .await
+        {
+            tracing::warn!(error = %err, "Tunnel failed");
+        }
+    });
+
+    // An empty 2xx response switches the connection into the tunnel
+    Response::new(full(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{server::conn::http1, service::service_fn};
+    use std::{convert::Infallible, sync::Arc};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    fn allowlist(yaml: &str) -> Vec<ConnectDestination> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// HTTP server that only tunnels, returns its port
+    async fn proxy(allowlist: Option<Vec<ConnectDestination>>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let allowlist = Arc::new(allowlist);
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            let service = service_fn(move |req| {
+                let allowlist = allowlist.clone();
+
+                async move { Ok::<_, Infallible>(tunnel(req, allowlist.as_deref(), "test").await) }
+            });
+
+            http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+                .unwrap();
+        });
+
+        port
+    }
+
+    async fn echo_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 5];
+
+            stream.read_exact(&mut buffer).await.unwrap();
+            stream.write_all(&buffer).await.unwrap();
+        });
+
+        port
+    }
+
+    /// Sends a CONNECT and returns the status line along with the connection
+    async fn send_connect(proxy_port: u16, authority: &str) -> (String, TcpStream) {
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy_port)).await.unwrap();
+
+        stream
+            .write_all(
+                format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n\r\n").as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        let mut head = vec![];
+
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+
+        let head = String::from_utf8(head).unwrap();
+        let status_line = head.lines().next().unwrap().to_owned();
+
+        (status_line, stream)
+    }
+
+    #[test]
+    fn destinations_are_matched_by_host_and_port() {
+        let allowlist = allowlist(
+            "
+            - host: '*.example.com'
+              ports: [443]
+            - host: internal.test
+            ",
+        );
+
+        let allowed = |host: &str, port| {
+            let host = Hostname::from_str(host).unwrap();
+
+            allowlist.iter().any(|dest| dest.allows(&host, port))
+        };
+
+        assert!(allowed("api.example.com", 443));
+        assert!(!allowed("api.example.com", 80));
+        assert!(!allowed("example.com", 443));
+        assert!(allowed("internal.test", 22));
+    }
+
+    #[tokio::test]
+    async fn tunnels_to_allowed_destination() {
+        let echo_port = echo_server().await;
+        let proxy_port = proxy(Some(allowlist("- host: localhost"))).await;
+
+        let (status_line, mut stream) =
+            send_connect(proxy_port, &format!("localhost:{}", echo_port)).await;
+
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+
+        stream.write_all(b"hello").await.unwrap();
+
+        let mut buffer = [0; 5];
+        stream.read_exact(&mut buffer).await.unwrap();
+
+        assert_eq!(&buffer, b"hello");
+    }
+
+    #[tokio::test]
+    async fn rejects_destination_outside_allowlist() {
+        let proxy_port = proxy(Some(allowlist("- host: localhost\n  ports: [443]"))).await;
+
+        let (status_line, _) = send_connect(proxy_port, "localhost:22").await;
+
+        assert_eq!(status_line, "HTTP/1.1 403 Forbidden");
+    }
+
+    #[tokio::test]
+    async fn rejects_ip_destinations() {
+        let proxy_port = proxy(Some(allowlist("- host: localhost"))).await;
+
+        let (status_line, _) = send_connect(proxy_port, "127.0.0.1:22").await;
+
+        assert_eq!(status_line, "HTTP/1.1 403 Forbidden");
+    }
+
+    #[tokio::test]
+    async fn rejects_connect_without_allowlist() {
+        let proxy_port = proxy(None).await;
+
+        let (status_line, _) = send_connect(proxy_port, "localhost:22").await;
+
+        assert_eq!(status_line, "HTTP/1.1 405 Method Not Allowed");
+    }
+}
@@ -0,0 +1,90 @@
+use std::{collections::HashMap, time::Duration};
+
+use futures::future::join_all;
+use itertools::Itertools;
+
+use crate::{
+    service::{config::BackendHost, resolver::resolver},
+    shutdown::Shutdown,
+};
+
+use super::backend_set::BackendSet;
+
+/// Longest a single name is waited for, the others are refreshed meanwhile and the name keeps
+/// the addresses it had
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Looks the names of a service's backends up every `interval` and swaps the addresses they
+/// resolve to into its backends, so they follow changes to their DNS records without waiting
+/// for the cached ones to expire. Backends are kept by name, so their state isn't touched when
+/// their addresses change. A name that fails to resolve keeps its last addresses.
+pub(crate) struct DnsRefresher {
+    /// Follows the backends as they're replaced
+    pub(crate) backends: BackendSet,
+    pub(crate) interval: Duration,
+}
+
+impl DnsRefresher {
+    /// Refreshes the names until `shutdown` completes
+    pub(crate) async fn run(self, shutdown: Shutdown) {
+        let mut ticks = tokio::time::interval(self.interval);
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {},
+                _ = shutdown.clone() => return,
+            }
+
+            self.refresh().await;
+        }
+    }
+
+    async fn refresh(&self) {
+        let names = self
+            .backends
+            .load()
+            .iter()
+            .filter_map(|backend| match &backend.host {
+                BackendHost::Name(name) => Some(name.to_string()),
+                BackendHost::Ip(_) => None,
+            })
+            .unique()
+            .collect::<Vec<_>>();
+
+        let lookups = names.into_iter().map(|name| async move {
+            match tokio::time::timeout(LOOKUP_TIMEOUT, resolver().refresh_now(&name)).await {
+                Ok(addresses) if !addresses.is_empty() => Some((name, addresses)),
+                Ok(_) => None,
+                Err(_) => {
+                    tracing::warn!(name, "Timed out refreshing name");
+
+                    None
+                }
+            }
+        });
+
+        let resolved: HashMap<_, _> = join_all(lookups).await.into_iter().flatten().collect();
+
+        let changed = self.backends.load().iter().any(|backend| {
+            resolved
+                .get(&backend.host.to_string())
+                .is_some_and(|addresses| *addresses != backend.addresses)
+        });
+
+        if !changed {
+            return;
+        }
+
+        self.backends.update(|backends| {
+            let mut backends = backends.clone();
+
+            for backend in backends.iter_mut() {
+                if let Some(addresses) = resolved.get(&backend.host.to_string()) {
+                    backend.addresses = addresses.clone();
+                }
+            }
+
+            backends
+        });
+    }
+}
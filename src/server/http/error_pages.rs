@@ -0,0 +1,185 @@
+use std::{collections::HashMap, io, path::PathBuf};
+
+use bytes::Bytes;
+use http::{header, HeaderValue, StatusCode};
+use http_body_util::combinators::BoxBody;
+use hyper::Response;
+use serde::{Deserialize, Serialize};
+
+use super::server::full;
+
+/// Pages by status code, e.g. `404: { file: /var/www/404.html }`
+pub(crate) type ErrorPagesConfig = HashMap<u16, ErrorPageConfig>;
+
+/// Body served instead of the plain text of an error response bifrost generates itself.
+/// Responses from backends are never touched.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ErrorPageConfig {
+    #[serde(default = "ErrorPageConfig::default_content_type")]
+    pub(crate) content_type: String,
+    #[serde(flatten)]
+    pub(crate) source: ErrorPageSource,
+}
+
+impl ErrorPageConfig {
+    fn default_content_type() -> String {
+        "text/html; charset=utf-8".to_owned()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ErrorPageSource {
+    /// The page itself, inline in the config
+    Body(String),
+    /// Read once on startup, changes to the file need a restart
+    File(PathBuf),
+}
+
+/// Marks responses bifrost generated itself, only those get error pages
+#[derive(Clone, Copy)]
+pub(crate) struct Generated;
+
+struct ErrorPage {
+    content_type: HeaderValue,
+    body: Bytes,
+}
+
+/// Error pages of a single server
+#[derive(Default)]
+pub(crate) struct ErrorPages(HashMap<StatusCode, ErrorPage>);
+
+impl ErrorPages {
+    /// Reads the pages a server uses, its own ones replace global ones for the same status
+    pub(crate) fn load(global: &ErrorPagesConfig, server: &ErrorPagesConfig) -> io::Result<Self> {
+        let mut pages = HashMap::new();
+
+        for (code, config) in global.iter().chain(server) {
+            let status = StatusCode::from_u16(*code)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+            pages.insert(status, config);
+        }
+
+        pages
+            .into_iter()
+            .map(|(status, config)| Ok((status, ErrorPage::load(config)?)))
+            .collect::<io::Result<_>>()
+            .map(Self)
+    }
+
+    /// Replaces the body of a generated response when there's a page for its status
+    pub(crate) fn apply(&self, response: &mut Response<BoxBody<Bytes, hyper::Error>>) {
+        if response.extensions().get::<Generated>().is_none() {
+            return;
+        }
+
+        let Some(page) = self.0.get(&response.status()) else {
+            return;
+        };
+
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, page.content_type.clone());
+        *response.body_mut() = full(page.body.clone());
+    }
+}
+
+impl ErrorPage {
+    fn load(config: &ErrorPageConfig) -> io::Result<Self> {
+        let content_type = HeaderValue::from_str(&config.content_type)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        let body = match &config.source {
+            ErrorPageSource::Body(body) => Bytes::from(body.clone()),
+            ErrorPageSource::File(path) => std::fs::read(path)
+                .map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!("Failed to read error page {}: {}", path.display(), err),
+                    )
+                })?
+                .into(),
+        };
+
+        Ok(Self { content_type, body })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    fn config(yaml: &str) -> ErrorPagesConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn generated(status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+        Response::builder()
+            .status(status)
+            .extension(Generated)
+            .body(full("plain"))
+            .unwrap()
+    }
+
+    async fn body(response: Response<BoxBody<Bytes, hyper::Error>>) -> Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn server_pages_override_global_ones() {
+        let global = config(
+            "
+            404: { body: global not found }
+            502: { body: global bad gateway, content-type: text/plain }
+            ",
+        );
+        let server = config("404: { body: server not found }");
+
+        let pages = ErrorPages::load(&global, &server).unwrap();
+
+        let mut not_found = generated(StatusCode::NOT_FOUND);
+        pages.apply(&mut not_found);
+
+        assert_eq!(
+            not_found.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(body(not_found).await, "server not found");
+
+        let mut bad_gateway = generated(StatusCode::BAD_GATEWAY);
+        pages.apply(&mut bad_gateway);
+
+        assert_eq!(bad_gateway.headers()[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(body(bad_gateway).await, "global bad gateway");
+    }
+
+    #[tokio::test]
+    async fn backend_responses_are_untouched() {
+        let pages = ErrorPages::load(&config("404: { body: branded }"), &config("{}")).unwrap();
+
+        let mut response = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(full("from backend"))
+            .unwrap();
+        pages.apply(&mut response);
+
+        assert_eq!(body(response).await, "from backend");
+
+        // No page for the status, the plain body stays
+        let mut response = generated(StatusCode::GATEWAY_TIMEOUT);
+        pages.apply(&mut response);
+
+        assert_eq!(body(response).await, "plain");
+    }
+
+    #[test]
+    fn missing_file_fails_loading() {
+        let pages = config("503: { file: /nonexistent/503.html }");
+
+        assert!(ErrorPages::load(&pages, &config("{}")).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use duration_string::DurationString;
+use serde::{Deserialize, Serialize};
+
+use super::backend_set::BackendSet;
+
+/// Number of servers that stopped listening because their backends are down, shared by the
+/// servers and the admin listener that reports it
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Readiness(Arc<AtomicUsize>);
+
+impl Readiness {
+    /// Ready while no server failed closed
+    pub(crate) fn is_ready(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == 0
+    }
+}
+
+/// Stop listening while every backend of the services the server routes to fails its health
+/// checks, so a load balancer in front sends clients elsewhere instead of them getting errors.
+/// The server listens again once a backend is back in rotation.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+pub(crate) struct FailClosedConfig {
+    /// How often the backends are looked at, they're only as fresh as their health checks
+    #[serde(default = "FailClosedConfig::default_check_interval")]
+    pub(crate) check_interval: DurationString,
+}
+
+impl FailClosedConfig {
+    fn default_check_interval() -> DurationString {
+        Duration::from_secs(1).into()
+    }
+}
+
+/// Backends a fail-closed server depends on, and whether it's closed because of them
+pub(crate) struct FailClosed {
+    backends: Vec<BackendSet>,
+    readiness: Readiness,
+    closed: bool,
+}
+
+impl FailClosed {
+    pub(crate) fn new(backends: Vec<BackendSet>, readiness: Readiness) -> Self {
+        Self {
+            backends,
+            readiness,
+            closed: false,
+        }
+    }
+
+    /// Whether any backend is in rotation, a server without backends, e.g. one serving only
+    /// static files, never closes
+    pub(crate) fn backends_up(&self) -> bool {
+        self.backends.is_empty()
+            || self.backends.iter().any(|backends| {
+                let backends = backends.load();
+
+                (0..backends.len()).any(|index| backends.state(index).is_up())
+            })
+    }
+
+    /// Reports the server closed or open to readiness
+    pub(crate) fn set_closed(&mut self, closed: bool) {
+        if closed == self.closed {
+            return;
+        }
+
+        if closed {
+            self.readiness.0.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.readiness.0.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        self.closed = closed;
+    }
+}
+
+impl Drop for FailClosed {
+    fn drop(&mut self) {
+        self.set_closed(false);
+    }
+}
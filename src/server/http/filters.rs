@@ -0,0 +1,656 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use http::{
+    header::{self, InvalidHeaderValue},
+    uri::{Authority, PathAndQuery},
+    HeaderMap, HeaderValue, StatusCode, Uri,
+};
+use http_body_util::combinators::BoxBody;
+use hyper::{body::Body, Request, Response};
+use serde::{Deserialize, Serialize};
+
+use crate::server::host::Hostname;
+
+use super::{
+    headers::ConfiguredHeaderName,
+    location::ClientOrigin,
+    matchers::{media_type, PathPrefix},
+    server::{bad_request, full},
+};
+
+/// Header value that's validated when the config is parsed
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct ConfiguredHeaderValue(HeaderValue);
+
+impl TryFrom<String> for ConfiguredHeaderValue {
+    type Error = InvalidHeaderValue;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        HeaderValue::try_from(value).map(Self)
+    }
+}
+
+impl From<ConfiguredHeaderValue> for String {
+    fn from(value: ConfiguredHeaderValue) -> Self {
+        String::from_utf8_lossy(value.0.as_bytes()).into_owned()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct HeaderConfig {
+    pub(crate) name: ConfiguredHeaderName,
+    pub(crate) value: ConfiguredHeaderValue,
+}
+
+/// Changes to the headers of a message, in the order they're applied: `set` replaces every
+/// value of a header, `add` appends a value and `remove` drops every value of a header.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub(crate) struct HeaderModifier {
+    #[serde(default)]
+    pub(crate) set: Vec<HeaderConfig>,
+    #[serde(default)]
+    pub(crate) add: Vec<HeaderConfig>,
+    #[serde(default)]
+    pub(crate) remove: Vec<ConfiguredHeaderName>,
+}
+
+impl HeaderModifier {
+    pub(crate) fn apply(&self, headers: &mut HeaderMap) {
+        for header in &self.set {
+            headers.insert(&header.name.0, header.value.0.clone());
+        }
+
+        for header in &self.add {
+            headers.append(&header.name.0, header.value.0.clone());
+        }
+
+        for name in &self.remove {
+            headers.remove(&name.0);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    Variable(String),
+}
+
+/// Header value with `{name}` placeholders for the named groups of the regex the rule matches
+/// the path by, e.g. `user-{id}`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct PathTemplate {
+    template: String,
+    parts: Vec<TemplatePart>,
+}
+
+impl TryFrom<String> for PathTemplate {
+    type Error = String;
+
+    fn try_from(template: String) -> Result<Self, Self::Error> {
+        let mut parts = vec![];
+        let mut rest = template.as_str();
+
+        while let Some(start) = rest.find(['{', '}']) {
+            let Some(end) = rest[start..]
+                .find('}')
+                .filter(|_| rest[start..].starts_with('{'))
+            else {
+                return Err(format!("Unbalanced braces in template {}", template));
+            };
+
+            let name = &rest[start + 1..start + end];
+
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!(
+                    "Invalid variable {{{}}} in template {}",
+                    name, template
+                ));
+            }
+
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_owned()));
+            }
+
+            parts.push(TemplatePart::Variable(name.to_owned()));
+            rest = &rest[start + end + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_owned()));
+        }
+
+        Ok(Self { template, parts })
+    }
+}
+
+impl From<PathTemplate> for String {
+    fn from(value: PathTemplate) -> Self {
+        value.template
+    }
+}
+
+impl PathTemplate {
+    pub(crate) fn variables(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            TemplatePart::Variable(name) => Some(name.as_str()),
+            TemplatePart::Literal(_) => None,
+        })
+    }
+
+    /// Variables that didn't capture anything are left empty
+    fn render(&self, variables: &HashMap<&str, &str>) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(literal) => literal.as_str(),
+                TemplatePart::Variable(name) => variables.get(name.as_str()).copied().unwrap_or(""),
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct HeaderTemplate {
+    pub(crate) name: ConfiguredHeaderName,
+    pub(crate) value: PathTemplate,
+}
+
+/// Sets request headers to values taken from the path, so backends get what the route already
+/// parsed out of it, e.g. `x-resource-id: {id}` for a rule matching `/resources/(?<id>[0-9]+)`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct PathVariableHeaders {
+    pub(crate) set: Vec<HeaderTemplate>,
+}
+
+impl PathVariableHeaders {
+    pub(crate) fn variables(&self) -> impl Iterator<Item = &str> {
+        self.set.iter().flat_map(|header| header.value.variables())
+    }
+
+    pub(crate) fn apply(&self, headers: &mut HeaderMap, variables: &HashMap<&str, &str>) {
+        for header in &self.set {
+            match HeaderValue::try_from(header.value.render(variables)) {
+                Ok(value) => {
+                    headers.insert(&header.name.0, value);
+                }
+                Err(err) => tracing::debug!(
+                    header = %header.name.0,
+                    error = %err,
+                    "Value of the header from the path is invalid"
+                ),
+            }
+        }
+    }
+}
+
+/// Status of a redirect, one of 301, 302, 303, 307 and 308
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(try_from = "u16", into = "u16")]
+pub(crate) struct RedirectStatus(StatusCode);
+
+impl TryFrom<u16> for RedirectStatus {
+    type Error = String;
+
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        match code {
+            301 | 302 | 303 | 307 | 308 => Ok(Self(StatusCode::from_u16(code).unwrap())),
+            _ => Err(format!("{} isn't a redirect status", code)),
+        }
+    }
+}
+
+impl From<RedirectStatus> for u16 {
+    fn from(status: RedirectStatus) -> Self {
+        status.0.as_u16()
+    }
+}
+
+impl Default for RedirectStatus {
+    fn default() -> Self {
+        Self(StatusCode::FOUND)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub(crate) enum PathModifier {
+    ReplaceFullPath {
+        value: String,
+    },
+    /// Replaces the path prefix the rule matched, what follows it is kept
+    ReplacePrefixMatch {
+        value: String,
+    },
+}
+
+impl PathModifier {
+    /// `prefix` is the path prefix the rule matched the request by
+    fn replace(&self, path: &str, prefix: Option<&PathPrefix>) -> String {
+        match self {
+            Self::ReplaceFullPath { value } => value.clone(),
+            Self::ReplacePrefixMatch { value } => {
+                match prefix.and_then(|prefix| prefix.strip(path)) {
+                    Some(rest) => match format!("{}{}", value.trim_end_matches('/'), rest) {
+                        replaced if replaced.is_empty() => "/".to_owned(),
+                        replaced => replaced,
+                    },
+                    // Validation only allows it on rules with a prefix, so it always matched
+                    None => path.to_owned(),
+                }
+            }
+        }
+    }
+}
+
+/// Redirect the client gets instead of its request being proxied. The location is the one of
+/// the request with whatever is set here replaced.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct RequestRedirect {
+    pub(crate) scheme: Option<String>,
+    pub(crate) hostname: Option<Hostname>,
+    /// Defaults to the port of the request, or to the one of the new scheme when it's set
+    pub(crate) port: Option<u16>,
+    pub(crate) path: Option<PathModifier>,
+    /// 302 when not set
+    #[serde(default)]
+    pub(crate) status_code: RedirectStatus,
+}
+
+impl RequestRedirect {
+    /// `prefix` is the path prefix the rule matched the request by
+    pub(crate) fn response<B>(
+        &self,
+        req: &Request<B>,
+        prefix: Option<&PathPrefix>,
+    ) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let Some(client) = ClientOrigin::of(req) else {
+            tracing::debug!("Request has no host to redirect from");
+
+            return bad_request();
+        };
+
+        let scheme = self
+            .scheme
+            .as_deref()
+            .or(client.scheme.as_deref())
+            .unwrap_or("http");
+
+        let host = match &self.hostname {
+            Some(hostname) => hostname.to_string(),
+            None => client.authority.host().to_owned(),
+        };
+
+        let port = match (self.port, &self.scheme) {
+            (Some(port), _) => Some(port),
+            (None, Some(_)) => None,
+            (None, None) => client.authority.port_u16(),
+        };
+
+        let authority = match port {
+            Some(443) if scheme == "https" => host,
+            Some(80) if scheme == "http" => host,
+            Some(port) => format!("{}:{}", host, port),
+            None => host,
+        };
+
+        let path = match &self.path {
+            Some(modifier) => modifier.replace(req.uri().path(), prefix),
+            None => req.uri().path().to_owned(),
+        };
+
+        let location = match req.uri().query() {
+            Some(query) => format!("{}://{}{}?{}", scheme, authority, path, query),
+            None => format!("{}://{}{}", scheme, authority, path),
+        };
+
+        match HeaderValue::try_from(location) {
+            Ok(location) => Response::builder()
+                .status(self.status_code.0)
+                .header(header::LOCATION, location)
+                .body(full(""))
+                .expect("Failed to build redirect"),
+            Err(_) => {
+                tracing::debug!(uri = %req.uri(), "Redirect isn't a valid location");
+
+                bad_request()
+            }
+        }
+    }
+}
+
+/// Changes where a request goes on the backend, named `URLRewrite` like in Gateway API
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct UrlRewrite {
+    /// Replaces the Host header, and the authority of requests that have one in their URI
+    pub(crate) hostname: Option<Hostname>,
+    pub(crate) path: Option<PathModifier>,
+}
+
+impl UrlRewrite {
+    /// `prefix` is the path prefix the rule matched the request by
+    pub(crate) fn apply<B>(&self, req: &mut Request<B>, prefix: Option<&PathPrefix>) {
+        let mut parts = req.uri().clone().into_parts();
+
+        if let Some(modifier) = &self.path {
+            let path = modifier.replace(req.uri().path(), prefix);
+
+            let path_and_query = match req.uri().query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path,
+            };
+
+            match PathAndQuery::try_from(path_and_query) {
+                Ok(path_and_query) => parts.path_and_query = Some(path_and_query),
+                Err(err) => {
+                    tracing::debug!(uri = %req.uri(), error = %err, "Rewritten path is invalid")
+                }
+            }
+        }
+
+        if let Some(hostname) = &self.hostname {
+            let host = hostname.to_string();
+
+            if parts.authority.is_some() {
+                parts.authority = Some(Authority::try_from(host.as_str()).expect("Valid hostname"));
+            }
+
+            req.headers_mut().insert(
+                header::HOST,
+                HeaderValue::try_from(host).expect("Valid hostname"),
+            );
+        }
+
+        match Uri::from_parts(parts) {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(err) => tracing::debug!(uri = %req.uri(), error = %err, "Rewritten URI is invalid"),
+        }
+    }
+}
+
+/// Media types request bodies can have, others are rejected with `415` before the request is
+/// sent to the backend. Parameters like `; charset=utf-8` are ignored. A rule has at most one.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct AllowedContentTypes {
+    pub(crate) types: Vec<String>,
+}
+
+impl AllowedContentTypes {
+    /// Requests without a body don't need a `Content-Type`
+    pub(crate) fn allows<B: Body>(&self, req: &Request<B>) -> bool {
+        if req.body().is_end_stream() {
+            return true;
+        }
+
+        media_type(req.headers()).is_some_and(|media_type| {
+            self.types
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(&media_type))
+        })
+    }
+}
+
+/// Sends a copy of every request the rule matches to another service, see `Mirrors`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct RequestMirror {
+    pub(crate) service: String,
+}
+
+/// Filters of a rule, named after the ones of Gateway API's `HTTPRoute`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub(crate) enum Filter {
+    /// Changes the headers of requests before they're sent to the backend
+    RequestHeaderModifier(HeaderModifier),
+    /// Changes the headers of responses before they're sent to the client, e.g. to add
+    /// `Strict-Transport-Security` or strip `Server`
+    ResponseHeaderModifier(HeaderModifier),
+    /// Responds with a redirect, the request isn't sent to the backend
+    RequestRedirect(RequestRedirect),
+    #[serde(rename = "URLRewrite")]
+    UrlRewrite(UrlRewrite),
+    /// Sends a copy of every request to another service, list several to copy to several
+    RequestMirror(RequestMirror),
+    AllowedContentTypes(AllowedContentTypes),
+    PathVariableHeaders(PathVariableHeaders),
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header;
+
+    use super::*;
+
+    fn modifier(yaml: &str) -> HeaderModifier {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        headers.append(header::ACCEPT, HeaderValue::from_static("text/html"));
+        headers.append(header::ACCEPT, HeaderValue::from_static("text/plain"));
+
+        headers
+    }
+
+    #[test]
+    fn set_replaces_every_value() {
+        let mut headers = headers();
+
+        modifier("set: [{ name: accept, value: application/json }]").apply(&mut headers);
+
+        let accept: Vec<_> = headers.get_all(header::ACCEPT).iter().collect();
+        assert_eq!(accept, ["application/json"]);
+    }
+
+    #[test]
+    fn add_appends_a_value() {
+        let mut headers = headers();
+
+        modifier(
+            "add: [{ name: accept, value: application/json }, { name: x-tenant, value: acme }]",
+        )
+        .apply(&mut headers);
+
+        let accept: Vec<_> = headers.get_all(header::ACCEPT).iter().collect();
+        assert_eq!(accept, ["text/html", "text/plain", "application/json"]);
+        assert_eq!(headers["x-tenant"], "acme");
+    }
+
+    #[test]
+    fn remove_drops_every_value() {
+        let mut headers = headers();
+
+        // A header that isn't there is fine
+        modifier("remove: [accept, x-missing]").apply(&mut headers);
+
+        assert!(headers.is_empty());
+    }
+
+    fn redirect(
+        yaml: &str,
+        prefix: Option<&str>,
+        uri: &str,
+    ) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let redirect: RequestRedirect = serde_yaml::from_str(yaml).unwrap();
+        let prefix = prefix.map(|prefix| prefix.parse::<PathPrefix>().unwrap());
+
+        let req = Request::get(uri)
+            .header(header::HOST, "example.com:8080")
+            .body(())
+            .unwrap();
+
+        redirect.response(&req, prefix.as_ref())
+    }
+
+    #[test]
+    fn redirect_replaces_full_path() {
+        let response = redirect(
+            "{ scheme: https, path: { type: ReplaceFullPath, value: /login }, status_code: 301 }",
+            None,
+            "/account/settings?tab=1",
+        );
+
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://example.com/login?tab=1"
+        );
+    }
+
+    #[test]
+    fn redirect_replaces_prefix() {
+        let replace = |value: &str, uri: &str| {
+            let response = redirect(
+                &format!(
+                    "{{ path: {{ type: ReplacePrefixMatch, value: '{}' }} }}",
+                    value
+                ),
+                Some("/v1"),
+                uri,
+            );
+
+            assert_eq!(response.status(), StatusCode::FOUND);
+
+            response.headers()[header::LOCATION]
+                .to_str()
+                .unwrap()
+                .to_owned()
+        };
+
+        assert_eq!(
+            replace("/v2", "/v1/users/7"),
+            "http://example.com:8080/v2/users/7"
+        );
+        assert_eq!(replace("/v2/", "/v1"), "http://example.com:8080/v2");
+        assert_eq!(replace("/", "/v1/users"), "http://example.com:8080/users");
+        assert_eq!(replace("/", "/v1"), "http://example.com:8080/");
+    }
+
+    fn rewritten(yaml: &str, prefix: &str, uri: &str) -> Request<()> {
+        let rewrite: UrlRewrite = serde_yaml::from_str(yaml).unwrap();
+        let prefix = prefix.parse::<PathPrefix>().unwrap();
+
+        let mut req = Request::get(uri)
+            .header(header::HOST, "example.com")
+            .body(())
+            .unwrap();
+
+        rewrite.apply(&mut req, Some(&prefix));
+
+        req
+    }
+
+    #[test]
+    fn rewrite_strips_matched_prefix() {
+        let strip = "path: { type: ReplacePrefixMatch, value: / }";
+
+        assert_eq!(
+            rewritten(strip, "/api", "/api/users?page=2").uri(),
+            "/users?page=2"
+        );
+        assert_eq!(rewritten(strip, "/api", "/api").uri(), "/");
+        assert_eq!(rewritten(strip, "/api/", "/api/").uri(), "/");
+
+        let replace = "path: { type: ReplacePrefixMatch, value: /internal/v2 }";
+
+        assert_eq!(
+            rewritten(replace, "/api", "/api/users/7").uri(),
+            "/internal/v2/users/7"
+        );
+        assert_eq!(rewritten(replace, "/api", "/api").uri(), "/internal/v2");
+    }
+
+    #[test]
+    fn rewrite_replaces_full_path_and_host() {
+        let req = rewritten(
+            "{ hostname: backend.internal, path: { type: ReplaceFullPath, value: /health } }",
+            "/api",
+            "http://example.com/api/status",
+        );
+
+        assert_eq!(req.uri(), "http://backend.internal/health");
+        assert_eq!(req.headers()[header::HOST], "backend.internal");
+    }
+
+    #[test]
+    fn content_type_has_to_be_allowed() {
+        let allowed: AllowedContentTypes =
+            serde_yaml::from_str("types: [application/json, text/plain]").unwrap();
+
+        let request = |content_type: Option<&str>, body: &'static str| {
+            let mut builder = Request::post("/");
+
+            if let Some(content_type) = content_type {
+                builder = builder.header(header::CONTENT_TYPE, content_type);
+            }
+
+            builder.body(full(body)).unwrap()
+        };
+
+        assert!(allowed.allows(&request(Some("application/json"), "{}")));
+        assert!(allowed.allows(&request(Some("Application/JSON; charset=utf-8"), "{}")));
+        assert!(!allowed.allows(&request(Some("application/xml"), "<a/>")));
+        assert!(!allowed.allows(&request(None, "{}")));
+
+        // There's nothing to validate without a body
+        assert!(allowed.allows(&request(None, "")));
+    }
+
+    #[test]
+    fn path_variables_are_templated_into_headers() {
+        let filter: PathVariableHeaders = serde_yaml::from_str(
+            "set: [{ name: x-resource, value: '{kind}/{id}' }, { name: x-user, value: 'user-{user}' }]",
+        )
+        .unwrap();
+
+        assert_eq!(
+            filter.variables().collect::<Vec<_>>(),
+            ["kind", "id", "user"]
+        );
+
+        let mut headers = HeaderMap::new();
+        filter.apply(
+            &mut headers,
+            &HashMap::from([("kind", "orders"), ("id", "12")]),
+        );
+
+        assert_eq!(headers["x-resource"], "orders/12");
+        assert_eq!(headers["x-user"], "user-");
+    }
+
+    #[test]
+    fn templates_have_to_be_balanced() {
+        for template in ["{id", "id}", "{}", "{a-b}", "}{"] {
+            assert!(PathTemplate::try_from(template.to_owned()).is_err());
+        }
+    }
+
+    #[test]
+    fn redirect_status_has_to_be_a_redirect() {
+        assert!(serde_yaml::from_str::<RequestRedirect>("{ status_code: 200 }").is_err());
+    }
+
+    #[test]
+    fn filters_are_tagged_by_type() {
+        let filter: Filter =
+            serde_yaml::from_str("{ type: RequestHeaderModifier, remove: [cookie] }").unwrap();
+
+        assert!(matches!(
+            filter,
+            Filter::RequestHeaderModifier(modifier) if modifier.remove.len() == 1
+        ));
+
+        let filter: Filter =
+            serde_yaml::from_str("{ type: ResponseHeaderModifier, remove: [server] }").unwrap();
+
+        assert!(matches!(filter, Filter::ResponseHeaderModifier(_)));
+        assert!(
+            serde_yaml::from_str::<HeaderModifier>("set: [{ name: 'a b', value: c }]").is_err()
+        );
+    }
+}
@@ -0,0 +1,440 @@
+//! Translation between gRPC-Web, which browsers can speak, and regular gRPC for the backends.
+//!
+//! Messages are framed the same way in both, the differences are the content type, trailers
+//! which gRPC-Web sends as a last frame of the body, and the text variant that base64 encodes the
+//! whole body.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{header, HeaderMap, HeaderValue};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::{
+    body::{Body, Frame},
+    Request, Response,
+};
+
+const GRPC: &str = "application/grpc";
+const GRPC_WEB: &str = "application/grpc-web";
+const GRPC_WEB_TEXT: &str = "application/grpc-web-text";
+
+/// Flag of the frame gRPC-Web puts trailers into
+const TRAILERS_FLAG: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Encoding {
+    Binary,
+    /// Base64 encoded body, for clients that can't read binary responses as a stream
+    Text,
+}
+
+impl Encoding {
+    /// gRPC-Web encoding of the request, `None` when it's not a gRPC-Web request
+    pub(crate) fn of<B>(req: &Request<B>) -> Option<Self> {
+        let content_type = req.headers().get(header::CONTENT_TYPE)?.to_str().ok()?;
+
+        if content_type.starts_with(GRPC_WEB_TEXT) {
+            Some(Self::Text)
+        } else if content_type.starts_with(GRPC_WEB) {
+            Some(Self::Binary)
+        } else {
+            None
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Self::Binary => GRPC_WEB,
+            Self::Text => GRPC_WEB_TEXT,
+        }
+    }
+}
+
+/// Replaces the `prefix` of a content type, keeping the rest, e.g. `+proto`
+fn replace_content_type(headers: &mut HeaderMap, prefix: &str, replacement: &str) {
+    let Some(rest) = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(prefix))
+    else {
+        return;
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&format!("{}{}", replacement, rest)) {
+        headers.insert(header::CONTENT_TYPE, value);
+    }
+}
+
+/// Turns a gRPC-Web request into a gRPC one, fails if a text request isn't valid base64
+pub(crate) async fn translate_request(
+    req: Request<BoxBody<Bytes, hyper::Error>>,
+    encoding: Encoding,
+) -> Result<Request<BoxBody<Bytes, hyper::Error>>, String> {
+    let (mut parts, body) = req.into_parts();
+
+    replace_content_type(&mut parts.headers, encoding.content_type(), GRPC);
+
+    // gRPC servers reject requests that don't promise to read trailers
+    parts
+        .headers
+        .insert(header::TE, HeaderValue::from_static("trailers"));
+
+    let body = match encoding {
+        Encoding::Binary => body,
+        Encoding::Text => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+
+            // Browsers can't stream request bodies, so it's always a single message
+            let text = body
+                .collect()
+                .await
+                .map_err(|err| err.to_string())?
+                .to_bytes();
+
+            let decoded = decode_text(&text)?;
+
+            Full::new(Bytes::from(decoded))
+                .map_err(|never| match never {})
+                .boxed()
+        }
+    };
+
+    Ok(Request::from_parts(parts, body))
+}
+
+/// Clients may encode every frame separately, so there can be padding in the middle
+fn decode_text(text: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoded = vec![];
+
+    for chunk in text.split_inclusive(|&byte| byte == b'=') {
+        if chunk.iter().all(|&byte| byte == b'=') {
+            // Leftover padding of the previous chunk
+            continue;
+        }
+
+        STANDARD
+            .decode_vec(pad(chunk), &mut decoded)
+            .map_err(|err| format!("Invalid gRPC-Web text body: {}", err))?;
+    }
+
+    Ok(decoded)
+}
+
+/// Splitting on `=` leaves only the first byte of a two byte padding with the chunk
+fn pad(chunk: &[u8]) -> Vec<u8> {
+    let mut chunk = chunk.to_vec();
+
+    while !chunk.len().is_multiple_of(4) {
+        chunk.push(b'=');
+    }
+
+    chunk
+}
+
+/// Turns a gRPC response into a gRPC-Web one with trailers moved into the body
+pub(crate) fn translate_response(
+    response: Response<BoxBody<Bytes, hyper::Error>>,
+    encoding: Encoding,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let (mut parts, body) = response.into_parts();
+
+    // Errors of the proxy itself aren't gRPC responses
+    let is_grpc = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(GRPC));
+
+    if !is_grpc {
+        return Response::from_parts(parts, body);
+    }
+
+    replace_content_type(&mut parts.headers, GRPC, encoding.content_type());
+    parts.headers.remove(header::CONTENT_LENGTH);
+
+    let body = GrpcWebBody {
+        inner: body,
+        encoding,
+        leftover: BytesMut::new(),
+        done: false,
+    };
+
+    Response::from_parts(parts, body.boxed())
+}
+
+/// Response body that turns trailers into a trailers frame and base64 encodes everything for
+/// text clients
+struct GrpcWebBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    encoding: Encoding,
+    /// Bytes that didn't fit into a whole base64 group yet, so the output has no padding in the
+    /// middle
+    leftover: BytesMut,
+    done: bool,
+}
+
+impl GrpcWebBody {
+    fn encode(&mut self, data: Bytes, last: bool) -> Bytes {
+        match self.encoding {
+            Encoding::Binary => data,
+            Encoding::Text => {
+                self.leftover.put(data);
+
+                let whole = if last {
+                    self.leftover.len()
+                } else {
+                    self.leftover.len() / 3 * 3
+                };
+
+                let chunk = self.leftover.split_to(whole);
+
+                Bytes::from(STANDARD.encode(chunk))
+            }
+        }
+    }
+}
+
+fn trailers_frame(trailers: &HeaderMap) -> Bytes {
+    let mut block = BytesMut::new();
+
+    for (name, value) in trailers {
+        block.put(name.as_str().as_bytes());
+        block.put_u8(b':');
+        block.put(value.as_bytes());
+        block.put(&b"\r\n"[..]);
+    }
+
+    let mut frame = BytesMut::with_capacity(5 + block.len());
+
+    frame.put_u8(TRAILERS_FLAG);
+    frame.put_u32(block.len() as u32);
+    frame.put(block);
+
+    frame.freeze()
+}
+
+impl Body for GrpcWebBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        loop {
+            if self.done {
+                return Poll::Ready(None);
+            }
+
+            let frame = match Pin::new(&mut self.inner).poll_frame(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(Some(Ok(frame))) => frame,
+                Poll::Ready(None) => {
+                    self.done = true;
+
+                    let rest = self.encode(Bytes::new(), true);
+
+                    return Poll::Ready((!rest.is_empty()).then(|| Ok(Frame::data(rest))));
+                }
+            };
+
+            let data = match frame.into_data() {
+                Ok(data) => self.encode(data, false),
+                Err(frame) => match frame.into_trailers() {
+                    Ok(trailers) => {
+                        // Trailers are always the end of the response
+                        self.done = true;
+
+                        self.encode(trailers_frame(&trailers), true)
+                    }
+                    Err(_) => continue,
+                },
+            };
+
+            if !data.is_empty() {
+                return Poll::Ready(Some(Ok(Frame::data(data))));
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+    use http_body_util::StreamBody;
+
+    use super::*;
+
+    /// Single message frame with the given payload
+    fn message(payload: &[u8]) -> Bytes {
+        let mut frame = BytesMut::new();
+
+        frame.put_u8(0);
+        frame.put_u32(payload.len() as u32);
+        frame.put(payload);
+
+        frame.freeze()
+    }
+
+    fn grpc_response(messages: Vec<Bytes>) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+
+        let frames = messages
+            .into_iter()
+            .map(Frame::data)
+            .chain([Frame::trailers(trailers)])
+            .map(Ok::<_, hyper::Error>);
+
+        Response::builder()
+            .header(header::CONTENT_TYPE, "application/grpc+proto")
+            .body(StreamBody::new(stream::iter(frames)).boxed())
+            .unwrap()
+    }
+
+    fn request(content_type: &str, body: &'static [u8]) -> Request<BoxBody<Bytes, hyper::Error>> {
+        Request::post("/pkg.Service/Method")
+            .header(header::CONTENT_TYPE, content_type)
+            .body(
+                Full::new(Bytes::from_static(body))
+                    .map_err(|never| match never {})
+                    .boxed(),
+            )
+            .unwrap()
+    }
+
+    async fn body_bytes(response: Response<BoxBody<Bytes, hyper::Error>>) -> Bytes {
+        response.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[test]
+    fn detects_encoding() {
+        assert_eq!(
+            Encoding::of(&request("application/grpc-web+proto", b"")),
+            Some(Encoding::Binary)
+        );
+        assert_eq!(
+            Encoding::of(&request("application/grpc-web-text", b"")),
+            Some(Encoding::Text)
+        );
+        assert_eq!(Encoding::of(&request("application/grpc", b"")), None);
+    }
+
+    #[tokio::test]
+    async fn binary_request_keeps_body() {
+        let req = translate_request(
+            request("application/grpc-web+proto", b"\0\0\0\0\x01a"),
+            Encoding::Binary,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            req.headers()[header::CONTENT_TYPE],
+            "application/grpc+proto"
+        );
+        assert_eq!(req.headers()[header::TE], "trailers");
+        assert_eq!(
+            req.into_body().collect().await.unwrap().to_bytes(),
+            &b"\0\0\0\0\x01a"[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn text_request_is_decoded() {
+        // Base64 of a single frame with `a` as the message
+        let req = translate_request(
+            request("application/grpc-web-text", b"AAAAAAFh"),
+            Encoding::Text,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(req.headers()[header::CONTENT_TYPE], "application/grpc");
+        assert_eq!(
+            req.into_body().collect().await.unwrap().to_bytes(),
+            &b"\0\0\0\0\x01a"[..]
+        );
+    }
+
+    #[test]
+    fn text_with_padding_in_the_middle() {
+        let first = STANDARD.encode(message(b"a"));
+        let second = STANDARD.encode(message(b"bc"));
+
+        let decoded = decode_text(format!("{}{}", first, second).as_bytes()).unwrap();
+
+        assert_eq!(decoded, [message(b"a"), message(b"bc")].concat());
+    }
+
+    #[tokio::test]
+    async fn binary_response_gets_trailers_frame() {
+        let response = translate_response(grpc_response(vec![message(b"hello")]), Encoding::Binary);
+
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/grpc-web+proto"
+        );
+
+        let expected = [
+            message(b"hello").to_vec(),
+            vec![0x80, 0, 0, 0, 15],
+            b"grpc-status:0\r\n".to_vec(),
+        ]
+        .concat();
+
+        assert_eq!(body_bytes(response).await, expected);
+    }
+
+    #[tokio::test]
+    async fn text_response_is_encoded() {
+        let messages = vec![message(b"a"), message(b"hello world")];
+        let response = translate_response(grpc_response(messages.clone()), Encoding::Text);
+
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/grpc-web-text+proto"
+        );
+
+        let body = body_bytes(response).await;
+        let decoded = STANDARD.decode(&body).unwrap();
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+
+        assert_eq!(
+            decoded,
+            [
+                messages[0].to_vec(),
+                messages[1].to_vec(),
+                trailers_frame(&trailers).to_vec()
+            ]
+            .concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn non_grpc_response_is_untouched() {
+        let response = Response::builder()
+            .status(502)
+            .body(
+                Full::new(Bytes::from_static(b"Bad gateway"))
+                    .map_err(|never| match never {})
+                    .boxed(),
+            )
+            .unwrap();
+
+        let response = translate_response(response, Encoding::Text);
+
+        assert_eq!(body_bytes(response).await, &b"Bad gateway"[..]);
+    }
+}
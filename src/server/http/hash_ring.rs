@@ -0,0 +1,105 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// Points every backend gets on the ring, more of them spread keys more evenly
+const POINTS_PER_BACKEND: usize = 100;
+
+/// Consistent hash ring of backends, identified by their addresses.
+///
+/// A key belongs to the backend of the first point at or after the key's hash. Adding or
+/// removing a backend only moves the keys of its own points, everything else stays where it was.
+#[derive(Debug)]
+pub(crate) struct HashRing {
+    /// Hash of a point and the index of its backend, sorted by the hash
+    points: Vec<(u64, usize)>,
+    backends: usize,
+}
+
+impl HashRing {
+    pub(crate) fn new<'a>(addresses: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut points = vec![];
+        let mut backends = 0;
+
+        for (index, address) in addresses.into_iter().enumerate() {
+            for point in 0..POINTS_PER_BACKEND {
+                points.push((hash(&(address, point)), index));
+            }
+
+            backends += 1;
+        }
+
+        points.sort_unstable();
+
+        Self { points, backends }
+    }
+
+    /// Indexes of the backends in the order `key` tries them. The first one is the key's own
+    /// backend, the rest is the order of failover, which is the same every time for a key: the
+    /// ring is walked from the key and every backend is taken once, the first time it's met.
+    pub(crate) fn walk(&self, key: u64) -> impl Iterator<Item = usize> + '_ {
+        let start = self.points.partition_point(|(point, _)| *point < key);
+        let mut seen = vec![false; self.backends];
+
+        self.points[start..]
+            .iter()
+            .chain(&self.points[..start])
+            .filter_map(move |&(_, index)| {
+                let first_time = !seen[index];
+                seen[index] = true;
+
+                first_time.then_some(index)
+            })
+    }
+}
+
+/// Stable within a build, which is as long as a ring lives
+pub(crate) fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    value.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKENDS: [&str; 4] = ["10.0.0.1:80", "10.0.0.2:80", "10.0.0.3:80", "10.0.0.4:80"];
+
+    fn walk_order(ring: &HashRing, addresses: &[&str], key: u64) -> Vec<String> {
+        ring.walk(key)
+            .map(|index| addresses[index].to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn walk_visits_every_backend_once() {
+        let ring = HashRing::new(BACKENDS);
+
+        for key in (0..1000).map(|key| hash(&key)) {
+            let mut order = walk_order(&ring, &BACKENDS, key);
+
+            order.sort();
+
+            assert_eq!(order, BACKENDS);
+        }
+    }
+
+    #[test]
+    fn removed_primary_fails_over_to_the_same_secondary() {
+        let ring = HashRing::new(BACKENDS);
+
+        for key in (0..1000).map(|key| hash(&format!("/path/{}", key))) {
+            let order = walk_order(&ring, &BACKENDS, key);
+
+            let remaining: Vec<&str> = BACKENDS
+                .into_iter()
+                .filter(|address| *address != order[0])
+                .collect();
+            let without_primary = HashRing::new(remaining.iter().copied());
+
+            // Failover while the primary is down goes where the key lands once it's removed
+            assert_eq!(walk_order(&without_primary, &remaining, key)[0], order[1]);
+        }
+    }
+}
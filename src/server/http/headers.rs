@@ -0,0 +1,21 @@
+use http::{header::InvalidHeaderName, HeaderName};
+use serde::{Deserialize, Serialize};
+
+/// Header name that's validated when the config is parsed
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct ConfiguredHeaderName(pub(crate) HeaderName);
+
+impl TryFrom<String> for ConfiguredHeaderName {
+    type Error = InvalidHeaderName;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        HeaderName::try_from(value).map(Self)
+    }
+}
+
+impl From<ConfiguredHeaderName> for String {
+    fn from(value: ConfiguredHeaderName) -> Self {
+        value.0.to_string()
+    }
+}
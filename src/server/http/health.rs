@@ -0,0 +1,190 @@
+use std::{collections::HashMap, error::Error, net::IpAddr, time::Duration};
+
+use bytes::Bytes;
+use duration_string::DurationString;
+use futures::future::join_all;
+use http::{header, Request};
+use http_body_util::Empty;
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    service::{config::BackendDefinition, tls::BackendTls},
+    shutdown::Shutdown,
+};
+
+use super::backend_set::BackendSet;
+
+/// Active health checks of a service's backends. Every `interval` each backend gets
+/// a `GET` of `path`, which passes when it's answered with a 2xx or 3xx status within `timeout`.
+/// A backend is taken out of rotation after `unhealthy-threshold` failed checks in a row and put
+/// back after `healthy-threshold` passed ones.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct HealthCheckConfig {
+    #[serde(default = "HealthCheckConfig::default_path")]
+    pub(crate) path: String,
+    pub(crate) interval: DurationString,
+    /// Defaults to the interval
+    pub(crate) timeout: Option<DurationString>,
+    #[serde(default = "HealthCheckConfig::default_healthy_threshold")]
+    pub(crate) healthy_threshold: u32,
+    #[serde(default = "HealthCheckConfig::default_unhealthy_threshold")]
+    pub(crate) unhealthy_threshold: u32,
+    /// `Host` of the checks, for backends that serve virtual hosts and answer anything else with
+    /// `404`. Defaults to the SNI of the service, then to the backend address.
+    pub(crate) host: Option<String>,
+}
+
+impl HealthCheckConfig {
+    fn default_path() -> String {
+        "/".to_owned()
+    }
+
+    fn default_healthy_threshold() -> u32 {
+        2
+    }
+
+    fn default_unhealthy_threshold() -> u32 {
+        3
+    }
+}
+
+pub(crate) struct HealthChecker {
+    pub(crate) service_name: String,
+    /// Shared with the load balancer of the service, which skips the backends taken out of
+    /// rotation. Checks follow the backends as they're replaced.
+    pub(crate) backends: BackendSet,
+    pub(crate) config: HealthCheckConfig,
+    /// Checks come from the source address of the service, like its requests
+    pub(crate) source_address: Option<IpAddr>,
+    /// Checks connect the way requests do
+    pub(crate) tls: Option<BackendTls>,
+}
+
+impl HealthChecker {
+    /// Checks the backends until `shutdown` completes
+    pub(crate) async fn run(self, shutdown: Shutdown) {
+        let Self {
+            service_name,
+            backends,
+            config,
+            source_address,
+            tls,
+        } = self;
+
+        let interval: Duration = config.interval.into();
+        let timeout = config.timeout.map_or(interval, Duration::from);
+        let mut ticks = tokio::time::interval(interval);
+
+        // Checks in a row that disagree with the current state of each backend, by address
+        let mut streaks: HashMap<String, u32> = HashMap::new();
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {},
+                _ = shutdown.clone() => return,
+            }
+
+            let backends = backends.load();
+            let checks = backends
+                .iter()
+                .map(|backend| check(backend, &config, timeout, source_address, tls.as_ref()));
+            let passed = join_all(checks).await;
+
+            // Backends that are gone don't have a streak to keep
+            streaks
+                .retain(|address, _| backends.iter().any(|backend| backend.address() == *address));
+
+            for (index, passed) in passed.into_iter().enumerate() {
+                let state = backends.state(index);
+                let address = backends[index].address();
+                let up = state.is_up();
+
+                if passed == up {
+                    streaks.remove(&address);
+                    continue;
+                }
+
+                let streak = streaks.entry(address.clone()).or_default();
+                *streak += 1;
+
+                let threshold = if up {
+                    config.unhealthy_threshold
+                } else {
+                    config.healthy_threshold
+                };
+
+                if *streak < threshold {
+                    continue;
+                }
+
+                *streak = 0;
+                state.set_up(passed);
+
+                if passed {
+                    tracing::info!(
+                        service = %service_name,
+                        backend = %address,
+                        "Backend is healthy again, putting it back in rotation"
+                    );
+                } else {
+                    tracing::warn!(
+                        service = %service_name,
+                        backend = %address,
+                        failed_checks = threshold,
+                        "Backend failed health checks, taking it out of rotation"
+                    );
+                }
+            }
+        }
+    }
+}
+
+async fn check(
+    backend: &BackendDefinition,
+    config: &HealthCheckConfig,
+    timeout: Duration,
+    source: Option<IpAddr>,
+    tls: Option<&BackendTls>,
+) -> bool {
+    let checked = async {
+        use hyper::client::conn::http1;
+
+        let stream = backend.get_connection(source, tls, false).await?;
+        let (mut sender, conn) = http1::handshake(TokioIo::new(stream)).await?;
+
+        tokio::spawn(async move {
+            // Ends once the sender is dropped, failures show up in the response
+            let _ = conn.await;
+        });
+
+        let host = match (&config.host, tls.and_then(BackendTls::sni)) {
+            (Some(host), _) => host.clone(),
+            (None, Some(sni)) => sni.to_owned(),
+            (None, None) => backend.address(),
+        };
+
+        let req = Request::get(&config.path)
+            .header(header::HOST, host)
+            .body(Empty::<Bytes>::new())?;
+
+        let response = sender.send_request(req).await?;
+
+        Ok::<_, Box<dyn Error + Send + Sync>>(response.status())
+    };
+
+    match tokio::time::timeout(timeout, checked).await {
+        Ok(Ok(status)) => status.is_success() || status.is_redirection(),
+        Ok(Err(err)) => {
+            tracing::debug!("Health check of {} failed: {}", backend.address(), err);
+
+            false
+        }
+        Err(_) => {
+            tracing::debug!("Health check of {} timed out", backend.address());
+
+            false
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+use duration_string::DurationString;
+use http::Method;
+use serde::{Deserialize, Serialize};
+
+/// Bytes of request body buffered to send copies of a request, larger ones aren't hedged
+pub(crate) const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Response times the percentile is taken from, the oldest one makes room for the newest
+const SAMPLES: usize = 100;
+
+/// Response times needed before the percentile is trusted over the configured delay
+const MIN_SAMPLES: usize = 20;
+
+/// Hedging as it's written in the config.
+///
+/// A request that hasn't been answered in time is sent once more to the next backend, whichever
+/// responds first wins and the other one is cancelled. Every hedge takes a token from the retry
+/// budget of the service, so slow backends don't get their load multiplied.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct HedgingConfig {
+    /// How long to wait for a response before hedging, used until there are enough responses to
+    /// tell the percentile
+    pub(crate) delay: DurationString,
+    /// Percentile of recent response times to wait for instead of `delay`, e.g. `95`
+    pub(crate) percentile: Option<f64>,
+    /// Hedges a single request can make besides the original
+    #[serde(default = "HedgingConfig::default_max_hedges")]
+    pub(crate) max_hedges: u32,
+}
+
+impl HedgingConfig {
+    fn default_max_hedges() -> u32 {
+        1
+    }
+}
+
+/// Hedging of a service along with the response times it picks the delay from
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(try_from = "HedgingConfig", into = "HedgingConfig")]
+pub(crate) struct Hedging {
+    config: HedgingConfig,
+    /// Only locked while it's read or updated, so requests to the service don't wait on each
+    /// other
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl Clone for Hedging {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            samples: Mutex::new(self.samples().clone()),
+        }
+    }
+}
+
+impl TryFrom<HedgingConfig> for Hedging {
+    type Error = String;
+
+    fn try_from(config: HedgingConfig) -> Result<Self, Self::Error> {
+        if let Some(percentile) = config.percentile {
+            if !(percentile > 0.0 && percentile <= 100.0) {
+                return Err(format!(
+                    "Hedging percentile has to be above 0 and at most 100, got {}",
+                    percentile
+                ));
+            }
+        }
+
+        if config.max_hedges == 0 {
+            return Err("Hedging needs max-hedges of at least 1".to_owned());
+        }
+
+        Ok(Self {
+            config,
+            samples: Mutex::new(VecDeque::with_capacity(SAMPLES)),
+        })
+    }
+}
+
+impl From<Hedging> for HedgingConfig {
+    fn from(value: Hedging) -> Self {
+        value.config
+    }
+}
+
+impl Hedging {
+    pub(crate) fn max_hedges(&self) -> u32 {
+        self.config.max_hedges
+    }
+
+    /// Only requests that can be sent twice without harm are hedged
+    pub(crate) fn applies(&self, method: &Method) -> bool {
+        [
+            Method::GET,
+            Method::HEAD,
+            Method::OPTIONS,
+            Method::TRACE,
+            Method::PUT,
+            Method::DELETE,
+        ]
+        .contains(method)
+    }
+
+    /// How long an attempt is given before the next one is sent
+    pub(crate) fn delay(&self) -> Duration {
+        let Some(percentile) = self.config.percentile else {
+            return self.config.delay.into();
+        };
+
+        let mut samples: Vec<Duration> = self.samples().iter().copied().collect();
+
+        if samples.len() < MIN_SAMPLES {
+            return self.config.delay.into();
+        }
+
+        samples.sort_unstable();
+
+        let rank = (percentile / 100.0 * samples.len() as f64).ceil() as usize;
+
+        samples[rank.clamp(1, samples.len()) - 1]
+    }
+
+    /// Time it took a backend to respond
+    pub(crate) fn record(&self, response_time: Duration) {
+        let mut samples = self.samples();
+
+        if samples.len() == SAMPLES {
+            samples.pop_front();
+        }
+
+        samples.push_back(response_time);
+    }
+
+    fn samples(&self) -> MutexGuard<'_, VecDeque<Duration>> {
+        self.samples.lock().expect("Hedging samples lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hedging(yaml: &str) -> Hedging {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn delay_is_configured_until_there_are_enough_samples() {
+        let hedging = hedging("{ delay: 50ms, percentile: 90 }");
+
+        for millis in 1..MIN_SAMPLES as u64 {
+            hedging.record(Duration::from_millis(millis));
+        }
+
+        assert_eq!(hedging.delay(), Duration::from_millis(50));
+
+        hedging.record(Duration::from_millis(MIN_SAMPLES as u64));
+
+        assert_eq!(hedging.delay(), Duration::from_millis(18));
+    }
+
+    #[test]
+    fn old_samples_make_room() {
+        let hedging = hedging("{ delay: 50ms, percentile: 100 }");
+
+        hedging.record(Duration::from_secs(10));
+
+        for _ in 0..SAMPLES {
+            hedging.record(Duration::from_millis(5));
+        }
+
+        assert_eq!(hedging.delay(), Duration::from_millis(5));
+    }
+
+    #[test]
+    fn only_idempotent_requests_are_hedged() {
+        let hedging = hedging("{ delay: 50ms }");
+
+        assert!(hedging.applies(&Method::GET));
+        assert!(hedging.applies(&Method::PUT));
+        assert!(!hedging.applies(&Method::POST));
+        assert!(!hedging.applies(&Method::PATCH));
+    }
+
+    #[test]
+    fn invalid_percentile_is_rejected() {
+        for percentile in ["0", "101", "-5"] {
+            let result: Result<Hedging, _> =
+                serde_yaml::from_str(&format!("{{ delay: 50ms, percentile: {} }}", percentile));
+
+            assert!(result.is_err(), "{}", percentile);
+        }
+    }
+}
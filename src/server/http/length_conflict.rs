@@ -0,0 +1,353 @@
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Longest request head looked at, hyper doesn't read more than its default buffer size before
+/// rejecting the request anyway
+const MAX_HEAD: usize = 8192 + 4096 * 100;
+
+/// Longest chunk size or trailer line looked at
+const MAX_LINE: usize = 4096;
+
+/// What to do with requests that have both `Transfer-Encoding` and `Content-Length`. Servers
+/// along the way may each frame the body by a different one, which lets a request be smuggled
+/// inside the body of another.
+///
+/// hyper frames such requests by `Transfer-Encoding` and drops `Content-Length` while parsing
+/// them, so they're told apart on the wire, before hyper sees the bytes. Only HTTP/1 has
+/// `Transfer-Encoding`, HTTP/2 connections aren't looked at.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum LengthConflict {
+    /// Respond with `400`
+    #[default]
+    Reject,
+    /// Leave them to hyper, which frames the body by `Transfer-Encoding` as RFC 7230 says and
+    /// closes the connection after the response
+    UseTransferEncoding,
+}
+
+/// Marks a request that had both `Transfer-Encoding` and `Content-Length`
+#[derive(Clone, Copy)]
+pub(crate) struct Conflicting;
+
+/// Whether each request head read from a connection had both headers, in the order they were
+/// read. hyper serves HTTP/1 requests of a connection in that order too, so each request takes
+/// the next one.
+#[derive(Clone, Default)]
+pub(crate) struct Conflicts(Arc<Mutex<VecDeque<bool>>>);
+
+impl Conflicts {
+    /// Whether the next request had both headers, requests of protocols that aren't looked at
+    /// never do
+    pub(crate) fn next(&self) -> bool {
+        self.0
+            .lock()
+            .expect("Conflicts lock poisoned")
+            .pop_front()
+            .unwrap_or(false)
+    }
+
+    fn push(&self, conflicting: bool) {
+        self.0
+            .lock()
+            .expect("Conflicts lock poisoned")
+            .push_back(conflicting);
+    }
+}
+
+/// Where a connection is in the stream of requests
+#[derive(Debug, PartialEq)]
+enum Framing {
+    /// Reading a request head
+    Head,
+    /// Bytes left of a body framed by `Content-Length`
+    Body(u64),
+    /// Reading the size line of the next chunk
+    ChunkSize,
+    /// Bytes left of a chunk, along with the line break after it
+    Chunk(u64),
+    /// Reading trailer lines after the last chunk, until an empty one
+    Trailers,
+    /// Not HTTP/1 requests anymore, e.g. HTTP/2, an upgraded connection or something hyper
+    /// can't parse and closes the connection over
+    Done,
+}
+
+/// Client stream that looks at the framing of the requests read from it, see `LengthConflict`
+pub(crate) struct Guarded<S> {
+    stream: S,
+    conflicts: Conflicts,
+    framing: Framing,
+    /// Head or line read so far
+    line: Vec<u8>,
+}
+
+impl<S> Guarded<S> {
+    pub(crate) fn new(stream: S, conflicts: Conflicts) -> Self {
+        Self {
+            stream,
+            conflicts,
+            framing: Framing::Head,
+            line: Vec::new(),
+        }
+    }
+
+    fn scan(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            match self.framing {
+                Framing::Done => return,
+                Framing::Body(left) | Framing::Chunk(left) => {
+                    let skipped = bytes.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+                    let left = left - skipped as u64;
+
+                    bytes = &bytes[skipped..];
+
+                    self.framing = match self.framing {
+                        Framing::Body(_) if left > 0 => Framing::Body(left),
+                        Framing::Chunk(_) if left > 0 => Framing::Chunk(left),
+                        Framing::Body(_) => Framing::Head,
+                        _ => Framing::ChunkSize,
+                    };
+                }
+                Framing::Head => {
+                    // Line breaks before the request line are ignored
+                    if self.line.is_empty() && matches!(bytes[0], b'\r' | b'\n') {
+                        bytes = &bytes[1..];
+                        continue;
+                    }
+
+                    let Some(end) = self.take_until_empty_line(&mut bytes, MAX_HEAD) else {
+                        continue;
+                    };
+
+                    self.framing = if end { self.head() } else { Framing::Done };
+                    self.line.clear();
+                }
+                Framing::ChunkSize | Framing::Trailers => {
+                    let Some(newline) = bytes.iter().position(|&byte| byte == b'\n') else {
+                        self.line.extend_from_slice(bytes);
+                        bytes = &[];
+
+                        if self.line.len() > MAX_LINE {
+                            self.framing = Framing::Done;
+                        }
+
+                        continue;
+                    };
+
+                    self.line.extend_from_slice(&bytes[..newline]);
+                    bytes = &bytes[newline + 1..];
+
+                    let line = String::from_utf8_lossy(&self.line);
+                    let line = line.trim_end_matches('\r');
+
+                    self.framing = match self.framing {
+                        Framing::ChunkSize => {
+                            let size = line.split(';').next().unwrap_or_default().trim();
+
+                            match u64::from_str_radix(size, 16) {
+                                Ok(0) => Framing::Trailers,
+                                Ok(size) => Framing::Chunk(size.saturating_add(2)),
+                                Err(_) => Framing::Done,
+                            }
+                        }
+                        _ if line.is_empty() => Framing::Head,
+                        _ => Framing::Trailers,
+                    };
+                    self.line.clear();
+                }
+            }
+        }
+    }
+
+    /// Reads head bytes up to and including the empty line that ends it. `Some(true)` once it's
+    /// read, `Some(false)` when it's longer than `max`, `None` when more is needed.
+    fn take_until_empty_line(&mut self, bytes: &mut &[u8], max: usize) -> Option<bool> {
+        for (index, &byte) in bytes.iter().enumerate() {
+            self.line.push(byte);
+
+            if byte == b'\n' && (self.line.ends_with(b"\r\n\r\n") || self.line.ends_with(b"\n\n")) {
+                *bytes = &bytes[index + 1..];
+
+                return Some(true);
+            }
+
+            if self.line.len() > max {
+                *bytes = &[];
+
+                return Some(false);
+            }
+        }
+
+        *bytes = &[];
+
+        None
+    }
+
+    /// Records whether the head that was read has both headers and where its body ends
+    fn head(&self) -> Framing {
+        let head = String::from_utf8_lossy(&self.line);
+        let mut lines = head.lines();
+
+        let request_line = lines.next().unwrap_or_default();
+
+        // The HTTP/2 preface, the rest of the connection is HTTP/2
+        if request_line.starts_with("PRI * HTTP/2.0") {
+            return Framing::Done;
+        }
+
+        let mut transfer_encoding = false;
+        let mut content_length = None;
+        let mut upgrade = request_line.starts_with("CONNECT ");
+
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+
+            match name.trim().to_ascii_lowercase().as_str() {
+                "transfer-encoding" => transfer_encoding = true,
+                "content-length" => match value.trim().parse::<u64>() {
+                    Ok(length) => content_length = Some(length),
+                    Err(_) => return Framing::Done,
+                },
+                "upgrade" => upgrade = true,
+                _ => {}
+            }
+        }
+
+        self.conflicts
+            .push(transfer_encoding && content_length.is_some());
+
+        match content_length {
+            _ if upgrade => Framing::Done,
+            _ if transfer_encoding => Framing::ChunkSize,
+            Some(length) if length > 0 => Framing::Body(length),
+            _ => Framing::Head,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Guarded<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.stream).poll_read(cx, buf);
+
+        if self.framing != Framing::Done {
+            self.scan(&buf.filled()[before..]);
+        }
+
+        polled
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Guarded<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether each request of `bytes` had both headers, fed in pieces of `piece` bytes
+    fn conflicts(bytes: &[u8], piece: usize) -> Vec<bool> {
+        let conflicts = Conflicts::default();
+        let mut guarded = Guarded::new((), conflicts.clone());
+
+        for piece in bytes.chunks(piece) {
+            guarded.scan(piece);
+        }
+
+        let found = conflicts.0.lock().unwrap().iter().copied().collect();
+
+        found
+    }
+
+    #[test]
+    fn both_headers_conflict_in_any_order_and_case() {
+        for request in [
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n"
+                .as_slice(),
+            b"POST / HTTP/1.1\r\nHost: a\r\ncontent-length: 5\r\ntransfer-encoding: chunked\r\n\r\n",
+            b"POST / HTTP/1.1\r\nHost: a\r\nTRANSFER-ENCODING: gzip, chunked\r\nContent-Length: 0\r\n\r\n",
+        ] {
+            assert_eq!(conflicts(request, request.len()), [true]);
+        }
+    }
+
+    #[test]
+    fn requests_are_followed_through_their_bodies() {
+        let smuggled = "GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n";
+        let requests = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {length}\r\n\r\n{smuggled}\
+             POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             {length:x};ext=1\r\n{smuggled}\r\n0\r\nTrailer: x\r\n\r\n\
+             POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3\r\n\r\n\
+             0\r\n\r\n\
+             \r\nGET / HTTP/1.1\r\n\r\n",
+            length = smuggled.len()
+        );
+        let requests = requests.as_bytes();
+
+        // Heads inside bodies aren't taken for requests, however the bytes arrive
+        for piece in [1, 2, 7, requests.len()] {
+            assert_eq!(
+                conflicts(requests, piece),
+                [false, false, true, false],
+                "{}",
+                piece
+            );
+        }
+    }
+
+    #[test]
+    fn http2_isnt_looked_at() {
+        let preface = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\
+            POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n";
+
+        assert!(conflicts(preface, preface.len()).is_empty());
+    }
+
+    #[test]
+    fn upgraded_connections_arent_looked_at() {
+        let requests = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n\r\n\
+            POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n";
+
+        assert_eq!(conflicts(requests, requests.len()), [false]);
+    }
+
+    #[test]
+    fn requests_without_a_record_dont_conflict() {
+        let conflicts = Conflicts::default();
+        conflicts.push(true);
+
+        assert!(conflicts.next());
+        assert!(!conflicts.next());
+    }
+}
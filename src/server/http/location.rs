@@ -0,0 +1,258 @@
+use http::{
+    header::{self, HeaderValue},
+    uri::Authority,
+};
+use hyper::{Request, Response};
+use serde::{Deserialize, Serialize};
+
+use super::service::ServedBy;
+
+/// How `Location` headers of backend responses are rewritten, so clients can follow redirects
+/// that point at a backend. Relative locations already point wherever the client is, they're
+/// left as they are. Protocol-relative ones, e.g. `//10.0.0.1:8080/login`, are rewritten too.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+pub(crate) enum LocationRewrite {
+    /// Locations pointing at the backend that sent them get the host the client requested and,
+    /// when it's known, the scheme
+    OriginalHost,
+    /// Locations pointing at the `from` origin of a mapping get the `to` origin instead
+    Map { mappings: Vec<OriginMapping> },
+}
+
+/// `scheme://host[:port]` of a backend and the origin clients see it at
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct OriginMapping {
+    pub(crate) from: String,
+    pub(crate) to: String,
+}
+
+/// Marks requests that came in over a TLS listener, the server puts it into their extensions
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct OverTls;
+
+/// Where the client sent the request to
+pub(crate) struct ClientOrigin {
+    /// From an absolute request URI, otherwise `https` over TLS listeners. Unknown for plaintext
+    /// ones, which may be behind something else terminating TLS.
+    pub(crate) scheme: Option<String>,
+    pub(crate) authority: Authority,
+}
+
+impl ClientOrigin {
+    pub(crate) fn of<B>(req: &Request<B>) -> Option<Self> {
+        let authority = match req.uri().authority() {
+            Some(authority) => authority.clone(),
+            None => Authority::try_from(req.headers().get(header::HOST)?.as_bytes()).ok()?,
+        };
+
+        let scheme = match req.uri().scheme_str() {
+            Some(scheme) => Some(scheme.to_owned()),
+            None => req
+                .extensions()
+                .get::<OverTls>()
+                .map(|_| "https".to_owned()),
+        };
+
+        Some(Self { scheme, authority })
+    }
+}
+
+impl LocationRewrite {
+    pub(crate) fn apply<B>(&self, response: &mut Response<B>, client: Option<&ClientOrigin>) {
+        let Some(location) = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+        else {
+            return;
+        };
+
+        let Some((scheme, authority, rest)) = split_location(location) else {
+            return;
+        };
+
+        let rewritten = match self {
+            LocationRewrite::OriginalHost => {
+                let from_backend = response
+                    .extensions()
+                    .get::<ServedBy>()
+                    .is_some_and(|ServedBy(backend)| backend.eq_ignore_ascii_case(authority));
+
+                client.filter(|_| from_backend).map(|client| {
+                    match client.scheme.as_deref().or(scheme) {
+                        Some(scheme) => format!("{}://{}{}", scheme, client.authority, rest),
+                        None => format!("//{}{}", client.authority, rest),
+                    }
+                })
+            }
+            LocationRewrite::Map { mappings } => mappings.iter().find_map(|mapping| {
+                let (from_scheme, from_authority, _) = split_origin(&mapping.from)?;
+
+                // Protocol-relative locations keep the scheme of the client, whatever it is
+                (scheme.is_none_or(|scheme| from_scheme.eq_ignore_ascii_case(scheme))
+                    && from_authority.eq_ignore_ascii_case(authority))
+                .then(|| format!("{}{}", mapping.to.trim_end_matches('/'), rest))
+            }),
+        };
+
+        match rewritten.map(HeaderValue::try_from) {
+            Some(Ok(location)) => {
+                response.headers_mut().insert(header::LOCATION, location);
+            }
+            Some(Err(_)) => {
+                tracing::warn!(location = ?location, "Rewritten location isn't a valid header")
+            }
+            None => {}
+        }
+    }
+}
+
+/// Scheme, authority and whatever follows them in an absolute URL, `None` for relative ones
+pub(crate) fn split_origin(url: &str) -> Option<(&str, &str, &str)> {
+    let (scheme, rest) = url.split_once("://")?;
+
+    if scheme.is_empty()
+        || !scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    {
+        return None;
+    }
+
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, rest) = rest.split_at(end);
+
+    (!authority.is_empty()).then_some((scheme, authority, rest))
+}
+
+/// Like `split_origin`, also taking protocol-relative URLs, which have no scheme
+fn split_location(location: &str) -> Option<(Option<&str>, &str, &str)> {
+    let Some(rest) = location.strip_prefix("//") else {
+        return split_origin(location)
+            .map(|(scheme, authority, rest)| (Some(scheme), authority, rest));
+    };
+
+    let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, rest) = rest.split_at(end);
+
+    (!authority.is_empty()).then_some((None, authority, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirect(location: &str) -> Response<()> {
+        let mut response = Response::builder()
+            .status(302)
+            .header(header::LOCATION, location)
+            .body(())
+            .unwrap();
+
+        response
+            .extensions_mut()
+            .insert(ServedBy("10.0.0.1:8080".to_owned()));
+
+        response
+    }
+
+    fn rewritten(rewrite: &str, location: &str, client: Option<&ClientOrigin>) -> String {
+        let rewrite: LocationRewrite = serde_yaml::from_str(rewrite).unwrap();
+        let mut response = redirect(location);
+
+        rewrite.apply(&mut response, client);
+
+        response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    const MAP: &str = "
+        type: map
+        mappings:
+        - { from: 'http://10.0.0.1:8080', to: 'https://example.com' }
+        ";
+
+    #[test]
+    fn mapped_origin_is_replaced() {
+        assert_eq!(
+            rewritten(MAP, "http://10.0.0.1:8080/login?next=/", None),
+            "https://example.com/login?next=/"
+        );
+        assert_eq!(
+            rewritten(MAP, "HTTP://10.0.0.1:8080", None),
+            "https://example.com"
+        );
+        assert_eq!(
+            rewritten(MAP, "//10.0.0.1:8080/login", None),
+            "https://example.com/login"
+        );
+    }
+
+    #[test]
+    fn other_origins_and_relative_locations_stay() {
+        for location in [
+            "http://10.0.0.1:80800/login",
+            "https://10.0.0.1:8080/login",
+            "/login",
+            "//10.0.0.2:8080/login",
+            "login?next=http://10.0.0.1:8080/",
+        ] {
+            assert_eq!(rewritten(MAP, location, None), location);
+        }
+    }
+
+    #[test]
+    fn backend_location_gets_client_host() {
+        let req = Request::get("/")
+            .header(header::HOST, "example.com")
+            .body(())
+            .unwrap();
+        let client = ClientOrigin::of(&req);
+
+        assert_eq!(
+            rewritten(
+                "type: original-host",
+                "http://10.0.0.1:8080/a#b",
+                client.as_ref()
+            ),
+            "http://example.com/a#b"
+        );
+
+        // Plaintext listeners don't know the scheme the client used
+        assert_eq!(
+            rewritten("type: original-host", "//10.0.0.1:8080/a", client.as_ref()),
+            "//example.com/a"
+        );
+
+        // Not the backend the response came from
+        assert_eq!(
+            rewritten(
+                "type: original-host",
+                "http://10.0.0.2:8080/a",
+                client.as_ref()
+            ),
+            "http://10.0.0.2:8080/a"
+        );
+    }
+
+    #[test]
+    fn locations_say_https_over_tls_listeners() {
+        let mut req = Request::get("/")
+            .header(header::HOST, "example.com")
+            .body(())
+            .unwrap();
+        req.extensions_mut().insert(OverTls);
+
+        let client = ClientOrigin::of(&req);
+
+        for location in ["http://10.0.0.1:8080/a", "//10.0.0.1:8080/a"] {
+            assert_eq!(
+                rewritten("type: original-host", location, client.as_ref()),
+                "https://example.com/a"
+            );
+        }
+    }
+}
@@ -4,7 +4,11 @@ use itertools::Itertools;
 use regex::Regex;
 use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 
-use hyper::{body::Incoming, Request};
+use hyper::Request;
+
+use crate::server::host::{HostSpec, Hostname};
+
+use super::body_match::{BodyMatch, BufferedBody};
 
 struct PrefixVisitor;
 
@@ -96,8 +100,18 @@ impl FromStr for PathPrefix {
 }
 
 impl PathPrefix {
+    /// Segments between the slashes, starting with the empty one before the first slash
+    pub(crate) fn segments(&self) -> &[String] {
+        &self.0
+    }
+
+    /// What follows the prefix in `path`, `None` when it doesn't match
+    pub(crate) fn strip<'a>(&self, path: &'a str) -> Option<&'a str> {
+        self.matches(path).then(|| &path[self.0.join("/").len()..])
+    }
+
     /// Match a string aganst a prefix
-    fn matches(&self, value_to_match: &str) -> bool {
+    pub(crate) fn matches(&self, value_to_match: &str) -> bool {
         let segments: Vec<&str> = value_to_match.split('/').collect();
         let prefix = &self.0;
 
@@ -137,6 +151,15 @@ mod test {
         assert!(prefix.is_err());
     }
 
+    #[test]
+    fn prefix_is_stripped() {
+        let prefix = PathPrefix::from_str("/abc/").unwrap();
+
+        assert_eq!(prefix.strip("/abc"), Some(""));
+        assert_eq!(prefix.strip("/abc/def"), Some("/def"));
+        assert_eq!(prefix.strip("/abcdef"), None);
+    }
+
     #[test]
     fn prefix_matches() {
         let prefix = PathPrefix::from_str("/abc").unwrap();
@@ -174,9 +197,14 @@ pub(crate) enum PathMatch {
     Prefix {
         value: PathPrefix,
     },
+    /// Matches anywhere in the path unless it's anchored, e.g. `/prefix/[0-9]+$` matches
+    /// `/another/prefix/1` as well
     Regex {
         #[serde(with = "serde_regex")]
         value: Regex,
+        /// Only match from the start of the path, as if the pattern began with `^`
+        #[serde(default)]
+        anchored: bool,
     },
 }
 
@@ -185,9 +213,49 @@ impl PathMatch {
         match self {
             PathMatch::Exact { value } => value_to_match == value,
             PathMatch::Prefix { value } => value.matches(value_to_match),
-            PathMatch::Regex { value } => value.is_match(value_to_match),
+            // The leftmost match starts at 0 whenever any match does
+            PathMatch::Regex {
+                value,
+                anchored: true,
+            } => value
+                .find(value_to_match)
+                .is_some_and(|found| found.start() == 0),
+            PathMatch::Regex {
+                value,
+                anchored: false,
+            } => value.is_match(value_to_match),
         }
     }
+
+    /// Names of the groups of a regex, other matchers don't capture anything
+    pub(crate) fn capture_names(&self) -> impl Iterator<Item = &str> {
+        let names = match self {
+            PathMatch::Regex { value, .. } => Some(value.capture_names().flatten()),
+            _ => None,
+        };
+
+        names.into_iter().flatten()
+    }
+
+    /// Values of the named groups of a regex matching `path`, the groups that didn't
+    /// participate in the match are left out
+    pub(crate) fn captures<'a>(&'a self, path: &'a str) -> Vec<(&'a str, &'a str)> {
+        let PathMatch::Regex { value, anchored } = self else {
+            return vec![];
+        };
+
+        let Some(captures) = value.captures(path).filter(|captures| {
+            !anchored || captures.get(0).is_some_and(|found| found.start() == 0)
+        }) else {
+            return vec![];
+        };
+
+        value
+            .capture_names()
+            .flatten()
+            .filter_map(|name| Some((name, captures.name(name)?.as_str())))
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -223,6 +291,7 @@ mod test_matches {
     fn regex_matcher() {
         let matcher = PathMatch::Regex {
             value: Regex::from_str("/prefix/[0-9]+$").unwrap(),
+            anchored: false,
         };
 
         assert!(!matcher.matches("/prefix"));
@@ -240,18 +309,60 @@ mod test_matches {
 
         let matcher = PathMatch::Regex {
             value: Regex::from_str("/prefix/[0-9A-Za-z-_]+/foo$").unwrap(),
+            anchored: false,
         };
 
         assert!(matcher.matches("/prefix/123foobarbaz/foo"));
         assert!(matcher.matches("/prefix/123-foo-bar_baz/foo"));
         assert!(!matcher.matches("/prefix/123-foo-bar_baz/foobar"));
 
-        // NOTE: I'm not sure if we should support this as it might be
-        // not expected behavior from consumers and kind of makes them add
-        // ^ prefix before each and every regex path that they use.
-        // This is going to have to be consulted with consumers.
+        // Unanchored patterns match anywhere, `anchored` opts out of it
         assert!(matcher.matches("another/prefix/123-foo-bar_baz/foo"));
     }
+
+    #[test]
+    fn anchored_regex_matcher() {
+        let matcher: PathMatch =
+            serde_yaml::from_str("{ type: Regex, value: '/prefix/[0-9]+$', anchored: true }")
+                .unwrap();
+
+        assert!(matcher.matches("/prefix/1"));
+        assert!(matcher.matches("/prefix/123"));
+        assert!(!matcher.matches("another/prefix/1"));
+        assert!(!matcher.matches("/another/prefix/1"));
+        assert!(!matcher.matches("/prefix/1a"));
+
+        // Alternatives are all anchored, not just the first one
+        let matcher: PathMatch =
+            serde_yaml::from_str("{ type: Regex, value: '/a|/b', anchored: true }").unwrap();
+
+        assert!(matcher.matches("/b/c"));
+        assert!(!matcher.matches("/c/b"));
+
+        let matcher: PathMatch =
+            serde_yaml::from_str("{ type: Regex, value: '/prefix/[0-9]+$' }").unwrap();
+
+        assert!(matcher.matches("another/prefix/1"));
+    }
+
+    #[test]
+    fn regex_captures_named_groups() {
+        let matcher: PathMatch = serde_yaml::from_str(
+            "{ type: Regex, value: '/users/(?<user>[0-9]+)(/orders/(?<order>[0-9]+))?', anchored: true }",
+        )
+        .unwrap();
+
+        assert_eq!(
+            matcher.capture_names().collect::<Vec<_>>(),
+            ["user", "order"]
+        );
+        assert_eq!(
+            matcher.captures("/users/7/orders/12"),
+            [("user", "7"), ("order", "12")]
+        );
+        assert_eq!(matcher.captures("/users/7"), [("user", "7")]);
+        assert!(matcher.captures("/api/users/7").is_empty());
+    }
 }
 
 use http::{HeaderMap, HeaderValue, Method};
@@ -264,11 +375,11 @@ impl MethodMatch {
         Ok(Self(Method::from_str(s)?))
     }
 
-    fn stringify(&self) -> String {
+    pub(crate) fn stringify(&self) -> String {
         self.0.to_string()
     }
 
-    fn matches(&self, req_method: &Method) -> bool {
+    pub(crate) fn matches(&self, req_method: &Method) -> bool {
         self.0 == req_method
     }
 }
@@ -323,57 +434,537 @@ pub(crate) enum HeaderMatch {
 }
 
 impl HeaderMatch {
-    // TODO: fix unwraps
+    fn name(&self) -> &str {
+        match self {
+            Self::Exact { name, .. } | Self::Regex { name, .. } => name,
+        }
+    }
+
+    fn lowercased(mut self) -> Self {
+        match &mut self {
+            Self::Exact { name, .. } | Self::Regex { name, .. } => name.make_ascii_lowercase(),
+        }
+
+        self
+    }
+
+    /// Values that aren't visible ASCII, e.g. obs-text, never match
     fn matches(&self, header_map: &HeaderMap<HeaderValue>) -> bool {
         match &self {
-            Self::Exact { name, value } => header_map.get(name).map_or(false, |header_value| {
-                header_value.to_str().unwrap() == value
-            }),
-            Self::Regex { name, value } => header_map.get(name).map_or(false, |header_value| {
-                value.is_match(header_value.to_str().unwrap())
-            }),
+            Self::Exact { name, value } => header_map
+                .get(name)
+                .is_some_and(|header_value| header_value.to_str().is_ok_and(|v| v == value)),
+            Self::Regex { name, value } => header_map
+                .get(name)
+                .is_some_and(|header_value| header_value.to_str().is_ok_and(|v| value.is_match(v))),
+        }
+    }
+}
+
+/// Matches the first value of a query param, names are case-sensitive and both names and
+/// values are compared decoded
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type")]
+pub(crate) enum QueryParamMatch {
+    Exact {
+        name: String,
+        value: String,
+    },
+    Regex {
+        name: String,
+        #[serde(with = "serde_regex")]
+        value: Regex,
+    },
+}
+
+impl QueryParamMatch {
+    fn name(&self) -> &str {
+        match self {
+            Self::Exact { name, .. } | Self::Regex { name, .. } => name,
+        }
+    }
+
+    fn matches(&self, query: Option<&str>) -> bool {
+        // Repeated params are left undefined by Gateway API, which recommends the first value
+        let Some(first) = url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+            .find_map(|(name, value)| (name == self.name()).then_some(value))
+        else {
+            return false;
+        };
+
+        match self {
+            Self::Exact { value, .. } => first == value.as_str(),
+            Self::Regex { value, .. } => value.is_match(&first),
         }
     }
 }
 
+/// Matches the media type of the request body, parameters like `; charset=utf-8` are ignored
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "type")]
+pub(crate) enum ContentTypeMatch {
+    Exact {
+        value: String,
+    },
+    /// e.g. `application/grpc` also matches `application/grpc+proto` and `application/grpc-web`
+    Prefix {
+        value: String,
+    },
+}
+
+/// Media type of the `Content-Type` header lowercased, without parameters
+pub(crate) fn media_type(header_map: &HeaderMap<HeaderValue>) -> Option<String> {
+    header_map
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|media_type| media_type.trim().to_ascii_lowercase())
+}
+
+impl ContentTypeMatch {
+    fn matches(&self, header_map: &HeaderMap<HeaderValue>) -> bool {
+        let Some(media_type) = media_type(header_map) else {
+            return false;
+        };
+
+        // Media types are case-insensitive
+        match self {
+            Self::Exact { value } => media_type.eq_ignore_ascii_case(value),
+            Self::Prefix { value } => media_type.starts_with(&value.to_ascii_lowercase()),
+        }
+    }
+}
+
+/// How requests that don't declare their length are classed, e.g. chunked uploads
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum UnknownLength {
+    #[default]
+    Small,
+    Large,
+}
+
+/// Matches requests by the size of their body as the client declares it in `Content-Length`,
+/// so large uploads can be kept apart from the rest of the traffic. The body itself isn't read.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct SizeMatch {
+    /// Requests with bodies of more bytes than this match
+    pub(crate) larger_than: u64,
+    /// Chunked HTTP/1 requests and HTTP/2 requests without `Content-Length` can be of any
+    /// size. Small by default, so they stay with the rest of the traffic.
+    #[serde(default)]
+    pub(crate) unknown_length: UnknownLength,
+}
+
+impl SizeMatch {
+    fn matches<B>(&self, req: &Request<B>) -> bool {
+        match declared_length(req) {
+            Some(length) => length > self.larger_than,
+            None => self.unknown_length == UnknownLength::Large,
+        }
+    }
+}
+
+/// Length of the request body, `None` when it isn't known before it's read. HTTP/1 requests
+/// with neither `Content-Length` nor `Transfer-Encoding` have no body.
+fn declared_length<B>(req: &Request<B>) -> Option<u64> {
+    let headers = req.headers();
+
+    if let Some(length) = headers.get(http::header::CONTENT_LENGTH) {
+        return length.to_str().ok()?.parse().ok();
+    }
+
+    let streamed = headers.contains_key(http::header::TRANSFER_ENCODING)
+        || req.version() >= http::Version::HTTP_2;
+
+    (!streamed).then_some(0)
+}
+
+/// Server name the client presented in the TLS handshake.
+///
+/// The listener puts it into the request extensions, plaintext connections never have one.
+#[derive(Debug, Clone)]
+pub(crate) struct ClientSni(pub(crate) Hostname);
+
+/// Matches the TLS server name instead of the Host header, which is controlled by the client
+/// and can differ from the name the TLS session was established for.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(transparent)]
+pub(crate) struct SniMatch(HostSpec);
+
+impl SniMatch {
+    fn matches(&self, sni: Option<&ClientSni>) -> bool {
+        sni.is_some_and(|ClientSni(hostname)| self.0.matches(hostname))
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub(crate) struct Matcher {
     // NOTE: All fields here should be matched using AND
     pub(crate) path: Option<PathMatch>,
     pub(crate) method: Option<MethodMatch>,
-    // TODO:
-    // If multiple entries specify equivalent header names, only the first entry with an equivalent
-    // name MUST be considered for a match. Subsequent entries with an equivalent header name MUST be ignored.
-    // Due to the case-insensitivity of header names, “foo” and “Foo” are considered equivalent.
-    // Might be better to use a hashmap
+    /// Names are lowercased, if multiple entries specify equivalent header names only the first
+    /// one is kept, e.g. "foo" and "Foo" are equivalent
+    #[serde(default, deserialize_with = "deserialize_headers")]
     pub(crate) headers: Option<Vec<HeaderMatch>>,
-    // TODO: query
-    // If multiple entries specify equivalent query param names, only the first entry with an equivalent name MUST be considered for a match.
-    // Subsequent entries with an equivalent query param name MUST be ignored.
-    // If a query param is repeated in an HTTP request, the behavior is purposely left undefined,
-    // since different data planes have different capabilities. However, it is recommended that implementations
-    // should match against the first value of the param if the data plane supports it, as this behavior is expected
-    // in other load balancing contexts outside of the Gateway API.
+    pub(crate) sni: Option<SniMatch>,
+    pub(crate) content_type: Option<ContentTypeMatch>,
+    /// Makes the route buffer request bodies, see `BodyBufferConfig`
+    pub(crate) body: Option<BodyMatch>,
+    /// If multiple entries specify equivalent query param names, only the first entry with an
+    /// equivalent name is considered for a match, the rest are ignored
+    pub(crate) query: Option<Vec<QueryParamMatch>>,
+    pub(crate) size: Option<SizeMatch>,
+}
+
+fn deserialize_headers<'de, D>(deserializer: D) -> Result<Option<Vec<HeaderMatch>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let headers = Option::<Vec<HeaderMatch>>::deserialize(deserializer)?;
+
+    Ok(headers.map(|headers| {
+        headers
+            .into_iter()
+            .map(HeaderMatch::lowercased)
+            .unique_by(|header| header.name().to_owned())
+            .collect()
+    }))
 }
 
 impl Matcher {
-    pub(crate) fn matches(&self, req: &Request<Incoming>) -> bool {
+    pub(crate) fn path_prefix(&self) -> Option<&PathPrefix> {
+        match &self.path {
+            Some(PathMatch::Prefix { value }) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn matches<B>(&self, req: &Request<B>) -> bool {
         let path_match = self
             .path
             .as_ref()
-            .map_or(true, |path| path.matches(req.uri().path()));
+            .is_none_or(|path| path.matches(req.uri().path()));
 
         let method_match = self
             .method
             .as_ref()
-            .map_or(true, |method| method.matches(req.method()));
+            .is_none_or(|method| method.matches(req.method()));
 
-        let headers_match = self.headers.as_ref().map_or(true, |headers| {
+        let headers_match = self.headers.as_ref().is_none_or(|headers| {
             headers
                 .iter()
                 .all(|headers_match| headers_match.matches(req.headers()))
         });
 
-        path_match && method_match && headers_match
+        let sni_match = self
+            .sni
+            .as_ref()
+            .is_none_or(|sni| sni.matches(req.extensions().get::<ClientSni>()));
+
+        let content_type_match = self
+            .content_type
+            .as_ref()
+            .is_none_or(|content_type| content_type.matches(req.headers()));
+
+        let body_match = self
+            .body
+            .as_ref()
+            .is_none_or(|body| body.matches(req.extensions().get::<BufferedBody>()));
+
+        let query_match = self.query.as_ref().is_none_or(|query| {
+            query
+                .iter()
+                .enumerate()
+                .filter(|(index, param)| {
+                    !query[..*index]
+                        .iter()
+                        .any(|earlier| earlier.name() == param.name())
+                })
+                .all(|(_, param)| param.matches(req.uri().query()))
+        });
+
+        let size_match = self.size.as_ref().is_none_or(|size| size.matches(req));
+
+        path_match
+            && method_match
+            && headers_match
+            && sni_match
+            && content_type_match
+            && body_match
+            && query_match
+            && size_match
+    }
+}
+
+#[cfg(test)]
+mod test_sni {
+    use super::*;
+
+    fn matcher(sni: &str) -> Matcher {
+        Matcher {
+            path: None,
+            method: None,
+            headers: None,
+            sni: Some(SniMatch(HostSpec::from_str(sni).unwrap())),
+            content_type: None,
+            body: None,
+            query: None,
+            size: None,
+        }
+    }
+
+    fn request(host: &str, sni: Option<&str>) -> Request<()> {
+        let mut req = Request::builder().header("host", host).body(()).unwrap();
+
+        if let Some(sni) = sni {
+            req.extensions_mut()
+                .insert(ClientSni(Hostname::from_str(sni).unwrap()));
+        }
+
+        req
+    }
+
+    #[test]
+    fn sni_matcher_matches_server_name() {
+        let matcher = matcher("secure.test.com");
+
+        assert!(matcher.matches(&request("secure.test.com", Some("secure.test.com"))));
+        assert!(!matcher.matches(&request("secure.test.com", Some("other.test.com"))));
+    }
+
+    #[test]
+    fn sni_matcher_ignores_host_header() {
+        let matcher = matcher("secure.test.com");
+
+        assert!(!matcher.matches(&request("secure.test.com", None)));
+        assert!(matcher.matches(&request("spoofed.test.com", Some("secure.test.com"))));
+    }
+
+    #[test]
+    fn sni_matcher_wildcard() {
+        let matcher = matcher("*.test.com");
+
+        assert!(matcher.matches(&request("test.com", Some("sub.test.com"))));
+        assert!(!matcher.matches(&request("test.com", Some("sub.other.com"))));
+    }
+}
+
+#[cfg(test)]
+mod test_content_type {
+    use super::*;
+
+    fn matcher(yaml: &str) -> Matcher {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn request(content_type: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder();
+
+        if let Some(content_type) = content_type {
+            builder = builder.header("content-type", content_type);
+        }
+
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn exact_content_type_ignores_parameters() {
+        let matcher = matcher("content_type: { type: Exact, value: application/json }");
+
+        assert!(matcher.matches(&request(Some("application/json"))));
+        assert!(matcher.matches(&request(Some("application/json; charset=utf-8"))));
+        assert!(matcher.matches(&request(Some("Application/JSON"))));
+        assert!(!matcher.matches(&request(Some("application/json-patch+json"))));
+        assert!(!matcher.matches(&request(None)));
+    }
+
+    #[test]
+    fn prefix_content_type() {
+        let matcher = matcher("content_type: { type: Prefix, value: application/grpc }");
+
+        assert!(matcher.matches(&request(Some("application/grpc"))));
+        assert!(matcher.matches(&request(Some("application/grpc+proto"))));
+        assert!(matcher.matches(&request(Some("application/grpc-web; charset=utf-8"))));
+        assert!(!matcher.matches(&request(Some("application/json"))));
+    }
+}
+
+#[cfg(test)]
+mod test_size {
+    use super::*;
+
+    fn matcher(yaml: &str) -> Matcher {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn request(version: http::Version, headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::post("/upload").version(version);
+
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn declared_length_is_compared() {
+        let matcher = matcher("size: { larger-than: 1024 }");
+        let length = |length: &str| request(http::Version::HTTP_11, &[("content-length", length)]);
+
+        assert!(matcher.matches(&length("1025")));
+        assert!(!matcher.matches(&length("1024")));
+        assert!(!matcher.matches(&length("not-a-number")));
+        assert!(!matcher.matches(&request(http::Version::HTTP_11, &[])));
+    }
+
+    #[test]
+    fn unknown_length_follows_the_policy() {
+        let chunked = request(http::Version::HTTP_11, &[("transfer-encoding", "chunked")]);
+        let streamed = request(http::Version::HTTP_2, &[]);
+
+        let small = matcher("size: { larger-than: 1024 }");
+
+        assert!(!small.matches(&chunked));
+        assert!(!small.matches(&streamed));
+
+        let large = matcher("size: { larger-than: 1024, unknown-length: large }");
+
+        assert!(large.matches(&chunked));
+        assert!(large.matches(&streamed));
+
+        // Bodyless HTTP/1 requests are known to be empty
+        assert!(!large.matches(&request(http::Version::HTTP_11, &[])));
+    }
+}
+
+#[cfg(test)]
+mod test_query {
+    use super::*;
+
+    fn query_matcher(yaml: &str) -> Matcher {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn request(uri: &str) -> Request<()> {
+        Request::get(uri).body(()).unwrap()
+    }
+
+    #[test]
+    fn exact_query_param() {
+        let matcher = query_matcher("query: [{ type: Exact, name: version, value: v2 }]");
+
+        assert!(matcher.matches(&request("/api?version=v2")));
+        assert!(matcher.matches(&request("/api?page=1&version=v2")));
+        assert!(!matcher.matches(&request("/api?version=v21")));
+        assert!(!matcher.matches(&request("/api?Version=v2")));
+    }
+
+    #[test]
+    fn regex_query_param() {
+        let matcher = query_matcher("query: [{ type: Regex, name: id, value: '^[0-9]+$' }]");
+
+        assert!(matcher.matches(&request("/orders?id=42")));
+        assert!(!matcher.matches(&request("/orders?id=42a")));
+
+        // Values are decoded before they're matched
+        let matcher = query_matcher("query: [{ type: Regex, name: q, value: '^hello world$' }]");
+
+        assert!(matcher.matches(&request("/search?q=hello%20world")));
+        assert!(matcher.matches(&request("/search?q=hello+world")));
+    }
+
+    #[test]
+    fn missing_query_param() {
+        let matcher = query_matcher(
+            "
+            query:
+            - { type: Exact, name: version, value: v2 }
+            - { type: Exact, name: debug, value: 'true' }
+            ",
+        );
+
+        assert!(matcher.matches(&request("/api?debug=true&version=v2")));
+        assert!(!matcher.matches(&request("/api?version=v2")));
+        assert!(!matcher.matches(&request("/api")));
+    }
+
+    #[test]
+    fn repeated_query_param_uses_first_value() {
+        let matcher = query_matcher("query: [{ type: Exact, name: tier, value: gold }]");
+
+        assert!(matcher.matches(&request("/api?tier=gold&tier=free")));
+        assert!(!matcher.matches(&request("/api?tier=free&tier=gold")));
+
+        // Only the first entry with a name counts
+        let matcher = query_matcher(
+            "
+            query:
+            - { type: Exact, name: tier, value: gold }
+            - { type: Exact, name: tier, value: free }
+            ",
+        );
+
+        assert!(matcher.matches(&request("/api?tier=gold")));
+    }
+}
+
+#[cfg(test)]
+mod test_headers {
+    use super::*;
+
+    fn headers_matcher(yaml: &str) -> Matcher {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request<()> {
+        let mut builder = Request::builder();
+
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn header_names_are_case_insensitive() {
+        let matcher = headers_matcher("headers: [{ type: Exact, name: X-Foo, value: bar }]");
+
+        assert!(matcher.matches(&request(&[("x-foo", "bar")])));
+        assert!(matcher.matches(&request(&[("X-FOO", "bar")])));
+        assert!(!matcher.matches(&request(&[("x-foo", "baz")])));
+    }
+
+    #[test]
+    fn non_ascii_header_values_dont_match() {
+        let mut request = request(&[]);
+        request
+            .headers_mut()
+            .insert("x-foo", HeaderValue::from_bytes("bär".as_bytes()).unwrap());
+
+        for matcher in [
+            "headers: [{ type: Exact, name: x-foo, value: bär }]",
+            "headers: [{ type: Regex, name: x-foo, value: '.*' }]",
+        ] {
+            assert!(!headers_matcher(matcher).matches(&request), "{}", matcher);
+        }
+    }
+
+    #[test]
+    fn equivalent_header_names_use_first_entry() {
+        let matcher = headers_matcher(
+            "
+            headers:
+            - { type: Exact, name: X-Foo, value: bar }
+            - { type: Exact, name: x-foo, value: baz }
+            ",
+        );
+
+        assert_eq!(matcher.headers.as_ref().unwrap().len(), 1);
+        assert!(matcher.matches(&request(&[("x-foo", "bar")])));
+        assert!(!matcher.matches(&request(&[("x-foo", "baz")])));
     }
 }
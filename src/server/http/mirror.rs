@@ -0,0 +1,231 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use http::request::Parts;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::Request;
+use tokio::sync::Semaphore;
+
+use super::{
+    body_match::{self, BufferedBody, MAX_BUFFER_SIZE},
+    server::full,
+    service::HttpService,
+    timeouts::Timeouts,
+};
+
+/// Mirrored requests a rule has in flight at most, copies over it aren't sent
+const MAX_IN_FLIGHT: usize = 64;
+
+/// Longest body that's mirrored, requests with longer ones go to the rule's service only and
+/// their body isn't held in memory
+const MAX_BODY_SIZE: usize = MAX_BUFFER_SIZE;
+
+/// Service a rule sends copies of its requests to
+#[derive(Debug, Clone)]
+pub(crate) struct Mirror {
+    /// Name of the service in the config
+    pub(crate) name: String,
+    pub(crate) service: Arc<HttpService>,
+    pub(crate) timeouts: Timeouts,
+}
+
+/// Shadow traffic of a rule. Every mirror gets its copy of a request in the background and its
+/// response is thrown away, so neither a slow nor a failing mirror holds up the rule's own
+/// service or the other mirrors.
+#[derive(Debug)]
+pub(crate) struct Mirrors {
+    mirrors: Vec<Mirror>,
+    in_flight: Arc<Semaphore>,
+}
+
+impl Mirrors {
+    pub(crate) fn new(mirrors: Vec<Mirror>) -> Self {
+        Self {
+            mirrors,
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+        }
+    }
+
+    /// Buffers the body of `req` and sends a copy to every mirror, unless it's over
+    /// `MAX_BODY_SIZE`. Returns the request to send to the rule's service, fails when the body
+    /// can't be read.
+    pub(super) async fn send(
+        &self,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> Result<Request<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+        let (req, _) = body_match::buffer(req, MAX_BODY_SIZE).await?;
+
+        let Some(BufferedBody(body)) = req.extensions().get::<BufferedBody>().cloned() else {
+            tracing::debug!("Request body is too large to mirror, not mirroring");
+
+            return Ok(req);
+        };
+
+        let (parts, original) = req.into_parts();
+
+        for mirror in &self.mirrors {
+            let Ok(permit) = self.in_flight.clone().try_acquire_owned() else {
+                tracing::debug!(
+                    mirror = %mirror.name,
+                    "Too many mirrored requests in flight, not mirroring"
+                );
+                continue;
+            };
+
+            let copy = copy(&parts, body.clone());
+            let mirror = mirror.clone();
+
+            tokio::spawn(async move {
+                let _permit = permit;
+
+                let Ok(response) = mirror
+                    .service
+                    .send_request(&mirror.name, copy, mirror.timeouts)
+                    .await;
+
+                tracing::debug!(
+                    mirror = %mirror.name,
+                    status = response.status().as_u16(),
+                    "Mirror responded"
+                );
+
+                // Read to the end, so the backend doesn't see the request as abandoned
+                let _ = response.into_body().collect().await;
+            });
+        }
+
+        Ok(Request::from_parts(parts, original))
+    }
+}
+
+/// Request with the same head as `parts`, extensions are only for the original
+pub(super) fn copy(parts: &Parts, body: Bytes) -> Request<BoxBody<Bytes, hyper::Error>> {
+    let mut req = Request::new(full(body));
+
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+
+    req
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+
+    /// Service with a backend that reports the requests it gets
+    async fn recording_service(requests: mpsc::UnboundedSender<String>) -> HttpService {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut request = vec![0; 1024];
+            let read = stream.read(&mut request).await.unwrap();
+
+            requests
+                .send(String::from_utf8_lossy(&request[..read]).into_owned())
+                .unwrap();
+
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        serde_yaml::from_str(&format!("backends: [{{ ip: 127.0.0.1, port: {} }}]", port)).unwrap()
+    }
+
+    fn mirror(name: &str, service: HttpService) -> Mirror {
+        Mirror {
+            name: name.to_owned(),
+            service: Arc::new(service),
+            timeouts: Timeouts::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn every_mirror_gets_a_copy() {
+        let (requests, mut received) = mpsc::unbounded_channel();
+
+        // Nothing listens on the first one, it doesn't stop the others
+        let unreachable: HttpService =
+            serde_yaml::from_str("backends: [{ ip: 127.0.0.1, port: 1 }]").unwrap();
+
+        let mirrors = Mirrors::new(vec![
+            mirror("unreachable", unreachable),
+            mirror("first", recording_service(requests.clone()).await),
+            mirror("second", recording_service(requests).await),
+        ]);
+
+        let req = Request::post("/orders")
+            .header(http::header::HOST, "test.com")
+            .body(full("order"))
+            .unwrap();
+
+        let req = mirrors.send(req).await.unwrap();
+
+        // The original keeps its body
+        let body = req.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "order");
+
+        for _ in 0..2 {
+            let mirrored = received.recv().await.unwrap();
+
+            assert!(mirrored.starts_with("POST /orders HTTP/1.1\r\n"));
+            assert!(mirrored.ends_with("\r\n\r\norder"));
+        }
+    }
+
+    #[tokio::test]
+    async fn large_bodies_arent_mirrored() {
+        let (requests, mut received) = mpsc::unbounded_channel();
+
+        let mirrors = Mirrors::new(vec![mirror("recording", recording_service(requests).await)]);
+
+        let body = Bytes::from(vec![b'a'; MAX_BODY_SIZE + 1]);
+        let req = mirrors
+            .send(Request::new(full(body.clone())))
+            .await
+            .unwrap();
+
+        // The original gets all of it
+        assert_eq!(req.into_body().collect().await.unwrap().to_bytes(), body);
+
+        let mirrored = tokio::time::timeout(Duration::from_millis(100), received.recv()).await;
+
+        assert!(mirrored.is_err());
+    }
+
+    #[tokio::test]
+    async fn mirrors_over_the_limit_are_skipped() {
+        let (requests, mut received) = mpsc::unbounded_channel();
+
+        let mirrors = Mirrors::new(vec![mirror("recording", recording_service(requests).await)]);
+
+        let all_in_flight = mirrors
+            .in_flight
+            .clone()
+            .acquire_many_owned(MAX_IN_FLIGHT as u32)
+            .await
+            .unwrap();
+
+        mirrors.send(Request::new(full(""))).await.unwrap();
+
+        drop(all_in_flight);
+
+        let mirrored = tokio::time::timeout(Duration::from_millis(100), received.recv()).await;
+
+        assert!(mirrored.is_err());
+    }
+}
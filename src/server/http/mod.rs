@@ -1,34 +1,81 @@
+pub(crate) mod backend_body;
+pub(crate) mod backend_groups;
+pub(crate) mod backend_set;
+pub(crate) mod body_match;
+pub(crate) mod cache;
+pub(crate) mod canary;
 pub(crate) mod cluster;
+pub(crate) mod connect;
+pub(crate) mod dns_refresh;
+pub(crate) mod error_pages;
+pub(crate) mod fail_closed;
+pub(crate) mod filters;
+pub(crate) mod grpc_web;
+pub(crate) mod hash_ring;
+pub(crate) mod headers;
+pub(crate) mod health;
+pub(crate) mod hedging;
+pub(crate) mod length_conflict;
+pub(crate) mod location;
 pub(crate) mod matchers;
+pub(crate) mod mirror;
+pub(crate) mod path_index;
+pub(crate) mod pool;
+pub(crate) mod retry;
 pub(crate) mod route;
+pub(crate) mod route_limits;
 pub(crate) mod server;
 pub(crate) mod service;
+pub(crate) mod static_files;
+pub(crate) mod synthesize;
+pub(crate) mod timeouts;
 
 use service::HttpService;
 use std::collections::HashMap;
 
 use super::host::HostSpec;
 
+use body_match::BodyBufferConfig;
+use cache::ResponseCacheConfig;
+use canary::CanaryConfig;
+use error_pages::ErrorPagesConfig;
+use filters::Filter;
+use location::LocationRewrite;
 use matchers::Matcher;
+use route_limits::RouteLimitsConfig;
 use serde::{Deserialize, Serialize};
 use server::HttpServerFields;
+use synthesize::SynthesizeConfig;
+use timeouts::TimeoutsConfig;
 
 pub(crate) use server::HttpServer;
 
 #[derive(Deserialize, Serialize, Debug)]
-#[serde(tag = "version")]
-pub(crate) enum HttpServerConfig {
-    #[serde(rename = "1")]
-    V1(HttpServerFields),
-    #[serde(rename = "2")]
-    V2(HttpServerFields),
-}
-
-#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) struct HttpRouteRuleConfig {
+    /// Defaults to the route name followed by the index of the rule, e.g. `api-route/0`
+    pub(crate) name: Option<String>,
     // NOTE: These ones are chained using OR
     pub(crate) matches: Vec<Matcher>,
     pub(crate) backend: String,
+    #[serde(default)]
+    pub(crate) timeouts: TimeoutsConfig,
+    /// Opt-in automatic promotion of one of the backend's servers, see `CanaryConfig`
+    pub(crate) canary: Option<CanaryConfig>,
+    /// Applied in the order they're listed
+    #[serde(default)]
+    pub(crate) filters: Vec<Filter>,
+}
+
+impl HttpRouteRuleConfig {
+    /// Services of the `RequestMirror` filters, each gets a copy of every request the rule
+    /// matches, see `Mirrors`
+    pub(crate) fn mirror_services(&self) -> impl Iterator<Item = &String> {
+        self.filters.iter().filter_map(|filter| match filter {
+            Filter::RequestMirror(mirror) => Some(&mirror.service),
+            _ => None,
+        })
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -37,6 +84,18 @@ pub(crate) struct HttpRouteConfig {
     pub(crate) hostnames: Option<Vec<HostSpec>>,
     pub(crate) server: String,
     pub(crate) rules: Vec<HttpRouteRuleConfig>,
+    /// Opt-in in-memory cache for `GET` responses the backend marks as cacheable
+    pub(crate) cache: Option<ResponseCacheConfig>,
+    /// Translate gRPC-Web requests into gRPC, the backends of the route have to speak gRPC
+    #[serde(default)]
+    pub(crate) grpc_web: bool,
+    /// Point redirects of the backends back at the proxy, locations are passed as is when not set
+    pub(crate) rewrite_location: Option<LocationRewrite>,
+    /// Only used when a rule of the route matches on the body, 64KiB when not set
+    pub(crate) body_buffer: Option<BodyBufferConfig>,
+    /// Answer `HEAD` and `OPTIONS` for backends that only handle `GET`
+    #[serde(default)]
+    pub(crate) synthesize: SynthesizeConfig,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -44,4 +103,13 @@ pub(crate) struct HttpConfig {
     pub(crate) servers: Vec<HttpServerFields>,
     pub(crate) services: HashMap<String, HttpService>,
     pub(crate) routes: Vec<HttpRouteConfig>,
+    /// Defaults for every service, services and route rules can override them
+    #[serde(default)]
+    pub(crate) timeouts: TimeoutsConfig,
+    /// Pages for errors bifrost responds with itself, by status code. Servers can override them.
+    #[serde(default)]
+    pub(crate) error_pages: ErrorPagesConfig,
+    /// Warns at startup about servers with more routes, or routes with more rules, than set
+    #[serde(default)]
+    pub(crate) route_limits: RouteLimitsConfig,
 }
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use super::{matchers::PathMatch, route::HttpRule};
+
+/// Rules of a route by the exact and prefix paths they match on, so a request is only matched
+/// against the rules its path can match instead of every rule of the route. Looking up a path
+/// takes as long as the path has segments, however many rules there are.
+///
+/// Every matcher of a rule has to match, so a rule is indexed by the first of its exact or
+/// prefix paths. Rules without one, e.g. the ones matching by a regex or not by path at all,
+/// are candidates for every path. Candidates are still matched in full and in the order of the
+/// rules, the index only leaves out the ones that can't match.
+#[derive(Debug, Default)]
+pub(crate) struct PathIndex {
+    root: Node,
+    /// Rules that can match any path
+    any_path: Vec<usize>,
+}
+
+/// Segment of the paths in the index
+#[derive(Debug, Default)]
+struct Node {
+    children: HashMap<String, Node>,
+    /// Rules matching exactly the path up to this segment
+    exact: Vec<usize>,
+    /// Rules matching the path up to this segment and anything under it
+    prefix: Vec<usize>,
+}
+
+impl Node {
+    /// Node for `segments` under this one, the ones missing on the way are added
+    fn insert<'a>(&mut self, segments: impl IntoIterator<Item = &'a str>) -> &mut Node {
+        segments.into_iter().fold(self, |node, segment| {
+            node.children.entry(segment.to_owned()).or_default()
+        })
+    }
+}
+
+impl PathIndex {
+    pub(crate) fn new(rules: &[HttpRule]) -> Self {
+        let mut index = Self::default();
+
+        for (rule_index, rule) in rules.iter().enumerate() {
+            let indexed_path = rule
+                .matchers
+                .iter()
+                .filter_map(|matcher| matcher.path.as_ref())
+                .find(|path| matches!(path, PathMatch::Exact { .. } | PathMatch::Prefix { .. }));
+
+            match indexed_path {
+                // Segments are split the same way the prefix matcher splits paths
+                Some(PathMatch::Exact { value }) => {
+                    index.root.insert(value.split('/')).exact.push(rule_index);
+                }
+                Some(PathMatch::Prefix { value }) => {
+                    index
+                        .root
+                        .insert(value.segments().iter().map(String::as_str))
+                        .prefix
+                        .push(rule_index);
+                }
+                _ => index.any_path.push(rule_index),
+            }
+        }
+
+        index
+    }
+
+    /// Indices of the rules that can match `path`, in the order of the rules
+    pub(crate) fn candidates(&self, path: &str) -> Vec<usize> {
+        let mut candidates = self.any_path.clone();
+        let mut node = &self.root;
+        let mut segments = path.split('/');
+
+        candidates.extend(&node.prefix);
+
+        let whole_path = loop {
+            let Some(segment) = segments.next() else {
+                break true;
+            };
+
+            let Some(child) = node.children.get(segment) else {
+                break false;
+            };
+
+            node = child;
+            candidates.extend(&node.prefix);
+        };
+
+        if whole_path {
+            candidates.extend(&node.exact);
+        }
+
+        candidates.sort_unstable();
+        candidates
+    }
+}
@@ -0,0 +1,358 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use duration_string::DurationString;
+use http_body_util::combinators::BoxBody;
+use hyper::client::conn::{http1::SendRequest, http2};
+use serde::{Deserialize, Serialize};
+use tokio::task::AbortHandle;
+
+use crate::shutdown::Shutdown;
+
+use super::backend_set::BackendSet;
+
+/// Longest a connection that's no longer used stays open for, idle timeouts under it are
+/// followed more closely
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// HTTP/1 connections to the backends of a service kept open between requests, so a request
+/// doesn't wait for a new connection and handshake. A connection goes back to the pool once the
+/// response to its request has been read to the end.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct HttpPoolConfig {
+    /// Idle connections kept for each backend, the ones over it are closed
+    #[serde(default = "HttpPoolConfig::default_max_idle_per_backend")]
+    pub(crate) max_idle_per_backend: usize,
+    /// How long a connection can sit in the pool before it's closed
+    #[serde(default = "HttpPoolConfig::default_idle_timeout")]
+    pub(crate) idle_timeout: DurationString,
+}
+
+impl HttpPoolConfig {
+    fn default_max_idle_per_backend() -> usize {
+        8
+    }
+
+    fn default_idle_timeout() -> DurationString {
+        Duration::from_secs(60).into()
+    }
+}
+
+/// HTTP/1 connection to a backend, put in the pool it's kept under once it's released
+#[derive(Debug)]
+pub(crate) struct Http1Connection {
+    pub(crate) sender: SendRequest<BoxBody<Bytes, hyper::Error>>,
+    /// Task driving the connection
+    pub(crate) task: AbortHandle,
+    /// Pool and `host:port` of the backend, not set when the service doesn't pool connections
+    pool: Option<(HttpPool, String)>,
+}
+
+impl Http1Connection {
+    pub(crate) fn new(
+        sender: SendRequest<BoxBody<Bytes, hyper::Error>>,
+        task: AbortHandle,
+        pool: Option<(HttpPool, String)>,
+    ) -> Self {
+        Self { sender, task, pool }
+    }
+
+    /// The request is over, the connection can take the next one
+    pub(crate) fn release(mut self) {
+        if let Some((pool, address)) = self.pool.take() {
+            pool.release(address, self);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Idle {
+    connection: Http1Connection,
+    since: Instant,
+}
+
+/// Idle connections of a service by the address of their backend
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(from = "HttpPoolConfig", into = "HttpPoolConfig")]
+pub(crate) struct HttpPool {
+    config: HttpPoolConfig,
+    idle: Arc<Mutex<HashMap<String, Vec<Idle>>>>,
+}
+
+impl From<HttpPoolConfig> for HttpPool {
+    fn from(config: HttpPoolConfig) -> Self {
+        Self {
+            config,
+            idle: Arc::default(),
+        }
+    }
+}
+
+impl From<HttpPool> for HttpPoolConfig {
+    fn from(pool: HttpPool) -> Self {
+        pool.config
+    }
+}
+
+impl HttpPool {
+    pub(crate) fn config(&self) -> &HttpPoolConfig {
+        &self.config
+    }
+
+    /// Most recently released connection to `address` the backend hasn't closed, the expired
+    /// ones are dropped on the way. It may still turn out closed once it's used.
+    pub(crate) fn take(&self, address: &str) -> Option<Http1Connection> {
+        let mut idle = self.idle.lock().expect("Pool lock poisoned");
+        let connections = idle.get_mut(address)?;
+        let idle_timeout: Duration = self.config.idle_timeout.into();
+
+        while let Some(Idle { connection, since }) = connections.pop() {
+            if since.elapsed() >= idle_timeout || connection.sender.is_closed() {
+                continue;
+            }
+
+            return Some(Http1Connection {
+                pool: Some((self.clone(), address.to_owned())),
+                ..connection
+            });
+        }
+
+        None
+    }
+
+    /// Keeps the connection for the next request to `address` unless there are enough already,
+    /// a dropped connection closes once it's done
+    fn release(&self, address: String, connection: Http1Connection) {
+        if connection.sender.is_closed() {
+            return;
+        }
+
+        let mut idle = self.idle.lock().expect("Pool lock poisoned");
+        let connections = idle.entry(address).or_default();
+
+        if connections.len() >= self.config.max_idle_per_backend {
+            return;
+        }
+
+        connections.push(Idle {
+            connection,
+            since: Instant::now(),
+        });
+    }
+
+    /// Closes the expired connections and the ones to backends that aren't in `addresses`
+    fn reap(&self, addresses: &HashSet<String>) {
+        let idle_timeout: Duration = self.config.idle_timeout.into();
+        let mut idle = self.idle.lock().expect("Pool lock poisoned");
+
+        idle.retain(|address, connections| {
+            connections.retain(|Idle { connection, since }| {
+                since.elapsed() < idle_timeout && !connection.sender.is_closed()
+            });
+
+            addresses.contains(address) && !connections.is_empty()
+        });
+    }
+}
+
+pub(crate) type Http2Sender = http2::SendRequest<BoxBody<Bytes, hyper::Error>>;
+
+/// HTTP/2 connections of a service by the address of their backend. A backend has one that
+/// every request to it goes over as a stream, so requests don't wait for a new connection and
+/// handshake, and its keep-alive pings watch the connection for as long as it's used.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Http2Connections(Arc<Mutex<HashMap<String, Http2Sender>>>);
+
+impl Http2Connections {
+    /// Connection to `address` the backend hasn't closed, a closed one is forgotten
+    pub(crate) fn get(&self, address: &str) -> Option<Http2Sender> {
+        let mut connections = self.0.lock().expect("Connections lock poisoned");
+
+        match connections.get(address) {
+            Some(sender) if !sender.is_closed() => Some(sender.clone()),
+            Some(_) => {
+                connections.remove(address);
+
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Shares a new connection to `address`. One that's there already, e.g. when two requests
+    /// connected at once, closes once its requests are done.
+    pub(crate) fn insert(&self, address: String, sender: Http2Sender) {
+        self.0
+            .lock()
+            .expect("Connections lock poisoned")
+            .insert(address, sender);
+    }
+
+    /// Forgets the closed connections and the ones to backends that aren't in `addresses`, each
+    /// closes once its last request is done
+    fn reap(&self, addresses: &HashSet<String>) {
+        self.0
+            .lock()
+            .expect("Connections lock poisoned")
+            .retain(|address, sender| addresses.contains(address) && !sender.is_closed());
+    }
+}
+
+/// Closes the connections a service keeps open that won't be used anymore: the idle ones past
+/// their timeout and the ones to backends that were removed or are down. Without it they'd only
+/// be looked at when a request goes to their backend again.
+pub(crate) struct ConnectionReaper {
+    /// Follows the backends as they're replaced
+    pub(crate) backends: BackendSet,
+    pub(crate) pool: Option<HttpPool>,
+    pub(crate) http2_connections: Http2Connections,
+}
+
+impl ConnectionReaper {
+    /// Reaps connections until `shutdown` completes
+    pub(crate) async fn run(self, shutdown: Shutdown) {
+        let interval = self.pool.as_ref().map_or(REAP_INTERVAL, |pool| {
+            Duration::from(pool.config.idle_timeout).min(REAP_INTERVAL)
+        });
+        let mut ticks = tokio::time::interval(interval);
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {},
+                _ = shutdown.clone() => return,
+            }
+
+            self.reap();
+        }
+    }
+
+    fn reap(&self) {
+        let backends = self.backends.load();
+        let addresses = backends
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| backends.state(index).is_up())
+            .map(|(_, backend)| backend.address())
+            .collect();
+
+        if let Some(pool) = &self.pool {
+            pool.reap(&addresses);
+        }
+
+        self.http2_connections.reap(&addresses);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper_util::rt::TokioIo;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    fn pool(yaml: &str) -> HttpPool {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// Connection to a server that never answers, but keeps the connection open
+    async fn connection(listener: &TcpListener, pool: &HttpPool, address: &str) -> Http1Connection {
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .unwrap();
+        let task = tokio::spawn(async move {
+            let _ = conn.await;
+        });
+
+        Http1Connection::new(
+            sender,
+            task.abort_handle(),
+            Some((pool.clone(), address.to_owned())),
+        )
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_limited_per_backend() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = pool("max-idle-per-backend: 1");
+
+        connection(&listener, &pool, "backend:80").await.release();
+        connection(&listener, &pool, "backend:80").await.release();
+
+        assert!(pool.take("backend:80").is_some());
+        assert!(pool.take("backend:80").is_none());
+        assert!(pool.take("other:80").is_none());
+    }
+
+    #[tokio::test]
+    async fn broken_and_expired_connections_are_evicted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pooled = pool("{}");
+
+        let broken = connection(&listener, &pooled, "backend:80").await;
+        broken.task.abort();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // The aborted task took the connection down with it
+        broken.release();
+        assert!(pooled.take("backend:80").is_none());
+
+        let expiring = pool("idle-timeout: 0s");
+        connection(&listener, &expiring, "backend:80")
+            .await
+            .release();
+
+        assert!(expiring.take("backend:80").is_none());
+    }
+
+    #[tokio::test]
+    async fn connections_that_wont_be_used_are_reaped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pooled = pool("{}");
+
+        for address in ["127.0.0.1:3000", "127.0.0.1:3001", "127.0.0.1:3002"] {
+            connection(&listener, &pooled, address).await.release();
+        }
+
+        let expiring = pool("idle-timeout: 0s");
+        connection(&listener, &expiring, "127.0.0.1:3000")
+            .await
+            .release();
+
+        let backends: BackendSet = serde_yaml::from_str(
+            "backends: [{ ip: 127.0.0.1, port: 3000 }, { ip: 127.0.0.1, port: 3001 }]",
+        )
+        .unwrap();
+
+        // 3001 is down and 3002 was removed
+        backends.load().state(1).set_up(false);
+
+        for pool in [&pooled, &expiring] {
+            ConnectionReaper {
+                backends: backends.clone(),
+                pool: Some(pool.clone()),
+                http2_connections: Http2Connections::default(),
+            }
+            .reap();
+        }
+
+        let kept = |pool: &HttpPool| {
+            pool.idle
+                .lock()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(kept(&pooled), ["127.0.0.1:3000"]);
+        assert!(kept(&expiring).is_empty());
+    }
+}
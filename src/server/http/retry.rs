@@ -0,0 +1,179 @@
+use std::sync::{Mutex, MutexGuard};
+
+use serde::{Deserialize, Serialize};
+
+/// Retries of failed backend connections as they're written in the config.
+///
+/// Only connecting is retried, once the request is sent its body is gone and can't be replayed.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RetryConfig {
+    /// Extra connection attempts a single request can make
+    #[serde(default = "RetryConfig::default_attempts")]
+    pub(crate) attempts: u32,
+    /// Retries allowed as a share of requests, e.g. `0.1` lets through at most 10% extra
+    /// connection attempts over time
+    #[serde(default = "RetryConfig::default_budget_ratio")]
+    pub(crate) budget_ratio: f64,
+    /// Retries that can be saved up while backends are healthy, so a short burst of failures
+    /// can still be retried on a quiet service
+    #[serde(default = "RetryConfig::default_budget_burst")]
+    pub(crate) budget_burst: u32,
+}
+
+impl RetryConfig {
+    fn default_attempts() -> u32 {
+        1
+    }
+
+    fn default_budget_ratio() -> f64 {
+        0.1
+    }
+
+    fn default_budget_burst() -> u32 {
+        10
+    }
+}
+
+/// Token bucket shared by all requests to a service, so when its backends are struggling
+/// retries can't multiply the load on them.
+///
+/// Every request puts `budget_ratio` tokens in and every retry takes a whole token out.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(try_from = "RetryConfig", into = "RetryConfig")]
+pub(crate) struct RetryBudget {
+    config: RetryConfig,
+    /// Only locked while it's updated, so requests to the service don't wait on each other
+    balance: Mutex<f64>,
+}
+
+impl Clone for RetryBudget {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            balance: Mutex::new(*self.balance()),
+        }
+    }
+}
+
+impl TryFrom<RetryConfig> for RetryBudget {
+    type Error = String;
+
+    fn try_from(config: RetryConfig) -> Result<Self, Self::Error> {
+        if !(config.budget_ratio >= 0.0 && config.budget_ratio.is_finite()) {
+            return Err(format!(
+                "Retry budget ratio must be a non-negative number, got {}",
+                config.budget_ratio
+            ));
+        }
+
+        Ok(Self {
+            balance: Mutex::new(config.budget_burst as f64),
+            config,
+        })
+    }
+}
+
+impl From<RetryBudget> for RetryConfig {
+    fn from(value: RetryBudget) -> Self {
+        value.config
+    }
+}
+
+impl RetryBudget {
+    pub(crate) fn attempts(&self) -> u32 {
+        self.config.attempts
+    }
+
+    /// Called once for every request, whether it ends up retrying or not
+    pub(crate) fn deposit(&self) {
+        let max_balance = self.max_balance();
+        let mut balance = self.balance();
+
+        *balance = (*balance + self.config.budget_ratio).min(max_balance);
+    }
+
+    /// Takes a token for a retry, `false` means the budget is exhausted and the failure should
+    /// be passed through
+    pub(crate) fn withdraw(&self) -> bool {
+        let mut balance = self.balance();
+
+        // Ratios like 0.1 don't add up to exactly 1.0 in floating point
+        if *balance < 1.0 - 1e-9 {
+            return false;
+        }
+
+        *balance = (*balance - 1.0).max(0.0);
+
+        true
+    }
+
+    fn balance(&self) -> MutexGuard<'_, f64> {
+        self.balance.lock().expect("Retry budget lock poisoned")
+    }
+
+    fn max_balance(&self) -> f64 {
+        // A burst smaller than a single retry would never allow any
+        (self.config.budget_burst as f64).max(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(ratio: f64, burst: u32) -> RetryBudget {
+        RetryBudget::try_from(RetryConfig {
+            attempts: 1,
+            budget_ratio: ratio,
+            budget_burst: burst,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn burst_is_available_right_away() {
+        let budget = budget(0.1, 3);
+
+        assert!(budget.withdraw());
+        assert!(budget.withdraw());
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+    }
+
+    #[test]
+    fn retries_are_limited_by_ratio() {
+        let budget = budget(0.1, 0);
+        let mut retries = 0;
+
+        for _ in 0..1000 {
+            budget.deposit();
+
+            if budget.withdraw() {
+                retries += 1;
+            }
+        }
+
+        assert!((99..=100).contains(&retries), "got {} retries", retries);
+    }
+
+    #[test]
+    fn balance_is_capped() {
+        let budget = budget(1.0, 2);
+
+        for _ in 0..100 {
+            budget.deposit();
+        }
+
+        assert!(budget.withdraw());
+        assert!(budget.withdraw());
+        assert!(!budget.withdraw());
+    }
+
+    #[test]
+    fn negative_ratio_is_rejected() {
+        let result: Result<RetryBudget, _> = serde_yaml::from_str("budget-ratio: -0.5");
+
+        assert!(result.is_err());
+    }
+}
@@ -1,21 +1,40 @@
 use bytes::Bytes;
 use http_body_util::combinators::BoxBody;
-use hyper::{body::Incoming, Request, Response};
-use std::{convert::Infallible, sync::Arc};
-use tokio::sync::Mutex;
+use hyper::{Request, Response};
+use std::{collections::HashMap, convert::Infallible, sync::Arc, time::Instant};
 
-use crate::server::host::HostSpec;
+use crate::{metrics::metrics, server::host::HostSpec};
 
-use super::{matchers::Matcher, service::HttpService};
+use super::{
+    body_match::BodyBufferConfig,
+    cache::ResponseCache,
+    filters::Filter,
+    location::{ClientOrigin, LocationRewrite},
+    matchers::Matcher,
+    mirror::Mirrors,
+    path_index::PathIndex,
+    server::{bad_request, unsupported_media_type},
+    service::HttpService,
+    synthesize::SynthesizeConfig,
+    timeouts::Timeouts,
+};
 
 #[derive(Debug)]
 pub(crate) struct HttpRule {
+    pub(crate) name: String,
     pub(crate) matchers: Vec<Matcher>,
-    backend: Arc<Mutex<HttpService>>,
+    /// Name of the service the rule sends requests to
+    pub(crate) service: String,
+    backend: Arc<HttpService>,
+    timeouts: Timeouts,
+    mirrors: Option<Mirrors>,
+    /// Set for every rule of the route
+    rewrite_location: Option<LocationRewrite>,
+    filters: Vec<Filter>,
 }
 
 impl HttpRule {
-    fn matches(&self, req: &Request<Incoming>) -> bool {
+    fn matches<B>(&self, req: &Request<B>) -> bool {
         if self.matchers.is_empty() {
             return true;
         }
@@ -25,28 +44,558 @@ impl HttpRule {
 
     pub(super) async fn send_request(
         &self,
-        req: Request<Incoming>,
+        mut req: Request<BoxBody<Bytes, hyper::Error>>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Infallible> {
-        self.backend.lock().await.send_request(req).await
+        let redirect = self.filters.iter().find_map(|filter| match filter {
+            Filter::RequestRedirect(redirect) => Some(redirect),
+            _ => None,
+        });
+
+        let prefix = self
+            .matchers
+            .iter()
+            .filter_map(Matcher::path_prefix)
+            .find(|prefix| prefix.matches(req.uri().path()));
+
+        // The backend is never asked, none of the other filters apply
+        if let Some(redirect) = redirect {
+            return Ok(redirect.response(&req, prefix));
+        }
+
+        let content_types = self.filters.iter().find_map(|filter| match filter {
+            Filter::AllowedContentTypes(allowed) => Some(allowed),
+            _ => None,
+        });
+
+        if content_types.is_some_and(|allowed| !allowed.allows(&req)) {
+            tracing::debug!(rule = %self.name, "Content type of the request isn't allowed");
+
+            return Ok(unsupported_media_type());
+        }
+
+        // Taken before a rewrite changes the path, only for the rules that set headers from it
+        let path = self
+            .filters
+            .iter()
+            .any(|filter| matches!(filter, Filter::PathVariableHeaders(_)))
+            .then(|| req.uri().path().to_owned());
+        let variables: HashMap<_, _> = path
+            .iter()
+            .flat_map(|path| {
+                self.matchers
+                    .iter()
+                    .filter_map(|matcher| matcher.path.as_ref())
+                    .flat_map(move |matcher| matcher.captures(path))
+            })
+            .rev()
+            .collect();
+
+        for filter in &self.filters {
+            match filter {
+                Filter::RequestHeaderModifier(modifier) => modifier.apply(req.headers_mut()),
+                Filter::UrlRewrite(rewrite) => rewrite.apply(&mut req, prefix),
+                Filter::PathVariableHeaders(headers) => {
+                    headers.apply(req.headers_mut(), &variables)
+                }
+                // The rule's mirrors are built from the filters, they're sent below
+                Filter::ResponseHeaderModifier(_)
+                | Filter::RequestRedirect(_)
+                | Filter::RequestMirror(_)
+                | Filter::AllowedContentTypes(_) => {}
+            }
+        }
+
+        // Mirrors get the request the way the rule's own service does
+        let req = match &self.mirrors {
+            Some(mirrors) => match mirrors.send(req).await {
+                Ok(req) => req,
+                Err(err) => {
+                    tracing::debug!(
+                        rule = %self.name,
+                        error = %err,
+                        "Failed to read the body of a mirrored request"
+                    );
+
+                    return Ok(bad_request());
+                }
+            },
+            None => req,
+        };
+
+        let client = self
+            .rewrite_location
+            .as_ref()
+            .and_then(|_| ClientOrigin::of(&req));
+
+        let mut response = self
+            .backend
+            .send_request(&self.service, req, self.timeouts)
+            .await?;
+
+        if let Some(rewrite) = &self.rewrite_location {
+            rewrite.apply(&mut response, client.as_ref());
+        }
+
+        for filter in &self.filters {
+            if let Filter::ResponseHeaderModifier(modifier) = filter {
+                modifier.apply(response.headers_mut());
+            }
+        }
+
+        Ok(response)
     }
 }
 
 // This route is def on steroids
 // Thanks networking-sig
 impl HttpRule {
-    pub(crate) fn new(matchers: Vec<Matcher>, backend: Arc<Mutex<HttpService>>) -> Self {
-        Self { matchers, backend }
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        name: String,
+        matchers: Vec<Matcher>,
+        service: String,
+        backend: Arc<HttpService>,
+        timeouts: Timeouts,
+        mirrors: Option<Mirrors>,
+        rewrite_location: Option<LocationRewrite>,
+        filters: Vec<Filter>,
+    ) -> Self {
+        Self {
+            name,
+            matchers,
+            service,
+            backend,
+            timeouts,
+            mirrors,
+            rewrite_location,
+            filters,
+        }
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct HttpRoute {
+    pub(crate) name: String,
     pub(crate) hostnames: Vec<HostSpec>,
     pub(crate) rules: Vec<HttpRule>,
+    /// Built from the rules, narrows them down to the ones a path can match
+    pub(crate) path_index: PathIndex,
+    pub(crate) cache: Option<ResponseCache>,
+    /// Translate gRPC-Web requests from browsers into gRPC for the backends
+    pub(crate) grpc_web: bool,
+    /// Set when a rule of the route matches on the body
+    pub(crate) body_buffer: Option<BodyBufferConfig>,
+    pub(crate) synthesize: SynthesizeConfig,
 }
 
 impl HttpRoute {
-    pub(crate) fn find_matching_rule(&self, req: &Request<Incoming>) -> Option<&HttpRule> {
-        self.rules.iter().find(|rule| rule.matches(req))
+    pub(crate) fn find_matching_rule<B>(&self, req: &Request<B>) -> Option<&HttpRule> {
+        self.candidates(req).find(|rule| rule.matches(req))
+    }
+
+    /// Rules that can match the path of `req`, in order
+    fn candidates<B>(&self, req: &Request<B>) -> impl Iterator<Item = &HttpRule> {
+        self.path_index
+            .candidates(req.uri().path())
+            .into_iter()
+            .map(|index| &self.rules[index])
+    }
+
+    /// Same as `find_matching_rule`, but also tells how many rules were evaluated, the ones the
+    /// path can't match aren't. When the listener is given, the time each rule took is recorded
+    /// under it, which is only worth it when looking for a slow matcher.
+    pub(crate) fn count_matching_rule<B>(
+        &self,
+        req: &Request<B>,
+        timed_on: Option<&str>,
+    ) -> (Option<&HttpRule>, usize) {
+        let mut evaluated = 0;
+
+        let rule = self.candidates(req).find(|rule| {
+            evaluated += 1;
+
+            let Some(listener) = timed_on else {
+                return rule.matches(req);
+            };
+
+            let started = Instant::now();
+            let matched = rule.matches(req);
+            metrics().rule_match(listener, &self.name, &rule.name, started.elapsed());
+
+            matched
+        });
+
+        (rule, evaluated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::server::http::server::full;
+
+    /// Backend that answers with a couple of headers to modify
+    async fn backend() -> HttpService {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut request = vec![0; 1024];
+            let _ = stream.read(&mut request).await.unwrap();
+
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nserver: backend/1.0\r\nvary: accept\r\n\
+                      content-length: 0\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+
+        serde_yaml::from_str(&format!("backends: [{{ ip: 127.0.0.1, port: {} }}]", port)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn response_headers_are_modified() {
+        let filters = serde_yaml::from_str(
+            "
+            - type: ResponseHeaderModifier
+              set: [{ name: strict-transport-security, value: max-age=31536000 }]
+              add: [{ name: vary, value: accept-encoding }]
+              remove: [server]
+            ",
+        )
+        .unwrap();
+
+        let rule = HttpRule::new(
+            "test/0".to_owned(),
+            vec![],
+            "test-service".to_owned(),
+            Arc::new(backend().await),
+            Default::default(),
+            None,
+            None,
+            filters,
+        );
+
+        let response = rule.send_request(Request::new(full(""))).await.unwrap();
+        let headers = response.headers();
+
+        assert_eq!(headers["strict-transport-security"], "max-age=31536000");
+        assert_eq!(
+            headers.get_all("vary").iter().collect::<Vec<_>>(),
+            ["accept", "accept-encoding"]
+        );
+        assert!(!headers.contains_key("server"));
+    }
+
+    /// Backend that reports the request it gets and answers with a body of its own
+    async fn shadow_backend(requests: mpsc::UnboundedSender<String>) -> HttpService {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut request = vec![0; 1024];
+            let read = stream.read(&mut request).await.unwrap();
+
+            requests
+                .send(String::from_utf8_lossy(&request[..read]).into_owned())
+                .unwrap();
+
+            stream
+                .write_all(b"HTTP/1.1 500 Internal Server Error\r\ncontent-length: 6\r\n\r\nshadow")
+                .await
+                .unwrap();
+        });
+
+        serde_yaml::from_str(&format!("backends: [{{ ip: 127.0.0.1, port: {} }}]", port)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn path_variables_are_sent_as_headers() {
+        let (requests, mut received) = mpsc::unbounded_channel();
+
+        let filters = serde_yaml::from_str(
+            "
+            - type: PathVariableHeaders
+              set: [{ name: x-resource-id, value: '{id}' }]
+            ",
+        )
+        .unwrap();
+
+        let rule = HttpRule::new(
+            "test/0".to_owned(),
+            serde_yaml::from_str("[{ path: { type: Regex, value: '/resources/(?<id>[0-9]+)' } }]")
+                .unwrap(),
+            "test-service".to_owned(),
+            Arc::new(shadow_backend(requests).await),
+            Default::default(),
+            None,
+            None,
+            filters,
+        );
+
+        let req = Request::get("/resources/12")
+            .header(http::header::HOST, "test.com")
+            .body(full(""))
+            .unwrap();
+
+        rule.send_request(req).await.unwrap();
+
+        let sent = received.recv().await.unwrap();
+
+        assert!(sent.contains("x-resource-id: 12\r\n"), "{}", sent);
+    }
+
+    #[tokio::test]
+    async fn requests_to_a_slow_service_dont_wait_on_each_other() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let mut request = vec![0; 1024];
+                    let _ = stream.read(&mut request).await.unwrap();
+
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                        .await
+                        .unwrap();
+                });
+            }
+        });
+
+        let service =
+            serde_yaml::from_str(&format!("backends: [{{ ip: 127.0.0.1, port: {} }}]", port))
+                .unwrap();
+
+        let rule = HttpRule::new(
+            "test/0".to_owned(),
+            vec![],
+            "test-service".to_owned(),
+            Arc::new(service),
+            Default::default(),
+            None,
+            None,
+            vec![],
+        );
+
+        let started = Instant::now();
+        let (first, second) = tokio::join!(
+            rule.send_request(Request::new(full(""))),
+            rule.send_request(Request::new(full("")))
+        );
+
+        assert_eq!(first.unwrap().status(), 200);
+        assert_eq!(second.unwrap().status(), 200);
+
+        // One after the other they'd take 600ms
+        assert!(
+            started.elapsed() < Duration::from_millis(500),
+            "{:?}",
+            started.elapsed()
+        );
+    }
+
+    /// Rule sending to a service nothing listens for, for tests that only match requests
+    fn rule(name: &str, matchers: &str) -> HttpRule {
+        HttpRule::new(
+            name.to_owned(),
+            serde_yaml::from_str(matchers).unwrap(),
+            name.to_owned(),
+            Arc::new(serde_yaml::from_str("backends: [{ ip: 127.0.0.1, port: 1 }]").unwrap()),
+            Default::default(),
+            None,
+            None,
+            vec![],
+        )
+    }
+
+    fn route(rules: Vec<HttpRule>) -> HttpRoute {
+        HttpRoute {
+            name: "api".to_owned(),
+            hostnames: vec![],
+            path_index: PathIndex::new(&rules),
+            rules,
+            cache: None,
+            grpc_web: false,
+            body_buffer: None,
+            synthesize: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn large_uploads_go_to_their_own_service() {
+        let route = route(vec![
+            rule(
+                "uploads",
+                "[{ size: { larger-than: 1048576, unknown-length: large } }]",
+            ),
+            rule("api", "[]"),
+        ]);
+
+        let service = |req: Request<()>| route.find_matching_rule(&req).unwrap().service.clone();
+        let upload = |header: &str, value: &str| {
+            Request::post("/files")
+                .header(header, value)
+                .body(())
+                .unwrap()
+        };
+
+        assert_eq!(service(upload("content-length", "5000000")), "uploads");
+        assert_eq!(service(upload("transfer-encoding", "chunked")), "uploads");
+        assert_eq!(service(upload("content-length", "512")), "api");
+        assert_eq!(service(Request::get("/files").body(()).unwrap()), "api");
+    }
+
+    #[tokio::test]
+    async fn rules_are_counted_until_one_matches() {
+        let route = route(vec![
+            rule("legacy", "[{ path: { type: Regex, value: ^/v1/ } }]"),
+            rule("users", "[{ path: { type: Prefix, value: /users } }]"),
+            rule("orders", "[{ path: { type: Prefix, value: /orders } }]"),
+        ]);
+
+        let count = |path: &str| {
+            let req = Request::get(path).body(()).unwrap();
+            let (rule, evaluated) = route.count_matching_rule(&req, Some("http-1"));
+
+            (rule.map(|rule| rule.name.clone()), evaluated)
+        };
+
+        // The rules the path can't match aren't evaluated
+        assert_eq!(count("/users/1"), (Some("users".to_owned()), 2));
+        assert_eq!(count("/orders/1"), (Some("orders".to_owned()), 2));
+        assert_eq!(count("/v1/orders"), (Some("legacy".to_owned()), 1));
+        assert_eq!(count("/carts/1"), (None, 1));
+    }
+
+    #[tokio::test]
+    async fn path_index_keeps_the_first_matching_rule() {
+        let route = route(vec![
+            rule("exact", "[{ path: { type: Exact, value: /api/users/ } }]"),
+            rule(
+                "posts",
+                "[{ path: { type: Prefix, value: /api/users }, method: POST }]",
+            ),
+            rule("regex", "[{ path: { type: Regex, value: /me$ } }]"),
+            rule("users", "[{ path: { type: Prefix, value: /api/users/ } }]"),
+            rule("api", "[{ path: { type: Prefix, value: /api } }]"),
+            rule("root", "[{ path: { type: Exact, value: / } }]"),
+            rule("any", "[]"),
+        ]);
+
+        let linear = |req: &Request<()>| {
+            route
+                .rules
+                .iter()
+                .find(|rule| rule.matches(req))
+                .map(|rule| rule.name.clone())
+        };
+        let indexed =
+            |req: &Request<()>| route.find_matching_rule(req).map(|rule| rule.name.clone());
+
+        let requests = [
+            ("GET", "/api/users/"),
+            ("GET", "/api/users"),
+            ("POST", "/api/users/1"),
+            ("GET", "/api/users/me"),
+            ("GET", "/api/users/1"),
+            ("GET", "/api/usersettings"),
+            ("GET", "/api"),
+            ("GET", "/"),
+            ("GET", "/other"),
+            ("GET", ""),
+        ];
+
+        for (method, path) in requests {
+            let req = Request::builder()
+                .method(method)
+                .uri(format!("http://test.com{}", path))
+                .body(())
+                .unwrap();
+
+            assert_eq!(indexed(&req), linear(&req), "{} {}", method, path);
+        }
+
+        let name = |path: &str| indexed(&Request::get(path).body(()).unwrap());
+
+        assert_eq!(name("/api/users/").as_deref(), Some("exact"));
+        assert_eq!(name("/api/users/me").as_deref(), Some("regex"));
+        assert_eq!(name("/api/users/1").as_deref(), Some("users"));
+        assert_eq!(name("/api/usersettings").as_deref(), Some("api"));
+        assert_eq!(name("/").as_deref(), Some("root"));
+        assert_eq!(name("/other").as_deref(), Some("any"));
+    }
+
+    /// Compares the index against looking through every rule, run with
+    /// `cargo test --release path_index_benchmark -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn path_index_benchmark() {
+        const RULES: usize = 1000;
+        const LOOKUPS: usize = 100_000;
+
+        let rules = (0..RULES)
+            .map(|index| {
+                let path = match index % 2 {
+                    0 => format!("{{ type: Prefix, value: /service-{}/api }}", index),
+                    _ => format!("{{ type: Exact, value: /service-{}/health }}", index),
+                };
+
+                rule(
+                    &format!("rule-{}", index),
+                    &format!("[{{ path: {} }}]", path),
+                )
+            })
+            .collect();
+        let route = route(rules);
+
+        let requests: Vec<_> = (0..LOOKUPS)
+            .map(|lookup| {
+                let index = lookup * 7 % RULES;
+                let path = match index % 2 {
+                    0 => format!("/service-{}/api/items/{}", index, lookup),
+                    _ => format!("/service-{}/health", index),
+                };
+
+                Request::get(path).body(()).unwrap()
+            })
+            .collect();
+
+        let started = Instant::now();
+        for req in &requests {
+            std::hint::black_box(route.rules.iter().find(|rule| rule.matches(req)));
+        }
+        let linear = started.elapsed();
+
+        let started = Instant::now();
+        for req in &requests {
+            std::hint::black_box(route.find_matching_rule(req));
+        }
+        let indexed = started.elapsed();
+
+        println!(
+            "{} lookups over {} rules: linear {:?}, indexed {:?}",
+            LOOKUPS, RULES, linear, indexed
+        );
+
+        assert!(indexed < linear);
     }
 }
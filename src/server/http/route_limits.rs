@@ -0,0 +1,144 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::HttpRouteConfig;
+
+/// Guardrail for configs that grow large enough to slow down matching. Every request looks
+/// through the routes of its server one by one, so each route adds to the latency of every
+/// request. Rules matching an exact or prefix path are looked up by the path, but the ones
+/// matching by a regex or not by path at all are still tried one by one on every request of
+/// the route.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RouteLimitsConfig {
+    /// Routes of a server over which startup warns
+    pub(crate) max_routes: Option<usize>,
+    /// Rules of a route over which startup warns
+    pub(crate) max_rules: Option<usize>,
+    /// Fail config validation instead of warning
+    #[serde(default)]
+    pub(crate) strict: bool,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub(crate) enum RouteLimitExceeded {
+    #[error("Server {server} has {routes} routes, over the limit of {limit}")]
+    Routes {
+        server: String,
+        routes: usize,
+        limit: usize,
+    },
+    #[error("Route {route} of server {server} has {rules} rules, over the limit of {limit}")]
+    Rules {
+        server: String,
+        route: String,
+        rules: usize,
+        limit: usize,
+    },
+}
+
+impl RouteLimitsConfig {
+    /// Logs how many routes and rules `server` has and warns about the ones over the limits,
+    /// which can only be there when the limits aren't strict
+    pub(crate) fn check<'a>(
+        &self,
+        server: &str,
+        routes: impl IntoIterator<Item = &'a HttpRouteConfig>,
+    ) {
+        let routes: Vec<_> = routes.into_iter().collect();
+        let rules: usize = routes.iter().map(|route| route.rules.len()).sum();
+
+        tracing::info!(server, routes = routes.len(), rules, "Loaded routes");
+
+        for exceeded in self.exceeded(server, routes) {
+            tracing::warn!(
+                "{}. Routes, and rules without an exact or prefix path, are matched one by one. \
+                 Consider splitting the server, merging routes or rules, or matching by path \
+                 instead of a regex.",
+                exceeded
+            );
+        }
+    }
+
+    /// Limits `server` is over with `routes`, the routes first
+    pub(crate) fn exceeded<'a>(
+        &self,
+        server: &str,
+        routes: impl IntoIterator<Item = &'a HttpRouteConfig>,
+    ) -> Vec<RouteLimitExceeded> {
+        let routes: Vec<_> = routes.into_iter().collect();
+
+        let too_many_routes = self
+            .max_routes
+            .filter(|limit| routes.len() > *limit)
+            .map(|limit| RouteLimitExceeded::Routes {
+                server: server.to_owned(),
+                routes: routes.len(),
+                limit,
+            });
+
+        let too_many_rules = routes.iter().filter_map(|route| {
+            self.max_rules
+                .filter(|limit| route.rules.len() > *limit)
+                .map(|limit| RouteLimitExceeded::Rules {
+                    server: server.to_owned(),
+                    route: route.name.clone(),
+                    rules: route.rules.len(),
+                    limit,
+                })
+        });
+
+        too_many_routes.into_iter().chain(too_many_rules).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn routes(rules: &[usize]) -> Vec<HttpRouteConfig> {
+        rules
+            .iter()
+            .enumerate()
+            .map(|(index, rules)| {
+                let rules = vec!["{ matches: [], backend: service }"; *rules].join(", ");
+
+                serde_yaml::from_str(&format!(
+                    "{{ name: route-{}, server: server, rules: [{}] }}",
+                    index, rules
+                ))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    fn limits(yaml: &str) -> RouteLimitsConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn routes_and_rules_over_the_limits() {
+        let routes = routes(&[1, 3]);
+
+        assert!(limits("{}").exceeded("server", &routes).is_empty());
+        assert!(limits("{ max-routes: 2, max-rules: 3 }")
+            .exceeded("server", &routes)
+            .is_empty());
+        assert_eq!(
+            limits("{ max-routes: 1, max-rules: 2 }").exceeded("server", &routes),
+            [
+                RouteLimitExceeded::Routes {
+                    server: "server".to_owned(),
+                    routes: 2,
+                    limit: 1,
+                },
+                RouteLimitExceeded::Rules {
+                    server: "server".to_owned(),
+                    route: "route-1".to_owned(),
+                    rules: 3,
+                    limit: 2,
+                }
+            ]
+        );
+    }
+}
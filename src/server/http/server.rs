@@ -1,65 +1,619 @@
 use crate::server::host::Hostname;
 use bytes::Bytes;
-use http::StatusCode;
+use futures::future::select_all;
+use http::{header, uri::Authority, HeaderName, HeaderValue, Method, StatusCode, Version};
 use http_body_util::{combinators::BoxBody, BodyExt, Full};
-use hyper::{body::Incoming, server::conn::http1, service::service_fn, Request, Response};
-use hyper_util::rt::TokioIo;
+use hyper::{body::Incoming, service::service_fn, Request, Response};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+};
 use serde::{Deserialize, Serialize};
-use std::{convert::Infallible, io, net::SocketAddr, str::FromStr, sync::Arc};
-use tokio::net::TcpListener;
+use std::{
+    convert::Infallible,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{field, Instrument};
 
-use super::route::HttpRoute;
+use crate::{
+    connection_events::{events, ConnectionProtocol, TrackedConnection},
+    metrics::metrics,
+    request_log::{request_log, RequestRecord},
+    server::{
+        listen::{self, ListenFields},
+        tls::{AlpnProtocol, ServerTls},
+    },
+    telemetry,
+};
+
+use super::{
+    backend_set::BackendSet,
+    body_match::{self, WhenTooLarge},
+    cache::{Lookup, ResponseCache},
+    connect::{self, ConnectDestination},
+    error_pages::{ErrorPages, ErrorPagesConfig, Generated},
+    fail_closed::{FailClosed, FailClosedConfig, Readiness},
+    grpc_web,
+    headers::ConfiguredHeaderName,
+    length_conflict::{Conflicting, Conflicts, Guarded, LengthConflict},
+    location::OverTls,
+    matchers::{ClientSni, MethodMatch},
+    route::HttpRoute,
+    service::ServedBy,
+    synthesize,
+};
+
+/// How to treat HTTP/1.0 clients, which don't keep connections alive by default and aren't
+/// required to send a Host header.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Http10Fields {
+    /// Whether to honor `Connection: keep-alive` from HTTP/1.0 clients, when disabled every
+    /// response to them closes the connection.
+    #[serde(default = "Http10Fields::default_keep_alive")]
+    pub(crate) keep_alive: bool,
+    /// Host used to route HTTP/1.0 requests without a Host header, those are rejected otherwise.
+    pub(crate) default_host: Option<Hostname>,
+}
+
+impl Http10Fields {
+    fn default_keep_alive() -> bool {
+        true
+    }
+}
+
+impl Default for Http10Fields {
+    fn default() -> Self {
+        Self {
+            keep_alive: Self::default_keep_alive(),
+            default_host: None,
+        }
+    }
+}
+
+/// Settings of HTTP/2 connections, which clients open with prior knowledge
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Http2Fields {
+    /// Streams a single connection can have open at once, hyper's default when not set
+    pub(crate) max_concurrent_streams: Option<u32>,
+}
+
+/// How much of request matching is measured, see the `route_match` metrics. Every request is
+/// matched, so the timing isn't free and is off by default.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum MatchMetrics {
+    #[default]
+    Off,
+    /// Time to find the route and rule of a request, and how many rules were evaluated
+    On,
+    /// Also the time of every rule evaluated, for debugging slow matchers
+    PerRule,
+}
+
+/// HTTP version a server speaks, clients that speak the other one can't connect
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum HttpVersion {
+    #[serde(rename = "1")]
+    V1,
+    /// With prior knowledge, as there's no upgrade from HTTP/1 to fall back to
+    #[serde(rename = "2")]
+    V2,
+}
+
+impl HttpVersion {
+    pub(crate) fn protocol(self) -> AlpnProtocol {
+        match self {
+            HttpVersion::V1 => AlpnProtocol::Http1,
+            HttpVersion::V2 => AlpnProtocol::Http2,
+        }
+    }
+}
+
+/// What to do with requests that have more than one Host header. RFC 7230 requires rejecting
+/// them, as the proxy and the backend may each pick a different one.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum DuplicateHost {
+    /// Respond with `400`
+    #[default]
+    Reject,
+    /// Route by the first one and drop the rest, so the backend sees the same one
+    UseFirst,
+}
+
+/// Port of HTTP servers that don't set one
+const DEFAULT_PORT: u16 = 80;
+
+/// Port of HTTPS servers that don't set one
+const DEFAULT_TLS_PORT: u16 = 443;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub(crate) struct HttpServerFields {
-    pub(crate) port: u16,
+    /// Defaults to 80, or 443 with TLS
+    pub(crate) port: Option<u16>,
     pub(crate) name: String,
+    /// Terminate TLS, the server only takes plaintext HTTP when not set
+    pub(crate) tls: Option<ServerTls>,
+    /// Both versions when not set, plain connections are told apart by the HTTP/2 preface and
+    /// TLS ones by ALPN. Over TLS, the version is up to `alpn`, which has to agree with it.
+    pub(crate) version: Option<HttpVersion>,
+    #[serde(default)]
+    pub(crate) http10: Http10Fields,
+    #[serde(default)]
+    pub(crate) http2: Http2Fields,
+    /// Header to pass the name of the matched route to backends in, e.g. `x-bifrost-route`
+    pub(crate) route_header: Option<ConfiguredHeaderName>,
+    /// Destinations clients can open `CONNECT` tunnels to, `CONNECT` is rejected when not set
+    pub(crate) allow_connect: Option<Vec<ConnectDestination>>,
+    /// Methods requests may use, others are rejected with `405` before routing. Any method is
+    /// allowed when not set
+    pub(crate) allowed_methods: Option<Vec<MethodMatch>>,
+    #[serde(default)]
+    pub(crate) duplicate_host: DuplicateHost,
+    #[serde(default)]
+    pub(crate) length_conflict: LengthConflict,
+    /// IPv4 and IPv6 by default
+    #[serde(default)]
+    pub(crate) listen: ListenFields,
+    /// Only listen on this address, e.g. `127.0.0.1` or the address of an internal interface,
+    /// instead of every address of the `listen` families
+    pub(crate) address: Option<IpAddr>,
+    /// Replace the global error pages of the same status
+    #[serde(default)]
+    pub(crate) error_pages: ErrorPagesConfig,
+    /// Stop listening while the backends of the server are down, it always listens when not set
+    pub(crate) fail_closed: Option<FailClosedConfig>,
+    #[serde(default)]
+    pub(crate) match_metrics: MatchMetrics,
+}
+
+/// Connection of a client, either plain TCP or TLS on top of it
+trait ClientIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientIo for T {}
+
+type ClientStream = Box<dyn ClientIo>;
+
+impl HttpServerFields {
+    pub(crate) fn port(&self) -> u16 {
+        match (self.port, &self.tls) {
+            (Some(port), _) => port,
+            (None, Some(_)) => DEFAULT_TLS_PORT,
+            (None, None) => DEFAULT_PORT,
+        }
+    }
 }
 
+/// Name of the route that handled the request, kept in the response extensions for metrics
+#[derive(Clone)]
+struct MatchedRoute(String);
+
 pub(crate) struct HttpServer {
-    port: u16,
+    config: Arc<HttpServerFields>,
     routes: Arc<Vec<HttpRoute>>,
+    error_pages: Arc<ErrorPages>,
+    fail_closed: Option<FailClosed>,
 }
 
 impl HttpServer {
-    pub(crate) fn new(config: HttpServerFields, routes: Vec<HttpRoute>) -> Self {
+    pub(crate) fn new(
+        config: HttpServerFields,
+        routes: Vec<HttpRoute>,
+        error_pages: ErrorPages,
+    ) -> Self {
         Self {
-            port: config.port,
+            config: Arc::new(config),
             routes: Arc::new(routes),
+            error_pages: Arc::new(error_pages),
+            fail_closed: None,
         }
     }
 
-    pub(crate) async fn run(self) -> Result<(), io::Error> {
-        let addr: SocketAddr = ([0, 0, 0, 0], self.port).into();
+    /// Stops listening while none of `backends` is in rotation, see `FailClosedConfig`. The
+    /// server is reported to `readiness` while it's closed.
+    pub(crate) fn failing_closed(
+        mut self,
+        backends: Vec<BackendSet>,
+        readiness: Readiness,
+    ) -> Self {
+        self.fail_closed = Some(FailClosed::new(backends, readiness));
+        self
+    }
+
+    /// Serves until `shutdown` completes, then stops accepting and waits for the requests that
+    /// are in flight
+    pub(crate) async fn run(self, shutdown: impl Future<Output = ()>) -> Result<(), io::Error> {
+        let listeners = match self.config.address {
+            Some(address) => vec![listen::bind(
+                (address, self.config.port()).into(),
+                self.config.listen.ipv6_only,
+            )?],
+            None => self.config.listen.bind(self.config.port())?,
+        };
 
-        let listener = TcpListener::bind(addr).await?;
+        for listener in &listeners {
+            tracing::info!(
+                server = %self.config.name,
+                address = %listener.local_addr()?,
+                "Listening for HTTP"
+            );
+        }
+
+        self.serve(listeners, shutdown).await
+    }
+
+    /// Connections from all `listeners` are served the same way
+    pub(super) async fn serve(
+        mut self,
+        mut listeners: Vec<TcpListener>,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), io::Error> {
+        let graceful = GracefulShutdown::new();
+
+        // Listeners are closed while the server fails closed, they're bound again to the same
+        // addresses once it opens
+        let addresses = listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut fail_closed = self.fail_closed.take();
+        let mut checks = tokio::time::interval(
+            self.config
+                .fail_closed
+                .map_or(Duration::from_secs(1), |config| {
+                    config.check_interval.into()
+                }),
+        );
+
+        tokio::pin!(shutdown);
 
-        println!("Listening for HTTP on port {}", self.port);
         loop {
-            let (stream, _) = listener.accept().await.unwrap();
+            let accepted = tokio::select! {
+                accepted = accept(&listeners), if !listeners.is_empty() => accepted,
+                _ = checks.tick(), if fail_closed.is_some() => {
+                    if let Some(fail_closed) = &mut fail_closed {
+                        self.check_backends(fail_closed, &mut listeners, &addresses);
+                    }
+
+                    continue;
+                }
+                _ = &mut shutdown => break,
+            };
+
+            // A connection that fails before it's accepted doesn't stop the server, e.g. when
+            // it's out of file descriptors for a moment
+            let (stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::warn!(
+                        server = %self.config.name,
+                        error = %err,
+                        "Failed to accept a connection"
+                    );
+                    continue;
+                }
+            };
 
-            let io = TokioIo::new(stream);
+            tracing::debug!(server = %self.config.name, %peer, "Accepted connection");
 
             let routes = self.routes.clone();
+            let config = self.config.clone();
+            let error_pages = self.error_pages.clone();
+            let tls = self.config.tls.clone();
 
-            let service = service_fn(move |req| {
-                let routes = routes.clone();
+            let watcher = graceful.watcher();
+            let max_concurrent_streams = self.config.http2.max_concurrent_streams;
+            let version = self.config.version;
+            let length_conflict = self.config.length_conflict;
 
-                async move { Self::proxy_request(req, routes).await }
-            });
+            // Shared with the requests, so it's reported closed after the last of them
+            let connection = events()
+                .opened(&self.config.name, ConnectionProtocol::Http, peer)
+                .map(Arc::new);
+
+            // Connections log in the span the server runs in, if any
+            let serving = async move {
+                // The handshake is done here, so a slow client doesn't hold up the accept loop
+                let (stream, sni, protocol): (ClientStream, Option<ClientSni>, _) = match tls {
+                    Some(tls) => match tls.acceptor().accept(stream).await {
+                        Ok(stream) => {
+                            let session = stream.get_ref().1;
+                            let sni = session
+                                .server_name()
+                                .and_then(|name| Hostname::from_str(name).ok())
+                                .map(ClientSni);
+                            let protocol = tls.protocol(session.alpn_protocol());
+
+                            (Box::new(stream), sni, Some(protocol))
+                        }
+                        Err(err) => {
+                            tracing::debug!(%peer, error = %err, "TLS handshake failed");
+                            return;
+                        }
+                    },
+                    // Plain connections are told apart by the HTTP/2 preface unless the version
+                    // is set
+                    None => (Box::new(stream), None, version.map(HttpVersion::protocol)),
+                };
+
+                // Bytes are counted decrypted, the way requests and responses are written
+                let stream: ClientStream = match &connection {
+                    Some(connection) => Box::new(connection.count(stream)),
+                    None => stream,
+                };
+
+                // HTTP/1 request heads are looked at before hyper parses them
+                let conflicts = (length_conflict == LengthConflict::Reject
+                    && protocol != Some(AlpnProtocol::Http2))
+                .then(Conflicts::default);
+
+                let stream: ClientStream = match &conflicts {
+                    Some(conflicts) => Box::new(Guarded::new(stream, conflicts.clone())),
+                    None => stream,
+                };
+
+                let io = TokioIo::new(stream);
 
-            tokio::spawn(async move {
-                if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                    println!("Error serving connection: {:?}", err);
+                let service = service_fn(move |mut req: Request<Incoming>| {
+                    // Taken in the order hyper serves requests, not once they're processed
+                    if conflicts.as_ref().is_some_and(Conflicts::next) {
+                        req.extensions_mut().insert(Conflicting);
+                    }
+
+                    let routes = routes.clone();
+                    let config = config.clone();
+                    let error_pages = error_pages.clone();
+                    let sni = sni.clone();
+                    let connection = connection.clone();
+
+                    async move {
+                        Self::proxy_request(req, routes, config, error_pages, sni, connection).await
+                    }
+                });
+
+                let mut builder = match protocol {
+                    Some(AlpnProtocol::Http2) => {
+                        auto::Builder::new(TokioExecutor::new()).http2_only()
+                    }
+                    Some(AlpnProtocol::Http1) => {
+                        auto::Builder::new(TokioExecutor::new()).http1_only()
+                    }
+                    None => auto::Builder::new(TokioExecutor::new()),
+                };
+
+                if let Some(max_concurrent_streams) = max_concurrent_streams {
+                    builder
+                        .http2()
+                        .max_concurrent_streams(max_concurrent_streams);
                 }
-            });
+
+                // Upgrades are needed for CONNECT tunnels over HTTP/1, HTTP/2 has them built in.
+                // Serving upgrades tells the protocol apart by the preface whatever the builder
+                // is limited to. On shutdown the watcher lets the request in flight finish and
+                // closes the connection after its response.
+                let served = match protocol {
+                    Some(AlpnProtocol::Http2) => {
+                        watcher.watch(builder.serve_connection(io, service)).await
+                    }
+                    _ => {
+                        watcher
+                            .watch(builder.serve_connection_with_upgrades(io, service))
+                            .await
+                    }
+                };
+
+                match served {
+                    Ok(()) => {}
+                    // Backend responses the client didn't wait for are dropped along with
+                    // the connection, which closes their backend connections
+                    Err(err) if is_client_gone(err.as_ref()) => {
+                        tracing::debug!("Client went away mid-response: {}", err);
+                    }
+                    Err(err) => tracing::warn!(%peer, error = ?err, "Error serving connection"),
+                }
+            };
+
+            tokio::spawn(serving.in_current_span());
         }
+
+        drop(listeners);
+
+        tracing::info!(
+            server = %self.config.name,
+            connections = graceful.count(),
+            "Draining HTTP connections"
+        );
+
+        graceful.shutdown().await;
+
+        Ok(())
+    }
+
+    /// Closes the listeners when the backends are down and binds them again once they're back
+    fn check_backends(
+        &self,
+        fail_closed: &mut FailClosed,
+        listeners: &mut Vec<TcpListener>,
+        addresses: &[SocketAddr],
+    ) {
+        let up = fail_closed.backends_up();
+
+        if !up && !listeners.is_empty() {
+            tracing::warn!(
+                server = %self.config.name,
+                "Every backend is down, not accepting connections"
+            );
+
+            listeners.clear();
+        }
+
+        if up && listeners.is_empty() {
+            let bound = addresses
+                .iter()
+                .map(|address| listen::bind(*address, self.config.listen.ipv6_only))
+                .collect::<io::Result<Vec<_>>>();
+
+            match bound {
+                Ok(bound) => {
+                    tracing::info!(
+                        server = %self.config.name,
+                        "Backends are back, accepting connections"
+                    );
+
+                    *listeners = bound;
+                }
+                // Tried again on the next check
+                Err(err) => tracing::warn!(
+                    server = %self.config.name,
+                    error = %err,
+                    "Failed to listen again"
+                ),
+            }
+        }
+
+        fail_closed.set_closed(listeners.is_empty());
     }
 
-    // TODO: http2 backend and protocol support
     async fn proxy_request(
         req: Request<Incoming>,
         routes: Arc<Vec<HttpRoute>>,
+        config: Arc<HttpServerFields>,
+        error_pages: Arc<ErrorPages>,
+        sni: Option<ClientSni>,
+        connection: Option<Arc<TrackedConnection>>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Infallible> {
+        let version = req.version();
+
+        let span = tracing::info_span!(
+            "proxy_request",
+            otel.kind = "server",
+            http.method = %req.method(),
+            http.target = %req.uri(),
+            http.status_code = field::Empty,
+            http.route = field::Empty,
+            http.rule = field::Empty,
+            backend = field::Empty,
+            latency_ms = field::Empty,
+        );
+
+        telemetry::continue_trace(&span, req.headers());
+
+        let started = Instant::now();
+
+        // The request is gone by the time it's logged
+        let method = req.method().clone();
+        let path = req.uri().path().to_owned();
+        let host = Self::request_host(&req, &config.http10);
+
+        let mut response = Self::route_request(req, &routes, &config, sni)
+            .instrument(span.clone())
+            .await?;
+
+        error_pages.apply(&mut response);
+
+        span.record("http.status_code", response.status().as_u16());
+        span.record("latency_ms", started.elapsed().as_millis() as u64);
+
+        let route = response
+            .extensions()
+            .get::<MatchedRoute>()
+            .map(|MatchedRoute(route)| route.as_str());
+
+        metrics().http_request(&config.name, route, response.status(), started.elapsed());
+
+        let served_by = response
+            .extensions()
+            .get::<ServedBy>()
+            .map(|ServedBy(backend)| backend.as_str());
+
+        if let (Some(connection), Some(backend)) = (&connection, served_by) {
+            connection.backend_selected(backend);
+        }
+
+        let latency = started.elapsed();
+
+        // Its own target, so operators can turn the access log down without the rest
+        tracing::info!(
+            target: "access_log",
+            server = %config.name,
+            %method,
+            path,
+            host = host.as_ref().map(tracing::field::display),
+            route,
+            backend = served_by,
+            status = response.status().as_u16(),
+            latency_us = latency.as_micros() as u64,
+            "Served request"
+        );
+
+        if let Some(log) = request_log() {
+            log.record(RequestRecord {
+                time: SystemTime::now(),
+                listener: config.name.clone(),
+                method,
+                path,
+                route: route.map(str::to_owned),
+                backend: served_by.map(str::to_owned),
+                status: response.status(),
+                latency,
+            });
+        }
+
+        // hyper closes the connection after a response with `Connection: close`
+        if version == Version::HTTP_10 && !config.http10.keep_alive {
+            response
+                .headers_mut()
+                .insert(header::CONNECTION, HeaderValue::from_static("close"));
+        }
+
+        Ok(response)
+    }
+
+    /// Host to route the request by, `None` when there's no way to tell.
+    ///
+    /// HTTP/2 clients send the host in `:authority`, which hyper puts into the URI, and usually
+    /// no `Host` header. The URI authority wins when both are present, the same way it does for
+    /// HTTP/1.1 requests in absolute form.
+    fn request_host<B>(req: &Request<B>, http10: &Http10Fields) -> Option<Hostname> {
+        let authority = match (req.uri().authority(), req.headers().get(header::HOST)) {
+            (Some(authority), _) => Some(authority.clone()),
+            (None, Some(host)) => Some(Authority::try_from(host.as_bytes()).ok()?),
+            (None, None) => None,
+        };
+
+        match authority {
+            Some(authority) => Hostname::from_str(authority.host()).ok(),
+            None if req.version() == Version::HTTP_10 => http10.default_host.clone(),
+            None => None,
+        }
+    }
+
+    fn set_route_header<B>(req: &mut Request<B>, header: &HeaderName, route_name: &str) {
+        match HeaderValue::from_str(route_name) {
+            Ok(value) => {
+                req.headers_mut().insert(header, value);
+            }
+            Err(_) => tracing::warn!(route = route_name, "Route name can't be sent in a header"),
+        }
+    }
+
+    // TODO: http2 backend and protocol support
+    async fn route_request(
+        mut req: Request<Incoming>,
+        routes: &[HttpRoute],
+        config: &HttpServerFields,
+        sni: Option<ClientSni>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Infallible> {
         // NOTE: Some considerations:
         //
@@ -82,48 +636,1325 @@ impl HttpServer {
         // different thread so it doesn't affect the main volume of traffic in any way, but that
         // might be complicated and actually less performant.
 
-        println!("{}", req.uri().path());
-        println!("{}", req.method());
+        tracing::debug!(method = %req.method(), path = req.uri().path(), "Routing request");
+
+        // Matchers read the server name from the extensions, same as the rest of the request
+        if let Some(sni) = sni {
+            req.extensions_mut().insert(sni);
+        }
+
+        // Redirects and rewritten locations point clients back at the scheme they came over
+        if config.tls.is_some() {
+            req.extensions_mut().insert(OverTls);
+        }
+
+        // HTTP/1 servers still get HTTP/2 from clients that send its preface
+        if config.version == Some(HttpVersion::V1) && req.version() == Version::HTTP_2 {
+            tracing::debug!("HTTP/2 request to an HTTP/1 server");
+
+            return Ok(http_version_not_supported());
+        }
 
-        let host_str = req.headers().get("host").unwrap().to_str().unwrap();
-        let host = Hostname::from_str(host_str).unwrap();
+        // hyper drops `Content-Length` when there's `Transfer-Encoding` too, the request was
+        // marked before that, see `LengthConflict`
+        if req.extensions().get::<Conflicting>().is_some() {
+            tracing::debug!("Request has both Transfer-Encoding and Content-Length");
 
+            return Ok(bad_request());
+        }
+
+        if let Some(allowed) = &config.allowed_methods {
+            if !allowed.iter().any(|method| method.matches(req.method())) {
+                tracing::debug!(method = %req.method(), "Method is not allowed");
+
+                return Ok(method_not_allowed(allowed));
+            }
+        }
+
+        // Tunnels go wherever the client asks (within the allowlist), not to a route
+        if connect::is_connect(&req) {
+            return Ok(connect::tunnel(req, config.allow_connect.as_deref(), &config.name).await);
+        }
+
+        if req.headers().get_all(header::HOST).iter().nth(1).is_some() {
+            if config.duplicate_host == DuplicateHost::Reject {
+                tracing::debug!("Request has more than one Host header");
+
+                return Ok(bad_request());
+            }
+
+            if let Some(first) = req.headers().get(header::HOST).cloned() {
+                req.headers_mut().insert(header::HOST, first);
+            }
+        }
+
+        let Some(host) = Self::request_host(&req, &config.http10) else {
+            tracing::debug!("Request has no host to route by");
+
+            return Ok(bad_request());
+        };
+
+        let matching = Instant::now();
         let route = routes.iter().find(|route| {
             route
                 .hostnames
                 .iter()
                 .any(|hostname| hostname.matches(&host))
         });
-
-        println!("Is there matching route: {:?}", route.is_some());
+        let route_lookup = matching.elapsed();
 
         if let Some(route) = route {
-            println!("The route has matched");
+            tracing::debug!(route = %route.name, "Route matched");
+
+            tracing::Span::current().record("http.route", &route.name);
+
+            let mut response = Self::send_to_route(req, route, config, route_lookup).await?;
+
+            response
+                .extensions_mut()
+                .insert(MatchedRoute(route.name.clone()));
+
+            Ok(response)
+        } else {
+            tracing::debug!(%host, "No route matched");
+
+            if config.match_metrics != MatchMetrics::Off {
+                metrics().route_match(&config.name, None, 0, route_lookup);
+            }
+
+            Ok(not_found())
+        }
+    }
+
+    /// `route_lookup` is how long finding the route took, it's part of the matching time
+    async fn send_to_route(
+        req: Request<Incoming>,
+        route: &HttpRoute,
+        config: &HttpServerFields,
+        route_lookup: Duration,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Infallible> {
+        let mut req = req.map(BodyExt::boxed);
+
+        if let Some(buffer) = &route.body_buffer {
+            req = match body_match::buffer(req, buffer.max_size).await {
+                Ok((req, true)) => req,
+                Ok(_) if buffer.when_too_large == WhenTooLarge::Reject => {
+                    tracing::debug!(route = %route.name, "Request body is over the buffer limit");
+
+                    return Ok(payload_too_large());
+                }
+                Ok((req, false)) => req,
+                Err(err) => {
+                    tracing::debug!(error = %err, "Failed to read the request body for matching");
+
+                    return Ok(bad_request());
+                }
+            };
+        }
+
+        let timed_on = (config.match_metrics == MatchMetrics::PerRule).then_some(&*config.name);
+        let matching = Instant::now();
+        let (mut matching_rule, mut evaluated) = route.count_matching_rule(&req, timed_on);
+
+        // Sent as `GET` to the rule that handles it, the body is dropped from the response
+        let synthesized_head =
+            matching_rule.is_none() && route.synthesize.head && req.method() == Method::HEAD;
+
+        if synthesized_head {
+            *req.method_mut() = Method::GET;
+
+            let (rule, evaluated_as_get) = route.count_matching_rule(&req, timed_on);
+            matching_rule = rule;
+            evaluated += evaluated_as_get;
+        }
+
+        if config.match_metrics != MatchMetrics::Off {
+            metrics().route_match(
+                &config.name,
+                Some(&route.name),
+                evaluated,
+                route_lookup + matching.elapsed(),
+            );
+        }
+
+        if matching_rule.is_none() && route.synthesize.options && req.method() == Method::OPTIONS {
+            let allowed = synthesize::allowed_methods(route, route.synthesize, &mut req);
+
+            if !allowed.is_empty() {
+                return Ok(synthesize::options(&allowed));
+            }
+        }
+
+        if let Some(rule) = matching_rule {
+            tracing::debug!(route = %route.name, rule = %rule.name, "Rule matched");
+
+            tracing::Span::current().record("http.rule", &rule.name);
+
+            if let Some(ConfiguredHeaderName(header)) = &config.route_header {
+                Self::set_route_header(&mut req, header, &route.name);
+            }
+
+            let cache_lookup = route.cache.as_ref().and_then(|cache| {
+                ResponseCache::key(&req).map(|key| (cache, key, req.headers().clone()))
+            });
+
+            // Held until the response is stored, so identical requests wait for it
+            let _flight = match &cache_lookup {
+                Some((cache, key, headers)) => match cache.lookup(key, headers).await {
+                    Lookup::Hit(response) if synthesized_head => {
+                        tracing::debug!(route = %route.name, "Serving a cached response to HEAD");
+
+                        return Ok(synthesize::head(response).await);
+                    }
+                    Lookup::Hit(response) => {
+                        tracing::debug!(route = %route.name, "Serving a cached response");
+
+                        return Ok(response);
+                    }
+                    Lookup::Miss(flight) => flight,
+                },
+                None => None,
+            };
+
+            let grpc_web = route
+                .grpc_web
+                .then(|| grpc_web::Encoding::of(&req))
+                .flatten();
+
+            if let Some(encoding) = grpc_web {
+                req = match grpc_web::translate_request(req, encoding).await {
+                    Ok(req) => req,
+                    Err(err) => {
+                        tracing::debug!(error = %err, "Failed to translate gRPC-Web request");
+
+                        return Ok(bad_request());
+                    }
+                };
+            }
+
+            let mut response = rule.send_request(req).await?;
+
+            if let Some(encoding) = grpc_web {
+                response = grpc_web::translate_response(response, encoding);
+            }
 
-            let matching_rule = route.find_matching_rule(&req);
+            if let Some((cache, key, headers)) = cache_lookup {
+                response = cache.store(key, &headers, response).await;
+            }
 
-            if let Some(rule) = matching_rule {
-                rule.send_request(req).await
-            } else {
-                Ok(not_found())
+            if synthesized_head {
+                response = synthesize::head(response).await;
             }
+
+            Ok(response)
         } else {
-            println!("The route didn't match");
-            Ok(Response::new(full("Not found")))
+            tracing::debug!(route = %route.name, "No rule matched");
+
+            Ok(not_found())
+        }
+    }
+}
+
+/// Next connection on any of the listeners
+async fn accept(listeners: &[TcpListener]) -> io::Result<(TcpStream, SocketAddr)> {
+    let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+
+    select_all(accepts).await.0
+}
+
+/// Whether serving failed because the client disconnected, which is routine and not worth
+/// more than a debug log
+fn is_client_gone(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(err);
+
+    while let Some(err) = source {
+        if err
+            .downcast_ref::<hyper::Error>()
+            .is_some_and(|err| err.is_incomplete_message() || err.is_canceled())
+        {
+            return true;
         }
+
+        if err.downcast_ref::<io::Error>().is_some_and(|err| {
+            matches!(
+                err.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+            )
+        }) {
+            return true;
+        }
+
+        source = err.source();
     }
+
+    false
 }
 
-fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
+pub(super) fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
     Full::new(chunk.into())
         .map_err(|never| match never {})
         .boxed()
 }
 
-fn not_found() -> Response<BoxBody<Bytes, hyper::Error>> {
+pub(super) fn not_found() -> Response<BoxBody<Bytes, hyper::Error>> {
+    generated(StatusCode::NOT_FOUND, "Not found")
+}
+
+pub(super) fn bad_request() -> Response<BoxBody<Bytes, hyper::Error>> {
+    generated(StatusCode::BAD_REQUEST, "Bad request")
+}
+
+fn http_version_not_supported() -> Response<BoxBody<Bytes, hyper::Error>> {
+    generated(
+        StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+        "HTTP version not supported",
+    )
+}
+
+pub(super) fn payload_too_large() -> Response<BoxBody<Bytes, hyper::Error>> {
+    generated(StatusCode::PAYLOAD_TOO_LARGE, "Payload too large")
+}
+
+pub(super) fn unsupported_media_type() -> Response<BoxBody<Bytes, hyper::Error>> {
+    generated(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported media type")
+}
+
+pub(super) fn bad_gateway() -> Response<BoxBody<Bytes, hyper::Error>> {
+    generated(StatusCode::BAD_GATEWAY, "Bad gateway")
+}
+
+pub(super) fn gateway_timeout() -> Response<BoxBody<Bytes, hyper::Error>> {
+    generated(StatusCode::GATEWAY_TIMEOUT, "Gateway timeout")
+}
+
+pub(super) fn service_unavailable() -> Response<BoxBody<Bytes, hyper::Error>> {
+    generated(StatusCode::SERVICE_UNAVAILABLE, "Service unavailable")
+}
+
+/// Lists the allowed methods in `Allow`, as required for `405`
+pub(super) fn method_not_allowed(
+    allowed: &[MethodMatch],
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut response = generated(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+
+    let allow = allowed
+        .iter()
+        .map(MethodMatch::stringify)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if let Ok(allow) = HeaderValue::from_str(&allow) {
+        response.headers_mut().insert(header::ALLOW, allow);
+    }
+
+    response
+}
+
+/// Plain text response of bifrost itself, the server swaps the body for a configured error page
+pub(super) fn generated(
+    status: StatusCode,
+    body: &'static str,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
     Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .body(full("Not found"))
+        .status(status)
+        .extension(Generated)
+        .body(full(body))
         // FIX: expect
         .expect("Failed to build response")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::host::HostSpec;
+    use crate::server::http::{path_index::PathIndex, route::HttpRule, service::HttpService};
+    use crate::test_helpers::{get, http_server, slow_backend};
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::oneshot,
+    };
+    use tracing_test::traced_test;
+
+    fn request(version: Version, host: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder().version(version);
+
+        if let Some(host) = host {
+            builder = builder.header("host", host);
+        }
+
+        builder.body(()).unwrap()
+    }
+
+    fn http10(default_host: Option<&str>) -> Http10Fields {
+        Http10Fields {
+            keep_alive: true,
+            default_host: default_host.map(|host| Hostname::from_str(host).unwrap()),
+        }
+    }
+
+    /// Backend that slowly answers every request with a cacheable response, counting them
+    async fn counting_backend(requests: Arc<AtomicUsize>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let requests = requests.clone();
+
+                let service = service_fn(move |_| {
+                    requests.fetch_add(1, Ordering::SeqCst);
+
+                    async {
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+
+                        let response = Response::builder()
+                            .header(header::CACHE_CONTROL, "max-age=60")
+                            .body(full("done"))
+                            .unwrap();
+
+                        Ok::<_, Infallible>(response)
+                    }
+                });
+
+                tokio::spawn(
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+
+        port
+    }
+
+    fn server(backend_port: u16, cache: Option<ResponseCache>) -> HttpServer {
+        http_server("test", 0, backend_port, cache)
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_request_in_flight_finish() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = server(slow_backend().await, None);
+        let serving = tokio::spawn(server.serve(vec![listener], async {
+            stopped.await.ok();
+        }));
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: test.com\r\n\r\n")
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stop.send(()).unwrap();
+
+        // The server closes the connection after the response instead of keeping it alive
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("connection: close"), "{}", response);
+        assert!(response.ends_with("done"), "{}", response);
+
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn http2_max_concurrent_streams_is_advertised() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let config =
+            serde_yaml::from_str("{ port: 0, name: test, http2: { max-concurrent-streams: 3 } }")
+                .unwrap();
+
+        tokio::spawn(
+            HttpServer::new(config, vec![], ErrorPages::default())
+                .serve(vec![listener], std::future::pending()),
+        );
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (client, connection) = h2::client::handshake(stream).await.unwrap();
+
+        tokio::spawn(connection);
+
+        let mut client = client.ready().await.unwrap();
+
+        // A response means the server settings have been received by then
+        let (response, _) = client
+            .send_request(Request::get("http://test.com/").body(()).unwrap(), true)
+            .unwrap();
+        response.await.unwrap();
+
+        assert_eq!(client.current_max_send_streams(), 3);
+    }
+
+    #[tokio::test]
+    async fn identical_cache_misses_reach_backend_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let requests = Arc::new(AtomicUsize::new(0));
+        let cache =
+            ResponseCache::new(serde_yaml::from_str("{ max-size: 1024, ttl: 1m }").unwrap());
+        let server = server(counting_backend(requests.clone()).await, Some(cache));
+
+        tokio::spawn(server.serve(vec![listener], std::future::pending()));
+
+        let responses = futures::future::join_all((0..5).map(|_| get(addr))).await;
+
+        for response in responses {
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+            assert!(response.ends_with("done"), "{}", response);
+        }
+
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn served_requests_are_logged() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let backend = counting_backend(Arc::default()).await;
+
+        tokio::spawn(
+            server(backend, None)
+                .serve(vec![listener], std::future::pending())
+                .in_current_span(),
+        );
+
+        let response = get(addr).await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+        logs_assert(|lines| {
+            let line = lines
+                .iter()
+                .find(|line| line.contains("Served request"))
+                .ok_or("no request was logged")?;
+
+            let backend = format!("backend=\"127.0.0.1:{}\"", backend);
+
+            for field in [
+                "access_log:",
+                "method=GET",
+                "path=\"/\"",
+                "host=test.com",
+                "route=\"test\"",
+                &backend,
+                "status=200",
+            ] {
+                if !line.contains(field) {
+                    return Err(format!("{} is missing from {}", field, line));
+                }
+            }
+
+            let latency = line
+                .split_once("latency_us=")
+                .and_then(|(_, rest)| rest.split(' ').next()?.parse::<u64>().ok())
+                .ok_or("latency is missing")?;
+
+            match latency {
+                0 => Err("latency is zero".to_owned()),
+                _ => Ok(()),
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn unknown_host_gets_error_page() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let config = serde_yaml::from_str("{ port: 0, name: test }").unwrap();
+        let pages = serde_yaml::from_str("404: { body: <h1>Nothing here</h1> }").unwrap();
+        let error_pages = ErrorPages::load(&pages, &Default::default()).unwrap();
+
+        tokio::spawn(
+            HttpServer::new(config, vec![], error_pages)
+                .serve(vec![listener], std::future::pending()),
+        );
+
+        let response = get(addr).await;
+
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("content-type: text/html; charset=utf-8"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("<h1>Nothing here</h1>"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn http2_requests_are_routed_by_authority() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = server(slow_backend().await, None);
+
+        tokio::spawn(server.serve(vec![listener], std::future::pending()));
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (client, connection) = h2::client::handshake(stream).await.unwrap();
+
+        tokio::spawn(connection);
+
+        let mut client = client.ready().await.unwrap();
+
+        // h2 sends the authority as `:authority` and no `Host` header
+        let (response, _) = client
+            .send_request(Request::get("http://test.com/").body(()).unwrap(), true)
+            .unwrap();
+
+        assert_eq!(response.await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn version_2_serves_prior_knowledge_only() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut server = server(slow_backend().await, None);
+        Arc::get_mut(&mut server.config).unwrap().version = Some(HttpVersion::V2);
+
+        tokio::spawn(server.serve(vec![listener], std::future::pending()));
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (client, connection) = h2::client::handshake(stream).await.unwrap();
+
+        tokio::spawn(connection);
+
+        let (response, _) = client
+            .ready()
+            .await
+            .unwrap()
+            .send_request(Request::get("http://test.com/").body(()).unwrap(), true)
+            .unwrap();
+
+        assert_eq!(response.await.unwrap().status(), StatusCode::OK);
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: test.com\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = vec![];
+        let _ = client.read_to_end(&mut response).await;
+
+        assert!(!response.starts_with(b"HTTP/1.1"));
+    }
+
+    #[tokio::test]
+    async fn version_1_turns_http2_away() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut server = server(slow_backend().await, None);
+        Arc::get_mut(&mut server.config).unwrap().version = Some(HttpVersion::V1);
+
+        tokio::spawn(server.serve(vec![listener], std::future::pending()));
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (client, connection) = h2::client::handshake(stream).await.unwrap();
+
+        tokio::spawn(connection);
+
+        let (response, _) = client
+            .ready()
+            .await
+            .unwrap()
+            .send_request(Request::get("http://test.com/").body(()).unwrap(), true)
+            .unwrap();
+
+        assert_eq!(
+            response.await.unwrap().status(),
+            StatusCode::HTTP_VERSION_NOT_SUPPORTED
+        );
+        assert!(get(addr).await.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    /// Backend that sends `response` as it is to every request, and passes the head of the
+    /// requests on
+    async fn raw_backend(
+        response: &'static str,
+    ) -> (u16, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, received) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let sender = sender.clone();
+
+                tokio::spawn(async move {
+                    let mut request = [0; 4096];
+                    let read = stream.read(&mut request).await.unwrap_or(0);
+
+                    if read > 0 {
+                        let _ = sender.send(String::from_utf8_lossy(&request[..read]).into_owned());
+                        let _ = stream.write_all(response.as_bytes()).await;
+                    }
+                });
+            }
+        });
+
+        (port, received)
+    }
+
+    #[tokio::test]
+    async fn empty_bodies_pass_through_intact() {
+        // Backends that get the framing of bodyless responses wrong are covered too, the client
+        // never gets a length for a body that can't be there
+        for (backend_response, status, content_length) in [
+            ("HTTP/1.1 204 No Content\r\n\r\n", "204 No Content", None),
+            (
+                "HTTP/1.1 204 No Content\r\ncontent-length: 3\r\n\r\n",
+                "204 No Content",
+                None,
+            ),
+            (
+                "HTTP/1.1 204 No Content\r\ntransfer-encoding: chunked\r\n\r\n",
+                "204 No Content",
+                None,
+            ),
+            (
+                "HTTP/1.1 304 Not Modified\r\netag: \"v1\"\r\n\r\n",
+                "304 Not Modified",
+                None,
+            ),
+            (
+                "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n",
+                "200 OK",
+                Some("0"),
+            ),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (port, mut received) = raw_backend(backend_response).await;
+
+            tokio::spawn(server(port, None).serve(vec![listener], std::future::pending()));
+
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(
+                    b"POST / HTTP/1.1\r\nHost: test.com\r\nContent-Length: 0\r\n\
+                    Connection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+
+            let request = received.recv().await.unwrap();
+
+            assert!(request.contains("content-length: 0\r\n"));
+            assert!(!request.contains("transfer-encoding"));
+
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+
+            assert!(head.starts_with(&format!("HTTP/1.1 {}\r\n", status)));
+            assert!(!head.contains("transfer-encoding"));
+            assert_eq!(
+                head.lines()
+                    .find_map(|line| line.strip_prefix("content-length: ")),
+                content_length
+            );
+            assert!(body.is_empty());
+        }
+    }
+
+    #[test]
+    fn authority_wins_over_host_header() {
+        let req = Request::get("http://authority.com:8443/")
+            .header("host", "header.com")
+            .body(())
+            .unwrap();
+
+        let host = HttpServer::request_host(&req, &http10(None));
+
+        assert!(HostSpec::from_str("authority.com")
+            .unwrap()
+            .matches(&host.unwrap()));
+    }
+
+    #[test]
+    fn host_header_port_is_ignored() {
+        let host = HttpServer::request_host(
+            &request(Version::HTTP_11, Some("test.com:8080")),
+            &http10(None),
+        );
+
+        assert!(HostSpec::from_str("test.com")
+            .unwrap()
+            .matches(&host.unwrap()));
+    }
+
+    #[test]
+    fn disconnects_are_told_apart() {
+        assert!(is_client_gone(&io::Error::from(
+            io::ErrorKind::ConnectionReset
+        )));
+        assert!(is_client_gone(&io::Error::from(io::ErrorKind::BrokenPipe)));
+        assert!(!is_client_gone(&io::Error::from(
+            io::ErrorKind::InvalidData
+        )));
+    }
+
+    #[tokio::test]
+    async fn methods_outside_allowlist_are_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let config =
+            serde_yaml::from_str("{ port: 0, name: test, allowed_methods: [GET, HEAD, purge] }")
+                .unwrap();
+
+        tokio::spawn(
+            HttpServer::new(config, vec![], ErrorPages::default())
+                .serve(vec![listener], std::future::pending()),
+        );
+
+        let send = |method: &'static str| async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(
+                    format!("{method} / HTTP/1.1\r\nHost: test.com\r\nConnection: close\r\n\r\n")
+                        .as_bytes(),
+                )
+                .await
+                .unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+
+            response
+        };
+
+        // Methods are case-sensitive, `purge` isn't `PURGE`
+        for method in ["TRACE", "PURGE", "get"] {
+            let response = send(method).await;
+
+            assert!(
+                response.starts_with("HTTP/1.1 405 Method Not Allowed"),
+                "{}",
+                response
+            );
+            assert!(response.contains("allow: GET, HEAD, purge"), "{}", response);
+        }
+
+        // Allowed methods get as far as routing
+        assert!(get(addr).await.starts_with("HTTP/1.1 404 Not Found"));
+        assert!(send("purge").await.starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[tokio::test]
+    async fn tls_is_terminated_with_configured_versions() {
+        use rustls::{
+            crypto::ring, version, ClientConfig, RootCertStore, SupportedProtocolVersion,
+        };
+
+        static TLS12: &[&SupportedProtocolVersion] = &[&version::TLS12];
+        static TLS13: &[&SupportedProtocolVersion] = &[&version::TLS13];
+        use rustls_pki_types::{pem::PemObject, CertificateDer, ServerName};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (cert, key) = crate::server::tls::tests::write_certificate();
+        let config = serde_yaml::from_str(&format!(
+            "{{ port: 0, name: test, tls: {{ cert: {}, key: {}, min-version: tls1.3 }} }}",
+            cert.display(),
+            key.display()
+        ))
+        .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(&cert).unwrap())
+            .unwrap();
+        std::fs::remove_dir_all(cert.parent().unwrap()).unwrap();
+
+        tokio::spawn(
+            HttpServer::new(config, vec![], ErrorPages::default())
+                .serve(vec![listener], std::future::pending()),
+        );
+
+        let connect = |versions| {
+            let client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_protocol_versions(versions)
+                .unwrap()
+                .with_root_certificates(roots.clone())
+                .with_no_client_auth();
+
+            async move {
+                let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+
+                tokio_rustls::TlsConnector::from(Arc::new(client))
+                    .connect(ServerName::try_from("localhost").unwrap(), stream)
+                    .await
+            }
+        };
+
+        assert!(connect(TLS12).await.is_err());
+
+        let mut client = connect(TLS13).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: test.com\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn server_fails_closed_while_backends_are_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let config =
+            serde_yaml::from_str("{ port: 0, name: test, fail_closed: { check_interval: 10ms } }")
+                .unwrap();
+        let service: HttpService =
+            serde_yaml::from_str("backends: [{ ip: 127.0.0.1, port: 3000 }]").unwrap();
+        let backends = service.backend_set().unwrap();
+        let readiness = Readiness::default();
+
+        tokio::spawn(
+            HttpServer::new(config, vec![], ErrorPages::default())
+                .failing_closed(vec![backends.clone()], readiness.clone())
+                .serve(vec![listener], std::future::pending()),
+        );
+
+        let listening = |expected: bool| async move {
+            tokio::time::timeout(Duration::from_secs(2), async {
+                while tokio::net::TcpStream::connect(addr).await.is_ok() != expected {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .is_ok()
+        };
+
+        assert!(listening(true).await);
+
+        backends.load().state(0).set_up(false);
+
+        assert!(listening(false).await);
+        assert!(!readiness.is_ready());
+
+        // Back in rotation, the server listens on the same address again
+        backends.load().state(0).set_up(true);
+
+        assert!(listening(true).await);
+        assert!(get(addr).await.starts_with("HTTP/1.1 404 Not Found"));
+        assert!(readiness.is_ready());
+    }
+
+    #[tokio::test]
+    async fn alpn_picks_the_protocol_of_tls_connections() {
+        use rustls::{ClientConfig, RootCertStore};
+        use rustls_pki_types::{pem::PemObject, CertificateDer, ServerName};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Both protocols are offered by default
+        let (cert, key) = crate::server::tls::tests::write_certificate();
+        let config = serde_yaml::from_str(&format!(
+            "{{ port: 0, name: test, tls: {{ cert: {}, key: {} }} }}",
+            cert.display(),
+            key.display()
+        ))
+        .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(&cert).unwrap())
+            .unwrap();
+        std::fs::remove_dir_all(cert.parent().unwrap()).unwrap();
+
+        tokio::spawn(
+            HttpServer::new(config, vec![], ErrorPages::default())
+                .serve(vec![listener], std::future::pending()),
+        );
+
+        let connect = |protocols: &[&[u8]]| {
+            let mut client = ClientConfig::builder()
+                .with_root_certificates(roots.clone())
+                .with_no_client_auth();
+            client.alpn_protocols = protocols.iter().map(|id| id.to_vec()).collect();
+
+            async move {
+                let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+
+                tokio_rustls::TlsConnector::from(Arc::new(client))
+                    .connect(ServerName::try_from("localhost").unwrap(), stream)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        for protocols in [&[b"http/1.1".as_slice()][..], &[]] {
+            let mut client = connect(protocols).await;
+            let negotiated = client.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+            assert_eq!(negotiated, protocols.first().map(|id| id.to_vec()));
+
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: test.com\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+
+            assert!(
+                response.starts_with("HTTP/1.1 404 Not Found"),
+                "{}",
+                response
+            );
+        }
+
+        let client = connect(&[b"h2", b"http/1.1"]).await;
+        assert_eq!(client.get_ref().1.alpn_protocol(), Some(b"h2".as_slice()));
+
+        let (mut sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(client))
+                .await
+                .unwrap();
+        tokio::spawn(conn);
+
+        let response = sender
+            .send_request(
+                Request::get("https://test.com/")
+                    .body(Full::new(Bytes::new()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.version(), Version::HTTP_2);
+    }
+
+    #[tokio::test]
+    async fn redirects_over_tls_keep_https() {
+        use rustls::{ClientConfig, RootCertStore};
+        use rustls_pki_types::{pem::PemObject, CertificateDer, ServerName};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (cert, key) = crate::server::tls::tests::write_certificate();
+        let config = serde_yaml::from_str(&format!(
+            "{{ port: 0, name: test, tls: {{ cert: {}, key: {} }} }}",
+            cert.display(),
+            key.display()
+        ))
+        .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(&cert).unwrap())
+            .unwrap();
+        std::fs::remove_dir_all(cert.parent().unwrap()).unwrap();
+
+        let service: HttpService =
+            serde_yaml::from_str("backends: [{ ip: 127.0.0.1, port: 1 }]").unwrap();
+        let rules = vec![HttpRule::new(
+            "test/0".to_owned(),
+            vec![],
+            "test-service".to_owned(),
+            Arc::new(service),
+            Default::default(),
+            None,
+            None,
+            vec![serde_yaml::from_str("{ type: RequestRedirect, hostname: example.com }").unwrap()],
+        )];
+        let route = HttpRoute {
+            name: "test".to_owned(),
+            hostnames: vec![HostSpec::from_str("test.com").unwrap()],
+            path_index: PathIndex::new(&rules),
+            rules,
+            cache: None,
+            grpc_web: false,
+            body_buffer: None,
+            synthesize: Default::default(),
+        };
+
+        tokio::spawn(
+            HttpServer::new(config, vec![route], ErrorPages::default())
+                .serve(vec![listener], std::future::pending()),
+        );
+
+        let client = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut client = tokio_rustls::TlsConnector::from(Arc::new(client))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+
+        // The request line has no scheme, it's the listener that says it's HTTPS
+        client
+            .write_all(b"GET /old HTTP/1.1\r\nHost: test.com\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 302 Found"), "{}", response);
+        assert!(
+            response
+                .to_ascii_lowercase()
+                .contains("\r\nlocation: https://example.com/old\r\n"),
+            "{}",
+            response
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicate_host_headers() {
+        async fn respond(duplicate_host: DuplicateHost) -> (String, Option<String>) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let (port, mut received) = raw_backend("HTTP/1.1 200 OK\r\n\r\n").await;
+
+            let mut server = server(port, None);
+            Arc::get_mut(&mut server.config).unwrap().duplicate_host = duplicate_host;
+
+            tokio::spawn(server.serve(vec![listener], std::future::pending()));
+
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(
+                    b"GET / HTTP/1.1\r\nHost: test.com\r\nHost: other.com\r\n\
+                      Connection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+
+            (response, received.try_recv().ok())
+        }
+
+        let (rejected, forwarded) = respond(DuplicateHost::Reject).await;
+        assert!(
+            rejected.starts_with("HTTP/1.1 400 Bad Request"),
+            "{}",
+            rejected
+        );
+        assert_eq!(forwarded, None);
+
+        let (routed, forwarded) = respond(DuplicateHost::UseFirst).await;
+        assert!(routed.starts_with("HTTP/1.1 200 OK"), "{}", routed);
+
+        let forwarded = forwarded.unwrap().to_ascii_lowercase();
+        assert!(forwarded.contains("host: test.com\r\n"), "{}", forwarded);
+        assert!(!forwarded.contains("other.com"), "{}", forwarded);
+    }
+
+    #[tokio::test]
+    async fn requests_without_hostname_are_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = server(slow_backend().await, None);
+
+        tokio::spawn(server.serve(vec![listener], std::future::pending()));
+
+        let respond = |request: &'static [u8]| async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client.write_all(request).await.unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+
+            response
+        };
+
+        for request in [
+            b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n".as_slice(),
+            b"GET / HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n",
+        ] {
+            let response = respond(request).await;
+
+            assert!(
+                response.starts_with("HTTP/1.1 400 Bad Request"),
+                "{}",
+                response
+            );
+        }
+
+        // The server keeps serving the ones that have a hostname
+        assert!(get(addr).await.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[tokio::test]
+    async fn transfer_encoding_with_content_length_is_rejected() {
+        let (port, mut received) =
+            raw_backend("HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").await;
+
+        let respond = |addr: SocketAddr, request: &'static [u8]| async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client.write_all(request).await.unwrap();
+
+            // The connection is closed after the response, the rest isn't read as a request
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+
+            response
+        };
+
+        let conflicting = [
+            b"POST / HTTP/1.1\r\nHost: test.com\r\nTransfer-Encoding: chunked\r\n\
+              Content-Length: 5\r\n\r\n0\r\n\r\nGET /smuggled HTTP/1.1\r\nHost: test.com\r\n\r\n"
+                .as_slice(),
+            b"POST / HTTP/1.1\r\nHost: test.com\r\nContent-Length: 5\r\n\
+              Transfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET /smuggled HTTP/1.1\r\nHost: test.com\r\n\r\n",
+            // Behind a request that's fine, on the same connection
+            b"GET / HTTP/1.1\r\nHost: test.com\r\n\r\n\
+              POST / HTTP/1.1\r\nHost: test.com\r\nContent-Length: 5\r\n\
+              Transfer-Encoding: chunked\r\n\r\n0\r\n\r\nGET /smuggled HTTP/1.1\r\nHost: test.com\r\n\r\n",
+        ];
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(server(port, None).serve(vec![listener], std::future::pending()));
+
+        for request in conflicting {
+            let response = respond(addr, request).await;
+
+            let last = &response[response.rfind("HTTP/1.1").unwrap()..];
+
+            assert!(last.starts_with("HTTP/1.1 400 Bad Request"), "{}", response);
+            assert!(!response.contains("/smuggled"), "{}", response);
+        }
+
+        // Only the request in front of the conflicting one was forwarded
+        let forwarded = received.recv().await.unwrap();
+
+        assert!(forwarded.starts_with("GET / HTTP/1.1"), "{}", forwarded);
+        assert!(!forwarded.contains("POST"), "{}", forwarded);
+        assert!(received.try_recv().is_err());
+
+        // Left to hyper, the body is framed by the chunks and the connection closed after it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut server = server(port, None);
+        Arc::get_mut(&mut server.config).unwrap().length_conflict =
+            LengthConflict::UseTransferEncoding;
+
+        tokio::spawn(server.serve(vec![listener], std::future::pending()));
+
+        for request in &conflicting[..2] {
+            let response = respond(addr, request).await;
+
+            assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+            assert_eq!(response.matches("HTTP/1.1").count(), 1, "{}", response);
+
+            let forwarded = received.recv().await.unwrap();
+
+            assert!(!forwarded.contains("content-length: 5"), "{}", forwarded);
+            assert!(!forwarded.contains("/smuggled"), "{}", forwarded);
+        }
+
+        // A body that isn't chunked last can't be framed at all
+        let response = respond(
+            addr,
+            b"POST / HTTP/1.1\r\nHost: test.com\r\nTransfer-Encoding: gzip\r\n\
+              Content-Length: 5\r\n\r\nhello",
+        )
+        .await;
+
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request"),
+            "{}",
+            response
+        );
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    fn route_header_overrides_client_value() {
+        let mut req = request(Version::HTTP_11, Some("test.com"));
+        let header = HeaderName::from_static("x-bifrost-route");
+
+        req.headers_mut()
+            .insert(&header, HeaderValue::from_static("spoofed"));
+
+        HttpServer::set_route_header(&mut req, &header, "api-route");
+
+        assert_eq!(req.headers().get_all(&header).iter().count(), 1);
+        assert_eq!(req.headers()[&header], "api-route");
+    }
+
+    #[test]
+    fn http10_without_host_uses_default() {
+        let host = HttpServer::request_host(
+            &request(Version::HTTP_10, None),
+            &http10(Some("default.com")),
+        );
+
+        assert!(HostSpec::from_str("default.com")
+            .unwrap()
+            .matches(&host.unwrap()));
+    }
+
+    #[test]
+    fn http10_without_host_and_default_is_rejected() {
+        let host = HttpServer::request_host(&request(Version::HTTP_10, None), &http10(None));
+
+        assert!(host.is_none());
+    }
+
+    #[test]
+    fn default_host_is_only_for_http10() {
+        let host = HttpServer::request_host(
+            &request(Version::HTTP_11, None),
+            &http10(Some("default.com")),
+        );
+
+        assert!(host.is_none());
+
+        let host = HttpServer::request_host(
+            &request(Version::HTTP_10, Some("explicit.com")),
+            &http10(Some("default.com")),
+        );
+
+        assert!(HostSpec::from_str("explicit.com")
+            .unwrap()
+            .matches(&host.unwrap()));
+    }
+}
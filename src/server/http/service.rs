@@ -1,13 +1,59 @@
 use bytes::Bytes;
+use futures::{stream::FuturesUnordered, StreamExt};
 use http_body_util::{combinators::BoxBody, BodyExt};
-use serde::{Deserialize, Serialize};
-use thiserror::Error;
-use tokio::net::TcpStream;
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
 
-use crate::service::config::BackendDefinition;
-use hyper::{body::Incoming, Request, Response};
-use hyper_util::rt::TokioIo;
-use std::convert::Infallible;
+use crate::{
+    metrics::metrics,
+    service::{
+        config::{BackendDefinition, BackendStream},
+        passive_health::{PassiveHealthCheck, PassiveHealthCheckConfig},
+        tls::BackendTls,
+        ConnectionError,
+    },
+    telemetry,
+};
+
+use super::{
+    backend_body::{BackendBody, PendingResponse},
+    backend_set::{BackendSet, BackendSnapshot, InFlightRequest},
+    body_match::{self, BufferedBody},
+    dns_refresh::DnsRefresher,
+    hash_ring::{self, HashRing},
+    headers::ConfiguredHeaderName,
+    health::{HealthCheckConfig, HealthChecker},
+    hedging::{self, Hedging},
+    mirror,
+    pool::{
+        ConnectionReaper, Http1Connection, Http2Connections, Http2Sender, HttpPool, HttpPoolConfig,
+    },
+    retry::RetryBudget,
+    server::{bad_gateway, bad_request, gateway_timeout, service_unavailable},
+    static_files::StaticFiles,
+    timeouts::{Timeouts, TimeoutsConfig},
+};
+use duration_string::DurationString;
+use http::{header, HeaderMap, HeaderValue, StatusCode, Uri};
+use hyper::{Request, Response};
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use rand::Rng;
+use std::{
+    convert::Infallible,
+    io,
+    net::IpAddr,
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+/// Response headers are limited to this many bytes unless a service sets its own limit
+const DEFAULT_MAX_RESPONSE_HEADER_SIZE: usize = 64 * 1024;
+
+/// hyper won't read HTTP/1 responses into a smaller buffer than this
+pub(crate) const MIN_RESPONSE_HEADER_SIZE: usize = 8 * 1024;
 
 #[derive(Deserialize, Serialize, Debug, Default)]
 #[serde(rename_all = "kebab-case")]
@@ -15,77 +61,2872 @@ pub(crate) enum LoadBalancingAlgorithm {
     #[default]
     RoundRobin,
     Random,
+    /// Random selection proportional to backend weights
+    WeightedRandom,
+    /// Rotation where every backend gets its weight's share of every window of picks,
+    /// spread out instead of in bursts
+    WeightedRoundRobin,
+    /// Requests with the same key go to the same backend, see `hash_header`. Retries walk the
+    /// ring, so a key fails over to the same backend every time.
+    ConsistentHash,
+    /// The backend with the fewest requests in flight, the first one of them on a tie. A request
+    /// is in flight until its response body ends.
+    LeastConnections,
+}
+
+/// HTTP version requests are sent to backends with
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum BackendProtocol {
+    #[default]
+    Http1,
+    /// HTTP/2 with prior knowledge, backends have to accept it without an upgrade
+    Http2,
+}
+
+/// What happens to the 1xx responses a backend sends before its final response, e.g.
+/// `103 Early Hints`.
+///
+/// NOTE: interim responses never reach clients. hyper's server can't write 1xx responses, only
+/// final ones, so they can't be relayed over either HTTP/1 or HTTP/2.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum InterimResponses {
+    #[default]
+    Drop,
+    /// Add the `Link` headers of early hints to the final response. Clients preload from those
+    /// as well, just later. Only HTTP/1 backends report interim responses.
+    MergeLinks,
+}
+
+/// Walker's alias table, allows picking a weighted index in O(1).
+///
+/// Every slot holds a probability of keeping its own index and an alias to jump to otherwise,
+/// so a sample is a single uniform slot pick and a single biased coin flip.
+#[derive(Debug)]
+struct AliasTable {
+    probabilities: Vec<f64>,
+    aliases: Vec<usize>,
+}
+
+impl AliasTable {
+    fn new(weights: &[u32]) -> Self {
+        let len = weights.len();
+        let total: f64 = weights.iter().map(|&weight| weight as f64).sum();
+
+        // All zero weights means there's no preference at all
+        let mut scaled: Vec<f64> = if total == 0.0 {
+            vec![1.0; len]
+        } else {
+            weights
+                .iter()
+                .map(|&weight| weight as f64 * len as f64 / total)
+                .collect()
+        };
+
+        let mut probabilities = vec![1.0; len];
+        let mut aliases: Vec<usize> = (0..len).collect();
+
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) =
+            (0..len).partition(|&index| scaled[index] < 1.0);
+
+        while let (Some(less), Some(more)) = (small.pop(), large.pop()) {
+            probabilities[less] = scaled[less];
+            aliases[less] = more;
+
+            scaled[more] -= 1.0 - scaled[less];
+
+            if scaled[more] < 1.0 {
+                small.push(more);
+            } else {
+                large.push(more);
+            }
+        }
+
+        // Whatever is left is 1.0 give or take floating point errors, so these slots keep their index
+
+        Self {
+            probabilities,
+            aliases,
+        }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> Option<usize> {
+        if self.probabilities.is_empty() {
+            return None;
+        }
+
+        let index = rng.gen_range(0..self.probabilities.len());
+
+        if rng.gen::<f64>() < self.probabilities[index] {
+            Some(index)
+        } else {
+            Some(self.aliases[index])
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 struct LoadBalancer {
     #[serde(default)]
-    current_connection_index: usize,
+    current_connection_index: AtomicUsize,
     #[serde(default, rename = "load_balancing_algorithm")]
     algo: LoadBalancingAlgorithm,
-    backends: Vec<BackendDefinition>,
+    #[serde(flatten)]
+    backends: BackendSet,
+    /// Only locked while a backend is picked or an outcome is recorded, never while a request
+    /// is in flight, so requests to the service don't wait on each other
+    #[serde(skip)]
+    state: Mutex<LoadBalancerState>,
+    /// Header consistent hashing keys requests by, the path is used when not set or when
+    /// a request doesn't have it
+    hash_header: Option<ConfiguredHeaderName>,
+    /// Ejects backends that fail requests, works along with active health checks
+    passive_health_check: Option<PassiveHealthCheckConfig>,
+    /// A connection is opened for every request when not set
+    #[serde(rename = "connection-pool")]
+    connection_pool: Option<HttpPool>,
+    /// TLS for the backends that don't set their own, the certificate of the backend is
+    /// verified against the web PKI roots unless a CA bundle is given
+    tls: Option<BackendTls>,
+    /// Kept here as HTTP/2 is offered to TLS backends with ALPN when connecting
+    #[serde(default)]
+    protocol: BackendProtocol,
+    /// Connections requests to HTTP/2 backends share
+    #[serde(skip)]
+    http2_connections: Http2Connections,
+}
+
+/// What the load balancer keeps track of between picks
+#[derive(Debug, Default)]
+struct LoadBalancerState {
+    /// Backends the state below is kept by index for, it's moved over once they're replaced
+    synced: Option<Arc<BackendSnapshot>>,
+    /// Built on the first weighted pick for the group it's built from, rebuilt when a canary
+    /// changes the weights, traffic moves to another group or the backends are replaced
+    alias_table: Option<(Range<usize>, AliasTable)>,
+    /// Weights from the config, kept once a canary starts changing them
+    configured_weights: Option<Vec<u32>>,
+    /// Running weights of smooth weighted round robin, one per backend once it starts
+    current_weights: Vec<i64>,
+    /// Built on the first consistent hash pick for the group it's built from, rebuilt once the
+    /// backends are replaced
+    hash_ring: Option<(Range<usize>, HashRing)>,
+    /// Created on the first outcome
+    passive_health: Option<PassiveHealthCheck>,
+}
+
+impl LoadBalancerState {
+    /// Current backends, the state kept by index is moved over to them when they've been
+    /// replaced since the last time
+    fn sync(&mut self, backends: &BackendSet) -> Arc<BackendSnapshot> {
+        let current = backends.load();
+
+        if let Some(synced) = &self.synced {
+            if Arc::ptr_eq(synced, &current) {
+                return current;
+            }
+
+            let previous = current.previous_indexes(synced);
+
+            if !self.current_weights.is_empty() {
+                self.current_weights = previous
+                    .iter()
+                    .map(|index| index.map_or(0, |index| self.current_weights[index]))
+                    .collect();
+            }
+
+            if let Some(configured) = &mut self.configured_weights {
+                *configured = previous
+                    .iter()
+                    .zip(current.iter())
+                    .map(|(index, backend)| {
+                        index.map_or(backend.weight(), |index| configured[index])
+                    })
+                    .collect();
+            }
+
+            if let Some(passive_health) = &mut self.passive_health {
+                passive_health.carry_over(&previous);
+            }
+
+            self.alias_table = None;
+            self.hash_ring = None;
+        }
+
+        self.synced = Some(current.clone());
+
+        current
+    }
+
+    fn is_up(&self, backends: &BackendSnapshot, index: usize) -> bool {
+        let ejected = self
+            .passive_health
+            .as_ref()
+            .is_some_and(|passive_health| passive_health.is_ejected(index));
+
+        !ejected && backends.state(index).is_up()
+    }
+
+    /// Backends traffic goes to, the group with the highest priority that has any backend in
+    /// rotation. `None` when every backend is down.
+    fn active_tier(&self, backends: &BackendSnapshot) -> Option<Range<usize>> {
+        backends
+            .tiers()
+            .iter()
+            .find(|&tier| tier.clone().any(|index| self.is_up(backends, index)))
+            .cloned()
+    }
+
+    /// `picked` when it's in rotation, otherwise the next backend of `tier` in the config that is
+    fn healthy_backend_index(
+        &self,
+        backends: &BackendSnapshot,
+        picked: usize,
+        tier: Range<usize>,
+    ) -> Option<usize> {
+        (0..tier.len())
+            .map(|offset| tier.start + (picked - tier.start + offset) % tier.len())
+            .find(|&index| self.is_up(backends, index))
+    }
+}
+
+/// Connection to a backend handed out by the load balancer
+struct BackendConnection {
+    /// `host:port` of the backend
+    address: String,
+    link: BackendLink,
+    in_flight: InFlightRequest,
 }
 
-#[derive(Debug, Error)]
-pub(crate) enum ConnectionError {
-    #[error("backend not found (that is usually our fault and should never happen)")]
-    BackendNotFound,
-    #[error("IO error occured: {0}")]
-    IoError(std::io::Error),
+enum BackendLink {
+    /// Just connected, nothing has been sent over it yet
+    Connected(BackendStream),
+    /// Idle HTTP/1 connection from the pool that's ready for the next request
+    Pooled(Http1Connection),
+    /// HTTP/2 connection other requests to the backend go over too
+    Shared(Http2Sender),
 }
 
 impl LoadBalancer {
-    async fn get_connection(&mut self) -> Result<TcpStream, ConnectionError> {
-        // TODO: load balancing
-        // e.g. give connections to different backends according
-        // to specified load balancing algo
-        let backend = self
-            .backends
-            .get(self.current_connection_index)
+    /// Key of the request for consistent hashing, `None` for other algorithms
+    fn hash_key<B>(&self, req: &Request<B>) -> Option<u64> {
+        if !matches!(self.algo, LoadBalancingAlgorithm::ConsistentHash) {
+            return None;
+        }
+
+        let header = self
+            .hash_header
+            .as_ref()
+            .and_then(|ConfiguredHeaderName(header)| req.headers().get(header));
+
+        Some(match header {
+            Some(value) => hash_ring::hash(value.as_bytes()),
+            None => hash_ring::hash(req.uri().path()),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, LoadBalancerState> {
+        self.state.lock().expect("Load balancer lock poisoned")
+    }
+
+    /// Whether a request to the backend at `address` succeeded, for passive health checks
+    fn record_outcome(&self, address: &str, succeeded: bool) {
+        let mut state = self.state();
+        let backends = state.sync(&self.backends);
+
+        let Some(config) = &self.passive_health_check else {
+            return;
+        };
+
+        // Taken out of the backends while the request was in flight
+        let Some(index) = backends
+            .iter()
+            .position(|backend| backend.address() == address)
+        else {
+            return;
+        };
+
+        let passive_health = state
+            .passive_health
+            .get_or_insert_with(|| PassiveHealthCheck::new(config.clone(), backends.len()));
+
+        if let Some(ejection_time) = passive_health.record(index, succeeded) {
+            tracing::warn!(
+                backend = address,
+                failures = config.max_failures,
+                ejection_time = ?ejection_time,
+                "Backend failed too many requests in a row, ejecting it"
+            );
+        }
+    }
+
+    /// Picks from the backends of `tier`, `attempt` counts connection retries of the same request
+    fn next_backend_index(
+        &self,
+        state: &mut LoadBalancerState,
+        all: &BackendSnapshot,
+        key: Option<u64>,
+        attempt: u32,
+        tier: Range<usize>,
+    ) -> Option<usize> {
+        let backends = &all[tier.clone()];
+
+        if backends.is_empty() {
+            return None;
+        }
+
+        match self.algo {
+            LoadBalancingAlgorithm::ConsistentHash => {
+                if state.hash_ring.as_ref().map(|(built, _)| built) != Some(&tier) {
+                    let addresses: Vec<String> =
+                        backends.iter().map(BackendDefinition::address).collect();
+                    let ring = HashRing::new(addresses.iter().map(String::as_str));
+
+                    state.hash_ring = Some((tier.clone(), ring));
+                }
+
+                let (_, ring) = state.hash_ring.as_ref()?;
+
+                // Past the last backend retries start over from the key's own one
+                let key = key.unwrap_or_default();
+
+                ring.walk(key)
+                    .nth(attempt as usize % tier.len())
+                    .map(|index| tier.start + index)
+            }
+            LoadBalancingAlgorithm::WeightedRandom => {
+                if state.alias_table.as_ref().map(|(built, _)| built) != Some(&tier) {
+                    let weights: Vec<u32> =
+                        backends.iter().map(BackendDefinition::weight).collect();
+
+                    state.alias_table = Some((tier.clone(), AliasTable::new(&weights)));
+                }
+
+                let (_, table) = state.alias_table.as_ref()?;
+
+                table
+                    .sample(&mut rand::thread_rng())
+                    .map(|index| tier.start + index)
+            }
+            LoadBalancingAlgorithm::WeightedRoundRobin => {
+                // Smooth weighted round robin, as in nginx: every backend gains its weight, the
+                // one that's furthest ahead is picked and set back by the total
+                let current_weights = &mut state.current_weights;
+
+                if current_weights.len() != all.len() {
+                    *current_weights = vec![0; all.len()];
+                }
+
+                let mut total = 0;
+                let mut picked = tier.start;
+
+                for index in tier {
+                    let weight = all[index].weight() as i64;
+
+                    current_weights[index] += weight;
+                    total += weight;
+
+                    if current_weights[index] > current_weights[picked] {
+                        picked = index;
+                    }
+                }
+
+                current_weights[picked] -= total;
+
+                Some(picked)
+            }
+            LoadBalancingAlgorithm::LeastConnections => {
+                let mut indexes: Vec<(usize, usize)> = tier
+                    .clone()
+                    .map(|index| (all.state(index).in_flight(), index))
+                    .collect();
+
+                // A failed backend doesn't get a request, so retries move on to the next least
+                // loaded one instead of picking it again
+                indexes.sort_unstable();
+
+                indexes
+                    .get(attempt as usize % tier.len())
+                    .map(|&(_, index)| index)
+            }
+            LoadBalancingAlgorithm::Random => Some(rand::thread_rng().gen_range(tier)),
+            LoadBalancingAlgorithm::RoundRobin => {
+                // Wraps around on overflow, same as `wrapping_add`
+                let next = self
+                    .current_connection_index
+                    .fetch_add(1, Ordering::Relaxed);
+
+                Some(tier.start + next % tier.len())
+            }
+        }
+    }
+
+    /// Connects to the next backend, the request is counted as in flight to it from then on
+    async fn get_connection(
+        &self,
+        service: &str,
+        connect_timeout: Duration,
+        key: Option<u64>,
+        attempt: u32,
+        source: Option<IpAddr>,
+    ) -> Result<BackendConnection, ConnectionError> {
+        let (backends, index) = self.pick(key, attempt)?;
+        let backend = backends
+            .get(index)
             .ok_or(ConnectionError::BackendNotFound)?;
 
-        println!("{}", backend.port);
+        let address = backend.address();
 
-        let connection = backend
-            .get_connection()
-            .await
-            .map_err(ConnectionError::IoError);
+        tracing::Span::current().record("backend", &address);
+
+        if let Some(sender) = self.http2_connections.get(&address) {
+            return Ok(BackendConnection {
+                address,
+                link: BackendLink::Shared(sender),
+                in_flight: InFlightRequest::new(backends.state(index).clone()),
+            });
+        }
+
+        if let Some(connection) = self.reuse(&address, connect_timeout).await {
+            return Ok(BackendConnection {
+                address,
+                link: BackendLink::Pooled(connection),
+                in_flight: InFlightRequest::new(backends.state(index).clone()),
+            });
+        }
+
+        let result = tokio::time::timeout(
+            connect_timeout,
+            backend.get_connection(
+                source,
+                self.tls.as_ref(),
+                self.protocol == BackendProtocol::Http2,
+            ),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out connecting to backend",
+            ))
+        });
+
+        metrics().backend_connection(service, &address, result.is_ok());
 
-        self.current_connection_index = (self.current_connection_index + 1) % self.backends.len();
+        let stream = match result {
+            Ok(stream) => stream,
+            Err(err) => {
+                self.record_outcome(&address, false);
+
+                return Err(ConnectionError::IoError(err));
+            }
+        };
 
-        connection
+        Ok(BackendConnection {
+            address,
+            link: BackendLink::Connected(stream),
+            in_flight: InFlightRequest::new(backends.state(index).clone()),
+        })
     }
-}
 
-#[derive(Deserialize, Serialize, Debug)]
-#[serde(rename_all = "kebab-case")]
-pub(crate) struct HttpService {
-    #[serde(flatten)]
-    load_balancer: LoadBalancer,
+    /// Backends along with the index of the one the next request goes to
+    fn pick(
+        &self,
+        key: Option<u64>,
+        attempt: u32,
+    ) -> Result<(Arc<BackendSnapshot>, usize), ConnectionError> {
+        let mut state = self.state();
+        let backends = state.sync(&self.backends);
+
+        if backends.is_empty() {
+            return Err(ConnectionError::NoBackends);
+        }
+
+        let tier = state
+            .active_tier(&backends)
+            .ok_or(ConnectionError::BackendNotFound)?;
+        let picked = self
+            .next_backend_index(&mut state, &backends, key, attempt, tier.clone())
+            .ok_or(ConnectionError::NoBackends)?;
+        let index = state
+            .healthy_backend_index(&backends, picked, tier)
+            .ok_or(ConnectionError::BackendNotFound)?;
+
+        Ok((backends, index))
+    }
+
+    /// Idle connection to the backend at `address` from the pool, the ones that turn out
+    /// closed or don't get ready within `timeout` are dropped
+    async fn reuse(&self, address: &str, timeout: Duration) -> Option<Http1Connection> {
+        let pool = self.connection_pool.as_ref()?;
+
+        while let Some(mut connection) = pool.take(address) {
+            // A connection is only ready once the previous response is through
+            if let Ok(Ok(())) = tokio::time::timeout(timeout, connection.sender.ready()).await {
+                return Some(connection);
+            }
+        }
+
+        None
+    }
+
+    /// Gives the `canary` backend `share` percent of the traffic, the rest is split between the
+    /// other backends by their configured weights. Returns `false` when there's no such backend.
+    fn split_traffic(&self, canary: &str, share: u32) -> bool {
+        let mut state = self.state();
+        let backends = state.sync(&self.backends);
+
+        let Some(canary_index) = backends
+            .iter()
+            .position(|backend| backend.address() == canary)
+        else {
+            return false;
+        };
+
+        let configured = state
+            .configured_weights
+            .get_or_insert_with(|| backends.iter().map(BackendDefinition::weight).collect());
+
+        let stable_total: u32 = configured
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != canary_index)
+            .map(|(_, weight)| weight)
+            .sum();
+
+        let weights: Vec<(String, u32)> = backends
+            .iter()
+            .enumerate()
+            .map(|(index, backend)| {
+                let weight = if index == canary_index {
+                    share * stable_total
+                } else {
+                    configured[index] * (100 - share)
+                };
+
+                (backend.address(), weight)
+            })
+            .collect();
+
+        // Backends replaced in the meantime keep the weights they come with
+        self.backends.update(|backends| {
+            let mut backends = backends.clone();
+
+            for backend in backends.iter_mut() {
+                if let Some((_, weight)) = weights
+                    .iter()
+                    .find(|(address, _)| *address == backend.address())
+                {
+                    backend.weight = Some(*weight);
+                }
+            }
+
+            backends
+        });
+
+        // Rebuilt with the new weights on the next pick
+        state.alias_table = None;
+        state.current_weights.clear();
+
+        true
+    }
 }
 
-impl HttpService {
-    pub(super) async fn send_request(
-        &mut self,
-        req: Request<Incoming>,
-    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Infallible> {
-        use hyper::client::conn::http1;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{server::http::backend_groups::Backends, test_helpers::closed_port};
+    use hyper::body::Incoming;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::{mpsc, oneshot},
+    };
+
+    fn distribution(weights: &[u32], iterations: usize) -> Vec<f64> {
+        let table = AliasTable::new(weights);
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut counts = vec![0; weights.len()];
+
+        for _ in 0..iterations {
+            counts[table.sample(&mut rng).unwrap()] += 1;
+        }
+
+        counts
+            .into_iter()
+            .map(|count| count as f64 / iterations as f64)
+            .collect()
+    }
+
+    #[test]
+    fn alias_table_follows_weights() {
+        let weights = [1, 2, 7, 0, 10];
+        let total: u32 = weights.iter().sum();
+
+        let shares = distribution(&weights, 200_000);
+
+        for (weight, share) in weights.iter().zip(shares) {
+            let expected = *weight as f64 / total as f64;
+
+            assert!(
+                (share - expected).abs() < 0.01,
+                "expected share {expected}, got {share}"
+            );
+        }
+    }
+
+    #[test]
+    fn alias_table_zero_weights_are_uniform() {
+        let shares = distribution(&[0, 0, 0, 0], 100_000);
+
+        for share in shares {
+            assert!((share - 0.25).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn service_errors_say_what_is_wrong() {
+        let err = serde_yaml::from_str::<HttpService>(
+            "{ backends: [], load_balancing_algorithm: fastest }",
+        )
+        .unwrap_err();
+
+        assert!(
+            err.to_string().contains("unknown variant `fastest`"),
+            "{}",
+            err
+        );
+
+        let err = serde_yaml::from_str::<HttpService>("{ type: static }").unwrap_err();
+
+        assert!(err.to_string().contains("missing field `root`"), "{}", err);
+
+        let service = serde_yaml::from_str("{ type: static, root: /var/www }").unwrap();
+
+        assert!(matches!(service, HttpService::Static(_)));
+    }
+
+    #[tokio::test]
+    async fn load_balancer_without_backends() {
+        for algo in [
+            "round-robin",
+            "random",
+            "weighted-random",
+            "weighted-round-robin",
+            "consistent-hash",
+            "least-connections",
+        ] {
+            let load_balancer: LoadBalancer = serde_yaml::from_str(&format!(
+                "{{ backends: [], load_balancing_algorithm: {} }}",
+                algo
+            ))
+            .unwrap();
+
+            assert!(matches!(
+                load_balancer
+                    .get_connection("test", Duration::from_secs(1), None, 0, None)
+                    .await,
+                Err(ConnectionError::NoBackends)
+            ));
+        }
+    }
 
-        // FIX: unwrap
-        let stream = self.load_balancer.get_connection().await.unwrap();
+    async fn service_with_unreachable_backend(retries: &str) -> (ProxyService, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
 
-        let io = TokioIo::new(stream);
+        let service = serde_yaml::from_str(&format!(
+            "
+            backends:
+            - {{ ip: 127.0.0.1, port: {} }}
+            - {{ ip: 127.0.0.1, port: {} }}
+            {}
+            ",
+            closed_port().await,
+            listener.local_addr().unwrap().port(),
+            retries,
+        ))
+        .unwrap();
 
-        let (mut sender, conn) = http1::Builder::new().handshake(io).await.unwrap();
+        (service, listener)
+    }
+
+    #[tokio::test]
+    async fn connection_is_retried_on_next_backend() {
+        let (service, _listener) =
+            service_with_unreachable_backend("retries: { attempts: 1 }").await;
+
+        assert!(service
+            .connect("test", Duration::from_secs(1), None)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn connection_is_not_retried_by_default() {
+        let (service, _listener) = service_with_unreachable_backend("").await;
+
+        assert!(matches!(
+            service.connect("test", Duration::from_secs(1), None).await,
+            Err(ConnectionError::IoError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unreachable_backend_is_bad_gateway() {
+        let service: ProxyService = serde_yaml::from_str(&format!(
+            "backends: [{{ ip: 127.0.0.1, port: {} }}]",
+            closed_port().await
+        ))
+        .unwrap();
+
+        let response = service
+            .send_request("test", get_request(), Timeouts::default())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    /// Backend that sends early hints before every response
+    async fn hinting_backend() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
 
         tokio::spawn(async move {
-            if let Err(err) = conn.await {
-                println!("Connection failed: {:?}", err);
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let mut request = [0; 1024];
+
+                    if stream.read(&mut request).await.unwrap_or(0) > 0 {
+                        let _ = stream
+                            .write_all(
+                                b"HTTP/1.1 103 Early Hints\r\n\
+                                link: </style.css>; rel=preload\r\n\
+                                link: </app.js>; rel=preload\r\n\r\n\
+                                HTTP/1.1 200 OK\r\n\
+                                link: </app.js>; rel=preload\r\n\
+                                content-length: 0\r\n\r\n",
+                            )
+                            .await;
+                    }
+                });
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn early_hints_links_are_merged_into_the_final_response() {
+        let port = hinting_backend().await;
+
+        let links = |interim_responses: &str| {
+            let service: ProxyService = serde_yaml::from_str(&format!(
+                "{{ backends: [{{ ip: 127.0.0.1, port: {} }}], interim-responses: {} }}",
+                port, interim_responses
+            ))
+            .unwrap();
+
+            async move {
+                let response = service
+                    .send_request("test", get_request(), Timeouts::default())
+                    .await
+                    .unwrap();
+
+                assert_eq!(response.status(), StatusCode::OK);
+
+                response
+                    .headers()
+                    .get_all(header::LINK)
+                    .iter()
+                    .map(|link| link.to_str().unwrap().to_owned())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(links("drop").await, ["</app.js>; rel=preload"]);
+
+        // Links the response repeats aren't doubled
+        assert_eq!(
+            links("merge-links").await,
+            ["</app.js>; rel=preload", "</style.css>; rel=preload"]
+        );
+    }
+
+    #[tokio::test]
+    async fn failure_passes_through_when_budget_is_exhausted() {
+        let (service, _listener) = service_with_unreachable_backend(
+            "retries: { attempts: 1, budget-ratio: 0.0, budget-burst: 1 }",
+        )
+        .await;
+
+        // The saved up retry makes the first request through
+        assert!(service
+            .connect("test", Duration::from_secs(1), None)
+            .await
+            .is_ok());
+
+        // Round robin is back at the unreachable backend, with nothing left in the budget
+        assert!(matches!(
+            service.connect("test", Duration::from_secs(1), None).await,
+            Err(ConnectionError::IoError(_))
+        ));
+    }
+
+    fn h2_service(port: u16, keepalive_interval: &str, keepalive_timeout: &str) -> ProxyService {
+        serde_yaml::from_str(&format!(
+            "
+            backends: [{{ ip: 127.0.0.1, port: {} }}]
+            protocol: http2
+            h2-keepalive-interval: {}
+            h2-keepalive-timeout: {}
+            ",
+            port, keepalive_interval, keepalive_timeout,
+        ))
+        .unwrap()
+    }
+
+    fn get_request() -> Request<BoxBody<Bytes, hyper::Error>> {
+        Request::get("/hello")
+            .header(header::HOST, "test.com")
+            .body(
+                http_body_util::Empty::new()
+                    .map_err(|never| match never {})
+                    .boxed(),
+            )
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_are_sent_over_http2() {
+        use hyper::{server::conn::http2, service::service_fn};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            let service = service_fn(|req: Request<Incoming>| async move {
+                let seen = format!("{:?} {}", req.version(), req.uri());
+
+                Ok::<_, Infallible>(Response::new(http_body_util::Full::new(Bytes::from(seen))))
+            });
+
+            http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+                .unwrap();
+        });
+
+        let service = h2_service(port, "10s", "20s");
+        let response = service
+            .send_request("test", get_request(), Timeouts::default())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        assert_eq!(body, "HTTP/2.0 http://test.com/hello");
+    }
+
+    #[tokio::test]
+    async fn requests_share_an_http2_connection() {
+        use hyper::{server::conn::http2, service::service_fn};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Answers with the number of the connection the request came over
+        tokio::spawn(async move {
+            for connection in 1.. {
+                let (stream, _) = listener.accept().await.unwrap();
+
+                let service = service_fn(move |_| async move {
+                    Ok::<_, Infallible>(Response::new(http_body_util::Full::new(Bytes::from(
+                        connection.to_string(),
+                    ))))
+                });
+
+                tokio::spawn(
+                    http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service),
+                );
             }
         });
 
-        let res = sender.send_request(req).await.unwrap();
+        let service = h2_service(port, "10s", "20s");
+        let send = || service.send_request("test", get_request(), Timeouts::default());
+
+        // Dropping a response only resets its stream
+        drop(send().await.unwrap());
+
+        let (first, second) = tokio::join!(send(), send());
+
+        for response in [first.unwrap(), second.unwrap(), send().await.unwrap()] {
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+
+            assert_eq!(body, "1");
+        }
+    }
+
+    #[tokio::test]
+    async fn keepalive_detects_dead_backend() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Accepts connections and never answers anything, pings included
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+
+            std::future::pending::<()>().await;
+        });
+
+        let service = h2_service(port, "100ms", "100ms");
+
+        let response = tokio::time::timeout(
+            Duration::from_secs(5),
+            service.send_request("test", get_request(), Timeouts::default()),
+        )
+        .await
+        .expect("Dead backend wasn't detected")
+        .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn upstream_response_time_is_reported() {
+        use hyper::{server::conn::http1, service::service_fn};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            let service = service_fn(|_| async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+
+                Ok::<_, Infallible>(Response::new(http_body_util::Empty::<Bytes>::new()))
+            });
+
+            http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+                .unwrap();
+        });
+
+        let service: ProxyService = serde_yaml::from_str(&format!(
+            "
+            backends: [{{ ip: 127.0.0.1, port: {} }}]
+            upstream-response-time-header: x-upstream-response-time
+            ",
+            port
+        ))
+        .unwrap();
+
+        let response = service
+            .send_request("test", get_request(), Timeouts::default())
+            .await
+            .unwrap();
+
+        let millis: u64 = response.headers()["x-upstream-response-time"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        assert!(millis >= 50, "{}", millis);
+    }
+
+    #[tokio::test]
+    async fn slow_backend_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+
+            std::future::pending::<()>().await;
+        });
+
+        let service: ProxyService =
+            serde_yaml::from_str(&format!("backends: [{{ ip: 127.0.0.1, port: {} }}]", port))
+                .unwrap();
+
+        let timeouts = Timeouts {
+            connect: Duration::from_secs(1),
+            request: Some(Duration::from_millis(100)),
+        };
+
+        let response = service
+            .send_request("test", get_request(), timeouts)
+            .await
+            .unwrap();
 
-        Ok(res.map(|res| res.boxed()))
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
     }
+
+    #[tokio::test]
+    async fn timeout_closes_backend_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let connects = Arc::new(AtomicUsize::new(0));
+        let (closed, backend_closed) = oneshot::channel();
+
+        // Answers the first request late, on a connection that's kept alive, and the others
+        // right away
+        tokio::spawn({
+            let connects = connects.clone();
+
+            async move {
+                let mut closed = Some(closed);
+
+                loop {
+                    let (mut stream, _) = listener.accept().await.unwrap();
+                    let first = connects.fetch_add(1, Ordering::SeqCst) == 0;
+                    let closed = closed.take();
+
+                    tokio::spawn(async move {
+                        let mut request = [0; 1024];
+
+                        while stream.read(&mut request).await.unwrap_or(0) > 0 {
+                            if first {
+                                tokio::time::sleep(Duration::from_millis(300)).await;
+                            }
+
+                            let answered = stream
+                                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                                .await;
+
+                            if answered.is_err() {
+                                break;
+                            }
+                        }
+
+                        if let Some(closed) = closed {
+                            closed.send(()).unwrap();
+                        }
+                    });
+                }
+            }
+        });
+
+        let service: ProxyService = serde_yaml::from_str(&format!(
+            "
+            backends: [{{ ip: 127.0.0.1, port: {} }}]
+            connection-pool: {{ max-idle-per-backend: 2 }}
+            ",
+            port,
+        ))
+        .unwrap();
+
+        let timeouts = Timeouts {
+            connect: Duration::from_secs(1),
+            request: Some(Duration::from_millis(100)),
+        };
+
+        let response = service
+            .send_request("test", get_request(), timeouts)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        tokio::time::timeout(Duration::from_secs(2), backend_closed)
+            .await
+            .expect("Backend connection wasn't closed")
+            .unwrap();
+
+        // The connection the late response comes on is never used again
+        for _ in 0..3 {
+            let response = service
+                .send_request("test", get_request(), timeouts)
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+
+            response.into_body().collect().await.unwrap();
+        }
+
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn timeout_resets_http2_stream() {
+        use hyper::{server::conn::http2, service::service_fn};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let (requests, mut received) = mpsc::unbounded_channel();
+
+        // Never answers, hyper drops the handler along with its sender once the stream is reset
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+
+            let service = service_fn(move |_| {
+                let (handled, dropped) = oneshot::channel::<()>();
+                requests.send(dropped).unwrap();
+
+                async move {
+                    let _handled = handled;
+
+                    std::future::pending::<Result<Response<http_body_util::Empty<Bytes>>, Infallible>>().await
+                }
+            });
+
+            http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+                .unwrap();
+        });
+
+        let service = h2_service(port, "10s", "20s");
+        let timeouts = Timeouts {
+            connect: Duration::from_secs(1),
+            request: Some(Duration::from_millis(100)),
+        };
+
+        // Both go over the one connection, which is still there after the first
+        for _ in 0..2 {
+            let response = service
+                .send_request("test", get_request(), timeouts)
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        }
+
+        for _ in 0..2 {
+            let dropped = async { received.recv().await.unwrap().await };
+
+            tokio::time::timeout(Duration::from_secs(2), dropped)
+                .await
+                .expect("Backend stream wasn't reset")
+                .unwrap_err();
+        }
+    }
+
+    #[tokio::test]
+    async fn dropped_response_closes_backend_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let (closed, backend_closed) = oneshot::channel();
+
+        // Streams an endless body until the connection goes away
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut request = [0; 1024];
+            assert!(stream.read(&mut request).await.unwrap() > 0);
+
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n")
+                .await
+                .unwrap();
+
+            while stream.write_all(b"5\r\nhello\r\n").await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+
+            closed.send(()).unwrap();
+        });
+
+        let service: ProxyService =
+            serde_yaml::from_str(&format!("backends: [{{ ip: 127.0.0.1, port: {} }}]", port))
+                .unwrap();
+
+        let response = service
+            .send_request("test", get_request(), Timeouts::default())
+            .await
+            .unwrap();
+
+        let mut body = response.into_body();
+        body.frame().await.unwrap().unwrap();
+
+        // Like a client that disconnected after the first chunk
+        drop(body);
+
+        tokio::time::timeout(Duration::from_secs(2), backend_closed)
+            .await
+            .expect("backend connection wasn't closed")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_response_headers_are_bad_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut request = [0; 1024];
+                assert!(stream.read(&mut request).await.unwrap() > 0);
+
+                let header = "a".repeat(12 * 1024);
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nx-large: {}\r\ncontent-length: 0\r\n\r\n",
+                    header
+                );
+
+                // The proxy can hang up before it's all sent
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        let service = |limit: usize| -> ProxyService {
+            serde_yaml::from_str(&format!(
+                "
+                backends: [{{ ip: 127.0.0.1, port: {} }}]
+                max-response-header-size: {}
+                ",
+                port, limit,
+            ))
+            .unwrap()
+        };
+
+        let response = service(8 * 1024)
+            .send_request("test", get_request(), Timeouts::default())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let response = service(16 * 1024)
+            .send_request("test", get_request(), Timeouts::default())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    /// Acceptor with a self-signed certificate for `backend.internal` that takes the `alpn`
+    /// protocols, and the path of a CA bundle with the certificate
+    fn backend_acceptor(alpn: Vec<Vec<u8>>) -> (tokio_rustls::TlsAcceptor, std::path::PathBuf) {
+        use rustls::{crypto::ring, ServerConfig};
+        use rustls_pki_types::PrivateKeyDer;
+
+        let certified =
+            rcgen::generate_simple_self_signed(["backend.internal".to_owned()]).unwrap();
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certified.cert.der().clone()],
+                PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into()),
+            )
+            .unwrap();
+        config.alpn_protocols = alpn;
+
+        let ca = std::env::temp_dir().join(format!(
+            "bifrost-test-backend-ca-{}-{}.pem",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        std::fs::write(&ca, certified.cert.pem()).unwrap();
+
+        (tokio_rustls::TlsAcceptor::from(Arc::new(config)), ca)
+    }
+
+    /// Backend behind TLS, see `backend_acceptor`, that answers every request with `200`.
+    /// Returns its port and the path of its CA bundle.
+    async fn tls_backend() -> (u16, std::path::PathBuf) {
+        let (acceptor, ca) = backend_acceptor(vec![]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+
+                    let mut request = [0; 1024];
+
+                    if stream.read(&mut request).await.unwrap_or(0) > 0 {
+                        let _ = stream
+                            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                            .await;
+                    }
+                });
+            }
+        });
+
+        (port, ca)
+    }
+
+    #[tokio::test]
+    async fn requests_reach_tls_backends() {
+        let (port, ca) = tls_backend().await;
+
+        let status = |tls: String| async move {
+            let service: ProxyService = serde_yaml::from_str(&format!(
+                "{{ backends: [{{ ip: 127.0.0.1, port: {} }}]{} }}",
+                port, tls
+            ))
+            .unwrap();
+
+            service
+                .send_request("test", get_request(), Timeouts::default())
+                .await
+                .unwrap()
+                .status()
+        };
+
+        let trusted = format!(", tls: {{ sni: backend.internal, ca: {} }}", ca.display());
+
+        assert_eq!(status(trusted).await, StatusCode::OK);
+
+        // The certificate isn't issued by a web PKI root
+        assert_eq!(
+            status(", tls: { sni: backend.internal }".to_owned()).await,
+            StatusCode::BAD_GATEWAY
+        );
+
+        std::fs::remove_file(ca).unwrap();
+    }
+
+    #[tokio::test]
+    async fn http2_is_offered_to_tls_backends() {
+        use hyper::{server::conn::http2, service::service_fn};
+
+        let (acceptor, ca) = backend_acceptor(vec![b"h2".to_vec()]);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+
+            assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+            let service = service_fn(|req: Request<Incoming>| async move {
+                let seen = format!("{:?}", req.version());
+
+                Ok::<_, Infallible>(Response::new(http_body_util::Full::new(Bytes::from(seen))))
+            });
+
+            http2::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+                .unwrap();
+        });
+
+        let service: ProxyService = serde_yaml::from_str(&format!(
+            "
+            backends: [{{ ip: 127.0.0.1, port: {} }}]
+            protocol: http2
+            tls: {{ sni: backend.internal, ca: {} }}
+            ",
+            port,
+            ca.display()
+        ))
+        .unwrap();
+
+        let response = service
+            .send_request("test", get_request(), Timeouts::default())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        assert_eq!(body, "HTTP/2.0");
+
+        std::fs::remove_file(ca).unwrap();
+    }
+
+    /// Backend that answers every request with `status`
+    async fn backend_responding(status: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let mut request = [0; 1024];
+
+                    if stream.read(&mut request).await.unwrap_or(0) > 0 {
+                        let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+                        let _ = stream.write_all(response.as_bytes()).await;
+                    }
+                });
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn checks_are_sent_to_the_configured_host() {
+        use futures::FutureExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let (hosts, mut received) = mpsc::unbounded_channel();
+
+        // Virtual-hosted backend, only `api.internal` is served
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut request = [0; 1024];
+                let read = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..read]).to_lowercase();
+
+                let host = request
+                    .lines()
+                    .find_map(|line| line.strip_prefix("host: "))
+                    .unwrap_or_default()
+                    .to_owned();
+
+                let status = if host == "api.internal" {
+                    "200 OK"
+                } else {
+                    "404 Not Found"
+                };
+                let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+                let _ = stream.write_all(response.as_bytes()).await;
+
+                hosts.send(host).unwrap();
+            }
+        });
+
+        let mut service: HttpService = serde_yaml::from_str(&format!(
+            "
+            backends: [{{ ip: 127.0.0.1, port: {} }}]
+            health-check:
+              interval: 20ms
+              unhealthy-threshold: 1
+              host: api.internal
+            ",
+            port,
+        ))
+        .unwrap();
+
+        let checker = service.health_checker("test").unwrap();
+        let backends = checker.backends.load();
+        let checking = tokio::spawn(checker.run(std::future::pending().boxed().shared()));
+
+        for _ in 0..3 {
+            assert_eq!(received.recv().await.unwrap(), "api.internal");
+        }
+
+        assert!(backends.state(0).is_up());
+
+        checking.abort();
+    }
+
+    #[tokio::test]
+    async fn failing_backend_is_taken_out_of_rotation() {
+        use futures::FutureExt;
+
+        let failing = backend_responding("500 Internal Server Error").await;
+        let healthy = backend_responding("200 OK").await;
+
+        let mut service: HttpService = serde_yaml::from_str(&format!(
+            "
+            backends:
+            - {{ ip: 127.0.0.1, port: {} }}
+            - {{ ip: 127.0.0.1, port: {} }}
+            health-check:
+              path: /healthz
+              interval: 20ms
+              unhealthy-threshold: 2
+            ",
+            failing, healthy,
+        ))
+        .unwrap();
+
+        let checker = service.health_checker("test").unwrap();
+        let backends = checker.backends.load();
+        let checking = tokio::spawn(checker.run(std::future::pending().boxed().shared()));
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while backends.state(0).is_up() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("failing backend wasn't taken out of rotation");
+
+        assert!(backends.state(1).is_up());
+
+        let HttpService::Proxy(service) = &mut service else {
+            unreachable!();
+        };
+
+        // Round robin would alternate between the two
+        for _ in 0..4 {
+            let connection = service
+                .load_balancer
+                .get_connection("test", Duration::from_secs(1), None, 0, None)
+                .await
+                .unwrap();
+
+            assert_eq!(connection.address, format!("127.0.0.1:{}", healthy));
+        }
+
+        checking.abort();
+        let _ = checking.await;
+
+        backends.state(1).set_up(false);
+
+        assert!(matches!(
+            service
+                .load_balancer
+                .get_connection("test", Duration::from_secs(1), None, 0, None)
+                .await,
+            Err(ConnectionError::BackendNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn failing_backend_is_ejected_until_cooldown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = format!("127.0.0.1:{}", listener.local_addr().unwrap().port());
+
+        let load_balancer: LoadBalancer = serde_yaml::from_str(&format!(
+            "
+            backends:
+            - {{ ip: 127.0.0.1, port: {} }}
+            - {{ ip: 127.0.0.1, port: {} }}
+            passive_health_check: {{ max_failures: 2, ejection_time: 200ms }}
+            ",
+            closed_port().await,
+            listener.local_addr().unwrap().port(),
+        ))
+        .unwrap();
+
+        let connect = async || {
+            load_balancer
+                .get_connection("test", Duration::from_secs(1), None, 0, None)
+                .await
+                .map(|connection| connection.address)
+        };
+
+        // Round robin alternates until the second failure in a row
+        for _ in 0..2 {
+            assert!(connect().await.is_err());
+            assert_eq!(connect().await.unwrap(), reachable);
+        }
+
+        for _ in 0..4 {
+            assert_eq!(connect().await.unwrap(), reachable);
+        }
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        // Tried again after the cooldown, and ejected again by the failure
+        assert!(connect().await.is_err());
+
+        for _ in 0..4 {
+            assert_eq!(connect().await.unwrap(), reachable);
+        }
+    }
+
+    #[tokio::test]
+    async fn traffic_fails_over_to_the_next_group() {
+        let mut listeners = vec![];
+
+        for _ in 0..3 {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+
+        let ports: Vec<u16> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap().port())
+            .collect();
+
+        let mut service: HttpService = serde_yaml::from_str(&format!(
+            "
+            backend-groups:
+            - priority: 1
+              backends: [{{ ip: 127.0.0.1, port: {} }}]
+            - priority: 0
+              backends:
+              - {{ ip: 127.0.0.1, port: {} }}
+              - {{ ip: 127.0.0.1, port: {} }}
+            health-check: {{ interval: 1m }}
+            ",
+            ports[2], ports[0], ports[1],
+        ))
+        .unwrap();
+
+        // Ejections are made by hand instead of by the checker
+        let backends = service.health_checker("test").unwrap().backends.load();
+
+        let HttpService::Proxy(service) = &mut service else {
+            unreachable!();
+        };
+
+        let served_ports = async |picks: usize| -> Vec<u16> {
+            let mut ports = vec![];
+
+            for _ in 0..picks {
+                let connection = service
+                    .load_balancer
+                    .get_connection("test", Duration::from_secs(1), None, 0, None)
+                    .await
+                    .unwrap();
+
+                ports.push(
+                    connection
+                        .address
+                        .rsplit(':')
+                        .next()
+                        .unwrap()
+                        .parse()
+                        .unwrap(),
+                );
+            }
+
+            ports.sort();
+            ports.dedup();
+            ports
+        };
+
+        let mut primary = [ports[0], ports[1]];
+        primary.sort();
+
+        assert_eq!(served_ports(4).await, primary);
+
+        // One primary backend left is enough to keep the traffic
+        backends.state(0).set_up(false);
+        assert_eq!(served_ports(4).await, [ports[1]]);
+
+        backends.state(1).set_up(false);
+        assert_eq!(served_ports(4).await, [ports[2]]);
+
+        // Back as soon as a primary backend recovers
+        backends.state(0).set_up(true);
+        assert_eq!(served_ports(4).await, [ports[0]]);
+    }
+
+    fn load_balancer(algo: &str) -> LoadBalancer {
+        serde_yaml::from_str(&format!(
+            "
+            load_balancing_algorithm: {}
+            backends:
+            - {{ ip: 127.0.0.1, port: 3000 }}
+            - {{ ip: 127.0.0.1, port: 3001 }}
+            - {{ ip: 127.0.0.1, port: 3002 }}
+            ",
+            algo
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn round_robin_rotates() {
+        let load_balancer = load_balancer("round-robin");
+
+        let mut state = load_balancer.state();
+        let backends = state.sync(&load_balancer.backends);
+        let picks: Vec<_> = (0..6)
+            .map(|_| {
+                load_balancer
+                    .next_backend_index(&mut state, &backends, None, 0, 0..3)
+                    .unwrap()
+            })
+            .collect();
+
+        assert_eq!(picks, [0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn weighted_round_robin_follows_weights() {
+        let load_balancer: LoadBalancer = serde_yaml::from_str(
+            "
+            load_balancing_algorithm: weighted-round-robin
+            backends:
+            - { ip: 127.0.0.1, port: 3000 }
+            - { ip: 127.0.0.1, port: 3001, weight: 2 }
+            - { ip: 127.0.0.1, port: 3002, weight: 3 }
+            ",
+        )
+        .unwrap();
+
+        let mut state = load_balancer.state();
+        let backends = state.sync(&load_balancer.backends);
+        let picks: Vec<usize> = (0..600)
+            .map(|_| {
+                load_balancer
+                    .next_backend_index(&mut state, &backends, None, 0, 0..3)
+                    .unwrap()
+            })
+            .collect();
+
+        for (index, weight) in [1, 2, 3].into_iter().enumerate() {
+            let count = picks.iter().filter(|&&pick| pick == index).count();
+
+            assert!(count.abs_diff(weight * 100) <= 6, "{index}: {count}");
+        }
+
+        // Spread out, the heaviest backend isn't picked three times in a row
+        assert_eq!(picks[..6], [2, 1, 0, 2, 1, 2]);
+    }
+
+    #[test]
+    fn random_is_not_a_rotation() {
+        let load_balancer = load_balancer("random");
+
+        let mut state = load_balancer.state();
+        let backends = state.sync(&load_balancer.backends);
+        let picks: Vec<_> = (0..300)
+            .map(|_| {
+                load_balancer
+                    .next_backend_index(&mut state, &backends, None, 0, 0..3)
+                    .unwrap()
+            })
+            .collect();
+
+        // Round robin would always move on to the next index
+        assert!(picks.windows(2).any(|pair| pair[1] != (pair[0] + 1) % 3));
+        assert!((0..3).all(|index| picks.contains(&index)));
+    }
+
+    /// Backend that answers every request with a body that never ends
+    async fn endless_backend() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let mut request = [0; 1024];
+                    assert!(stream.read(&mut request).await.unwrap() > 0);
+
+                    stream
+                        .write_all(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n")
+                        .await
+                        .unwrap();
+
+                    std::future::pending::<()>().await;
+                });
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn least_connections_picks_least_loaded_backend() {
+        let ports = [
+            endless_backend().await,
+            endless_backend().await,
+            endless_backend().await,
+        ];
+
+        let service: ProxyService = serde_yaml::from_str(&format!(
+            "
+            load_balancing_algorithm: least-connections
+            backends:
+            - {{ ip: 127.0.0.1, port: {} }}
+            - {{ ip: 127.0.0.1, port: {} }}
+            - {{ ip: 127.0.0.1, port: {} }}
+            ",
+            ports[0], ports[1], ports[2],
+        ))
+        .unwrap();
+
+        // Responses are in flight until their bodies end, so they're all open at once
+        let mut in_flight = vec![];
+
+        for _ in 0..5 {
+            let response = service
+                .send_request("test", get_request(), Timeouts::default())
+                .await
+                .unwrap();
+
+            let ServedBy(backend) = response.extensions().get::<ServedBy>().unwrap().clone();
+
+            in_flight.push((backend, response));
+        }
+
+        let address = |index: usize| format!("127.0.0.1:{}", ports[index]);
+        let served_by: Vec<&String> = in_flight.iter().map(|(backend, _)| backend).collect();
+
+        // Ties go to the first backend
+        assert_eq!(served_by, [0, 1, 2, 0, 1].map(address).each_ref());
+
+        // The third backend has one request left, the others two, until one of them finishes
+        let finished = in_flight.remove(1);
+        drop(finished);
+
+        let response = service
+            .send_request("test", get_request(), Timeouts::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            response.extensions().get::<ServedBy>().unwrap().0,
+            address(1)
+        );
+
+        drop(in_flight);
+        drop(response);
+
+        let backends = service.load_balancer.backends.load();
+        let counts: Vec<usize> = (0..3)
+            .map(|index| backends.state(index).in_flight())
+            .collect();
+
+        assert_eq!(counts, [0, 0, 0]);
+    }
+
+    /// Keep-alive backend answering with a body, counts the connections it accepts
+    async fn counting_backend(connects: Arc<AtomicUsize>) -> u16 {
+        use hyper::{server::conn::http1, service::service_fn};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+
+                connects.fetch_add(1, Ordering::SeqCst);
+
+                let service = service_fn(|_| async {
+                    Ok::<_, Infallible>(Response::new(http_body_util::Full::new(
+                        Bytes::from_static(b"pooled"),
+                    )))
+                });
+
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        port
+    }
+
+    #[tokio::test]
+    async fn pooled_connections_are_reused() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let port = counting_backend(connects.clone()).await;
+
+        let service: ProxyService = serde_yaml::from_str(&format!(
+            "
+            backends: [{{ ip: 127.0.0.1, port: {} }}]
+            connection-pool: {{ max-idle-per-backend: 2 }}
+            ",
+            port
+        ))
+        .unwrap();
+
+        for _ in 0..100 {
+            let response = service
+                .send_request("test", get_request(), Timeouts::default())
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, "pooled");
+        }
+
+        assert!(connects.load(Ordering::SeqCst) <= 2, "{:?}", connects);
+
+        // A response that isn't read to the end takes its connection down with it
+        drop(
+            service
+                .send_request("test", get_request(), Timeouts::default())
+                .await
+                .unwrap(),
+        );
+
+        let response = service
+            .send_request("test", get_request(), Timeouts::default())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn backends_are_replaced_with_requests_in_flight() {
+        let ports = [
+            endless_backend().await,
+            endless_backend().await,
+            endless_backend().await,
+        ];
+
+        let backends = |first: u16, second: u16| {
+            serde_yaml::from_str::<LoadBalancer>(&format!(
+                "backends: [{{ ip: 127.0.0.1, port: {} }}, {{ ip: 127.0.0.1, port: {} }}]",
+                first, second
+            ))
+            .unwrap()
+            .backends
+        };
+
+        let service: ProxyService = serde_yaml::from_str(&format!(
+            "
+            load_balancing_algorithm: least-connections
+            backends:
+            - {{ ip: 127.0.0.1, port: {} }}
+            - {{ ip: 127.0.0.1, port: {} }}
+            ",
+            ports[0], ports[1],
+        ))
+        .unwrap();
+
+        let set = service.load_balancer.backends.clone();
+
+        let send = async || {
+            let response = service
+                .send_request("test", get_request(), Timeouts::default())
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let ServedBy(backend) = response.extensions().get::<ServedBy>().unwrap().clone();
+
+            (backend, response)
+        };
+
+        let address = |index: usize| format!("127.0.0.1:{}", ports[index]);
+        let mut in_flight = vec![send().await, send().await];
+
+        // The first backend goes away and a third one comes in, the second one stays
+        let replacement = backends(ports[1], ports[2]).load();
+        set.update(|_| Backends::clone(&replacement));
+
+        // The second backend still has its request, so the new one is the least loaded
+        in_flight.push(send().await);
+        in_flight.push(send().await);
+
+        let served_by: Vec<&String> = in_flight.iter().map(|(backend, _)| backend).collect();
+        assert_eq!(served_by, [0, 1, 2, 1].map(address).each_ref());
+
+        let current = set.load();
+        assert_eq!(current.state(0).in_flight(), 2);
+        assert_eq!(current.state(1).in_flight(), 1);
+
+        // Responses of the backend that's gone are still there
+        let (_, response) = in_flight.remove(0);
+        let mut body = response.into_body();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), body.frame())
+                .await
+                .is_err()
+        );
+
+        drop(body);
+        drop(in_flight);
+
+        assert_eq!(current.state(0).in_flight(), 0);
+        assert_eq!(current.state(1).in_flight(), 0);
+    }
+
+    #[test]
+    fn canary_gets_its_share_of_weights() {
+        let load_balancer: LoadBalancer = serde_yaml::from_str(
+            "
+            load_balancing_algorithm: weighted-random
+            backends:
+            - { ip: 127.0.0.1, port: 3000, weight: 3 }
+            - { ip: 127.0.0.1, port: 3001 }
+            - { ip: 127.0.0.1, port: 3002, weight: 1 }
+            ",
+        )
+        .unwrap();
+
+        let weights = |load_balancer: &LoadBalancer| -> Vec<u32> {
+            load_balancer
+                .backends
+                .load()
+                .iter()
+                .map(BackendDefinition::weight)
+                .collect()
+        };
+
+        assert!(load_balancer.split_traffic("127.0.0.1:3002", 25));
+
+        // 25% for the canary, the rest keeps the 3 to 1 ratio
+        assert_eq!(weights(&load_balancer), [225, 75, 100]);
+
+        // Steps are computed from the configured weights, not the previous step
+        assert!(load_balancer.split_traffic("127.0.0.1:3002", 100));
+        assert_eq!(weights(&load_balancer), [0, 0, 400]);
+
+        assert!(!load_balancer.split_traffic("127.0.0.1:4000", 50));
+    }
+
+    #[tokio::test]
+    async fn slow_backend_is_hedged() {
+        use hyper::{server::conn::http1, service::service_fn};
+
+        let serve = |delay: Duration, name: &'static str| async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+
+                    let service = service_fn(move |_| async move {
+                        tokio::time::sleep(delay).await;
+
+                        Ok::<_, Infallible>(Response::new(http_body_util::Full::new(Bytes::from(
+                            name,
+                        ))))
+                    });
+
+                    tokio::spawn(
+                        http1::Builder::new().serve_connection(TokioIo::new(stream), service),
+                    );
+                }
+            });
+
+            port
+        };
+
+        let service: ProxyService = serde_yaml::from_str(&format!(
+            "
+            backends:
+            - {{ ip: 127.0.0.1, port: {} }}
+            - {{ ip: 127.0.0.1, port: {} }}
+            retries: {{ attempts: 0 }}
+            hedging: {{ delay: 50ms }}
+            ",
+            serve(Duration::from_secs(5), "slow").await,
+            serve(Duration::ZERO, "fast").await,
+        ))
+        .unwrap();
+
+        let started = Instant::now();
+        let response = service
+            .send_request("test", get_request(), Timeouts::default())
+            .await
+            .unwrap();
+
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        assert_eq!(body, "fast");
+
+        // Requests with side effects wait for the backend they're sent to
+        let response = tokio::time::timeout(
+            Duration::from_millis(500),
+            service.send_request(
+                "test",
+                Request::post("/").body(get_request().into_body()).unwrap(),
+                Timeouts::default(),
+            ),
+        )
+        .await;
+
+        assert!(response.is_err());
+    }
+
+    #[test]
+    fn host_header_is_taken_from_authority() {
+        let mut req = Request::get("http://test.com:8080/hello").body(()).unwrap();
+
+        set_host_header(&mut req);
+
+        assert_eq!(req.headers()[header::HOST], "test.com:8080");
+
+        // A client's own header stays
+        let mut req = get_request();
+
+        set_host_header(&mut req);
+
+        assert_eq!(req.headers()[header::HOST], "test.com");
+    }
+
+    #[test]
+    fn alias_table_empty() {
+        let table = AliasTable::new(&[]);
+
+        assert_eq!(table.sample(&mut rand::thread_rng()), None);
+    }
+}
+
+/// `host:port` of the backend a request was sent to, kept in the response extensions
+#[derive(Clone)]
+pub(crate) struct ServedBy(pub(crate) String);
+
+/// Where rules send the requests they match
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub(crate) enum HttpService {
+    /// Services with `type: static`
+    Static(StaticFiles),
+    Proxy(Box<ProxyService>),
+}
+
+/// Picks the variant by whether there's a `type`, so the error of the variant, e.g. an unknown
+/// key, isn't lost the way it is with `untagged`
+impl<'de> Deserialize<'de> for HttpService {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let value = serde_yaml::Value::deserialize(deserializer)?;
+
+        let service = if value.get("type").is_some() {
+            StaticFiles::deserialize(value).map(HttpService::Static)
+        } else {
+            ProxyService::deserialize(value).map(|service| HttpService::Proxy(Box::new(service)))
+        };
+
+        service.map_err(D::Error::custom)
+    }
+}
+
+impl HttpService {
+    pub(crate) fn backends(&self) -> Option<Arc<BackendSnapshot>> {
+        match self {
+            HttpService::Static(_) => None,
+            HttpService::Proxy(service) => Some(service.load_balancer.backends.load()),
+        }
+    }
+
+    /// Shared with the load balancer, follows the backends as they're replaced or taken out of
+    /// rotation
+    pub(crate) fn backend_set(&self) -> Option<BackendSet> {
+        match self {
+            HttpService::Static(_) => None,
+            HttpService::Proxy(service) => Some(service.load_balancer.backends.clone()),
+        }
+    }
+
+    pub(crate) fn timeouts(&self) -> TimeoutsConfig {
+        match self {
+            HttpService::Static(_) => TimeoutsConfig::default(),
+            HttpService::Proxy(service) => service.timeouts,
+        }
+    }
+
+    /// See `LoadBalancer::split_traffic`
+    pub(crate) fn split_traffic(&self, canary: &str, share: u32) -> bool {
+        match self {
+            HttpService::Static(_) => false,
+            HttpService::Proxy(service) => service.load_balancer.split_traffic(canary, share),
+        }
+    }
+
+    /// Weights only make a difference with weighted load balancing
+    pub(crate) fn is_weighted(&self) -> bool {
+        match self {
+            HttpService::Static(_) => false,
+            HttpService::Proxy(service) => matches!(
+                service.load_balancer.algo,
+                LoadBalancingAlgorithm::WeightedRandom | LoadBalancingAlgorithm::WeightedRoundRobin
+            ),
+        }
+    }
+
+    pub(crate) fn has_unused_h2_keepalive(&self) -> bool {
+        match self {
+            HttpService::Static(_) => false,
+            HttpService::Proxy(service) => service.has_unused_h2_keepalive(),
+        }
+    }
+
+    pub(crate) fn connection_pool(&self) -> Option<&HttpPoolConfig> {
+        match self {
+            HttpService::Static(_) => None,
+            HttpService::Proxy(service) => service
+                .load_balancer
+                .connection_pool
+                .as_ref()
+                .map(HttpPool::config),
+        }
+    }
+
+    pub(crate) fn is_http2(&self) -> bool {
+        match self {
+            HttpService::Static(_) => false,
+            HttpService::Proxy(service) => service.load_balancer.protocol == BackendProtocol::Http2,
+        }
+    }
+
+    /// Hedges take from the retry budget, without one nothing limits them
+    pub(crate) fn has_unlimited_hedging(&self) -> bool {
+        match self {
+            HttpService::Static(_) => false,
+            HttpService::Proxy(service) => service.hedging.is_some() && service.retries.is_none(),
+        }
+    }
+
+    /// Checker for the backends of the service, when it's configured. The service skips the
+    /// backends the checker takes out of rotation from then on.
+    pub(crate) fn health_checker(&mut self, name: &str) -> Option<HealthChecker> {
+        match self {
+            HttpService::Static(_) => None,
+            HttpService::Proxy(service) => service.health_checker(name),
+        }
+    }
+
+    /// Refresher of the backends' DNS names, when the service sets an interval
+    pub(crate) fn dns_refresher(&self) -> Option<DnsRefresher> {
+        match self {
+            HttpService::Static(_) => None,
+            HttpService::Proxy(service) => service.dns_refresher(),
+        }
+    }
+
+    /// Reaper of the connections the service keeps open, when it keeps any
+    pub(crate) fn connection_reaper(&self) -> Option<ConnectionReaper> {
+        match self {
+            HttpService::Static(_) => None,
+            HttpService::Proxy(service) => service.connection_reaper(),
+        }
+    }
+
+    pub(crate) fn dns_refresh_interval(&self) -> Option<Duration> {
+        match self {
+            HttpService::Static(_) => None,
+            HttpService::Proxy(service) => service.dns_refresh_interval.map(Duration::from),
+        }
+    }
+
+    pub(crate) fn health_check(&self) -> Option<&HealthCheckConfig> {
+        match self {
+            HttpService::Static(_) => None,
+            HttpService::Proxy(service) => service.health_check.as_ref(),
+        }
+    }
+
+    pub(crate) fn passive_health_check(&self) -> Option<&PassiveHealthCheckConfig> {
+        match self {
+            HttpService::Static(_) => None,
+            HttpService::Proxy(service) => service.load_balancer.passive_health_check.as_ref(),
+        }
+    }
+
+    pub(crate) fn source_address(&self) -> Option<IpAddr> {
+        match self {
+            HttpService::Static(_) => None,
+            HttpService::Proxy(service) => service.source_address,
+        }
+    }
+
+    pub(crate) fn max_response_header_size(&self) -> Option<usize> {
+        match self {
+            HttpService::Static(_) => None,
+            HttpService::Proxy(service) => Some(service.max_response_header_size()),
+        }
+    }
+
+    /// `name` is the name of this service in the config. Requests to the same service are sent
+    /// concurrently, nothing is locked while they're in flight
+    pub(super) async fn send_request(
+        &self,
+        name: &str,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+        timeouts: Timeouts,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Infallible> {
+        match self {
+            HttpService::Static(files) => Ok(files.send_request(req).await),
+            HttpService::Proxy(service) => service.send_request(name, req, timeouts).await,
+        }
+    }
+}
+
+/// Service that proxies requests to its backends
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ProxyService {
+    #[serde(flatten)]
+    load_balancer: LoadBalancer,
+    /// Connecting to a backend isn't retried unless this is set
+    retries: Option<RetryBudget>,
+    /// How often HTTP/2 connections are pinged to keep them alive, no pings when unset
+    h2_keepalive_interval: Option<DurationString>,
+    /// How long to wait for a ping to be acknowledged before closing the connection
+    h2_keepalive_timeout: Option<DurationString>,
+    /// Overrides `http.timeouts`, route rules can override these in turn
+    #[serde(default)]
+    timeouts: TimeoutsConfig,
+    /// Response header to report how long the backend took to respond in, in milliseconds,
+    /// e.g. `x-upstream-response-time`
+    upstream_response_time_header: Option<ConfiguredHeaderName>,
+    /// Bytes of response headers a backend can send, the client gets a `502` instead of
+    /// a response with more. 64KiB when not set.
+    max_response_header_size: Option<usize>,
+    /// Every backend stays in rotation when not set
+    health_check: Option<HealthCheckConfig>,
+    /// Local IP connections to the backends come from, left to the system when not set
+    source_address: Option<IpAddr>,
+    /// Send copies of slow idempotent requests to other backends, needs `retries` to limit them
+    hedging: Option<Hedging>,
+    #[serde(default)]
+    interim_responses: InterimResponses,
+    /// Look the names of the backends up this often, rather than when a request finds their
+    /// addresses older than the DNS TTL
+    dns_refresh_interval: Option<DurationString>,
+}
+
+impl ProxyService {
+    fn health_checker(&mut self, name: &str) -> Option<HealthChecker> {
+        let config = self.health_check.clone()?;
+
+        Some(HealthChecker {
+            service_name: name.to_owned(),
+            backends: self.load_balancer.backends.clone(),
+            config,
+            source_address: self.source_address,
+            tls: self.load_balancer.tls.clone(),
+        })
+    }
+
+    fn dns_refresher(&self) -> Option<DnsRefresher> {
+        Some(DnsRefresher {
+            backends: self.load_balancer.backends.clone(),
+            interval: self.dns_refresh_interval?.into(),
+        })
+    }
+
+    fn connection_reaper(&self) -> Option<ConnectionReaper> {
+        let load_balancer = &self.load_balancer;
+
+        if load_balancer.connection_pool.is_none()
+            && load_balancer.protocol != BackendProtocol::Http2
+        {
+            return None;
+        }
+
+        Some(ConnectionReaper {
+            backends: load_balancer.backends.clone(),
+            pool: load_balancer.connection_pool.clone(),
+            http2_connections: load_balancer.http2_connections.clone(),
+        })
+    }
+
+    /// Connects to a backend, trying others while the retry budget allows it
+    /// `key` is the consistent hashing key of the request
+    async fn connect(
+        &self,
+        name: &str,
+        timeout: Duration,
+        key: Option<u64>,
+    ) -> Result<BackendConnection, ConnectionError> {
+        let Some(retries) = &self.retries else {
+            return self
+                .load_balancer
+                .get_connection(name, timeout, key, 0, self.source_address)
+                .await;
+        };
+
+        retries.deposit();
+
+        let mut attempt = 0;
+
+        loop {
+            match self
+                .load_balancer
+                .get_connection(name, timeout, key, attempt, self.source_address)
+                .await
+            {
+                Err(ConnectionError::IoError(err))
+                    if attempt < retries.attempts() && retries.withdraw() =>
+                {
+                    attempt += 1;
+
+                    tracing::warn!(
+                        service = name,
+                        error = %err,
+                        "Failed to connect to backend, retrying"
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn send_request(
+        &self,
+        name: &str,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+        timeouts: Timeouts,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Infallible> {
+        let key = self.load_balancer.hash_key(&req);
+
+        // Hedges are copies of the request, so its body has to be buffered for them
+        let hedged = self
+            .hedging
+            .as_ref()
+            .is_some_and(|hedging| hedging.applies(req.method()));
+
+        let (mut req, buffered) = if hedged {
+            match body_match::buffer(req, hedging::MAX_BODY_SIZE).await {
+                Ok(buffered) => buffered,
+                Err(err) => {
+                    tracing::debug!(
+                        service = name,
+                        error = %err,
+                        "Failed to read the body of a hedged request"
+                    );
+
+                    return Ok(bad_request());
+                }
+            }
+        } else {
+            (req, false)
+        };
+
+        let connection = match self.connect(name, timeouts.connect, key).await {
+            Ok(connected) => connected,
+            Err(ConnectionError::NoBackends) => {
+                tracing::warn!(service = name, "No backends to send the request to");
+
+                return Ok(service_unavailable());
+            }
+            Err(ConnectionError::BackendNotFound) => {
+                tracing::warn!(service = name, "No healthy backend to send the request to");
+
+                return Ok(service_unavailable());
+            }
+            Err(err) => {
+                tracing::warn!(service = name, error = %err, "Failed to connect to backend");
+
+                return Ok(bad_gateway());
+            }
+        };
+
+        telemetry::propagate_trace(req.headers_mut());
+
+        // Hedges are made from the request as it's sent, the original is sent as a copy too
+        let body = buffered
+            .then(|| req.extensions().get::<BufferedBody>().cloned())
+            .flatten();
+
+        let (req, template) = match body {
+            Some(BufferedBody(body)) => {
+                let (parts, _) = req.into_parts();
+
+                (mirror::copy(&parts, body.clone()), Some((parts, body)))
+            }
+            None => (req, None),
+        };
+
+        let max_hedges = match (&template, &self.hedging) {
+            (Some(_), Some(hedging)) => hedging.max_hedges(),
+            _ => 0,
+        };
+
+        let transport = self.transport();
+        let started = Instant::now();
+        let deadline = timeouts.request.map(|timeout| started + timeout);
+
+        // Backends of the attempts still waiting for a response, the first one is the original
+        let mut waiting = vec![connection.address.clone()];
+        let mut attempts = FuturesUnordered::new();
+        let mut hedges = 0;
+
+        attempts.push(attempt(transport.clone(), connection, req));
+
+        let (mut response, backend) = loop {
+            let hedge_at = self
+                .hedging
+                .as_ref()
+                .filter(|_| hedges < max_hedges)
+                .map(|hedging| started + hedging.delay() * (hedges + 1));
+
+            tokio::select! {
+                Some((sent, response)) = attempts.next() => {
+                    waiting.retain(|backend| *backend != sent.address);
+
+                    self.load_balancer
+                        .record_outcome(&sent.address, response.is_ok());
+
+                    let response = match response {
+                        Ok(response) => response,
+                        Err(err) => {
+                            tracing::warn!(
+                                service = name,
+                                backend = sent.address,
+                                error = %err,
+                                "Failed to send request to backend"
+                            );
+
+                            metrics().backend_response(
+                                name,
+                                &sent.address,
+                                StatusCode::BAD_GATEWAY,
+                            );
+
+                            // Hedges may still answer
+                            if !attempts.is_empty() {
+                                continue;
+                            }
+
+                            break (bad_gateway(), sent.address);
+                        }
+                    };
+
+                    metrics().backend_response(name, &sent.address, response.status());
+                    metrics().backend_response_time(name, &sent.address, sent.at.elapsed());
+
+                    let mut response =
+                        response.map(|body| body.holding(sent.in_flight).boxed());
+
+                    if let Some(hedging) = &self.hedging {
+                        hedging.record(sent.at.elapsed());
+                    }
+
+                    if let Some(ConfiguredHeaderName(header)) = &self.upstream_response_time_header
+                    {
+                        response.headers_mut().insert(
+                            header,
+                            HeaderValue::from(sent.at.elapsed().as_millis() as u64),
+                        );
+                    }
+
+                    // The other attempts are dropped, which closes their connections
+                    break (response, sent.address);
+                }
+                _ = sleep_until(hedge_at) => {
+                    hedges += 1;
+
+                    // Hedges are extra load on the backends just like retries are
+                    if !self.retries.as_ref().is_some_and(RetryBudget::withdraw) {
+                        tracing::debug!(
+                            service = name,
+                            "Retry budget is exhausted, the request isn't hedged"
+                        );
+
+                        hedges = max_hedges;
+
+                        continue;
+                    }
+
+                    let Some((parts, body)) = &template else {
+                        continue;
+                    };
+
+                    match self
+                        .load_balancer
+                        .get_connection(name, timeouts.connect, key, hedges, self.source_address)
+                        .await
+                    {
+                        Ok(connection) => {
+                            tracing::debug!(
+                                service = name,
+                                backend = connection.address,
+                                "Backend is slow to respond, hedging the request"
+                            );
+
+                            waiting.push(connection.address.clone());
+                            attempts.push(attempt(
+                                transport.clone(),
+                                connection,
+                                mirror::copy(parts, body.clone()),
+                            ));
+                        }
+                        Err(err) => tracing::warn!(
+                            service = name,
+                            error = %err,
+                            "Failed to connect to backend to hedge"
+                        ),
+                    }
+                }
+                _ = sleep_until(deadline) => {
+                    tracing::warn!(
+                        service = name,
+                        elapsed = ?started.elapsed(),
+                        "Backend didn't respond in time"
+                    );
+
+                    for backend in &waiting {
+                        self.load_balancer.record_outcome(backend, false);
+
+                        metrics().backend_response(name, backend, StatusCode::GATEWAY_TIMEOUT);
+                    }
+
+                    let backend = waiting.swap_remove(0);
+
+                    break (gateway_timeout(), backend);
+                }
+            }
+        };
+
+        response.extensions_mut().insert(ServedBy(backend));
+
+        Ok(response)
+    }
+
+    fn transport(&self) -> Transport {
+        Transport {
+            protocol: self.load_balancer.protocol,
+            max_response_header_size: self.max_response_header_size(),
+            h2_keepalive_interval: self.h2_keepalive_interval.map(Duration::from),
+            h2_keepalive_timeout: self.h2_keepalive_timeout.map(Duration::from),
+            pool: self.load_balancer.connection_pool.clone(),
+            http2_connections: self.load_balancer.http2_connections.clone(),
+            interim_responses: self.interim_responses,
+        }
+    }
+
+    fn max_response_header_size(&self) -> usize {
+        self.max_response_header_size
+            .unwrap_or(DEFAULT_MAX_RESPONSE_HEADER_SIZE)
+    }
+
+    /// Keep-alive is only set up for HTTP/2 connections
+    fn has_unused_h2_keepalive(&self) -> bool {
+        self.load_balancer.protocol != BackendProtocol::Http2
+            && (self.h2_keepalive_interval.is_some() || self.h2_keepalive_timeout.is_some())
+    }
+}
+
+/// How requests go over backend connections, cloned out of the service so the attempts of a
+/// request don't hold on to it
+#[derive(Debug, Clone)]
+struct Transport {
+    protocol: BackendProtocol,
+    max_response_header_size: usize,
+    h2_keepalive_interval: Option<Duration>,
+    h2_keepalive_timeout: Option<Duration>,
+    /// New HTTP/1 connections go to it once they're done with the request
+    pool: Option<HttpPool>,
+    /// New HTTP/2 connections are shared through it right after the handshake
+    http2_connections: Http2Connections,
+    interim_responses: InterimResponses,
+}
+
+impl Transport {
+    async fn send(
+        self,
+        address: String,
+        link: BackendLink,
+        req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> hyper::Result<Response<BackendBody>> {
+        match (self.protocol, link) {
+            (_, BackendLink::Shared(sender)) => Self::send_http2(sender, req).await,
+            (BackendProtocol::Http2, BackendLink::Connected(stream)) => {
+                let sender = self.handshake_http2(stream).await?;
+
+                self.http2_connections.insert(address, sender.clone());
+
+                Self::send_http2(sender, req).await
+            }
+            (_, link) => self.send_http1(address, link, req).await,
+        }
+    }
+
+    async fn send_http1(
+        self,
+        address: String,
+        link: BackendLink,
+        mut req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> hyper::Result<Response<BackendBody>> {
+        use hyper::client::conn::http1;
+
+        set_host_header(&mut req);
+
+        let mut connection = match link {
+            BackendLink::Pooled(connection) => connection,
+            BackendLink::Shared(_) => {
+                unreachable!("HTTP/2 connections are sent over by send_http2")
+            }
+            BackendLink::Connected(stream) => {
+                let io = TokioIo::new(stream);
+
+                // The head has to fit in the read buffer, a bigger one fails to parse
+                let (sender, conn) = http1::Builder::new()
+                    .max_buf_size(self.max_response_header_size)
+                    .handshake(io)
+                    .await?;
+
+                let task = tokio::spawn(async move {
+                    if let Err(err) = conn.await {
+                        tracing::debug!(error = ?err, "Backend connection failed");
+                    }
+                });
+
+                let pool = self.pool.map(|pool| (pool, address));
+
+                Http1Connection::new(sender, task.abort_handle(), pool)
+            }
+        };
+
+        let hints = Arc::new(Mutex::new(vec![]));
+
+        if self.interim_responses == InterimResponses::MergeLinks {
+            let hints = hints.clone();
+
+            hyper::ext::on_informational(&mut req, move |response| {
+                if response.status() == StatusCode::EARLY_HINTS {
+                    hints
+                        .lock()
+                        .expect("Hints lock poisoned")
+                        .extend(response.headers().get_all(header::LINK).iter().cloned());
+                }
+            });
+        }
+
+        let pending = PendingResponse::new(connection.task.clone());
+        let mut response = connection.sender.send_request(req).await?;
+
+        let hints = std::mem::take(&mut *hints.lock().expect("Hints lock poisoned"));
+        add_early_hints(response.headers_mut(), hints);
+
+        Ok(response.map(|body| pending.received(body).returning(connection)))
+    }
+
+    /// Opens an HTTP/2 connection, it's closed once the last sender for it is dropped
+    async fn handshake_http2(&self, stream: BackendStream) -> hyper::Result<Http2Sender> {
+        use hyper::client::conn::http2;
+
+        let mut builder = http2::Builder::new(TokioExecutor::new());
+
+        builder
+            .timer(TokioTimer::new())
+            .keep_alive_interval(self.h2_keepalive_interval)
+            .keep_alive_while_idle(true)
+            .max_header_list_size(self.max_response_header_size.try_into().unwrap_or(u32::MAX));
+
+        if let Some(timeout) = self.h2_keepalive_timeout {
+            builder.keep_alive_timeout(timeout);
+        }
+
+        let (sender, conn) = builder.handshake(TokioIo::new(stream)).await?;
+
+        tokio::spawn(async move {
+            if let Err(err) = conn.await {
+                tracing::debug!(error = ?err, "Backend connection failed");
+            }
+        });
+
+        Ok(sender)
+    }
+
+    /// Sends the request as a new stream of the connection, dropping the response early only
+    /// resets the stream
+    async fn send_http2(
+        mut sender: Http2Sender,
+        mut req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> hyper::Result<Response<BackendBody>> {
+        // HTTP/2 carries the host in the `:authority` pseudo-header, built from the URI
+        if let Some(uri) = absolute_uri(&req) {
+            *req.uri_mut() = uri;
+        }
+
+        // Waits for the backend to allow another stream when it caps concurrent ones
+        sender.ready().await?;
+
+        let pending = PendingResponse::stream();
+        let response = sender.send_request(req).await?;
+
+        Ok(response.map(|body| pending.received(body)))
+    }
+}
+
+/// Adds the `Link` headers of early hints the final response doesn't repeat already
+fn add_early_hints(headers: &mut HeaderMap, hints: Vec<HeaderValue>) {
+    for hint in hints {
+        if !headers
+            .get_all(header::LINK)
+            .iter()
+            .any(|link| *link == hint)
+        {
+            headers.append(header::LINK, hint);
+        }
+    }
+}
+
+/// Request that went out to a backend, either the original or a hedge
+struct SentAttempt {
+    address: String,
+    in_flight: InFlightRequest,
+    at: Instant,
+}
+
+async fn attempt(
+    transport: Transport,
+    connection: BackendConnection,
+    req: Request<BoxBody<Bytes, hyper::Error>>,
+) -> (SentAttempt, hyper::Result<Response<BackendBody>>) {
+    let BackendConnection {
+        address,
+        link,
+        in_flight,
+    } = connection;
+
+    let sent = SentAttempt {
+        address: address.clone(),
+        in_flight,
+        at: Instant::now(),
+    };
+
+    (sent, transport.send(address, link, req).await)
+}
+
+/// Never completes without a deadline
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Requests from HTTP/2 clients carry the host only in the URI, HTTP/1.1 backends need it in
+/// `Host`
+fn set_host_header<B>(req: &mut Request<B>) {
+    if req.headers().contains_key(header::HOST) {
+        return;
+    }
+
+    let host = req
+        .uri()
+        .authority()
+        .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok());
+
+    if let Some(host) = host {
+        req.headers_mut().insert(header::HOST, host);
+    }
+}
+
+/// Request URI with the scheme and authority HTTP/2 requires, taken from the `Host` header
+fn absolute_uri<B>(req: &Request<B>) -> Option<Uri> {
+    if req.uri().authority().is_some() {
+        return None;
+    }
+
+    let host = req.headers().get(header::HOST)?.to_str().ok()?;
+    let path = req
+        .uri()
+        .path_and_query()
+        .map_or("/", |path_and_query| path_and_query.as_str());
+
+    Uri::builder()
+        .scheme("http")
+        .authority(host)
+        .path_and_query(path)
+        .build()
+        .ok()
 }
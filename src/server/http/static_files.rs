@@ -0,0 +1,265 @@
+use std::path::{Component, Path, PathBuf};
+
+use bytes::Bytes;
+use http::{header, HeaderValue, Method, StatusCode};
+use http_body_util::combinators::BoxBody;
+use hyper::{Request, Response};
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+
+use super::server::{full, not_found};
+
+/// Marker for `type: static`, services without a type proxy to backends
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum StaticFilesType {
+    Static,
+}
+
+/// Service that serves files from a directory instead of proxying requests
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct StaticFiles {
+    #[serde(rename = "type")]
+    kind: StaticFilesType,
+    /// Directory request paths are resolved against
+    root: PathBuf,
+    /// File served for requests to a directory
+    #[serde(default = "StaticFiles::default_index")]
+    index: String,
+}
+
+impl StaticFiles {
+    fn default_index() -> String {
+        "index.html".to_owned()
+    }
+
+    pub(super) async fn send_request<B>(
+        &self,
+        req: Request<B>,
+    ) -> Response<BoxBody<Bytes, hyper::Error>> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return method_not_allowed();
+        }
+
+        let Some(path) = self.resolve(req.uri().path()).await else {
+            return not_found();
+        };
+
+        let contents = match tokio::fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(err) => {
+                tracing::warn!(
+                    path = %path.display(),
+                    error = %err,
+                    "Failed to read a static file"
+                );
+
+                return not_found();
+            }
+        };
+
+        let content_type = mime_guess::from_path(&path).first_or_octet_stream();
+        let content_length = contents.len();
+
+        let body = if req.method() == Method::HEAD {
+            full(Bytes::new())
+        } else {
+            full(contents)
+        };
+
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type.as_ref())
+            .header(header::CONTENT_LENGTH, HeaderValue::from(content_length))
+            .body(body)
+            // FIX: expect
+            .expect("Failed to build response")
+    }
+
+    /// File under the root the request path points to, `None` when there's no such file or the
+    /// path tries to get out of the root
+    async fn resolve(&self, request_path: &str) -> Option<PathBuf> {
+        let relative = relative_path(request_path)?;
+
+        let root = tokio::fs::canonicalize(&self.root).await.ok()?;
+        let mut path = tokio::fs::canonicalize(root.join(relative)).await.ok()?;
+
+        if tokio::fs::metadata(&path).await.ok()?.is_dir() {
+            path = tokio::fs::canonicalize(path.join(&self.index)).await.ok()?;
+        }
+
+        // Symlinks inside the root can still point outside of it
+        if !path.starts_with(&root) || !tokio::fs::metadata(&path).await.ok()?.is_file() {
+            return None;
+        }
+
+        Some(path)
+    }
+}
+
+/// Decodes the request path into a path relative to the root, rejecting anything that could
+/// escape it
+fn relative_path(request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(request_path).decode_utf8().ok()?;
+
+    // Windows style separators and NUL bytes are never valid in a request path for a file
+    if decoded.contains('\\') || decoded.contains('\0') {
+        return None;
+    }
+
+    let mut relative = PathBuf::new();
+
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(segment) => relative.push(segment),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(relative)
+}
+
+fn method_not_allowed() -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(StatusCode::METHOD_NOT_ALLOWED)
+        .header(header::ALLOW, "GET, HEAD")
+        .body(full("Method not allowed"))
+        // FIX: expect
+        .expect("Failed to build response")
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+
+    use super::*;
+
+    struct TempRoot(PathBuf);
+
+    impl TempRoot {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "bifrost-static-{}-{}",
+                std::process::id(),
+                rand::random::<u64>()
+            ));
+
+            std::fs::create_dir_all(path.join("public/assets")).unwrap();
+            std::fs::write(path.join("public/index.html"), "<h1>index</h1>").unwrap();
+            std::fs::write(path.join("public/assets/app.js"), "console.log(1)").unwrap();
+            std::fs::write(path.join("secret.txt"), "secret").unwrap();
+
+            Self(path)
+        }
+
+        fn service(&self) -> StaticFiles {
+            serde_yaml::from_str(&format!(
+                "{{ type: static, root: {} }}",
+                self.0.join("public").display()
+            ))
+            .unwrap()
+        }
+    }
+
+    impl Drop for TempRoot {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    async fn get(service: &StaticFiles, path: &str) -> (StatusCode, Option<String>, String) {
+        let req = Request::get(path).body(()).unwrap();
+        let response = service.send_request(req).await;
+
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|value| value.to_str().unwrap().to_owned());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+
+        (
+            status,
+            content_type,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn serves_files_with_content_type() {
+        let root = TempRoot::new();
+
+        let (status, content_type, body) = get(&root.service(), "/assets/app.js").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.as_deref(), Some("text/javascript"));
+        assert_eq!(body, "console.log(1)");
+    }
+
+    #[tokio::test]
+    async fn serves_directory_index() {
+        let root = TempRoot::new();
+
+        let (status, content_type, body) = get(&root.service(), "/").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type.as_deref(), Some("text/html"));
+        assert_eq!(body, "<h1>index</h1>");
+
+        // Directory without an index
+        let (status, _, _) = get(&root.service(), "/assets").await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn missing_file_is_not_found() {
+        let root = TempRoot::new();
+
+        let (status, _, _) = get(&root.service(), "/missing.css").await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn traversal_out_of_root_is_rejected() {
+        let root = TempRoot::new();
+
+        for path in [
+            "/../secret.txt",
+            "/assets/../../secret.txt",
+            "/%2e%2e/secret.txt",
+        ] {
+            let (status, _, _) = get(&root.service(), path).await;
+
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_get_and_head_are_allowed() {
+        let root = TempRoot::new();
+
+        let req = Request::post("/index.html").body(()).unwrap();
+        let response = root.service().send_request(req).await;
+
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let req = Request::head("/index.html").body(()).unwrap();
+        let response = root.service().send_request(req).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "14");
+    }
+
+    #[test]
+    fn relative_path_is_normalized() {
+        assert_eq!(
+            relative_path("/a/./b%20c/d.txt"),
+            Some(PathBuf::from("a/b c/d.txt"))
+        );
+        assert_eq!(relative_path("/a/../b"), None);
+        assert_eq!(relative_path("/a%5c..%5cb"), None);
+    }
+}
@@ -0,0 +1,228 @@
+use bytes::Bytes;
+use http::{header, HeaderValue, Method, Request, Response, StatusCode};
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, LengthLimitError, Limited};
+use hyper::body::Body;
+use serde::{Deserialize, Serialize};
+
+use super::route::HttpRoute;
+
+/// Methods bifrost answers on behalf of backends, for requests no rule of the route matches
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct SynthesizeConfig {
+    /// `HEAD` is sent to the rule matching it as a `GET`, the body of the response is dropped
+    #[serde(default)]
+    pub(crate) head: bool,
+    /// `OPTIONS` is answered with `204` listing the methods the rules match in `Allow`
+    #[serde(default)]
+    pub(crate) options: bool,
+}
+
+/// Longest body of unknown size that's read to count it, `Content-Length` is left out for
+/// longer ones
+const MAX_COUNTED_BODY: usize = 1024 * 1024;
+
+/// Methods `Allow` can list, `OPTIONS` is always there
+const PROBED_METHODS: [Method; 6] = [
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::PATCH,
+];
+
+/// Methods some rule of `route` would match `req` with, the method of `req` is restored after
+pub(crate) fn allowed_methods<B>(
+    route: &HttpRoute,
+    config: SynthesizeConfig,
+    req: &mut Request<B>,
+) -> Vec<Method> {
+    let original = req.method().clone();
+
+    let mut allowed: Vec<Method> = PROBED_METHODS
+        .into_iter()
+        .filter(|method| {
+            *req.method_mut() = method.clone();
+
+            route.find_matching_rule(req).is_some()
+        })
+        .collect();
+
+    *req.method_mut() = original;
+
+    if config.head && allowed.contains(&Method::GET) && !allowed.contains(&Method::HEAD) {
+        allowed.insert(1, Method::HEAD);
+    }
+
+    allowed
+}
+
+pub(crate) fn options(allowed: &[Method]) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let allow = allowed
+        .iter()
+        .chain([&Method::OPTIONS])
+        .map(Method::as_str)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut response = Response::new(empty());
+    *response.status_mut() = StatusCode::NO_CONTENT;
+
+    if let Ok(allow) = HeaderValue::from_str(&allow) {
+        response.headers_mut().insert(header::ALLOW, allow);
+    }
+
+    response
+}
+
+/// Drops the body of a response to `GET`, `Content-Length` is kept at the size of the body the
+/// way it would be for the `GET`. Bodies of unknown size are read to count them, up to
+/// `MAX_COUNTED_BODY`.
+pub(crate) async fn head(
+    response: Response<BoxBody<Bytes, hyper::Error>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let (mut parts, body) = response.into_parts();
+
+    if !parts.headers.contains_key(header::CONTENT_LENGTH) {
+        let length = match body.size_hint().exact() {
+            Some(length) => Some(length),
+            None => match Limited::new(body, MAX_COUNTED_BODY).collect().await {
+                Ok(collected) => Some(collected.to_bytes().len() as u64),
+                Err(err) if err.is::<LengthLimitError>() => {
+                    tracing::debug!("Body of a response to HEAD is too long to count");
+
+                    None
+                }
+                Err(err) => {
+                    tracing::warn!(error = %err, "Failed to read the body of a response to HEAD");
+
+                    None
+                }
+            },
+        };
+
+        if let Some(length) = length {
+            parts.headers.remove(header::TRANSFER_ENCODING);
+            parts.headers.insert(header::CONTENT_LENGTH, length.into());
+        }
+    }
+
+    Response::from_parts(parts, empty())
+}
+
+fn empty() -> BoxBody<Bytes, hyper::Error> {
+    Empty::new().map_err(|never| match never {}).boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, sync::Arc};
+
+    use futures::{stream, StreamExt};
+    use http_body_util::StreamBody;
+    use hyper::body::Frame;
+
+    use super::*;
+    use crate::server::{
+        host::HostSpec,
+        http::{path_index::PathIndex, route::HttpRule, server::full, service::HttpService},
+    };
+
+    fn route(methods: &[&str]) -> HttpRoute {
+        let service: HttpService =
+            serde_yaml::from_str("backends: [{ ip: 127.0.0.1, port: 1 }]").unwrap();
+        let service = Arc::new(service);
+
+        let rules: Vec<_> = methods
+            .iter()
+            .enumerate()
+            .map(|(index, method)| {
+                HttpRule::new(
+                    format!("test/{}", index),
+                    vec![serde_yaml::from_str(&format!("method: {}", method)).unwrap()],
+                    "test-service".to_owned(),
+                    service.clone(),
+                    Default::default(),
+                    None,
+                    None,
+                    vec![],
+                )
+            })
+            .collect();
+
+        HttpRoute {
+            name: "test".to_owned(),
+            hostnames: vec![HostSpec::from_str("test.com").unwrap()],
+            path_index: PathIndex::new(&rules),
+            rules,
+            cache: None,
+            grpc_web: false,
+            body_buffer: None,
+            synthesize: Default::default(),
+        }
+    }
+
+    #[test]
+    fn allowed_methods_are_the_ones_rules_match() {
+        let route = route(&["GET", "POST"]);
+        let mut req = Request::options("/").body(()).unwrap();
+
+        let allowed = allowed_methods(&route, SynthesizeConfig::default(), &mut req);
+
+        assert_eq!(allowed, [Method::GET, Method::POST]);
+        assert_eq!(req.method(), Method::OPTIONS);
+
+        let config = SynthesizeConfig {
+            head: true,
+            options: true,
+        };
+
+        assert_eq!(
+            allowed_methods(&route, config, &mut req),
+            [Method::GET, Method::HEAD, Method::POST]
+        );
+
+        let response = options(&allowed_methods(&route, config, &mut req));
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.headers()[header::ALLOW],
+            "GET, HEAD, POST, OPTIONS"
+        );
+    }
+
+    #[tokio::test]
+    async fn head_keeps_content_length_of_body() {
+        let response = head(Response::new(full("hello"))).await;
+
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "5");
+        assert!(response
+            .into_body()
+            .collect()
+            .await
+            .unwrap()
+            .to_bytes()
+            .is_empty());
+
+        // The length the backend sent is left alone
+        let response = Response::builder()
+            .header(header::CONTENT_LENGTH, "42")
+            .body(full(""))
+            .unwrap();
+
+        assert_eq!(head(response).await.headers()[header::CONTENT_LENGTH], "42");
+    }
+
+    #[tokio::test]
+    async fn head_leaves_out_content_length_of_long_bodies() {
+        let chunk = Bytes::from(vec![0; MAX_COUNTED_BODY / 2 + 1]);
+        let chunks = stream::iter([chunk.clone(), chunk])
+            .map(|chunk| Ok::<_, hyper::Error>(Frame::data(chunk)));
+        let response = Response::new(BodyExt::boxed(StreamBody::new(chunks)));
+
+        let response = head(response).await;
+
+        assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+    }
+}
@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use duration_string::DurationString;
+use serde::{Deserialize, Serialize};
+
+/// Used when the connect timeout isn't set at any level
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Timeouts of requests to backends. They can be set for all HTTP services under `http`, per
+/// service and per route rule, the most specific one wins: rule > service > `http`.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct TimeoutsConfig {
+    /// How long connecting to a single backend may take, retries get a timeout of their own.
+    /// 10 seconds when not set anywhere
+    pub(crate) connect: Option<DurationString>,
+    /// How long to wait for response headers once connected, unlimited when not set anywhere
+    pub(crate) request: Option<DurationString>,
+}
+
+impl TimeoutsConfig {
+    /// Fills whatever isn't set here from a less specific level
+    pub(crate) fn or(self, fallback: Self) -> Self {
+        Self {
+            connect: self.connect.or(fallback.connect),
+            request: self.request.or(fallback.request),
+        }
+    }
+}
+
+/// Timeouts a rule sends requests with, resolved from every level when the cluster is built
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Timeouts {
+    pub(crate) connect: Duration,
+    pub(crate) request: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        TimeoutsConfig::default().into()
+    }
+}
+
+impl From<TimeoutsConfig> for Timeouts {
+    fn from(config: TimeoutsConfig) -> Self {
+        Self {
+            connect: config
+                .connect
+                .map_or(DEFAULT_CONNECT_TIMEOUT, Duration::from),
+            request: config.request.map(Duration::from),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> TimeoutsConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn most_specific_level_wins() {
+        let global = config("{ connect: 5s, request: 30s }");
+        let service = config("{ request: 10s }");
+        let rule = config("{ request: 2s }");
+
+        let timeouts: Timeouts = rule.or(service).or(global).into();
+
+        assert_eq!(
+            timeouts,
+            Timeouts {
+                connect: Duration::from_secs(5),
+                request: Some(Duration::from_secs(2)),
+            }
+        );
+    }
+
+    #[test]
+    fn defaults_when_not_set_anywhere() {
+        assert_eq!(
+            Timeouts::default(),
+            Timeouts {
+                connect: DEFAULT_CONNECT_TIMEOUT,
+                request: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+
+/// Pending connections the kernel queues for a listener, same as tokio uses
+const BACKLOG: i32 = 1024;
+
+/// Address families a server listens on. By default there's a socket per family, the IPv6
+/// one with `IPV6_V6ONLY` set, so it behaves the same no matter what the system default is.
+/// Hosts without IPv6 only get the IPv4 one unless `ipv6` is set explicitly.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ListenFields {
+    #[serde(default = "ListenFields::enabled")]
+    pub(crate) ipv4: bool,
+    /// On by default, but only required when set
+    pub(crate) ipv6: Option<bool>,
+    /// When disabled the IPv6 socket takes IPv4 connections too, as IPv4-mapped addresses, on
+    /// systems that allow it. `ipv4` has to be disabled then, both sockets would need the
+    /// same IPv4 port otherwise.
+    #[serde(default = "ListenFields::enabled")]
+    pub(crate) ipv6_only: bool,
+}
+
+impl ListenFields {
+    fn enabled() -> bool {
+        true
+    }
+
+    pub(crate) fn listens_on_ipv6(&self) -> bool {
+        self.ipv6.unwrap_or(true)
+    }
+
+    /// Binds a listener for every enabled family on `port`
+    pub(crate) fn bind(&self, port: u16) -> io::Result<Vec<TcpListener>> {
+        let mut listeners = vec![];
+
+        if self.ipv4 {
+            listeners.push(bind((Ipv4Addr::UNSPECIFIED, port).into(), false)?);
+        }
+
+        if self.listens_on_ipv6() {
+            match bind((Ipv6Addr::UNSPECIFIED, port).into(), self.ipv6_only) {
+                Ok(listener) => listeners.push(listener),
+                // Servers used to listen on IPv4 only, they still start on hosts without IPv6
+                Err(err) if self.ipv6.is_none() && !listeners.is_empty() => tracing::warn!(
+                    port,
+                    error = %err,
+                    "Failed to listen on IPv6, listening on IPv4 only"
+                ),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(listeners)
+    }
+}
+
+impl Default for ListenFields {
+    fn default() -> Self {
+        Self {
+            ipv4: true,
+            ipv6: None,
+            ipv6_only: true,
+        }
+    }
+}
+
+/// Listener on a single address, `ipv6_only` only applies to IPv6 addresses
+pub(crate) fn bind(addr: SocketAddr, ipv6_only: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(ipv6_only)?;
+    }
+
+    // Lets a restarted server bind while connections of the previous one are in TIME_WAIT
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn both_families_are_served() {
+        let listeners = ListenFields::default().bind(0).unwrap();
+
+        assert_eq!(listeners.len(), 2);
+
+        for listener in &listeners {
+            let port = listener.local_addr().unwrap().port();
+            let ip = listener.local_addr().unwrap().ip();
+
+            let client = if ip.is_ipv4() {
+                TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await
+            } else {
+                TcpStream::connect((Ipv6Addr::LOCALHOST, port)).await
+            };
+
+            client.unwrap();
+            listener.accept().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn ipv6_only_socket_rejects_ipv4() {
+        let listeners = ListenFields {
+            ipv4: false,
+            ..Default::default()
+        }
+        .bind(0)
+        .unwrap();
+
+        let port = listeners[0].local_addr().unwrap().port();
+
+        assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn ipv6_by_default_is_skipped_when_it_cant_listen() {
+        // Takes the IPv6 port only
+        let taken = bind((Ipv6Addr::UNSPECIFIED, 0).into(), true).unwrap();
+        let port = taken.local_addr().unwrap().port();
+
+        let listeners = ListenFields::default().bind(port).unwrap();
+
+        assert_eq!(listeners.len(), 1);
+        assert!(listeners[0].local_addr().unwrap().is_ipv4());
+
+        drop(listeners);
+
+        let explicit = ListenFields {
+            ipv6: Some(true),
+            ..Default::default()
+        };
+
+        assert!(explicit.bind(port).is_err());
+    }
+
+    #[tokio::test]
+    async fn single_address_is_served() {
+        let listener = bind((Ipv4Addr::LOCALHOST, 0).into(), true).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        assert_eq!(addr.ip(), Ipv4Addr::LOCALHOST);
+
+        TcpStream::connect(addr).await.unwrap();
+        listener.accept().await.unwrap();
+    }
+}
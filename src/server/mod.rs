@@ -1,13 +1,26 @@
 pub(crate) mod host;
 pub(crate) mod http;
+pub(crate) mod listen;
 pub(crate) mod stream;
+pub(crate) mod tls;
+pub(crate) mod validation;
 
 use http::HttpConfig;
 use serde::{Deserialize, Serialize};
 use stream::StreamingConfig;
 
+use crate::{
+    admin::AdminConfig, request_log::RequestLogConfig, service::resolver::DnsConfig,
+    shutdown::ShutdownConfig, telemetry::TracingConfig,
+};
+
 #[derive(Deserialize, Serialize, Debug)]
 pub(crate) struct Config {
     pub(crate) stream: Option<StreamingConfig>,
     pub(crate) http: Option<HttpConfig>,
+    pub(crate) tracing: Option<TracingConfig>,
+    pub(crate) admin: Option<AdminConfig>,
+    pub(crate) request_log: Option<RequestLogConfig>,
+    pub(crate) dns: Option<DnsConfig>,
+    pub(crate) shutdown: Option<ShutdownConfig>,
 }
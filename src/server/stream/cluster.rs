@@ -2,9 +2,13 @@ use std::collections::HashMap;
 
 use futures::future::join_all;
 
-use crate::service::Service;
+use crate::{service::Service, shutdown::Shutdown};
 
-use super::{StreamServer, StreamServerConfig, StreamingConfig};
+use super::{
+    host_routing::{HostRoute, HostRoutes},
+    limit::ConnectionLimit,
+    StreamServer, StreamServerConfig, StreamingConfig,
+};
 
 pub(crate) struct StreamServerCluster {
     servers: Vec<StreamServer>,
@@ -18,6 +22,8 @@ impl StreamServerCluster {
             .map(|(name, config)| (name, Service::new(config)))
             .collect();
 
+        let connection_limit = config.connection_limit.as_ref().map(ConnectionLimit::new);
+
         let servers= config.servers.into_iter().map(|config| {
             let service_name = match &config {
                 StreamServerConfig::Tcp(config) => config.service.clone(),
@@ -30,15 +36,35 @@ impl StreamServerCluster {
                 .clone();
 
             match (config, service) {
-                (StreamServerConfig::Tcp(config), Service::Tcp(service)) => {
-                    StreamServer::tcp(config, service)
+                (StreamServerConfig::Tcp(mut config), Service::Tcp(service)) => {
+                    let host_routes = config.host_routing.take().map(|host_routing| HostRoutes {
+                        routes: host_routing
+                            .routes
+                            .into_iter()
+                            .map(|route| HostRoute {
+                                hostnames: route.hostnames,
+                                service: match services.get(&route.service) {
+                                    Some(Service::Tcp(service)) => service.clone(),
+                                    _ => panic!(
+                                        "Host routes of TCP server {} must use TCP services",
+                                        config.name
+                                    ),
+                                },
+                                service_name: route.service,
+                            })
+                            .collect(),
+                        fallback: host_routing
+                            .fallback
+                            .then(|| (config.service.clone(), service.clone())),
+                    });
+
+                    StreamServer::tcp(config, service, host_routes, connection_limit.clone())
                 }
                 (StreamServerConfig::Udp(config), Service::Udp(service)) => {
                     StreamServer::udp(config, service)
                 }
                 (server_config, service) => {
-                    // NOTE: What are we going to do when we have a dynamic configuration? Maybe some
-                    // pre-validation step?
+                    // Validation rejects these before the cluster is built
                     panic!(
                         "Invalid stream service config, server and an upstream service must use same protocol. Server is {:?}, service is {:?}",
                         server_config.get_protocol(),
@@ -51,7 +77,15 @@ impl StreamServerCluster {
         Self { servers }
     }
 
-    pub(crate) async fn run_all(self) -> Vec<Result<(), Box<dyn std::error::Error>>> {
-        join_all(self.servers.into_iter().map(StreamServer::run)).await
+    pub(crate) async fn run_all(
+        self,
+        shutdown: Shutdown,
+    ) -> Vec<Result<(), Box<dyn std::error::Error>>> {
+        join_all(
+            self.servers
+                .into_iter()
+                .map(|server| server.run(shutdown.clone())),
+        )
+        .await
     }
 }
@@ -0,0 +1,206 @@
+use std::{io, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{
+    server::host::{HostSpec, Hostname},
+    service::TcpService,
+};
+
+/// The head of the first request has to fit into this many bytes
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// How long a client gets to send the head of its first request
+const HEAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Picks the service for a TCP connection by the `Host` of the HTTP request it starts with.
+///
+/// Only the head of the first request is parsed, everything after it is relayed untouched, so
+/// the connection is pinned to that service for its whole life.
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct HostRoutingConfig {
+    pub(crate) routes: Vec<HostRouteConfig>,
+    /// Send connections that don't start with an HTTP request for a known host to the server's
+    /// `service`, they're dropped otherwise
+    #[serde(default)]
+    pub(crate) fallback: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct HostRouteConfig {
+    pub(crate) hostnames: Vec<HostSpec>,
+    pub(crate) service: String,
+}
+
+pub(crate) struct HostRoute {
+    pub(crate) hostnames: Vec<HostSpec>,
+    pub(crate) service_name: String,
+    pub(crate) service: TcpService,
+}
+
+pub(crate) struct HostRoutes {
+    pub(crate) routes: Vec<HostRoute>,
+    /// Name and service of the server's own service, when fallback is enabled
+    pub(crate) fallback: Option<(String, TcpService)>,
+}
+
+impl HostRoutes {
+    /// Name and service for a connection that started with `head`, `None` when it should be
+    /// dropped
+    pub(crate) fn select(&self, head: &[u8]) -> Option<(&str, &TcpService)> {
+        let routed = request_host(head).and_then(|host| {
+            self.routes
+                .iter()
+                .find(|route| route.hostnames.iter().any(|spec| spec.matches(&host)))
+        });
+
+        match routed {
+            Some(route) => Some((&route.service_name, &route.service)),
+            None => self
+                .fallback
+                .as_ref()
+                .map(|(name, service)| (name.as_str(), service)),
+        }
+    }
+}
+
+/// Reads from the client until the head of the first request is complete. Whatever has been
+/// read is returned even when it's not HTTP, so it can still be relayed to a fallback.
+pub(crate) async fn read_head<S>(client: &mut S) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = Vec::with_capacity(MAX_HEAD_SIZE);
+
+    let reading = async {
+        while head.len() < MAX_HEAD_SIZE && is_partial(&head) {
+            let mut chunk = [0; 1024];
+            let read = client.read(&mut chunk).await?;
+
+            if read == 0 {
+                break;
+            }
+
+            head.extend_from_slice(&chunk[..read]);
+        }
+
+        Ok::<_, io::Error>(())
+    };
+
+    // A client that's too slow is routed with what it sent so far
+    if let Ok(result) = tokio::time::timeout(HEAD_TIMEOUT, reading).await {
+        result?;
+    }
+
+    Ok(head)
+}
+
+fn is_partial(head: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+
+    matches!(
+        httparse::Request::new(&mut headers).parse(head),
+        Ok(httparse::Status::Partial)
+    )
+}
+
+/// Host of the request `head` starts with, `None` when it's not a complete HTTP request head
+fn request_host(head: &[u8]) -> Option<Hostname> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+
+    if !request.parse(head).ok()?.is_complete() {
+        return None;
+    }
+
+    let host = request
+        .headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("host"))?;
+    let host = std::str::from_utf8(host.value).ok()?;
+
+    // The port doesn't take part in routing
+    let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+
+    Hostname::from_str(&host.to_ascii_lowercase()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::service::config::ServiceConfigFields;
+
+    use super::*;
+
+    fn service(port: u16) -> TcpService {
+        let config: ServiceConfigFields =
+            serde_yaml::from_str(&format!("backends: [{{ ip: 127.0.0.1, port: {} }}]", port))
+                .unwrap();
+
+        TcpService::new(config)
+    }
+
+    fn routes(fallback: bool) -> HostRoutes {
+        HostRoutes {
+            routes: vec![HostRoute {
+                hostnames: vec![HostSpec::from_str("*.example.com").unwrap()],
+                service_name: "routed".to_owned(),
+                service: service(1),
+            }],
+            fallback: fallback.then(|| ("fallback".to_owned(), service(2))),
+        }
+    }
+
+    fn selected_port(routes: &HostRoutes, head: &[u8]) -> Option<u16> {
+        routes
+            .select(head)
+            .map(|(_, service)| service.config.backends[0].port)
+    }
+
+    #[test]
+    fn routes_by_host_header() {
+        let head = b"GET / HTTP/1.1\r\nHost: api.example.com:8080\r\n\r\n";
+
+        assert_eq!(selected_port(&routes(false), head), Some(1));
+    }
+
+    #[test]
+    fn unknown_host_uses_fallback() {
+        let head = b"GET / HTTP/1.1\r\nHost: other.com\r\n\r\n";
+
+        assert_eq!(selected_port(&routes(true), head), Some(2));
+        assert_eq!(selected_port(&routes(false), head), None);
+    }
+
+    #[test]
+    fn not_http_uses_fallback() {
+        let head = b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03";
+
+        assert_eq!(selected_port(&routes(true), head), Some(2));
+        assert_eq!(selected_port(&routes(false), head), None);
+    }
+
+    #[tokio::test]
+    async fn head_is_read_up_to_the_end_of_headers() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+
+            client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+            client
+                .write_all(b"Host: a.example.com\r\n\r\n")
+                .await
+                .unwrap();
+
+            // Keeps the connection open, the head is complete without it
+            std::future::pending::<()>().await;
+        });
+
+        let head = read_head(&mut server).await.unwrap();
+
+        assert_eq!(head, b"GET / HTTP/1.1\r\nHost: a.example.com\r\n\r\n");
+    }
+}
@@ -0,0 +1,219 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+/// Cap on TCP connections relayed at once by all stream servers together
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ConnectionLimitConfig {
+    pub(crate) max_connections: usize,
+    #[serde(default)]
+    pub(crate) when_exhausted: WhenExhausted,
+}
+
+/// What happens to a connection accepted while the limit is reached
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum WhenExhausted {
+    /// Wait for another connection to finish. The server stops accepting meanwhile, so further
+    /// clients wait in the listen backlog
+    #[default]
+    Hold,
+    /// Close the connection right away
+    Close,
+}
+
+/// Shared by every TCP server of the cluster
+#[derive(Clone)]
+pub(crate) struct ConnectionLimit {
+    permits: Arc<Semaphore>,
+    when_exhausted: WhenExhausted,
+}
+
+impl ConnectionLimit {
+    pub(crate) fn new(config: &ConnectionLimitConfig) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(config.max_connections)),
+            when_exhausted: config.when_exhausted,
+        }
+    }
+
+    /// Slot for a connection, held until the relay ends. `None` means the connection has to be
+    /// closed.
+    pub(crate) async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        match self.when_exhausted {
+            WhenExhausted::Hold => self.permits.clone().acquire_owned().await.ok(),
+            WhenExhausted::Close => self.permits.clone().try_acquire_owned().ok(),
+        }
+    }
+}
+
+/// Cap on how fast a TCP server takes new connections, so a connection storm reaches the
+/// backends spread out. Unlike the connection limit it doesn't care how many are open.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct AcceptRateConfig {
+    /// New connections a second over time
+    pub(crate) per_second: f64,
+    /// Connections that can be taken at once after a quiet period, a second's worth when not
+    /// set
+    pub(crate) burst: Option<u32>,
+    #[serde(default)]
+    pub(crate) when_exceeded: WhenExceeded,
+}
+
+/// What happens to a connection accepted over the rate
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum WhenExceeded {
+    /// Wait until the rate allows it. The server stops accepting meanwhile, so further clients
+    /// wait in the listen backlog
+    #[default]
+    Delay,
+    /// Close the connection right away
+    Close,
+}
+
+/// Token bucket of a single TCP server, every connection takes a token out and tokens are put
+/// back at the configured rate
+pub(crate) struct AcceptRate {
+    config: AcceptRateConfig,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl AcceptRate {
+    pub(crate) fn new(config: &AcceptRateConfig) -> Self {
+        let rate = Self {
+            config: config.clone(),
+            tokens: 0.0,
+            refilled: Instant::now(),
+        };
+
+        Self {
+            tokens: rate.burst(),
+            ..rate
+        }
+    }
+
+    /// Takes a token for a new connection, waiting for one unless the server closes the
+    /// connections over the rate. `false` means the connection has to be closed.
+    pub(crate) async fn admit(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens < 1.0 {
+            match self.config.when_exceeded {
+                WhenExceeded::Close => return false,
+                WhenExceeded::Delay => {
+                    let missing = 1.0 - self.tokens;
+
+                    tokio::time::sleep(Duration::from_secs_f64(missing / self.config.per_second))
+                        .await;
+
+                    self.refill();
+                }
+            }
+        }
+
+        // Sleeping for the missing share may leave a hair under a whole token
+        self.tokens = (self.tokens - 1.0).max(0.0);
+
+        true
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let refilled = (now - self.refilled).as_secs_f64() * self.config.per_second;
+
+        self.tokens = (self.tokens + refilled).min(self.burst());
+        self.refilled = now;
+    }
+
+    fn burst(&self) -> f64 {
+        // A burst under a single connection would never let any through
+        let burst = match self.config.burst {
+            Some(burst) => burst as f64,
+            None => self.config.per_second,
+        };
+
+        burst.max(1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn limit(yaml: &str) -> ConnectionLimit {
+        ConnectionLimit::new(&serde_yaml::from_str(yaml).unwrap())
+    }
+
+    #[tokio::test]
+    async fn exhausted_limit_closes() {
+        let limit = limit("{ max-connections: 1, when-exhausted: close }");
+
+        let permit = limit.acquire().await;
+
+        assert!(permit.is_some());
+        assert!(limit.acquire().await.is_none());
+
+        drop(permit);
+
+        assert!(limit.acquire().await.is_some());
+    }
+
+    fn rate(yaml: &str) -> AcceptRate {
+        AcceptRate::new(&serde_yaml::from_str(yaml).unwrap())
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_rate_are_closed() {
+        let mut rate = rate("{ per-second: 20, burst: 2, when-exceeded: close }");
+
+        assert!(rate.admit().await);
+        assert!(rate.admit().await);
+        assert!(!rate.admit().await);
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        assert!(rate.admit().await);
+        assert!(!rate.admit().await);
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_rate_are_delayed() {
+        let mut rate = rate("{ per-second: 20, burst: 1 }");
+
+        let started = Instant::now();
+
+        assert!(rate.admit().await);
+        assert!(started.elapsed() < Duration::from_millis(10));
+
+        assert!(rate.admit().await);
+        assert!(rate.admit().await);
+
+        // A token comes back every 50ms
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn exhausted_limit_holds_until_a_connection_ends() {
+        let limit = limit("{ max-connections: 1 }");
+
+        let permit = limit.acquire().await;
+
+        let waiting = tokio::time::timeout(Duration::from_millis(50), limit.acquire()).await;
+
+        assert!(waiting.is_err());
+
+        drop(permit);
+
+        assert!(limit.acquire().await.is_some());
+    }
+}
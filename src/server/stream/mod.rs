@@ -1,28 +1,110 @@
 pub(crate) mod cluster;
-mod tcp;
-mod udp;
+pub(crate) mod host_routing;
+pub(crate) mod limit;
+mod proxy_protocol;
+#[cfg(target_os = "linux")]
+mod splice;
+pub(crate) mod tcp;
+pub(crate) mod udp;
 
 use duration_string::DurationString;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+};
 
+use host_routing::{HostRoutes, HostRoutingConfig};
+use limit::{AcceptRateConfig, ConnectionLimit, ConnectionLimitConfig};
 use tcp::TcpServer;
 use udp::UdpServer;
 
 use crate::protocol::StreamProtocol;
 use crate::service::config::StreamServiceConfig;
-use crate::service::{TcpService, UdpService};
+use crate::{
+    service::{TcpService, UdpService},
+    shutdown::Shutdown,
+};
+
+/// Relay buffer sizes in bytes for each direction, so asymmetric traffic (e.g. large downloads
+/// and small uploads) doesn't have to pay for the bigger buffer both ways.
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct RelayBuffers {
+    /// Size of both buffers, the sizes of each direction take precedence over it
+    pub(crate) buffer_size: Option<usize>,
+    pub(crate) client_to_upstream_buffer: Option<usize>,
+    pub(crate) upstream_to_client_buffer: Option<usize>,
+}
+
+impl RelayBuffers {
+    pub(crate) fn client_to_upstream(&self, default: usize) -> usize {
+        self.client_to_upstream_buffer
+            .or(self.buffer_size)
+            .unwrap_or(default)
+    }
+
+    pub(crate) fn upstream_to_client(&self, default: usize) -> usize {
+        self.upstream_to_client_buffer
+            .or(self.buffer_size)
+            .unwrap_or(default)
+    }
+
+    /// Smallest of the configured sizes
+    pub(crate) fn smallest(&self) -> Option<usize> {
+        [
+            self.buffer_size,
+            self.client_to_upstream_buffer,
+            self.upstream_to_client_buffer,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Nothing could be relayed through an empty buffer
+    pub(crate) fn has_empty(&self) -> bool {
+        [
+            self.buffer_size,
+            self.client_to_upstream_buffer,
+            self.upstream_to_client_buffer,
+        ]
+        .contains(&Some(0))
+    }
+}
+
+/// Stream servers listen on every IPv4 address unless they set one
+fn listen_address(address: Option<IpAddr>) -> IpAddr {
+    address.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+}
 
 #[derive(Deserialize, Serialize, Debug)]
 pub(crate) struct TcpFields {
     pub(crate) port: u16,
+    /// Only listen on this address, e.g. `127.0.0.1`, instead of every IPv4 address
+    pub(crate) address: Option<IpAddr>,
     pub(crate) name: String,
+    /// With host routing this is only where connections that weren't routed go
     pub(crate) service: String,
+    /// Pick the service by the `Host` of the HTTP request a connection starts with
+    pub(crate) host_routing: Option<HostRoutingConfig>,
+    #[serde(flatten)]
+    pub(crate) buffers: RelayBuffers,
+    /// Relay with `splice`, so bytes aren't copied through user space. Only Linux supports it,
+    /// elsewhere connections are relayed through buffers as usual. The buffer sizes cap how much
+    /// is moved at once.
+    #[serde(default)]
+    pub(crate) zero_copy: bool,
+    /// How fast new connections are taken, however many are open
+    pub(crate) accept_rate: Option<AcceptRateConfig>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub(crate) struct UdpFields {
     pub(crate) port: u16,
+    /// Only listen on this address, e.g. `127.0.0.1`, instead of every IPv4 address
+    pub(crate) address: Option<IpAddr>,
     pub(crate) name: String,
     pub(crate) service: String,
 
@@ -35,6 +117,8 @@ pub(crate) struct UdpFields {
     /// (NOTE: what to do when ports run out is there a
     /// way to use the same port and underrstand which messages are for which peers?)
     pub(crate) biderectional_connection_ttl: Option<DurationString>,
+    #[serde(flatten)]
+    pub(crate) buffers: RelayBuffers,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -49,6 +133,8 @@ pub(crate) enum StreamServerConfig {
 pub(crate) struct StreamingConfig {
     pub(crate) servers: Vec<StreamServerConfig>,
     pub(crate) services: HashMap<String, StreamServiceConfig>,
+    /// Unlimited when not set
+    pub(crate) connection_limit: Option<ConnectionLimitConfig>,
 }
 
 impl StreamServerConfig {
@@ -61,23 +147,101 @@ impl StreamServerConfig {
 }
 
 pub(crate) enum StreamServer {
-    Tcp(TcpServer),
+    /// Boxed as it's much larger, and there are only a few servers
+    Tcp(Box<TcpServer>),
     Udp(UdpServer),
 }
 
 impl StreamServer {
-    pub(crate) fn tcp(config: TcpFields, service: TcpService) -> Self {
-        Self::Tcp(TcpServer { config, service })
+    pub(crate) fn tcp(
+        config: TcpFields,
+        service: TcpService,
+        host_routes: Option<HostRoutes>,
+        connection_limit: Option<ConnectionLimit>,
+    ) -> Self {
+        Self::Tcp(Box::new(TcpServer {
+            config,
+            service,
+            host_routes: host_routes.map(Arc::new),
+            connection_limit,
+        }))
     }
 
     pub(crate) fn udp(config: UdpFields, service: UdpService) -> Self {
         Self::Udp(UdpServer::new(config, service))
     }
 
-    pub(crate) async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+    pub(crate) async fn run(self, shutdown: Shutdown) -> Result<(), Box<dyn std::error::Error>> {
         match self {
-            StreamServer::Tcp(server) => server.run().await,
-            StreamServer::Udp(server) => server.run().await,
+            StreamServer::Tcp(server) => (*server).run(shutdown).await,
+            StreamServer::Udp(server) => server.run(shutdown).await,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::service::config::SendProxyProtocol;
+
+    use super::*;
+
+    #[test]
+    fn relay_buffers_are_parsed_per_direction() {
+        let config: StreamServerConfig = serde_yaml::from_str(
+            "
+            name: tcp-server
+            port: 8082
+            protocol: tcp
+            service: tcp-service
+            client-to-upstream-buffer: 1024
+            ",
+        )
+        .unwrap();
+
+        let StreamServerConfig::Tcp(fields) = config else {
+            panic!("expected a TCP server");
+        };
+
+        assert_eq!(fields.buffers.client_to_upstream(4096), 1024);
+        assert_eq!(fields.buffers.upstream_to_client(4096), 4096);
+    }
+
+    #[test]
+    fn buffer_size_sets_both_directions() {
+        let buffers: RelayBuffers =
+            serde_yaml::from_str("{ buffer-size: 512, upstream-to-client-buffer: 1024 }").unwrap();
+
+        assert_eq!(buffers.client_to_upstream(4096), 512);
+        assert_eq!(buffers.upstream_to_client(4096), 1024);
+        assert!(!buffers.has_empty());
+        assert_eq!(buffers.smallest(), Some(512));
+
+        let buffers: RelayBuffers = serde_yaml::from_str("buffer-size: 0").unwrap();
+
+        assert!(buffers.has_empty());
+    }
+
+    #[test]
+    fn udp_services_can_send_proxy_protocol() {
+        let config: StreamServiceConfig = serde_yaml::from_str(
+            "
+            protocol: udp
+            send-proxy-protocol: every-datagram
+            backends:
+            - { ip: 127.0.0.1, port: 53 }
+            ",
+        )
+        .unwrap();
+
+        let service = UdpService::new(match config {
+            StreamServiceConfig::Udp(config) => config,
+            StreamServiceConfig::Tcp(_) => panic!("expected a UDP service"),
+        });
+
+        assert_eq!(
+            service.send_proxy_protocol,
+            Some(SendProxyProtocol::EveryDatagram)
+        );
+        assert_eq!(service.config.backends.len(), 1);
+    }
+}
@@ -0,0 +1,93 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+/// Every v2 header starts with it, so backends can tell it apart from v1 and from payload
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Version 2, `PROXY` command
+const VERSION_COMMAND: u8 = 0x21;
+
+const AF_INET: u8 = 0x10;
+const AF_INET6: u8 = 0x20;
+const DGRAM: u8 = 0x02;
+
+/// PROXY protocol v2 header for a datagram `client` sent to `destination`.
+///
+/// Datagrams can't be split or joined like a stream, so the header goes in front of the payload
+/// of the same datagram. When the two addresses are of different families the IPv4 one is sent
+/// as IPv4-mapped IPv6.
+pub(crate) fn v2_datagram_header(client: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    header.push(VERSION_COMMAND);
+
+    let addresses = match (client.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            header.push(AF_INET | DGRAM);
+
+            [source.octets(), destination.octets()].concat()
+        }
+        (source, destination) => {
+            header.push(AF_INET6 | DGRAM);
+
+            [ipv6(source).octets(), ipv6(destination).octets()].concat()
+        }
+    };
+
+    let length = (addresses.len() + 4) as u16;
+
+    header.extend_from_slice(&length.to_be_bytes());
+    header.extend_from_slice(&addresses);
+    header.extend_from_slice(&client.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+
+    header
+}
+
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv4_header() {
+        let header = v2_datagram_header(
+            "192.168.1.10:53000".parse().unwrap(),
+            "10.0.0.1:53".parse().unwrap(),
+        );
+
+        assert_eq!(
+            header,
+            [
+                &SIGNATURE[..],
+                &[0x21, 0x12, 0, 12],
+                &[192, 168, 1, 10, 10, 0, 0, 1],
+                &53000u16.to_be_bytes(),
+                &53u16.to_be_bytes(),
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn mixed_families_use_ipv6() {
+        let header = v2_datagram_header(
+            "[2001:db8::1]:4000".parse().unwrap(),
+            "0.0.0.0:53".parse().unwrap(),
+        );
+
+        assert_eq!(header[13], 0x22);
+        assert_eq!(u16::from_be_bytes([header[14], header[15]]), 36);
+        assert_eq!(header.len(), 16 + 36);
+
+        // Destination is IPv4-mapped
+        assert_eq!(
+            header[32..48],
+            "::ffff:0.0.0.0".parse::<Ipv6Addr>().unwrap().octets()
+        );
+    }
+}
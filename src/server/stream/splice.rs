@@ -0,0 +1,234 @@
+use std::{
+    io,
+    net::Shutdown,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+};
+
+use socket2::SockRef;
+use tokio::{io::Interest, net::TcpStream};
+
+use crate::metrics::{ByteCounter, RelayCounters};
+
+/// Pipe the bytes of one direction go through. `splice` moves them from the socket into the
+/// pipe and from the pipe into the other socket without copying them to user space.
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds: [RawFd; 2] = [0; 2];
+
+        // SAFETY: `fds` has room for both ends pipe2 writes
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // SAFETY: both ends were just opened and nothing else owns them
+        unsafe {
+            Ok(Self {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+/// Pipes for both directions of a connection
+pub(crate) struct Pipes {
+    client_to_upstream: Pipe,
+    upstream_to_client: Pipe,
+}
+
+impl Pipes {
+    /// Fails when the process is out of file descriptors, the caller falls back to buffers then
+    pub(crate) fn new() -> io::Result<Self> {
+        Ok(Self {
+            client_to_upstream: Pipe::new()?,
+            upstream_to_client: Pipe::new()?,
+        })
+    }
+}
+
+/// Same as the buffered [`relay`](super::tcp::relay), but bytes stay in the kernel. At most
+/// `client_to_upstream_chunk` and `upstream_to_client_chunk` bytes are moved at once.
+pub(crate) async fn relay(
+    client: &TcpStream,
+    upstream: &TcpStream,
+    pipes: Pipes,
+    client_to_upstream_chunk: usize,
+    upstream_to_client_chunk: usize,
+    counters: &RelayCounters,
+) -> io::Result<()> {
+    let client_to_upstream = async {
+        relay_one_way(
+            client,
+            upstream,
+            &pipes.client_to_upstream,
+            client_to_upstream_chunk,
+            &counters.client_to_upstream,
+        )
+        .await?;
+
+        tracing::debug!("Peer is done sending, shutting down the upstream for writing");
+
+        SockRef::from(upstream).shutdown(Shutdown::Write)
+    };
+
+    let upstream_to_client = async {
+        relay_one_way(
+            upstream,
+            client,
+            &pipes.upstream_to_client,
+            upstream_to_client_chunk,
+            &counters.upstream_to_client,
+        )
+        .await?;
+
+        tracing::debug!("Upstream is done sending, shutting down the peer for writing");
+
+        SockRef::from(client).shutdown(Shutdown::Write)
+    };
+
+    tokio::try_join!(client_to_upstream, upstream_to_client)?;
+
+    Ok(())
+}
+
+/// Moves bytes from `from` to `to` until `from` is closed
+async fn relay_one_way(
+    from: &TcpStream,
+    to: &TcpStream,
+    pipe: &Pipe,
+    chunk: usize,
+    counter: &ByteCounter,
+) -> io::Result<()> {
+    loop {
+        // The pipe is always drained below, so it's never full here and WouldBlock can only
+        // mean the socket has nothing to read
+        let received = from
+            .async_io(Interest::READABLE, || {
+                splice(from.as_raw_fd(), pipe.write.as_raw_fd(), chunk)
+            })
+            .await?;
+
+        if received == 0 {
+            return Ok(());
+        }
+
+        let mut pending = received;
+
+        while pending > 0 {
+            let sent = to
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), to.as_raw_fd(), pending)
+                })
+                .await?;
+
+            if sent == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+
+            pending -= sent;
+        }
+
+        counter.inc_by(received as u64);
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    // SAFETY: both descriptors are open for the duration of the call, and null offsets make
+    // splice use and advance their own positions
+    let moved = unsafe {
+        libc::splice(
+            from,
+            ptr::null_mut(),
+            to,
+            ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+
+    if moved == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(moved as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::metrics::metrics;
+
+    use super::*;
+
+    /// Both ends of a fresh TCP connection
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connecting = TcpStream::connect(listener.local_addr().unwrap());
+
+        let (connected, accepted) = tokio::join!(connecting, listener.accept());
+
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn relays_both_ways_until_both_sides_are_done() {
+        let (mut client, client_side) = connection().await;
+        let (upstream_side, mut upstream) = connection().await;
+
+        let counters = metrics().relay_counters("splice-test");
+        let sent_before = counters.client_to_upstream.get();
+
+        let relaying = tokio::spawn(async move {
+            relay(
+                &client_side,
+                &upstream_side,
+                Pipes::new().unwrap(),
+                16,
+                16,
+                &metrics().relay_counters("splice-test"),
+            )
+            .await
+        });
+
+        let mut buffer = [0; 64];
+
+        // Bigger than a chunk, so it takes a few splices
+        client
+            .write_all(b"ping from the client side")
+            .await
+            .unwrap();
+        upstream.read_exact(&mut buffer[..25]).await.unwrap();
+        assert_eq!(&buffer[..25], b"ping from the client side");
+
+        upstream.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buffer[..4]).await.unwrap();
+        assert_eq!(&buffer[..4], b"pong");
+
+        client.shutdown().await.unwrap();
+
+        // The upstream was shut down as well, and can still answer
+        assert_eq!(upstream.read(&mut buffer).await.unwrap(), 0);
+
+        upstream.write_all(b"bye").await.unwrap();
+        drop(upstream);
+
+        let mut answer = vec![];
+        client.read_to_end(&mut answer).await.unwrap();
+
+        assert_eq!(answer, b"bye");
+
+        relaying.await.unwrap().unwrap();
+
+        assert_eq!(counters.client_to_upstream.get() - sent_before, 25);
+    }
+}
@@ -1,104 +1,928 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpListener,
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
 };
 
-use crate::service::TcpService;
+use crate::{
+    connection_events::{events, ConnectionProtocol},
+    metrics::{metrics, RelayCounters},
+    server::listen,
+    service::{pool::ConnectionPool, BackendSelection, TcpService},
+    shutdown::ConnectionTracker,
+};
 
-use super::TcpFields;
+use super::{
+    host_routing::{read_head, HostRoutes},
+    limit::{AcceptRate, ConnectionLimit},
+    listen_address, TcpFields,
+};
 
 // This buffer size is closest to the size of a memory page in most systems.
 // Ideally we can read the actual size using a package, but for now this is good enough.
-// Also it's possible to make it configurable.
-const DEFAULT_BUFFER_SIZE: usize = 4 * 1024; // 2KB
+// Servers can set their own with `buffer-size`.
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 4 * 1024; // 4KB
 
 // TODO: TLS and TLS routing https://gateway-api.sigs.k8s.io/reference/spec/
 pub(crate) struct TcpServer {
     pub(crate) config: TcpFields,
     pub(crate) service: TcpService,
+    pub(crate) host_routes: Option<Arc<HostRoutes>>,
+    /// Shared with the other TCP servers of the cluster
+    pub(crate) connection_limit: Option<ConnectionLimit>,
 }
 
 impl TcpServer {
-    pub(crate) async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Relays until `shutdown` completes, then stops accepting and waits for the connections
+    /// being relayed to finish
+    pub(crate) async fn run(
+        self,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         let fields = &self.config;
 
-        let listener = TcpListener::bind(("0.0.0.0", fields.port)).await?;
+        let listener = listen::bind((listen_address(fields.address), fields.port).into(), true)?;
+
+        let client_to_upstream_buffer = fields.buffers.client_to_upstream(DEFAULT_BUFFER_SIZE);
+        let upstream_to_client_buffer = fields.buffers.upstream_to_client(DEFAULT_BUFFER_SIZE);
+        let zero_copy = fields.zero_copy;
+
+        if zero_copy && cfg!(not(target_os = "linux")) {
+            tracing::warn!(
+                server = %fields.name,
+                "Zero-copy relay isn't supported on this system, relaying through buffers"
+            );
+        }
 
-        println!("Listening for TCP on port {}", fields.port);
+        tracing::info!(
+            server = %fields.name,
+            address = %listener.local_addr()?,
+            "Listening for TCP"
+        );
+
+        let connections = ConnectionTracker::new();
+
+        // Only this listener takes tokens out, so the bucket lives with the accept loop
+        let mut accept_rate = fields.accept_rate.as_ref().map(AcceptRate::new);
+
+        tokio::pin!(shutdown);
 
         loop {
-            let (stream, _) = listener.accept().await?;
-            let mut upstream = self.service.get_connection().await?;
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = &mut shutdown => break,
+            };
 
-            let peer_addr = stream.peer_addr()?;
+            // A connection that fails before it's accepted doesn't stop the listener
+            let (stream, peer_addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    tracing::warn!(
+                        server = %fields.name,
+                        error = %err,
+                        "Failed to accept a connection"
+                    );
+                    continue;
+                }
+            };
 
-            println!("Accepted connection from {}", peer_addr);
+            tracing::info!(server = %fields.name, peer = %peer_addr, "Accepted connection");
 
-            tokio::spawn(async move {
-                let mut peer_stream = stream;
-                let mut buffer_client = [0; DEFAULT_BUFFER_SIZE];
-                let mut buffer_upstream = [0; DEFAULT_BUFFER_SIZE];
-
-                // TODO: fix unwraps?
-                loop {
-                    let bytes_from_client = peer_stream.read(&mut buffer_client);
-                    let bytes_from_upstream = upstream.read(&mut buffer_upstream);
-
-                    // Bidirectional listen implemented as a race of messeages from two sources
-                    // on every iteration. This works because read() is cancel safe and if one of
-                    // the futures wins the race it's guaranteed that the other one has not read
-                    // the stream so no bytes are lost.
-                    tokio::select! {
-                        // Listen for client messages and send them to upstream
-                        bytes_from_client = bytes_from_client => {
-                            let bytes_from_client = bytes_from_client.unwrap();
-                            if bytes_from_client == 0 {
-                                println!(
-                                    "Peer {} disconnected closing connection to upstream",
-                                    peer_addr
-                                );
-
-                                upstream.shutdown().await.unwrap();
-                                break;
-                            }
+            // Delaying here holds up the accept loop, so the connections after this one wait
+            // in the listen backlog. A slow rate can hold it up for long, so shutdown isn't
+            // waited out.
+            if let Some(accept_rate) = &mut accept_rate {
+                let admitted = tokio::select! {
+                    admitted = accept_rate.admit() => admitted,
+                    _ = &mut shutdown => break,
+                };
 
-                            println!(
-                                "Received {} bytes from client, sending to upstream {}",
-                                bytes_from_client,
-                                upstream.peer_addr().unwrap()
-                            );
+                if !admitted {
+                    tracing::warn!(
+                        server = %fields.name,
+                        peer = %peer_addr,
+                        "Accept rate exceeded, closing connection"
+                    );
+                    continue;
+                }
+            }
+
+            // Held by the relay task, the slot frees up when the connection ends
+            let permit = match &self.connection_limit {
+                Some(limit) => match limit.acquire().await {
+                    Some(permit) => Some(permit),
+                    None => {
+                        tracing::warn!(
+                            server = %fields.name,
+                            peer = %peer_addr,
+                            "Connection limit reached, closing connection"
+                        );
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            // Reported closed when it's dropped along with the relay
+            let connection = events().opened(&fields.name, ConnectionProtocol::Tcp, peer_addr);
 
-                            upstream.write_all(&buffer_client[..bytes_from_client]).await.unwrap();
+            let counters = match &connection {
+                Some(connection) => {
+                    connection.relay_counters(metrics().relay_counters(&fields.name))
+                }
+                None => metrics().relay_counters(&fields.name),
+            };
+
+            if let Some(host_routes) = &self.host_routes {
+                let host_routes = host_routes.clone();
+                let name = fields.name.clone();
+
+                let open = connections.track();
+
+                // Reading the head can take a while, so it's done off the accept loop
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    let _open = open;
+                    let mut peer_stream = stream;
+
+                    let head = match read_head(&mut peer_stream).await {
+                        Ok(head) => head,
+                        Err(err) => {
+                            tracing::debug!(peer = %peer_addr, error = %err, "Failed to read from peer");
+                            return;
+                        }
+                    };
 
-                            println!("Sent");
+                    let Some((service_name, service)) = host_routes.select(&head) else {
+                        tracing::debug!(peer = %peer_addr, "No service for connection, dropping it");
+                        return;
+                    };
 
-                        },
-                        // Listen for upstream messages and send them to client
-                        bytes_from_upstream = bytes_from_upstream => {
-                            let bytes_from_upstream = bytes_from_upstream.unwrap();
+                    let (mut upstream, selection) = match service.get_connection().await {
+                        Ok((upstream, selection)) => {
+                            log_selection(&name, service_name, peer_addr, &selection);
 
-                            if bytes_from_upstream == 0 {
-                                println!(
-                                    "Upstream {} disconnected closing connection to peer",
-                                    peer_addr
-                                );
-                                peer_stream.shutdown().await.unwrap();
-                                break;
+                            if let Some(connection) = &connection {
+                                connection.backend_selected(&selection.address);
                             }
 
-                            println!(
-                                "Received {} bytes from upstream, sending to client",
-                                bytes_from_upstream
+                            (upstream, selection)
+                        }
+                        Err(err) => {
+                            tracing::warn!(
+                                server = %name,
+                                service = service_name,
+                                peer = %peer_addr,
+                                error = %err,
+                                "Failed to connect to upstream, dropping connection"
                             );
-
-                            peer_stream
-                                .write_all(&buffer_upstream[..bytes_from_upstream])
-                                .await
-                                .unwrap();
+                            return;
                         }
+                    };
+
+                    metrics().stream_connection(&name, service_name, &selection.address);
+
+                    let relayed = async {
+                        // What was read to route the connection is the start of the stream
+                        upstream.write_all(&head).await?;
+                        counters.client_to_upstream.inc_by(head.len() as u64);
+
+                        relay_upstream(
+                            &mut peer_stream,
+                            (upstream, selection),
+                            service.pool(),
+                            (client_to_upstream_buffer, upstream_to_client_buffer),
+                            zero_copy,
+                            &counters,
+                        )
+                        .await
+                    };
+
+                    if let Err(err) = relayed.await {
+                        tracing::warn!(peer = %peer_addr, error = %err, "Relay failed");
                     }
+                });
+
+                continue;
+            }
+
+            let upstream = match self.service.get_connection().await {
+                Ok((upstream, selection)) => {
+                    log_selection(&fields.name, &fields.service, peer_addr, &selection);
+
+                    if let Some(connection) = &connection {
+                        connection.backend_selected(&selection.address);
+                    }
+
+                    (upstream, selection)
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        server = %fields.name,
+                        service = %fields.service,
+                        peer = %peer_addr,
+                        error = %err,
+                        "Failed to connect to upstream, dropping connection"
+                    );
+                    continue;
+                }
+            };
+
+            metrics().stream_connection(&fields.name, &fields.service, &upstream.1.address);
+
+            let pool = self.service.pool().cloned();
+            let open = connections.track();
+
+            tokio::spawn(async move {
+                let _permit = permit;
+                let _open = open;
+                let _connection = connection;
+                let mut peer_stream = stream;
+
+                if let Err(err) = relay_upstream(
+                    &mut peer_stream,
+                    upstream,
+                    pool.as_ref(),
+                    (client_to_upstream_buffer, upstream_to_client_buffer),
+                    zero_copy,
+                    &counters,
+                )
+                .await
+                {
+                    tracing::warn!(peer = %peer_addr, error = %err, "Relay failed");
+                }
+            });
+        }
+
+        drop(listener);
+
+        tracing::info!(
+            server = %fields.name,
+            connections = connections.open(),
+            "Draining TCP connections"
+        );
+
+        connections.closed().await;
+
+        Ok(())
+    }
+}
+
+/// One line per connection, for telling how connections are spread over the backends
+fn log_selection(server: &str, service: &str, peer: SocketAddr, selection: &BackendSelection) {
+    tracing::info!(
+        server,
+        service,
+        %peer,
+        backend = %selection.address,
+        index = selection.index,
+        algorithm = ?selection.algorithm,
+        failed_over = selection.failed_over,
+        reused = selection.reused,
+        "Selected backend"
+    );
+}
+
+/// Relays a TCP connection, upstreams of a pool go back to it when the client disconnects
+/// first after the upstream answered its last bytes and nothing more is waiting to be read.
+/// Otherwise the rest of the response still goes to the client and the upstream is shut down.
+/// Pooled upstreams are always relayed through buffers, `splice` can't leave the upstream open.
+/// `buffers` are the client to upstream and upstream to client sizes.
+async fn relay_upstream(
+    client: &mut TcpStream,
+    (mut upstream, selection): (TcpStream, BackendSelection),
+    pool: Option<&ConnectionPool>,
+    (client_to_upstream_buffer, upstream_to_client_buffer): (usize, usize),
+    zero_copy: bool,
+    counters: &RelayCounters,
+) -> io::Result<()> {
+    let Some(pool) = pool else {
+        return relay_connection(
+            client,
+            &mut upstream,
+            client_to_upstream_buffer,
+            upstream_to_client_buffer,
+            zero_copy,
+            counters,
+        )
+        .await;
+    };
+
+    let closed = relay_until_closed(
+        client,
+        &mut upstream,
+        client_to_upstream_buffer,
+        upstream_to_client_buffer,
+        counters,
+    )
+    .await?;
+
+    let answered = match closed {
+        Closed::Client { answered } => answered,
+        Closed::Upstream => return client.shutdown().await,
+    };
+
+    let mut unread = [0; 1];
+
+    let read = match upstream.try_read(&mut unread) {
+        Err(err) if answered && err.kind() == io::ErrorKind::WouldBlock => {
+            pool.release(upstream, selection);
+
+            return Ok(());
+        }
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => 0,
+        read => read?,
+    };
+
+    // The client may only have closed its half, it still gets the rest of the response and the
+    // upstream, left in the middle of an exchange, isn't reused
+    let mut client = CountedClient {
+        stream: client,
+        counters,
+    };
+
+    client.write_all(&unread[..read]).await?;
+    upstream.shutdown().await?;
+
+    let mut upstream = io::BufReader::with_capacity(upstream_to_client_buffer, upstream);
+    io::copy_buf(&mut upstream, &mut client).await?;
+
+    client.shutdown().await
+}
+
+/// Relays a TCP connection with `splice` when `zero_copy` is set and the system supports it,
+/// through buffers otherwise
+async fn relay_connection(
+    client: &mut TcpStream,
+    upstream: &mut TcpStream,
+    client_to_upstream_buffer: usize,
+    upstream_to_client_buffer: usize,
+    zero_copy: bool,
+    counters: &RelayCounters,
+) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    if zero_copy {
+        match super::splice::Pipes::new() {
+            Ok(pipes) => {
+                return super::splice::relay(
+                    client,
+                    upstream,
+                    pipes,
+                    client_to_upstream_buffer,
+                    upstream_to_client_buffer,
+                    counters,
+                )
+                .await;
+            }
+            Err(err) => tracing::warn!(
+                error = %err,
+                "Failed to create pipes, relaying through buffers"
+            ),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = zero_copy;
+
+    relay(
+        client,
+        upstream,
+        client_to_upstream_buffer,
+        upstream_to_client_buffer,
+        counters,
+    )
+    .await
+}
+
+/// Relays bytes between the client and the upstream. When either of them is done sending, the
+/// other one is shut down for writing and can still send until it's done too.
+pub(crate) async fn relay<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    client_to_upstream_buffer: usize,
+    upstream_to_client_buffer: usize,
+    counters: &RelayCounters,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client = CountedClient {
+        stream: client,
+        counters,
+    };
+
+    let (to_upstream, to_client) = io::copy_bidirectional_with_sizes(
+        &mut client,
+        upstream,
+        client_to_upstream_buffer,
+        upstream_to_client_buffer,
+    )
+    .await?;
+
+    tracing::info!(to_upstream, to_client, "Connection closed");
+
+    Ok(())
+}
+
+/// Client of a relay, counts the bytes as they go through so long connections show up in the
+/// metrics before they end
+struct CountedClient<'a, C> {
+    stream: &'a mut C,
+    counters: &'a RelayCounters,
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for CountedClient<'_, C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut *self.stream).poll_read(cx, buf);
+
+        self.counters
+            .client_to_upstream
+            .inc_by((buf.filled().len() - before) as u64);
+
+        polled
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for CountedClient<'_, C> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let polled = Pin::new(&mut *self.stream).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(written)) = polled {
+            self.counters.upstream_to_client.inc_by(written as u64);
+        }
+
+        polled
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.stream).poll_shutdown(cx)
+    }
+}
+
+/// Side of a relayed connection that disconnected first
+enum Closed {
+    /// `answered` when the upstream sent something after the last bytes of the client
+    Client {
+        answered: bool,
+    },
+    Upstream,
+}
+
+/// Relays bytes between the client and the upstream until either of them disconnects, the
+/// other one is left open
+async fn relay_until_closed<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    client_to_upstream_buffer: usize,
+    upstream_to_client_buffer: usize,
+    counters: &RelayCounters,
+) -> io::Result<Closed>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buffer_client = vec![0; client_to_upstream_buffer];
+    let mut buffer_upstream = vec![0; upstream_to_client_buffer];
+    let mut answered = true;
+
+    loop {
+        let bytes_from_client = client.read(&mut buffer_client);
+        let bytes_from_upstream = upstream.read(&mut buffer_upstream);
+
+        // Bidirectional listen implemented as a race of messeages from two sources
+        // on every iteration. This works because read() is cancel safe and if one of
+        // the futures wins the race it's guaranteed that the other one has not read
+        // the stream so no bytes are lost.
+        tokio::select! {
+            // Listen for client messages and send them to upstream
+            bytes_from_client = bytes_from_client => {
+                let bytes_from_client = bytes_from_client?;
+
+                if bytes_from_client == 0 {
+                    return Ok(Closed::Client { answered });
                 }
+
+                tracing::trace!(bytes = bytes_from_client, "Relaying from client to upstream");
+
+                upstream.write_all(&buffer_client[..bytes_from_client]).await?;
+                answered = false;
+
+                counters.client_to_upstream.inc_by(bytes_from_client as u64);
+            },
+            // Listen for upstream messages and send them to client
+            bytes_from_upstream = bytes_from_upstream => {
+                let bytes_from_upstream = bytes_from_upstream?;
+
+                if bytes_from_upstream == 0 {
+                    return Ok(Closed::Upstream);
+                }
+
+                tracing::trace!(bytes = bytes_from_upstream, "Relaying from upstream to client");
+
+                client.write_all(&buffer_upstream[..bytes_from_upstream]).await?;
+                answered = true;
+
+                counters.upstream_to_client.inc_by(bytes_from_upstream as u64);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{net::TcpListener, sync::oneshot};
+    use tracing_test::traced_test;
+
+    use crate::service::{
+        config::{LoadBalancingAlgorithm, ServiceConfigFields},
+        pool::ConnectionPoolConfig,
+    };
+
+    use super::*;
+
+    /// Both ends of a fresh TCP connection
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let (connected, accepted) = tokio::join!(
+            TcpStream::connect(listener.local_addr().unwrap()),
+            listener.accept()
+        );
+
+        (connected.unwrap(), accepted.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn upstream_goes_back_to_the_pool_when_the_client_leaves() {
+        let (mut client, mut client_side) = connection().await;
+        let (upstream_side, mut upstream) = connection().await;
+
+        let config: ConnectionPoolConfig = serde_yaml::from_str("max-idle: 1").unwrap();
+        let pool = ConnectionPool::new(&config);
+        let selection = BackendSelection {
+            algorithm: LoadBalancingAlgorithm::RoundRobin,
+            index: 0,
+            address: upstream.local_addr().unwrap().to_string(),
+            failed_over: 0,
+            reused: false,
+        };
+
+        let relay_pool = pool.clone();
+        let relaying = tokio::spawn(async move {
+            relay_upstream(
+                &mut client_side,
+                (upstream_side, selection),
+                Some(&relay_pool),
+                (16, 16),
+                false,
+                &metrics().relay_counters("pool-test"),
+            )
+            .await
+        });
+
+        client.write_all(b"ping").await.unwrap();
+
+        let mut buffer = [0; 4];
+        upstream.read_exact(&mut buffer).await.unwrap();
+        upstream.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut buffer).await.unwrap();
+
+        assert_eq!(&buffer, b"pong");
+
+        drop(client);
+        relaying.await.unwrap().unwrap();
+
+        // The upstream is still connected, it serves the next client
+        let (mut reused, selection) = pool.take().unwrap();
+
+        assert!(selection.reused);
+
+        reused.write_all(b"next").await.unwrap();
+        upstream.read_exact(&mut buffer).await.unwrap();
+
+        assert_eq!(&buffer, b"next");
+    }
+
+    #[tokio::test]
+    async fn half_closed_client_gets_the_rest_of_the_response() {
+        let (mut client, mut client_side) = connection().await;
+        let (upstream_side, mut upstream) = connection().await;
+
+        let config: ConnectionPoolConfig = serde_yaml::from_str("max-idle: 1").unwrap();
+        let pool = ConnectionPool::new(&config);
+        let selection = BackendSelection {
+            algorithm: LoadBalancingAlgorithm::RoundRobin,
+            index: 0,
+            address: upstream.local_addr().unwrap().to_string(),
+            failed_over: 0,
+            reused: false,
+        };
+
+        let relay_pool = pool.clone();
+        let relaying = tokio::spawn(async move {
+            relay_upstream(
+                &mut client_side,
+                (upstream_side, selection),
+                Some(&relay_pool),
+                (16, 16),
+                false,
+                &metrics().relay_counters("pool-test"),
+            )
+            .await
+        });
+
+        // The client is done sending before the response started
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut buffer = [0; 4];
+        upstream.read_exact(&mut buffer).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        upstream.write_all(b"po").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        upstream.write_all(b"ng").await.unwrap();
+
+        // Shut down instead of going back to the pool with the exchange unfinished
+        let closed = tokio::time::timeout(Duration::from_secs(1), upstream.read(&mut buffer));
+
+        assert_eq!(closed.await.unwrap().unwrap(), 0);
+        drop(upstream);
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+
+        assert_eq!(response, b"pong");
+
+        relaying.await.unwrap().unwrap();
+
+        assert!(pool.take().is_none());
+    }
+
+    #[tokio::test]
+    async fn bytes_round_trip_through_an_echo_upstream() {
+        let (mut client, mut client_side) = connection().await;
+        let (mut upstream_side, mut upstream) = connection().await;
+
+        // Echoes everything back, then closes once the client is done sending
+        tokio::spawn(async move {
+            let (mut read, mut write) = upstream.split();
+            io::copy(&mut read, &mut write).await.unwrap();
+            write.shutdown().await.unwrap();
+        });
+
+        let counters = metrics().relay_counters("echo-test");
+        let relay_counters = counters.clone();
+        let relaying = tokio::spawn(async move {
+            relay(
+                &mut client_side,
+                &mut upstream_side,
+                16,
+                16,
+                &relay_counters,
+            )
+            .await
+        });
+
+        let sent: Vec<u8> = (0..100).collect();
+        client.write_all(&sent).await.unwrap();
+
+        // Done sending, the echo still comes back over the half-closed connection
+        client.shutdown().await.unwrap();
+
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, sent);
+
+        relaying.await.unwrap().unwrap();
+
+        assert_eq!(counters.client_to_upstream.get(), 100);
+        assert_eq!(counters.upstream_to_client.get(), 100);
+    }
+
+    #[tokio::test]
+    async fn half_closed_client_gets_the_answer() {
+        for zero_copy in [false, true] {
+            let (mut client, mut client_side) = connection().await;
+            let (mut upstream_side, mut upstream) = connection().await;
+
+            // Only answers once the client is done sending
+            tokio::spawn(async move {
+                let mut request = vec![];
+                upstream.read_to_end(&mut request).await.unwrap();
+                upstream.write_all(&request).await.unwrap();
             });
+
+            let relaying = tokio::spawn(async move {
+                relay_connection(
+                    &mut client_side,
+                    &mut upstream_side,
+                    16,
+                    16,
+                    zero_copy,
+                    &metrics().relay_counters("half-close-test"),
+                )
+                .await
+            });
+
+            let sent: Vec<u8> = (0..100).collect();
+            client.write_all(&sent).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            let mut received = vec![];
+            client.read_to_end(&mut received).await.unwrap();
+
+            assert_eq!(received, sent, "zero copy: {}", zero_copy);
+
+            relaying.await.unwrap().unwrap();
         }
     }
+
+    /// Port of a backend that echoes every connection back until the client is done sending
+    async fn echo_backend() -> u16 {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = backend.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut upstream, _) = backend.accept().await.unwrap();
+
+                tokio::spawn(async move {
+                    let (mut read, mut write) = upstream.split();
+                    io::copy(&mut read, &mut write).await.unwrap();
+                    write.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        port
+    }
+
+    /// Server relaying to the echo backend on `backend_port`, and the port it listens on
+    async fn echo_server(backend_port: u16) -> (TcpServer, u16) {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let config = serde_yaml::from_str(&format!(
+            "{{ name: echo-server, port: {}, address: 127.0.0.1, service: echo }}",
+            port
+        ))
+        .unwrap();
+        let service: ServiceConfigFields = serde_yaml::from_str(&format!(
+            "backends: [{{ ip: 127.0.0.1, port: {} }}]",
+            backend_port
+        ))
+        .unwrap();
+
+        let server = TcpServer {
+            config,
+            service: TcpService::new(service),
+            host_routes: None,
+            connection_limit: None,
+        };
+
+        (server, port)
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn accepted_connections_are_logged_with_the_peer() {
+        let (server, port) = echo_server(echo_backend().await).await;
+
+        let running = server.run(std::future::pending());
+        tokio::pin!(running);
+
+        let client = tokio::select! {
+            biased;
+            _ = &mut running => panic!("server stopped"),
+            client = async {
+                let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                let mut buffer = [0; 4];
+
+                client.write_all(b"ping").await.unwrap();
+                client.read_exact(&mut buffer).await.unwrap();
+
+                client
+            } => client,
+        };
+
+        assert!(logs_contain("Accepted connection"));
+        assert!(logs_contain(&format!(
+            "peer={}",
+            client.local_addr().unwrap()
+        )));
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_connections_in_flight_finish() {
+        let (server, port) = echo_server(echo_backend().await).await;
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = server.run(async {
+            let _ = stopped.await;
+        });
+        tokio::pin!(running);
+
+        let mut client = tokio::select! {
+            biased;
+            _ = &mut running => panic!("server stopped before shutdown"),
+            client = async {
+                let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                let mut buffer = [0; 4];
+
+                client.write_all(b"ping").await.unwrap();
+                client.read_exact(&mut buffer).await.unwrap();
+                assert_eq!(&buffer, b"ping");
+
+                stop.send(()).unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+
+                // Nothing accepts new connections anymore
+                assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+
+                // The connection accepted before is still relayed
+                client.write_all(b"pong").await.unwrap();
+                client.read_exact(&mut buffer).await.unwrap();
+                assert_eq!(&buffer, b"pong");
+
+                client
+            } => client,
+        };
+
+        client.shutdown().await.unwrap();
+
+        let mut rest = vec![];
+        client.read_to_end(&mut rest).await.unwrap();
+
+        // The server is done once its last connection is
+        tokio::time::timeout(Duration::from_secs(1), running)
+            .await
+            .expect("server kept running after its connections finished")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_isnt_held_up_by_the_accept_rate() {
+        let (mut server, port) = echo_server(echo_backend().await).await;
+        server.config.accept_rate =
+            Some(serde_yaml::from_str("{ per-second: 0.01, burst: 1 }").unwrap());
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let running = server.run(async {
+            let _ = stopped.await;
+        });
+        tokio::pin!(running);
+
+        tokio::select! {
+            biased;
+            _ = &mut running => panic!("server stopped before shutdown"),
+            _ = async {
+                let mut client = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                let mut buffer = [0; 4];
+
+                client.write_all(b"ping").await.unwrap();
+                client.read_exact(&mut buffer).await.unwrap();
+                client.shutdown().await.unwrap();
+                client.read_to_end(&mut vec![]).await.unwrap();
+
+                // The next token is 100s away, the server waits for it
+                let _delayed = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            } => {}
+        };
+
+        stop.send(()).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(1), running)
+            .await
+            .expect("server kept waiting for the accept rate after shutdown")
+            .unwrap();
+    }
 }
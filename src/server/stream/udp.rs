@@ -1,20 +1,44 @@
-use super::UdpFields;
+use super::{listen_address, UdpFields};
 use std::collections::hash_map::Entry;
 use std::time::{Duration, Instant};
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex as SyncMutex},
+};
 
 use duration_string::DurationString;
+use prometheus::IntCounter;
+use socket2::Type;
 use tokio::net::UdpSocket;
 use tokio::sync::{oneshot, Mutex};
 
-use crate::service::UdpService;
+use crate::{
+    connection_events::{events, ConnectionProtocol, TrackedConnection},
+    metrics::{metrics, RelayCounters},
+    service::{
+        config::{bound_socket, SendProxyProtocol},
+        UdpService,
+    },
+};
 
-const DEFAULT_BUFFER_SIZE: usize = 8 * 1024; // 8KB
+use super::proxy_protocol;
+
+/// Largest payload of a UDP datagram over IPv4. Buffers are at least this big, a datagram that
+/// doesn't fit is truncated.
+pub(crate) const MAX_DATAGRAM_SIZE: usize = 65507;
 
 pub(crate) struct UdpServer {
     pub(crate) port: u16,
+    pub(crate) address: IpAddr,
+    pub(crate) name: String,
+    /// Name of the service in the config, for metrics
+    pub(crate) service_name: String,
 
-    pub(crate) service: UdpService,
+    /// Shared with the sessions, which report errors of their backends to it
+    pub(crate) service: Arc<UdpService>,
 
     /// Time during which the server is going to be holding a biderectional connection.
     ///
@@ -27,53 +51,113 @@ pub(crate) struct UdpServer {
     /// (NOTE: what to do when ports run out is there a way to use the same port and
     /// underrstand which messages are for which peers?)
     pub(crate) biderectional_connection_ttl: Duration,
+
+    pub(crate) client_to_upstream_buffer: usize,
+    pub(crate) upstream_to_client_buffer: usize,
 }
 
 impl UdpServer {
     pub(crate) fn new(config: UdpFields, service: UdpService) -> Self {
         Self {
             port: config.port,
-            service,
+            address: listen_address(config.address),
+            name: config.name,
+            service_name: config.service,
+            service: Arc::new(service),
 
             biderectional_connection_ttl: config
                 .biderectional_connection_ttl
                 .map_or(Duration::from_secs(10), DurationString::into),
+
+            client_to_upstream_buffer: config.buffers.client_to_upstream(MAX_DATAGRAM_SIZE),
+            upstream_to_client_buffer: config.buffers.upstream_to_client(MAX_DATAGRAM_SIZE),
         }
     }
 }
 
+/// Backend a session relays to
+#[derive(Clone)]
+struct Upstream {
+    address: SocketAddr,
+    /// Index of the backend in the service
+    index: usize,
+    service: Arc<UdpService>,
+    errors: IntCounter,
+}
+
+impl Upstream {
+    /// Errors are only reported on connected sockets, e.g. `ConnectionRefused` after an ICMP
+    /// port unreachable. They count against the backend until it's ejected.
+    fn failed(&self, err: &io::Error) {
+        tracing::warn!(backend = %self.address, error = %err, "Error relaying to UDP backend");
+
+        self.errors.inc();
+        self.service.record_outcome(self.index, false);
+    }
+
+    fn answered(&self) {
+        self.service.record_outcome(self.index, true);
+    }
+}
+
 struct UdpConnection {
     client: SocketAddr,
+    /// Connected to the upstream, only its datagrams and errors are received on it
     receiver_socket: Arc<UdpSocket>,
-    upstream_address: SocketAddr,
+    upstream: Upstream,
     server: Arc<UdpSocket>,
     close_tx: Option<oneshot::Sender<()>>,
     is_serving: bool,
+    buffer_size: usize,
+    counters: RelayCounters,
+    /// PROXY protocol header with the client address, `None` once it doesn't need to be sent
+    /// anymore
+    proxy_header: Option<(SendProxyProtocol, Vec<u8>)>,
+    /// Reported closed when the session is
+    _tracked: Option<TrackedConnection>,
 
     // NOTE: Maybe it makes sense to separate this into a separate struct
     // that owns simple UdpConnection
-    last_activity: Arc<Mutex<Instant>>,
+    //
+    // This lock is never held across an await, so the reaper can check staleness without
+    // yielding while it holds the client map.
+    last_activity: Arc<SyncMutex<Instant>>,
     time_to_live: Duration,
 }
 
 struct UdpConnectionBuilder {
     client: SocketAddr,
-    upstream_address: SocketAddr,
+    upstream: Upstream,
     server: Arc<UdpSocket>,
 
     time_to_live: Duration,
+    buffer_size: usize,
+    counters: RelayCounters,
+    proxy_header: Option<(SendProxyProtocol, Vec<u8>)>,
+    source_address: Option<IpAddr>,
+    tracked: Option<TrackedConnection>,
 }
 
 impl UdpConnectionBuilder {
     const DEFAULT_TIME_TO_LIVE: Duration = Duration::from_secs(10);
 
-    fn new(client: SocketAddr, upstream_address: SocketAddr, server: Arc<UdpSocket>) -> Self {
+    fn new(
+        client: SocketAddr,
+        upstream: Upstream,
+        server: Arc<UdpSocket>,
+        counters: RelayCounters,
+    ) -> Self {
         Self {
             client,
-            upstream_address,
+            upstream,
             server,
+            counters,
 
             time_to_live: Self::DEFAULT_TIME_TO_LIVE,
+            buffer_size: MAX_DATAGRAM_SIZE,
+            proxy_header: None,
+            source_address: None,
+            tracked: None,
         }
     }
 
@@ -83,32 +167,92 @@ impl UdpConnectionBuilder {
         self
     }
 
-    async fn build(self) -> UdpConnection {
-        UdpConnection {
+    /// Size of the buffer for messages coming back from upstream
+    fn buffer_size(&mut self, size: usize) -> &mut Self {
+        self.buffer_size = size;
+
+        self
+    }
+
+    /// Prepend a PROXY protocol v2 header with the client address to datagrams sent upstream.
+    /// `destination` is the address the client sent its datagrams to.
+    fn proxy_protocol(&mut self, mode: SendProxyProtocol, destination: SocketAddr) -> &mut Self {
+        let header = proxy_protocol::v2_datagram_header(self.client, destination);
+
+        self.proxy_header = Some((mode, header));
+
+        self
+    }
+
+    /// Local IP datagrams to the upstream are sent from
+    fn source_address(&mut self, source: IpAddr) -> &mut Self {
+        self.source_address = Some(source);
+
+        self
+    }
+
+    /// Counts the bytes of the session for its lifecycle events
+    fn track(&mut self, connection: TrackedConnection) -> &mut Self {
+        self.counters = connection.relay_counters(self.counters.clone());
+        self.tracked = Some(connection);
+
+        self
+    }
+
+    async fn build(self) -> io::Result<UdpConnection> {
+        let receiver_socket = match self.source_address {
+            Some(source) => UdpSocket::from_std(bound_socket(source, Type::DGRAM)?.into())?,
+            None if self.upstream.address.is_ipv6() => UdpSocket::bind("[::]:0").await?,
+            None => UdpSocket::bind("0.0.0.0:0").await?,
+        };
+
+        receiver_socket.connect(self.upstream.address).await?;
+
+        Ok(UdpConnection {
             client: self.client,
-            // FIX: unwrap
-            receiver_socket: Arc::new(UdpSocket::bind("0.0.0.0:0").await.unwrap()),
-            upstream_address: self.upstream_address,
+            receiver_socket: Arc::new(receiver_socket),
+            upstream: self.upstream,
             server: self.server,
             close_tx: None,
             is_serving: false,
+            buffer_size: self.buffer_size,
+            counters: self.counters,
+            proxy_header: self.proxy_header,
+            _tracked: self.tracked,
 
-            last_activity: Arc::new(Mutex::new(Instant::now())),
+            last_activity: Arc::new(SyncMutex::new(Instant::now())),
             time_to_live: self.time_to_live,
-        }
+        })
     }
 }
 
 impl UdpConnection {
-    async fn relay_client_message(&self, message: Vec<u8>) {
-        {
-            *self.last_activity.lock().await = Instant::now();
+    async fn relay_client_message(&mut self, message: Vec<u8>) {
+        self.touch();
+
+        let sent = match &self.proxy_header {
+            Some((_, header)) => {
+                let datagram = [header.as_slice(), &message].concat();
+
+                self.receiver_socket.send(&datagram).await
+            }
+            None => self.receiver_socket.send(&message).await,
+        };
+
+        // The datagram is lost, the client retries if it cares
+        if let Err(err) = sent {
+            self.upstream.failed(&err);
+
+            return;
         }
 
-        self.receiver_socket
-            .send_to(&message, self.upstream_address)
-            .await
-            .unwrap();
+        if let Some((SendProxyProtocol::FirstDatagram, _)) = self.proxy_header {
+            self.proxy_header = None;
+        }
+
+        self.counters
+            .client_to_upstream
+            .inc_by(message.len() as u64);
     }
 
     fn serve_bidirectional(&mut self) {
@@ -116,12 +260,14 @@ impl UdpConnection {
             return;
         }
 
-        let mut buffer = [0; DEFAULT_BUFFER_SIZE];
+        let mut buffer = vec![0; self.buffer_size];
         let receiver_socket = self.receiver_socket.clone();
-        let upstream_address = self.upstream_address;
+        let upstream = self.upstream.clone();
+        let upstream_address = upstream.address;
         let client = self.client;
         let server = self.server.clone();
         let last_activity = self.last_activity.clone();
+        let counters = self.counters.clone();
 
         let (close_tx, close_rx) = oneshot::channel();
         self.close_tx = Some(close_tx);
@@ -129,42 +275,61 @@ impl UdpConnection {
         self.is_serving = true;
 
         tokio::spawn(async move {
-            println!(
-                "Serving bidirectional connection for {} and {}",
-                client, upstream_address
+            tracing::debug!(
+                peer = %client,
+                backend = %upstream_address,
+                "Serving bidirectional connection"
             );
 
             tokio::pin!(close_rx);
 
             loop {
                 tokio::select! {
-                    result = receiver_socket.recv_from(&mut buffer) => {
+                    result = receiver_socket.recv(&mut buffer) => {
                         match result {
-                            Ok((bytes_read, peer_addr)) => {
-                                if peer_addr != upstream_address {
-                                    println!("Received message from an unknown peer. Skipping the message.");
+                            Ok(bytes_read) => {
+                                *last_activity.lock().unwrap() = Instant::now();
+                                upstream.answered();
 
-                                    continue;
-                                }
+                                if let Err(err) = server.send_to(&buffer[..bytes_read], client).await {
+                                    tracing::debug!(
+                                        peer = %client,
+                                        error = %err,
+                                        "Failed to send message to peer"
+                                    );
 
-                                {
-                                    *last_activity.lock().await = Instant::now();
+                                    continue;
                                 }
 
-                                println!("Received message from {}", peer_addr);
+                                counters.upstream_to_client.inc_by(bytes_read as u64);
 
-                                server.send_to(&buffer[..bytes_read], client).await.unwrap();
-
-                                println!("Sent message to {}", client);
+                                tracing::trace!(
+                                    peer = %client,
+                                    backend = %upstream_address,
+                                    bytes = bytes_read,
+                                    "Relayed message from upstream to peer"
+                                );
+                            }
+                            // Reported for an earlier datagram, the socket is still usable
+                            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                                upstream.failed(&e);
                             }
                             Err(e) => {
-                                eprintln!("Error receiving from upstream: {}", e);
+                                tracing::warn!(
+                                    backend = %upstream_address,
+                                    error = %e,
+                                    "Error receiving from upstream"
+                                );
                                 break;
                             }
                         }
                     }
                     _ = &mut close_rx => {
-                        println!("Connection {} to {} is closing", client, upstream_address);
+                        tracing::debug!(
+                            peer = %client,
+                            backend = %upstream_address,
+                            "Connection is closing"
+                        );
                         break;
                     }
                 }
@@ -172,23 +337,31 @@ impl UdpConnection {
         });
     }
 
-    fn close(mut self) {
+    fn close(&mut self) {
         if let Some(close_tx) = self.close_tx.take() {
             let _ = close_tx.send(()); // Send the close signal
         }
     }
 
-    async fn is_stale(&self) -> bool {
-        self.last_activity.lock().await.elapsed() > self.time_to_live
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    fn is_stale(&self, now: Instant) -> bool {
+        now.duration_since(*self.last_activity.lock().unwrap()) > self.time_to_live
     }
 }
 
 impl UdpServer {
-    pub(crate) async fn run(self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Relays until `shutdown` completes, then stops taking messages from clients. The sessions
+    /// keep relaying upstream answers until they go stale.
+    pub(crate) async fn run(
+        self,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         let client_map: Arc<Mutex<HashMap<SocketAddr, UdpConnection>>> =
             Arc::new(Mutex::new(HashMap::new()));
-        let server_socket = Arc::new(UdpSocket::bind(("0.0.0.0", self.port)).await?);
-        let port = self.port;
+        let server_socket = Arc::new(UdpSocket::bind((self.address, self.port)).await?);
 
         let client_map_clone = client_map.clone();
 
@@ -198,29 +371,38 @@ impl UdpServer {
             loop {
                 sec.tick().await;
 
-                let mut client_map = client_map_clone.lock().await;
-                let vec: Vec<SocketAddr> = client_map.keys().map(SocketAddr::clone).collect();
+                let now = Instant::now();
 
-                for addr in vec {
-                    if client_map.get(&addr).unwrap().is_stale().await {
-                        println!("Closing connection to {}", addr);
-                        if let Some(connection) = client_map.remove(&addr) {
-                            connection.close();
-                        }
+                client_map_clone.lock().await.retain(|addr, connection| {
+                    if !connection.is_stale(now) {
+                        return true;
                     }
-                }
+
+                    tracing::debug!(peer = %addr, "Closing stale connection");
+                    connection.close();
+
+                    false
+                });
             }
         });
 
-        println!("Listening for UDP on port {}", port);
+        tracing::info!(
+            server = %self.name,
+            address = %server_socket.local_addr()?,
+            "Listening for UDP"
+        );
 
-        loop {
-            let mut buffer = [0; DEFAULT_BUFFER_SIZE];
-            let (bytes_read, peer_addr) = server_socket.recv_from(&mut buffer).await?;
+        let mut buffer = vec![0; self.client_to_upstream_buffer];
+
+        tokio::pin!(shutdown);
 
-            let upstream_address = self.service.get_address();
+        loop {
+            let (bytes_read, peer_addr) = tokio::select! {
+                received = server_socket.recv_from(&mut buffer) => received?,
+                _ = &mut shutdown => break,
+            };
 
-            println!("Received {} bytes from {}", bytes_read, peer_addr);
+            tracing::trace!(peer = %peer_addr, bytes = bytes_read, "Received message");
 
             let client_map = client_map.clone();
             let server_socket = server_socket.clone();
@@ -236,15 +418,77 @@ impl UdpServer {
                         .await;
                 }
                 Entry::Vacant(entry) => {
+                    let (index, address) = match self.service.get_address().await {
+                        Ok(picked) => picked,
+                        Err(err) => {
+                            tracing::warn!(
+                                server = %self.name,
+                                peer = %peer_addr,
+                                error = %err,
+                                "Dropping message"
+                            );
+                            continue;
+                        }
+                    };
+
+                    let backend = self.service.config.backends[index].address();
+
+                    metrics().stream_connection(&self.name, &self.service_name, &backend);
+
+                    tracing::info!(
+                        server = %self.name,
+                        peer = %peer_addr,
+                        %backend,
+                        "Opened session"
+                    );
+
+                    let upstream = Upstream {
+                        address,
+                        index,
+                        service: self.service.clone(),
+                        errors: metrics().udp_backend_errors(&self.service_name, &backend),
+                    };
+
                     let mut builder = UdpConnectionBuilder::new(
                         peer_addr,
-                        upstream_address,
+                        upstream,
                         server_socket.clone(),
+                        metrics().relay_counters(&self.name),
                     );
 
-                    builder.time_to_live(self.biderectional_connection_ttl);
+                    builder
+                        .time_to_live(self.biderectional_connection_ttl)
+                        .buffer_size(self.upstream_to_client_buffer);
 
-                    let mut new_connection = builder.build().await;
+                    if let Some(connection) =
+                        events().opened(&self.name, ConnectionProtocol::Udp, peer_addr)
+                    {
+                        connection.backend_selected(&backend);
+                        builder.track(connection);
+                    }
+
+                    // Without knowing which local address the datagram came to, the listen
+                    // address is sent, e.g. `0.0.0.0` with the server port
+                    if let Some(source) = self.service.config.source_address {
+                        builder.source_address(source);
+                    }
+
+                    if let Some(mode) = self.service.send_proxy_protocol {
+                        builder.proxy_protocol(mode, server_socket.local_addr()?);
+                    }
+
+                    let mut new_connection = match builder.build().await {
+                        Ok(connection) => connection,
+                        Err(err) => {
+                            tracing::warn!(
+                                server = %self.name,
+                                peer = %peer_addr,
+                                error = %err,
+                                "Dropping message"
+                            );
+                            continue;
+                        }
+                    };
 
                     new_connection
                         .relay_client_message(buffer[..bytes_read].to_vec())
@@ -256,5 +500,226 @@ impl UdpServer {
                 }
             }
         }
+
+        tracing::info!(
+            server = %self.name,
+            sessions = client_map.lock().await.len(),
+            "Draining UDP sessions"
+        );
+
+        // Stale sessions are closed once a second
+        while !client_map.lock().await.is_empty() {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::service::ConnectionError;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unreachable_backend_is_ejected() {
+        // Nothing listens on the port once it's dropped, it answers with port unreachable
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let config = serde_yaml::from_str(&format!(
+            "
+            backends: [{{ ip: 127.0.0.1, port: {} }}]
+            passive_health_check: {{ max_failures: 2, ejection_time: 1m }}
+            ",
+            port
+        ))
+        .unwrap();
+        let service = Arc::new(UdpService::new(config));
+
+        let (index, address) = service.get_address().await.unwrap();
+        let errors = IntCounter::new("errors", "errors").unwrap();
+
+        let upstream = Upstream {
+            address,
+            index,
+            service: service.clone(),
+            errors: errors.clone(),
+        };
+
+        let server = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = server.local_addr().unwrap();
+        let counters = metrics().relay_counters("unreachable-test");
+
+        let mut connection = UdpConnectionBuilder::new(client, upstream, server, counters)
+            .build()
+            .await
+            .unwrap();
+
+        connection.serve_bidirectional();
+
+        for _ in 0..20 {
+            connection.relay_client_message(b"ping".to_vec()).await;
+
+            if errors.get() >= 2 {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        connection.close();
+
+        assert!(errors.get() >= 2);
+        assert!(matches!(
+            service.get_address().await,
+            Err(ConnectionError::BackendNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn configured_buffer_size_is_used() {
+        let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let service = || {
+            serde_yaml::from_str(&format!(
+                "backends: [{{ ip: 127.0.0.1, port: {} }}]",
+                backend.local_addr().unwrap().port()
+            ))
+            .unwrap()
+        };
+
+        let fields =
+            serde_yaml::from_str("{ name: udp-server, port: 0, service: udp-service }").unwrap();
+        let server = UdpServer::new(fields, UdpService::new(service()));
+
+        assert_eq!(server.client_to_upstream_buffer, MAX_DATAGRAM_SIZE);
+        assert_eq!(server.upstream_to_client_buffer, MAX_DATAGRAM_SIZE);
+
+        let fields = serde_yaml::from_str(
+            "{ name: udp-server, port: 0, service: udp-service, buffer-size: 70000 }",
+        )
+        .unwrap();
+        let server = UdpServer::new(fields, UdpService::new(service()));
+
+        assert_eq!(server.client_to_upstream_buffer, 70000);
+        assert_eq!(server.upstream_to_client_buffer, 70000);
+
+        let (index, address) = server.service.get_address().await.unwrap();
+        let upstream = Upstream {
+            address,
+            index,
+            service: server.service.clone(),
+            errors: IntCounter::new("errors", "errors").unwrap(),
+        };
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let counters = metrics().relay_counters("buffer-size-test");
+
+        let mut builder = UdpConnectionBuilder::new(
+            client.local_addr().unwrap(),
+            upstream,
+            server_socket,
+            counters,
+        );
+        builder.buffer_size(server.upstream_to_client_buffer);
+
+        let mut connection = builder.build().await.unwrap();
+        connection.serve_bidirectional();
+        connection.relay_client_message(b"ping".to_vec()).await;
+
+        let mut buffer = vec![0; 70000];
+        let (_, session) = backend.recv_from(&mut buffer).await.unwrap();
+        backend
+            .send_to(&[7; MAX_DATAGRAM_SIZE], session)
+            .await
+            .unwrap();
+
+        // The largest datagram there is makes it through whole
+        let received = client.recv(&mut buffer).await.unwrap();
+        assert_eq!(received, MAX_DATAGRAM_SIZE);
+
+        connection.close();
+    }
+
+    #[tokio::test]
+    async fn datagrams_are_relayed_with_proxy_headers() {
+        let backend = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // Free port for the server, which binds its own socket
+        let port = UdpSocket::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server_address: SocketAddr = ([127, 0, 0, 1], port).into();
+
+        let service = serde_yaml::from_str(&format!(
+            "
+            backends: [{{ ip: 127.0.0.1, port: {} }}]
+            send-proxy-protocol: every-datagram
+            ",
+            backend.local_addr().unwrap().port()
+        ))
+        .unwrap();
+        let fields = serde_yaml::from_str(&format!(
+            "{{ name: proxy-protocol-relay-test, address: 127.0.0.1, port: {}, service: udp-service }}",
+            port
+        ))
+        .unwrap();
+
+        let server = UdpServer::new(fields, UdpService::new(service));
+
+        // The server isn't `Send`, it runs along with the test instead of being spawned
+        let relayed = async {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let header =
+                proxy_protocol::v2_datagram_header(client.local_addr().unwrap(), server_address);
+            let mut buffer = [0; 128];
+
+            // The server may not be bound yet, datagrams sent before that are lost
+            let (received, session) = tokio::time::timeout(Duration::from_secs(2), async {
+                loop {
+                    client.send_to(b"ping", server_address).await.unwrap();
+
+                    let received = tokio::time::timeout(
+                        Duration::from_millis(50),
+                        backend.recv_from(&mut buffer),
+                    )
+                    .await;
+
+                    if let Ok(received) = received {
+                        break received.unwrap();
+                    }
+                }
+            })
+            .await
+            .expect("Nothing was relayed to the backend");
+
+            assert_eq!(buffer[..received], [header.as_slice(), b"ping"].concat());
+
+            backend.send_to(b"pong", session).await.unwrap();
+
+            let received = client.recv(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..received], b"pong");
+
+            // Every datagram of the session carries the header
+            client.send_to(b"again", server_address).await.unwrap();
+
+            let received = backend.recv(&mut buffer).await.unwrap();
+            assert_eq!(buffer[..received], [header.as_slice(), b"again"].concat());
+        };
+
+        tokio::select! {
+            served = server.run(std::future::pending()) => panic!("Server stopped: {:?}", served),
+            _ = relayed => {}
+        }
     }
 }
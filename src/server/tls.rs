@@ -0,0 +1,392 @@
+use std::{fmt, path::PathBuf, sync::Arc};
+
+use arc_swap::ArcSwap;
+use rustls::{crypto::ring, version, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use serde::{Deserialize, Serialize};
+use tokio_rustls::TlsAcceptor;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub(crate) enum TlsVersion {
+    #[serde(rename = "tls1.0")]
+    Tls10,
+    #[serde(rename = "tls1.1")]
+    Tls11,
+    #[serde(rename = "tls1.2")]
+    Tls12,
+    #[serde(rename = "tls1.3")]
+    Tls13,
+}
+
+/// Protocol a client can pick with ALPN during the handshake
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AlpnProtocol {
+    #[serde(rename = "h2")]
+    Http2,
+    #[serde(rename = "http/1.1")]
+    Http1,
+}
+
+impl AlpnProtocol {
+    fn id(self) -> &'static [u8] {
+        match self {
+            AlpnProtocol::Http2 => b"h2",
+            AlpnProtocol::Http1 => b"http/1.1",
+        }
+    }
+}
+
+/// TLS settings of a listener as they're written in the config
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ServerTlsConfig {
+    /// PEM chain of the certificate, the leaf first
+    pub(crate) cert: PathBuf,
+    /// PEM private key of the certificate
+    pub(crate) key: PathBuf,
+    /// Oldest version clients can use, TLS 1.2 when not set. Older ones than that are insecure
+    /// and rejected.
+    #[serde(default = "ServerTlsConfig::default_min_version")]
+    pub(crate) min_version: TlsVersion,
+    /// Names of the suites to accept, e.g. `TLS13_AES_256_GCM_SHA384`. Only the AEAD suites
+    /// with forward secrecy are supported, all of them are accepted when not set
+    pub(crate) cipher_suites: Option<Vec<String>>,
+    /// Protocols offered with ALPN, in order of preference. A client offering none of them is
+    /// refused, none are offered when it's empty.
+    #[serde(default = "ServerTlsConfig::default_alpn")]
+    pub(crate) alpn: Vec<AlpnProtocol>,
+    /// Protocol served to the clients that don't pick one with ALPN
+    #[serde(default = "ServerTlsConfig::default_alpn_fallback")]
+    pub(crate) alpn_fallback: AlpnProtocol,
+}
+
+impl ServerTlsConfig {
+    fn default_min_version() -> TlsVersion {
+        TlsVersion::Tls12
+    }
+
+    fn default_alpn() -> Vec<AlpnProtocol> {
+        vec![AlpnProtocol::Http2, AlpnProtocol::Http1]
+    }
+
+    fn default_alpn_fallback() -> AlpnProtocol {
+        AlpnProtocol::Http1
+    }
+}
+
+/// TLS termination of a listener, built when the config is loaded so missing certificates and
+/// insecure settings are reported at startup. The certificate can be reloaded while it serves,
+/// the connections made before keep the one they were made with.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(try_from = "ServerTlsConfig", into = "ServerTlsConfig")]
+pub(crate) struct ServerTls {
+    config: ServerTlsConfig,
+    server_config: Arc<ArcSwap<ServerConfig>>,
+}
+
+impl ServerTls {
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.server_config.load_full())
+    }
+
+    /// Reads the certificate and the key again, e.g. after they've been renewed. The old ones
+    /// stay in use when the new ones can't be loaded.
+    pub(crate) fn reload(&self) -> Result<(), String> {
+        self.server_config
+            .store(Arc::new(server_config(&self.config)?));
+
+        Ok(())
+    }
+
+    /// Protocol to serve on a connection the client picked `negotiated` for with ALPN
+    pub(crate) fn protocol(&self, negotiated: Option<&[u8]>) -> AlpnProtocol {
+        self.config
+            .alpn
+            .iter()
+            .copied()
+            .find(|protocol| Some(protocol.id()) == negotiated)
+            .unwrap_or(self.config.alpn_fallback)
+    }
+
+    /// Whether every connection is served with `protocol`, whatever the client picks
+    pub(crate) fn only_serves(&self, protocol: AlpnProtocol) -> bool {
+        self.config.alpn_fallback == protocol
+            && self.config.alpn.iter().all(|offered| *offered == protocol)
+    }
+}
+
+impl fmt::Debug for ServerTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+impl TryFrom<ServerTlsConfig> for ServerTls {
+    type Error = String;
+
+    fn try_from(config: ServerTlsConfig) -> Result<Self, Self::Error> {
+        let server_config = server_config(&config)?;
+
+        Ok(Self {
+            config,
+            server_config: Arc::new(ArcSwap::from_pointee(server_config)),
+        })
+    }
+}
+
+fn server_config(config: &ServerTlsConfig) -> Result<ServerConfig, String> {
+    let versions: &[&SupportedProtocolVersion] = match config.min_version {
+        TlsVersion::Tls10 | TlsVersion::Tls11 => {
+            return Err(format!(
+                "{:?} is insecure, the minimum TLS version has to be tls1.2 or tls1.3",
+                config.min_version
+            ))
+        }
+        TlsVersion::Tls12 => &[&version::TLS12, &version::TLS13],
+        TlsVersion::Tls13 => &[&version::TLS13],
+    };
+
+    let mut provider = ring::default_provider();
+
+    if let Some(names) = &config.cipher_suites {
+        provider.cipher_suites = names
+            .iter()
+            .map(|name| cipher_suite(&provider.cipher_suites, name))
+            .collect::<Result<_, _>>()?;
+    }
+
+    let certs = CertificateDer::pem_file_iter(&config.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("Failed to read {}: {}", config.cert.display(), err))?;
+
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|err| format!("Failed to read {}: {}", config.key.display(), err))?;
+
+    if !config.alpn.is_empty() && !config.alpn.contains(&config.alpn_fallback) {
+        return Err(format!(
+            "ALPN fallback {:?} has to be one of the offered protocols",
+            config.alpn_fallback
+        ));
+    }
+
+    let mut server_config = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_protocol_versions(versions)
+        .map_err(|err| format!("Invalid TLS settings: {}", err))?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("Invalid certificate {}: {}", config.cert.display(), err))?;
+
+    server_config.alpn_protocols = config
+        .alpn
+        .iter()
+        .map(|protocol| protocol.id().to_vec())
+        .collect();
+
+    Ok(server_config)
+}
+
+impl From<ServerTls> for ServerTlsConfig {
+    fn from(value: ServerTls) -> Self {
+        value.config
+    }
+}
+
+fn cipher_suite(
+    supported: &[SupportedCipherSuite],
+    name: &str,
+) -> Result<SupportedCipherSuite, String> {
+    supported
+        .iter()
+        .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+        .copied()
+        .ok_or_else(|| format!("Cipher suite {} isn't supported", name))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rustls::{ClientConfig, RootCertStore};
+    use rustls_pki_types::ServerName;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    /// Self-signed certificate for `localhost`, written to files the config can point at
+    pub(crate) fn write_certificate() -> (PathBuf, PathBuf) {
+        static WRITTEN: AtomicUsize = AtomicUsize::new(0);
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+
+        let dir = std::env::temp_dir().join(format!(
+            "bifrost-test-tls-{}-{}",
+            std::process::id(),
+            WRITTEN.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir(&dir).unwrap();
+
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+
+        (cert, key)
+    }
+
+    #[tokio::test]
+    async fn handshake_tells_the_client_sni() {
+        let (cert, key) = write_certificate();
+        let tls: ServerTls = serde_yaml::from_str(&format!(
+            "{{ cert: {}, key: {} }}",
+            cert.display(),
+            key.display()
+        ))
+        .unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(&cert).unwrap())
+            .unwrap();
+        std::fs::remove_dir_all(cert.parent().unwrap()).unwrap();
+
+        let client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+        let connect = TlsConnector::from(Arc::new(client))
+            .connect(ServerName::try_from("localhost").unwrap(), client_io);
+
+        let (client, server) = tokio::join!(connect, tls.acceptor().accept(server_io));
+
+        client.unwrap();
+        assert_eq!(server.unwrap().get_ref().1.server_name(), Some("localhost"));
+    }
+
+    fn tls(settings: &str) -> Result<ServerTls, serde_yaml::Error> {
+        let (cert, key) = write_certificate();
+
+        let tls = serde_yaml::from_str(&format!(
+            "{{ cert: {}, key: {}, {} }}",
+            cert.display(),
+            key.display(),
+            settings
+        ));
+
+        std::fs::remove_dir_all(cert.parent().unwrap()).unwrap();
+
+        tls
+    }
+
+    #[test]
+    fn insecure_versions_are_rejected() {
+        let err = tls("min-version: tls1.0").unwrap_err();
+
+        assert!(err.to_string().contains("insecure"), "{}", err);
+        assert!(tls("min-version: tls1.3").is_ok());
+    }
+
+    #[test]
+    fn cipher_suites_are_checked() {
+        assert!(tls("cipher-suites: [TLS13_AES_256_GCM_SHA384]").is_ok());
+
+        let err = tls("cipher-suites: [TLS_RSA_WITH_RC4_128_SHA]").unwrap_err();
+        assert!(err.to_string().contains("isn't supported"), "{}", err);
+
+        // None of the suites can be used with TLS 1.3
+        assert!(tls(
+            "min-version: tls1.3, cipher-suites: [TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256]"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn missing_certificate_fails_when_loaded() {
+        let (cert, key) = write_certificate();
+        std::fs::remove_file(&cert).unwrap();
+
+        let err = serde_yaml::from_str::<ServerTls>(&format!(
+            "{{ cert: {}, key: {} }}",
+            cert.display(),
+            key.display()
+        ))
+        .unwrap_err();
+
+        std::fs::remove_dir_all(cert.parent().unwrap()).unwrap();
+
+        assert!(err.to_string().contains("Failed to read"), "{}", err);
+    }
+
+    #[test]
+    fn alpn_fallback_has_to_be_offered() {
+        assert!(tls("alpn: [h2], alpn-fallback: h2").is_ok());
+        assert!(tls("alpn: [], alpn-fallback: h2").is_ok());
+
+        let err = tls("alpn: [h2]").unwrap_err();
+        assert!(err.to_string().contains("offered protocols"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn reloaded_certificate_is_used_for_new_connections() {
+        let (cert, key) = write_certificate();
+        let tls: ServerTls = serde_yaml::from_str(&format!(
+            "{{ cert: {}, key: {} }}",
+            cert.display(),
+            key.display()
+        ))
+        .unwrap();
+
+        let connect = |trusted: &CertificateDer<'static>| {
+            let mut roots = RootCertStore::empty();
+            roots.add(trusted.clone()).unwrap();
+
+            let client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+
+            let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+            let connect = TlsConnector::from(Arc::new(client))
+                .connect(ServerName::try_from("localhost").unwrap(), client_io);
+            let accept = tls.acceptor().accept(server_io);
+
+            async move {
+                let (client, server) = tokio::join!(connect, accept);
+
+                client.and_then(|client| Ok((client, server?)))
+            }
+        };
+
+        let old = CertificateDer::from_pem_file(&cert).unwrap();
+
+        let (mut client, mut server) = connect(&old).await.unwrap();
+
+        // The renewed certificate is written over the old one
+        let (renewed_cert, renewed_key) = write_certificate();
+        std::fs::copy(&renewed_cert, &cert).unwrap();
+        std::fs::copy(&renewed_key, &key).unwrap();
+        std::fs::remove_dir_all(renewed_cert.parent().unwrap()).unwrap();
+
+        tls.reload().unwrap();
+        let renewed = CertificateDer::from_pem_file(&cert).unwrap();
+
+        assert!(connect(&old).await.is_err());
+        assert!(connect(&renewed).await.is_ok());
+
+        // The connection made before goes on with the old certificate
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+
+        // A failed reload keeps the certificate in use
+        std::fs::remove_dir_all(cert.parent().unwrap()).unwrap();
+
+        assert!(tls.reload().is_err());
+        assert!(connect(&renewed).await.is_ok());
+    }
+}
@@ -0,0 +1,1613 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, UdpSocket},
+    time::Duration,
+};
+
+use derive_more::Display;
+use http::uri::PathAndQuery;
+use thiserror::Error;
+
+use crate::{request_log, service::config::StreamServiceConfig};
+
+use super::{
+    http::{
+        body_match,
+        filters::{Filter, PathModifier},
+        location::{split_origin, LocationRewrite},
+        route_limits::RouteLimitExceeded,
+        service::MIN_RESPONSE_HEADER_SIZE,
+    },
+    stream::{limit::AcceptRateConfig, udp, StreamServerConfig},
+    Config,
+};
+
+/// What a service is for, services of one kind can't be used where another is expected
+#[derive(Debug, Display, Clone, Copy, PartialEq)]
+pub(crate) enum ServiceKind {
+    #[display(fmt = "HTTP")]
+    Http,
+    #[display(fmt = "TCP")]
+    Tcp,
+    #[display(fmt = "UDP")]
+    Udp,
+}
+
+/// Problems that parse fine but can't be run, reported before any listener is started
+#[derive(Debug, Error, PartialEq)]
+pub(crate) enum ConfigError {
+    #[error("service {0} has no backends")]
+    EmptyBackends(String),
+    #[error("service {0} sets HTTP/2 keep-alive without using HTTP/2 for its backends")]
+    UnusedH2Keepalive(String),
+    #[error("service {0} hedges requests without a retry budget to limit them")]
+    UnlimitedHedging(String),
+    #[error(
+        "service {0} has to allow at least {} bytes of response headers",
+        MIN_RESPONSE_HEADER_SIZE
+    )]
+    ResponseHeaderSize(String),
+    /// Mostly happens when several HTTP servers leave their port to the default
+    #[error("servers {first} and {second} both listen on TCP port {port}")]
+    PortConflict {
+        port: u16,
+        first: String,
+        second: String,
+    },
+    #[error("{referrer} uses service {service} which doesn't exist")]
+    UnknownService { referrer: String, service: String },
+    #[error("{referrer} needs a {expected} service, but {service} is a {found} service")]
+    WrongServiceKind {
+        referrer: String,
+        service: String,
+        expected: ServiceKind,
+        found: ServiceKind,
+    },
+    #[error("error pages are only served for 4xx and 5xx responses, {0} isn't one")]
+    ErrorPageStatus(u16),
+    #[error(
+        "request log capacity has to be from 1 to {}, not {0}",
+        request_log::MAX_CAPACITY
+    )]
+    RequestLogCapacity(usize),
+    #[error("stream connection limit has to allow at least one connection")]
+    EmptyConnectionLimit,
+    #[error("server {0} doesn't listen on either IPv4 or IPv6")]
+    NoAddressFamily(String),
+    /// The IPv6 socket takes the IPv4 port as well when it's not IPv6-only
+    #[error("server {0} listens on IPv4 twice, disable ipv4 or enable ipv6-only")]
+    DualStackConflict(String),
+    #[error("canary of route {route} {reason}")]
+    InvalidCanary { route: String, reason: &'static str },
+    #[error("health check of service {service} {reason}")]
+    InvalidHealthCheck {
+        service: String,
+        reason: &'static str,
+    },
+    #[error(
+        "route {route} rewrites locations of {origin}, which isn't a scheme://host[:port] origin"
+    )]
+    LocationOrigin { route: String, origin: String },
+    #[error(
+        "route {0} has to buffer from 1 to {} bytes of request bodies",
+        body_match::MAX_BUFFER_SIZE
+    )]
+    BodyBufferSize(String),
+    #[error("filter of route {route} {reason}")]
+    InvalidFilter { route: String, reason: &'static str },
+    #[error("service {service} can't connect from {address}, it isn't an address of this host")]
+    SourceAddress { service: String, address: IpAddr },
+    /// Backends only go out of rotation when their health checks fail
+    #[error("server {0} fails closed, but none of the services it routes to are health checked")]
+    UncheckedFailClosed(String),
+    /// ALPN would let clients pick a protocol the server doesn't speak
+    #[error(
+        "server {0} sets a version, but its TLS alpn and alpn-fallback don't all agree with it"
+    )]
+    AlpnVersionMismatch(String),
+    #[error("rule of route {route} uses path variable {variable}, which its path doesn't capture")]
+    UnknownPathVariable { route: String, variable: String },
+    #[error("service {0} needs a DNS refresh interval above zero")]
+    DnsRefreshInterval(String),
+    #[error("server {0} needs relay buffers of at least 1 byte")]
+    EmptyRelayBuffer(String),
+    #[error(
+        "UDP server {0} needs relay buffers of at least {} bytes, datagrams don't fit in smaller ones",
+        udp::MAX_DATAGRAM_SIZE
+    )]
+    SmallDatagramBuffer(String),
+    #[error("server {0} needs an accept rate above zero connections per second")]
+    EmptyAcceptRate(String),
+    /// Stream backends are relayed as plain TCP or UDP, TLS would silently not happen
+    #[error("service {0} is a stream service, its backends can't use TLS")]
+    StreamBackendTls(String),
+    #[error("connection pool of service {service} {reason}")]
+    InvalidConnectionPool {
+        service: String,
+        reason: &'static str,
+    },
+    /// Only in strict mode, the limits are warned about otherwise
+    #[error("{0}")]
+    RouteLimit(RouteLimitExceeded),
+}
+
+impl Config {
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(stream) = &self.stream {
+            for (name, service) in &stream.services {
+                if service.fields().backends.is_empty() {
+                    return Err(ConfigError::EmptyBackends(name.clone()));
+                }
+
+                validate_source_address(name, service.fields().source_address)?;
+
+                if service
+                    .fields()
+                    .backends
+                    .iter()
+                    .any(|backend| backend.tls.is_some())
+                {
+                    return Err(ConfigError::StreamBackendTls(name.clone()));
+                }
+
+                if let Some(pool) = &service.fields().connection_pool {
+                    let invalid = |reason| ConfigError::InvalidConnectionPool {
+                        service: name.clone(),
+                        reason,
+                    };
+
+                    // Sessions aren't connections, there's nothing to reuse
+                    if let StreamServiceConfig::Udp(_) = service {
+                        return Err(invalid("is only supported for TCP services"));
+                    }
+
+                    if pool.max_idle == 0 {
+                        return Err(invalid("needs max-idle of at least 1"));
+                    }
+                }
+
+                if let StreamServiceConfig::Udp(config) = service {
+                    if config
+                        .passive_health_check
+                        .as_ref()
+                        .is_some_and(|check| check.max_failures == 0)
+                    {
+                        return Err(ConfigError::InvalidHealthCheck {
+                            service: name.clone(),
+                            reason: "needs max_failures of at least 1",
+                        });
+                    }
+                }
+            }
+
+            for server in &stream.servers {
+                let (name, buffers) = match server {
+                    StreamServerConfig::Tcp(fields) => (&fields.name, &fields.buffers),
+                    StreamServerConfig::Udp(fields) => (&fields.name, &fields.buffers),
+                };
+
+                if buffers.has_empty() {
+                    return Err(ConfigError::EmptyRelayBuffer(name.clone()));
+                }
+
+                let truncates = buffers
+                    .smallest()
+                    .is_some_and(|size| size < udp::MAX_DATAGRAM_SIZE);
+
+                if matches!(server, StreamServerConfig::Udp(_)) && truncates {
+                    return Err(ConfigError::SmallDatagramBuffer(name.clone()));
+                }
+
+                if let StreamServerConfig::Tcp(fields) = server {
+                    let empty = |rate: &AcceptRateConfig| {
+                        !(rate.per_second.is_finite() && rate.per_second > 0.0)
+                    };
+
+                    if fields.accept_rate.as_ref().is_some_and(empty) {
+                        return Err(ConfigError::EmptyAcceptRate(name.clone()));
+                    }
+                }
+            }
+
+            if stream
+                .connection_limit
+                .as_ref()
+                .is_some_and(|limit| limit.max_connections == 0)
+            {
+                return Err(ConfigError::EmptyConnectionLimit);
+            }
+        }
+
+        if let Some(http) = &self.http {
+            for (name, service) in &http.services {
+                if service
+                    .backends()
+                    .is_some_and(|backends| backends.is_empty())
+                {
+                    return Err(ConfigError::EmptyBackends(name.clone()));
+                }
+
+                validate_source_address(name, service.source_address())?;
+
+                if service.has_unused_h2_keepalive() {
+                    return Err(ConfigError::UnusedH2Keepalive(name.clone()));
+                }
+
+                if service.has_unlimited_hedging() {
+                    return Err(ConfigError::UnlimitedHedging(name.clone()));
+                }
+
+                if let Some(pool) = service.connection_pool() {
+                    let invalid = |reason| ConfigError::InvalidConnectionPool {
+                        service: name.clone(),
+                        reason,
+                    };
+
+                    // Requests to HTTP/2 backends share one connection per backend instead
+                    if service.is_http2() {
+                        return Err(invalid("is only supported for HTTP/1 backends"));
+                    }
+
+                    if pool.max_idle_per_backend == 0 {
+                        return Err(invalid("needs max-idle-per-backend of at least 1"));
+                    }
+                }
+
+                if service
+                    .max_response_header_size()
+                    .is_some_and(|size| size < MIN_RESPONSE_HEADER_SIZE)
+                {
+                    return Err(ConfigError::ResponseHeaderSize(name.clone()));
+                }
+
+                if let Some(health_check) = service.health_check() {
+                    let invalid = |reason| ConfigError::InvalidHealthCheck {
+                        service: name.clone(),
+                        reason,
+                    };
+
+                    if !health_check.path.starts_with('/')
+                        || health_check.path.parse::<PathAndQuery>().is_err()
+                    {
+                        return Err(invalid("needs a path starting with /"));
+                    }
+
+                    if health_check.healthy_threshold == 0 || health_check.unhealthy_threshold == 0
+                    {
+                        return Err(invalid("needs thresholds of at least 1"));
+                    }
+
+                    if Duration::from(health_check.interval).is_zero() {
+                        return Err(invalid("needs an interval above zero"));
+                    }
+                }
+
+                if service
+                    .dns_refresh_interval()
+                    .is_some_and(|interval| interval.is_zero())
+                {
+                    return Err(ConfigError::DnsRefreshInterval(name.clone()));
+                }
+
+                if service
+                    .passive_health_check()
+                    .is_some_and(|check| check.max_failures == 0)
+                {
+                    return Err(ConfigError::InvalidHealthCheck {
+                        service: name.clone(),
+                        reason: "needs max_failures of at least 1",
+                    });
+                }
+            }
+
+            for server in &http.servers {
+                let listen = server.listen;
+
+                if !listen.ipv4 && !listen.listens_on_ipv6() {
+                    return Err(ConfigError::NoAddressFamily(server.name.clone()));
+                }
+
+                if listen.ipv4 && listen.listens_on_ipv6() && !listen.ipv6_only {
+                    return Err(ConfigError::DualStackConflict(server.name.clone()));
+                }
+
+                if let (Some(version), Some(tls)) = (server.version, &server.tls) {
+                    if !tls.only_serves(version.protocol()) {
+                        return Err(ConfigError::AlpnVersionMismatch(server.name.clone()));
+                    }
+                }
+
+                let health_checked = http
+                    .routes
+                    .iter()
+                    .filter(|route| route.server == server.name)
+                    .flat_map(|route| &route.rules)
+                    .filter_map(|rule| http.services.get(&rule.backend))
+                    .any(|service| service.health_check().is_some());
+
+                if server.fail_closed.is_some() && !health_checked {
+                    return Err(ConfigError::UncheckedFailClosed(server.name.clone()));
+                }
+
+                if http.route_limits.strict {
+                    let routes = http
+                        .routes
+                        .iter()
+                        .filter(|route| route.server == server.name);
+
+                    if let Some(exceeded) = http
+                        .route_limits
+                        .exceeded(&server.name, routes)
+                        .into_iter()
+                        .next()
+                    {
+                        return Err(ConfigError::RouteLimit(exceeded));
+                    }
+                }
+            }
+
+            for route in &http.routes {
+                if route.body_buffer.is_some_and(|buffer| {
+                    !(1..=body_match::MAX_BUFFER_SIZE).contains(&buffer.max_size)
+                }) {
+                    return Err(ConfigError::BodyBufferSize(route.name.clone()));
+                }
+
+                for rule in &route.rules {
+                    let replaces_prefix = rule.filters.iter().any(|filter| {
+                        let path = match filter {
+                            Filter::RequestRedirect(redirect) => &redirect.path,
+                            Filter::UrlRewrite(rewrite) => &rewrite.path,
+                            _ => return false,
+                        };
+
+                        matches!(path, Some(PathModifier::ReplacePrefixMatch { .. }))
+                    });
+
+                    if replaces_prefix && !rule.matches.iter().any(|m| m.path_prefix().is_some()) {
+                        return Err(ConfigError::InvalidFilter {
+                            route: route.name.clone(),
+                            reason: "replaces the path prefix of a rule that doesn't match one",
+                        });
+                    }
+
+                    let variables = rule.filters.iter().flat_map(|filter| match filter {
+                        Filter::PathVariableHeaders(headers) => Some(headers.variables()),
+                        _ => None,
+                    });
+
+                    for variable in variables.flatten() {
+                        let captured = rule
+                            .matches
+                            .iter()
+                            .filter_map(|matcher| matcher.path.as_ref())
+                            .any(|path| path.capture_names().any(|name| name == variable));
+
+                        if !captured {
+                            return Err(ConfigError::UnknownPathVariable {
+                                route: route.name.clone(),
+                                variable: variable.to_owned(),
+                            });
+                        }
+                    }
+
+                    let allowed_content_types = rule
+                        .filters
+                        .iter()
+                        .filter(|filter| matches!(filter, Filter::AllowedContentTypes(_)))
+                        .count();
+
+                    // Only one is applied, the types of the others would be left out silently
+                    if allowed_content_types > 1 {
+                        return Err(ConfigError::InvalidFilter {
+                            route: route.name.clone(),
+                            reason: "has more than one AllowedContentTypes filter in a rule",
+                        });
+                    }
+
+                    let allowed_types = rule.filters.iter().flat_map(|filter| match filter {
+                        Filter::AllowedContentTypes(allowed) => &allowed.types[..],
+                        _ => &[],
+                    });
+
+                    for media_type in allowed_types {
+                        // Parameters are ignored, so they'd never match
+                        if !media_type.split_once('/').is_some_and(|(kind, subtype)| {
+                            !kind.is_empty() && !subtype.is_empty() && !media_type.contains(';')
+                        }) {
+                            return Err(ConfigError::InvalidFilter {
+                                route: route.name.clone(),
+                                reason: "allows a content type that isn't a type/subtype",
+                            });
+                        }
+                    }
+                }
+
+                let Some(LocationRewrite::Map { mappings }) = &route.rewrite_location else {
+                    continue;
+                };
+
+                for origin in mappings
+                    .iter()
+                    .flat_map(|mapping| [&mapping.from, &mapping.to])
+                {
+                    if !split_origin(origin)
+                        .is_some_and(|(_, _, rest)| rest.is_empty() || rest == "/")
+                    {
+                        return Err(ConfigError::LocationOrigin {
+                            route: route.name.clone(),
+                            origin: origin.clone(),
+                        });
+                    }
+                }
+            }
+
+            let server_pages = http.servers.iter().flat_map(|server| &server.error_pages);
+
+            for code in http
+                .error_pages
+                .keys()
+                .chain(server_pages.map(|(code, _)| code))
+            {
+                if !(400..=599).contains(code) {
+                    return Err(ConfigError::ErrorPageStatus(*code));
+                }
+            }
+        }
+
+        if let Some(request_log) = &self.request_log {
+            if !(1..=request_log::MAX_CAPACITY).contains(&request_log.capacity) {
+                return Err(ConfigError::RequestLogCapacity(request_log.capacity));
+            }
+        }
+
+        self.validate_references()?;
+        self.validate_canaries()?;
+        self.validate_ports()
+    }
+
+    /// Canaries change the weights of their service, so there can only be one per service and
+    /// the service has to pick backends by weight. Runs after the references are checked.
+    fn validate_canaries(&self) -> Result<(), ConfigError> {
+        let Some(http) = &self.http else {
+            return Ok(());
+        };
+
+        let mut canary_services = HashSet::new();
+
+        for route in &http.routes {
+            for rule in &route.rules {
+                let Some(canary) = &rule.canary else {
+                    continue;
+                };
+
+                let invalid = |reason| ConfigError::InvalidCanary {
+                    route: route.name.clone(),
+                    reason,
+                };
+
+                let service = &http.services[&rule.backend];
+
+                if !service.is_weighted() {
+                    return Err(invalid("needs a service with weighted load balancing"));
+                }
+
+                if !service.backends().is_some_and(|backends| {
+                    backends
+                        .iter()
+                        .any(|backend| backend.address() == canary.backend)
+                }) {
+                    return Err(invalid("isn't one of the backends of its service"));
+                }
+
+                if !(1..=100).contains(&canary.step) {
+                    return Err(invalid("needs a step from 1 to 100"));
+                }
+
+                if !(0.0..=1.0).contains(&canary.error_threshold) {
+                    return Err(invalid("needs an error threshold from 0 to 1"));
+                }
+
+                if !canary_services.insert(&rule.backend) {
+                    return Err(invalid("shares its service with another canary"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every server and route has to use a service that exists and is of the kind it can send
+    /// traffic to. HTTP and stream services live in separate maps, so a name can point to
+    /// a service of a different kind, or to both.
+    fn validate_references(&self) -> Result<(), ConfigError> {
+        let mut kinds = HashMap::<&str, Vec<ServiceKind>>::new();
+
+        for name in self.http.iter().flat_map(|http| http.services.keys()) {
+            kinds.entry(name).or_default().push(ServiceKind::Http);
+        }
+
+        for (name, service) in self.stream.iter().flat_map(|stream| &stream.services) {
+            let kind = match service {
+                StreamServiceConfig::Tcp(_) => ServiceKind::Tcp,
+                StreamServiceConfig::Udp(_) => ServiceKind::Udp,
+            };
+
+            kinds.entry(name).or_default().push(kind);
+        }
+
+        let check = |referrer: String, service: &str, expected: ServiceKind| match kinds
+            .get(service)
+            .map(Vec::as_slice)
+        {
+            None => Err(ConfigError::UnknownService {
+                referrer,
+                service: service.to_owned(),
+            }),
+            Some(found) if found.contains(&expected) => Ok(()),
+            Some(found) => Err(ConfigError::WrongServiceKind {
+                referrer,
+                service: service.to_owned(),
+                expected,
+                found: found[0],
+            }),
+        };
+
+        for route in self.http.iter().flat_map(|http| &http.routes) {
+            for rule in &route.rules {
+                check(
+                    format!("route {}", route.name),
+                    &rule.backend,
+                    ServiceKind::Http,
+                )?;
+
+                for mirror in rule.mirror_services() {
+                    check(format!("route {}", route.name), mirror, ServiceKind::Http)?;
+                }
+            }
+        }
+
+        for server in self.stream.iter().flat_map(|stream| &stream.servers) {
+            match server {
+                StreamServerConfig::Tcp(fields) => {
+                    let referrer = || format!("TCP server {}", fields.name);
+
+                    check(referrer(), &fields.service, ServiceKind::Tcp)?;
+
+                    for route in fields
+                        .host_routing
+                        .iter()
+                        .flat_map(|routing| &routing.routes)
+                    {
+                        check(referrer(), &route.service, ServiceKind::Tcp)?;
+                    }
+                }
+                StreamServerConfig::Udp(fields) => {
+                    check(
+                        format!("UDP server {}", fields.name),
+                        &fields.service,
+                        ServiceKind::Udp,
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// HTTP and TCP servers share the TCP port space, UDP servers have their own
+    fn validate_ports(&self) -> Result<(), ConfigError> {
+        let http = self
+            .http
+            .iter()
+            .flat_map(|http| &http.servers)
+            .map(|server| (server.name.as_str(), server.address, server.port()));
+
+        let tcp = self
+            .stream
+            .iter()
+            .flat_map(|stream| &stream.servers)
+            .filter_map(|server| match server {
+                StreamServerConfig::Tcp(fields) => {
+                    Some((fields.name.as_str(), fields.address, fields.port))
+                }
+                StreamServerConfig::Udp(_) => None,
+            });
+
+        let mut taken: Vec<(&str, Option<IpAddr>, u16)> = vec![];
+
+        // Port 0 picks a free port every time, so it never conflicts
+        for (name, address, port) in http.chain(tcp).filter(|(_, _, port)| *port != 0) {
+            // Servers on different addresses of the host can share a port
+            let overlaps = |other: Option<IpAddr>| match (address, other) {
+                (Some(address), Some(other)) => {
+                    address == other || address.is_unspecified() || other.is_unspecified()
+                }
+                _ => true,
+            };
+
+            if let Some((first, _, _)) = taken
+                .iter()
+                .find(|(_, other, taken)| *taken == port && overlaps(*other))
+            {
+                return Err(ConfigError::PortConflict {
+                    port,
+                    first: (*first).to_owned(),
+                    second: name.to_owned(),
+                });
+            }
+
+            taken.push((name, address, port));
+        }
+
+        Ok(())
+    }
+}
+
+/// Binding fails for addresses that aren't assigned to any interface of the host
+fn validate_source_address(service: &str, address: Option<IpAddr>) -> Result<(), ConfigError> {
+    match address {
+        Some(address) if UdpSocket::bind((address, 0)).is_err() => {
+            Err(ConfigError::SourceAddress {
+                service: service.to_owned(),
+                address,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_config() {
+        let config: Config =
+            serde_yaml::from_str(include_str!("../../fixtures/combined.yaml")).unwrap();
+
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn empty_stream_backends() {
+        let config: Config = serde_yaml::from_str(
+            "
+            stream:
+              servers: []
+              services:
+                tcp-service:
+                  protocol: tcp
+                  backends: []
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::EmptyBackends("tcp-service".to_owned()))
+        );
+    }
+
+    #[test]
+    fn empty_http_backends() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes: []
+              services:
+                http-service:
+                  backends: []
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::EmptyBackends("http-service".to_owned()))
+        );
+    }
+
+    #[test]
+    fn h2_keepalive_requires_http2() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes: []
+              services:
+                http-service:
+                  backends: [{ ip: 127.0.0.1, port: 3000 }]
+                  h2-keepalive-interval: 30s
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::UnusedH2Keepalive("http-service".to_owned()))
+        );
+    }
+
+    #[test]
+    fn hedging_requires_retry_budget() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes: []
+              services:
+                http-service:
+                  backends: [{ ip: 127.0.0.1, port: 3000 }]
+                  hedging: { delay: 50ms }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::UnlimitedHedging("http-service".to_owned()))
+        );
+    }
+
+    #[test]
+    fn http_connection_pool_is_only_for_http1() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes: []
+              services:
+                http-service:
+                  backends: [{ ip: 127.0.0.1, port: 3000 }]
+                  protocol: http2
+                  connection-pool: { max-idle-per-backend: 4 }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::InvalidConnectionPool {
+                service: "http-service".to_owned(),
+                reason: "is only supported for HTTP/1 backends",
+            })
+        );
+    }
+
+    #[test]
+    fn default_ports_conflict() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers:
+              - name: public
+              - name: internal
+              routes: []
+              services: {}
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::PortConflict {
+                port: 80,
+                first: "public".to_owned(),
+                second: "internal".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn fail_closed_needs_health_checks() {
+        let config = |health_check: &str| -> Config {
+            serde_yaml::from_str(&format!(
+                "
+                http:
+                  servers:
+                  - {{ name: public, fail_closed: {{}} }}
+                  routes:
+                  - name: api
+                    server: public
+                    rules: [{{ matches: [], backend: api }}]
+                  services:
+                    api:
+                      backends: [{{ ip: 127.0.0.1, port: 3000 }}]
+                      {}
+                ",
+                health_check
+            ))
+            .unwrap()
+        };
+
+        assert_eq!(
+            config("").validate(),
+            Err(ConfigError::UncheckedFailClosed("public".to_owned()))
+        );
+        assert_eq!(config("health-check: { interval: 5s }").validate(), Ok(()));
+    }
+
+    #[test]
+    fn servers_on_different_addresses_share_a_port() {
+        let config = |internal: &str| -> Config {
+            serde_yaml::from_str(&format!(
+                "
+                http:
+                  servers:
+                  - {{ name: public, address: 203.0.113.10 }}
+                  routes: []
+                  services: {{}}
+                stream:
+                  servers:
+                  - {{ name: internal, port: 80, protocol: tcp, service: tcp, address: {} }}
+                  services:
+                    tcp: {{ protocol: tcp, backends: [{{ ip: 127.0.0.1, port: 3000 }}] }}
+                ",
+                internal
+            ))
+            .unwrap()
+        };
+
+        assert_eq!(config("127.0.0.1").validate(), Ok(()));
+
+        // Every address of the host takes the port on the public one too
+        assert_eq!(
+            config("0.0.0.0").validate(),
+            Err(ConfigError::PortConflict {
+                port: 80,
+                first: "public".to_owned(),
+                second: "internal".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn udp_server_can_share_port_with_http() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers:
+              - name: http
+                port: 8080
+              routes: []
+              services: {}
+            stream:
+              servers:
+              - name: udp
+                port: 8080
+                protocol: udp
+                service: udp-service
+              services:
+                udp-service:
+                  protocol: udp
+                  backends: [{ ip: 127.0.0.1, port: 53 }]
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn empty_relay_buffers_are_rejected() {
+        let config: Config = serde_yaml::from_str(
+            "
+            stream:
+              servers:
+              - name: udp-server
+                port: 8080
+                protocol: udp
+                service: udp-service
+                buffer-size: 0
+              services:
+                udp-service:
+                  protocol: udp
+                  backends: [{ ip: 127.0.0.1, port: 53 }]
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::EmptyRelayBuffer("udp-server".to_owned()))
+        );
+    }
+
+    #[test]
+    fn udp_buffers_fit_any_datagram() {
+        let config = |buffers: &str| -> Config {
+            serde_yaml::from_str(&format!(
+                "
+                stream:
+                  servers:
+                  - name: udp-server
+                    port: 8080
+                    protocol: udp
+                    service: udp-service
+                    {}
+                  services:
+                    udp-service:
+                      protocol: udp
+                      backends: [{{ ip: 127.0.0.1, port: 53 }}]
+                ",
+                buffers
+            ))
+            .unwrap()
+        };
+
+        assert_eq!(
+            config("upstream-to-client-buffer: 8192").validate(),
+            Err(ConfigError::SmallDatagramBuffer("udp-server".to_owned()))
+        );
+        assert_eq!(config("buffer-size: 65507").validate(), Ok(()));
+    }
+
+    #[test]
+    fn empty_accept_rate_is_rejected() {
+        let config = |per_second: &str| -> Config {
+            serde_yaml::from_str(&format!(
+                "
+                stream:
+                  servers:
+                  - name: tcp-server
+                    port: 8080
+                    protocol: tcp
+                    service: tcp-service
+                    accept_rate: {{ per-second: {} }}
+                  services:
+                    tcp-service:
+                      protocol: tcp
+                      backends: [{{ ip: 127.0.0.1, port: 80 }}]
+                ",
+                per_second
+            ))
+            .unwrap()
+        };
+
+        assert_eq!(config("100").validate(), Ok(()));
+        assert_eq!(
+            config("0").validate(),
+            Err(ConfigError::EmptyAcceptRate("tcp-server".to_owned()))
+        );
+    }
+
+    #[test]
+    fn version_has_to_agree_with_alpn() {
+        let (cert, key) = crate::server::tls::tests::write_certificate();
+        let config = |version: &str, alpn: &str| -> Config {
+            serde_yaml::from_str(&format!(
+                "
+                http:
+                  servers:
+                  - name: public
+                    version: {}
+                    tls: {{ cert: {}, key: {}, {} }}
+                  routes: []
+                  services: {{}}
+                ",
+                version,
+                cert.display(),
+                key.display(),
+                alpn
+            ))
+            .unwrap()
+        };
+
+        assert_eq!(
+            config("1", "alpn-fallback: http/1.1").validate(),
+            Err(ConfigError::AlpnVersionMismatch("public".to_owned()))
+        );
+        assert_eq!(
+            config("1", "alpn: [http/1.1], alpn-fallback: http/1.1").validate(),
+            Ok(())
+        );
+        assert_eq!(
+            config("2", "alpn: [h2], alpn-fallback: h2").validate(),
+            Ok(())
+        );
+
+        std::fs::remove_dir_all(cert.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn path_variables_have_to_be_captured() {
+        let config = |variable: &str| -> Config {
+            serde_yaml::from_str(&format!(
+                "
+                http:
+                  servers: [{{ name: public }}]
+                  services: {{ api: {{ backends: [{{ ip: 127.0.0.1, port: 3000 }}] }} }}
+                  routes:
+                  - name: resources
+                    server: public
+                    rules:
+                    - backend: api
+                      matches: [{{ path: {{ type: Regex, value: '/resources/(?<id>[0-9]+)' }} }}]
+                      filters:
+                      - type: PathVariableHeaders
+                        set: [{{ name: x-resource-id, value: '{{{}}}' }}]
+                ",
+                variable
+            ))
+            .unwrap()
+        };
+
+        assert_eq!(config("id").validate(), Ok(()));
+        assert_eq!(
+            config("name").validate(),
+            Err(ConfigError::UnknownPathVariable {
+                route: "resources".to_owned(),
+                variable: "name".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn strict_route_limits_fail_validation() {
+        let config = |strict: bool| -> Config {
+            serde_yaml::from_str(&format!(
+                "
+                http:
+                  servers: [{{ port: 8080, name: web }}]
+                  route_limits: {{ max-rules: 1, strict: {} }}
+                  services:
+                    api:
+                      backends: [{{ ip: 127.0.0.1, port: 3000 }}]
+                  routes:
+                  - name: api-route
+                    server: web
+                    rules:
+                    - {{ matches: [], backend: api }}
+                    - {{ matches: [], backend: api }}
+                ",
+                strict
+            ))
+            .unwrap()
+        };
+
+        assert_eq!(
+            config(true).validate(),
+            Err(ConfigError::RouteLimit(RouteLimitExceeded::Rules {
+                server: "web".to_owned(),
+                route: "api-route".to_owned(),
+                rules: 2,
+                limit: 1,
+            }))
+        );
+        assert_eq!(config(false).validate(), Ok(()));
+    }
+
+    #[test]
+    fn dns_refresh_interval_has_to_be_above_zero() {
+        let config = |interval: &str| -> Config {
+            serde_yaml::from_str(&format!(
+                "
+                http:
+                  servers: []
+                  routes: []
+                  services:
+                    api:
+                      backends: [{{ host: api.internal, port: 3000 }}]
+                      dns-refresh-interval: {}
+                ",
+                interval
+            ))
+            .unwrap()
+        };
+
+        assert_eq!(
+            config("0s").validate(),
+            Err(ConfigError::DnsRefreshInterval("api".to_owned()))
+        );
+        assert_eq!(config("10s").validate(), Ok(()));
+    }
+
+    #[test]
+    fn stream_server_using_http_service() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes: []
+              services:
+                web:
+                  backends: [{ ip: 127.0.0.1, port: 3000 }]
+            stream:
+              servers:
+              - name: tcp-server
+                port: 8082
+                protocol: tcp
+                service: web
+              services: {}
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::WrongServiceKind {
+                referrer: "TCP server tcp-server".to_owned(),
+                service: "web".to_owned(),
+                expected: ServiceKind::Tcp,
+                found: ServiceKind::Http,
+            })
+        );
+    }
+
+    #[test]
+    fn tcp_server_using_udp_service() {
+        let config: Config = serde_yaml::from_str(
+            "
+            stream:
+              servers:
+              - name: tcp-server
+                port: 8082
+                protocol: tcp
+                service: dns
+              services:
+                dns:
+                  protocol: udp
+                  backends: [{ ip: 127.0.0.1, port: 53 }]
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::WrongServiceKind {
+                referrer: "TCP server tcp-server".to_owned(),
+                service: "dns".to_owned(),
+                expected: ServiceKind::Tcp,
+                found: ServiceKind::Udp,
+            })
+        );
+    }
+
+    #[test]
+    fn health_check_thresholds_have_to_be_positive() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes: []
+              services:
+                api:
+                  backends: [{ ip: 127.0.0.1, port: 3000 }]
+                  health-check: { interval: 5s, healthy-threshold: 0 }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::InvalidHealthCheck {
+                service: "api".to_owned(),
+                reason: "needs thresholds of at least 1",
+            })
+        );
+    }
+
+    #[test]
+    fn source_address_has_to_be_local() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes: []
+              services:
+                api:
+                  backends: [{ ip: 127.0.0.1, port: 3000 }]
+                  source-address: 127.0.0.1
+            stream:
+              servers: []
+              services:
+                dns:
+                  protocol: udp
+                  backends: [{ ip: 127.0.0.1, port: 53 }]
+                  source-address: 192.0.2.1
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::SourceAddress {
+                service: "dns".to_owned(),
+                address: "192.0.2.1".parse().unwrap(),
+            })
+        );
+    }
+
+    #[test]
+    fn connection_pool_is_only_for_tcp() {
+        let config: Config = serde_yaml::from_str(
+            "
+            stream:
+              servers: []
+              services:
+                cache:
+                  protocol: tcp
+                  backends: [{ ip: 127.0.0.1, port: 11211 }]
+                  connection-pool: { max-idle: 16 }
+                dns:
+                  protocol: udp
+                  backends: [{ ip: 127.0.0.1, port: 53 }]
+                  connection-pool: {}
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::InvalidConnectionPool {
+                service: "dns".to_owned(),
+                reason: "is only supported for TCP services",
+            })
+        );
+    }
+
+    #[test]
+    fn stream_backends_cant_use_tls() {
+        let config: Config = serde_yaml::from_str(
+            "
+            stream:
+              servers: []
+              services:
+                database:
+                  protocol: tcp
+                  backends:
+                  - { ip: 127.0.0.1, port: 5432 }
+                  - { ip: 127.0.0.1, port: 5433, tls: { insecure-skip-verify: true } }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::StreamBackendTls("database".to_owned()))
+        );
+    }
+
+    #[test]
+    fn udp_passive_health_check_needs_failures() {
+        let config: Config = serde_yaml::from_str(
+            "
+            stream:
+              servers: []
+              services:
+                dns:
+                  protocol: udp
+                  backends: [{ ip: 127.0.0.1, port: 53 }]
+                  passive_health_check: { max_failures: 0, ejection_time: 10s }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::InvalidHealthCheck {
+                service: "dns".to_owned(),
+                reason: "needs max_failures of at least 1",
+            })
+        );
+    }
+
+    #[test]
+    fn response_header_size_below_minimum() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes: []
+              services:
+                api:
+                  backends: [{ ip: 127.0.0.1, port: 3000 }]
+                  max-response-header-size: 1024
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::ResponseHeaderSize("api".to_owned()))
+        );
+    }
+
+    #[test]
+    fn route_using_unknown_service() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes:
+              - name: api
+                server: http-1
+                rules:
+                - backend: missing
+                  matches: []
+              services: {}
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::UnknownService {
+                referrer: "route api".to_owned(),
+                service: "missing".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn route_mirroring_to_unknown_service() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes:
+              - name: api
+                server: http-1
+                rules:
+                - backend: api-service
+                  matches: []
+                  filters:
+                  - type: RequestMirror
+                    service: api-service
+                  - type: RequestMirror
+                    service: api-candidate
+              services:
+                api-service:
+                  backends: [{ ip: 127.0.0.1, port: 3000 }]
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::UnknownService {
+                referrer: "route api".to_owned(),
+                service: "api-candidate".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn canary_backend_has_to_be_in_the_service() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes:
+              - name: api
+                server: http-1
+                rules:
+                - backend: api-service
+                  matches: []
+                  canary:
+                    backend: 127.0.0.1:3002
+                    error-threshold: 0.01
+                    step: 10
+                    interval: 5m
+              services:
+                api-service:
+                  load_balancing_algorithm: weighted-random
+                  backends:
+                  - { ip: 127.0.0.1, port: 3000 }
+                  - { ip: 127.0.0.1, port: 3001 }
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::InvalidCanary {
+                route: "api".to_owned(),
+                reason: "isn't one of the backends of its service",
+            })
+        );
+    }
+
+    #[test]
+    fn location_rewrite_maps_origins() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes:
+              - name: app
+                server: http-1
+                rules: []
+                rewrite_location:
+                  type: map
+                  mappings:
+                  - { from: 'http://10.0.0.1:8080/app', to: 'https://example.com' }
+              services: {}
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::LocationOrigin {
+                route: "app".to_owned(),
+                origin: "http://10.0.0.1:8080/app".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn body_buffer_is_bounded() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes:
+              - name: api
+                server: http-1
+                rules: []
+                body_buffer: { max-size: 104857600 }
+              services: {}
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::BodyBufferSize("api".to_owned()))
+        );
+    }
+
+    #[test]
+    fn prefix_redirect_needs_prefix_match() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes:
+              - name: legacy
+                server: http-1
+                rules:
+                - backend: api-service
+                  matches: [{ path: { type: Exact, value: /v1 } }]
+                  filters:
+                  - type: RequestRedirect
+                    path: { type: ReplacePrefixMatch, value: /v2 }
+              services:
+                api-service:
+                  backends: [{ ip: 127.0.0.1, port: 3000 }]
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::InvalidFilter {
+                route: "legacy".to_owned(),
+                reason: "replaces the path prefix of a rule that doesn't match one",
+            })
+        );
+    }
+
+    #[test]
+    fn allowed_content_types_have_no_parameters() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes:
+              - name: api
+                server: http-1
+                rules:
+                - backend: api-service
+                  matches: []
+                  filters:
+                  - type: AllowedContentTypes
+                    types: [application/json, text/plain; charset=utf-8]
+              services:
+                api-service:
+                  backends: [{ ip: 127.0.0.1, port: 3000 }]
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::InvalidFilter {
+                route: "api".to_owned(),
+                reason: "allows a content type that isn't a type/subtype",
+            })
+        );
+    }
+
+    #[test]
+    fn allowed_content_types_are_listed_once() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes:
+              - name: api
+                server: http-1
+                rules:
+                - backend: api-service
+                  matches: []
+                  filters:
+                  - type: AllowedContentTypes
+                    types: [application/json]
+                  - type: AllowedContentTypes
+                    types: [text/plain]
+              services:
+                api-service:
+                  backends: [{ ip: 127.0.0.1, port: 3000 }]
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::InvalidFilter {
+                route: "api".to_owned(),
+                reason: "has more than one AllowedContentTypes filter in a rule",
+            })
+        );
+    }
+
+    #[test]
+    fn error_pages_are_only_for_errors() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers:
+              - name: http-1
+                error_pages:
+                  302: { body: moved }
+              routes: []
+              services: {}
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(config.validate(), Err(ConfigError::ErrorPageStatus(302)));
+    }
+
+    #[test]
+    fn ipv6_socket_taking_ipv4_conflicts_with_ipv4_socket() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers:
+              - name: http-1
+                listen: { ipv6-only: false }
+              routes: []
+              services: {}
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::DualStackConflict("http-1".to_owned()))
+        );
+    }
+
+    #[test]
+    fn static_service_has_no_backends() {
+        let config: Config = serde_yaml::from_str(
+            "
+            http:
+              servers: []
+              routes: []
+              services:
+                assets:
+                  type: static
+                  root: /var/www
+            ",
+        )
+        .unwrap();
+
+        assert_eq!(config.validate(), Ok(()));
+    }
+}
@@ -1,39 +1,278 @@
-use std::net::IpAddr;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
 
+use derive_more::Display;
+use duration_string::DurationString;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpStream;
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpSocket, TcpStream},
+};
 
-#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+use super::{
+    passive_health::PassiveHealthCheckConfig, pool::ConnectionPoolConfig, resolver::resolver,
+    tls::BackendTls,
+};
+use crate::server::host::Hostname;
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy)]
 pub(crate) enum LoadBalancingAlgorithm {
     #[default]
     RoundRobin,
     Random,
 }
 
+/// Where a backend is, names are resolved through the shared resolver, so backends follow
+/// changes to their DNS records within its TTL
+#[derive(Deserialize, Serialize, Debug, Display, Clone)]
+#[serde(untagged)]
+pub(crate) enum BackendHost {
+    Ip(IpAddr),
+    Name(Hostname),
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub(crate) struct BackendDefinition {
     pub(crate) port: u16,
-    // TODO: support for hostnames
-    pub(crate) ip: IpAddr,
+    /// IP or DNS name, `ip` is what it used to be called
+    #[serde(alias = "ip")]
+    pub(crate) host: BackendHost,
+    /// Relative share of traffic for weighted algorithms, backends without a weight get 1.
+    pub(crate) weight: Option<u32>,
+    /// Connect to the backend over TLS, only HTTP services can
+    pub(crate) tls: Option<BackendTls>,
+    /// Addresses of a name as of the last DNS refresh of its service, connections go to them
+    /// instead of the ones the resolver caches. Empty for services that don't refresh names.
+    #[serde(skip)]
+    pub(crate) addresses: Vec<IpAddr>,
 }
 
+/// Connection to a backend, either plain TCP or TLS on top of it
+pub(crate) trait BackendIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> BackendIo for T {}
+
+pub(crate) type BackendStream = Box<dyn BackendIo>;
+
 impl BackendDefinition {
-    pub(crate) async fn get_connection(&self) -> std::io::Result<TcpStream> {
-        TcpStream::connect((self.ip, self.port)).await
+    pub(crate) fn weight(&self) -> u32 {
+        self.weight.unwrap_or(1)
+    }
+
+    /// `host:port`, the way backends are referred to in metrics and the config. IPv6 hosts are
+    /// in brackets, e.g. `[::1]:8080`.
+    pub(crate) fn address(&self) -> String {
+        match &self.host {
+            BackendHost::Ip(ip) => SocketAddr::new(*ip, self.port).to_string(),
+            BackendHost::Name(name) => format!("{}:{}", name, self.port),
+        }
+    }
+
+    /// Every address the backend is at, IPs are used as they are without a lookup
+    pub(crate) async fn resolve(&self) -> io::Result<Vec<SocketAddr>> {
+        match &self.host {
+            BackendHost::Ip(ip) => Ok(vec![SocketAddr::new(*ip, self.port)]),
+            BackendHost::Name(_) if !self.addresses.is_empty() => {
+                // Starts at a random one, so connections spread over all of them
+                let start = rand::thread_rng().gen_range(0..self.addresses.len());
+
+                Ok(self.addresses[start..]
+                    .iter()
+                    .chain(&self.addresses[..start])
+                    .map(|ip| SocketAddr::new(*ip, self.port))
+                    .collect())
+            }
+            BackendHost::Name(name) => resolver().resolve(&name.to_string(), self.port).await,
+        }
+    }
+
+    /// Tries the resolved addresses in order until one of them accepts the connection.
+    /// Connections come from `source` when it's set, only addresses of its family are tried.
+    pub(crate) async fn connect_tcp(&self, source: Option<IpAddr>) -> io::Result<TcpStream> {
+        let addresses = self.resolve().await?;
+
+        let Some(source) = source else {
+            return TcpStream::connect(addresses.as_slice()).await;
+        };
+
+        let mut last_err = None;
+
+        for address in addresses {
+            if address.is_ipv4() != source.is_ipv4() {
+                continue;
+            }
+
+            let socket = bound_socket(source, Type::STREAM)
+                .map(|socket| TcpSocket::from_std_stream(socket.into()));
+
+            match socket?.connect(address).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!(
+                    "{} has no address of the same family as {}",
+                    self.host, source
+                ),
+            )
+        }))
+    }
+
+    /// `default_tls` is used when the backend doesn't set its own, `http2` is offered with ALPN
+    /// when the connection is over TLS
+    pub(crate) async fn get_connection(
+        &self,
+        source: Option<IpAddr>,
+        default_tls: Option<&BackendTls>,
+        http2: bool,
+    ) -> io::Result<BackendStream> {
+        let stream = self.connect_tcp(source).await?;
+
+        match self.tls.as_ref().or(default_tls) {
+            Some(tls) => Ok(Box::new(tls.connect(&self.host, stream, http2).await?)),
+            None => Ok(Box::new(stream)),
+        }
     }
 }
 
+/// Nonblocking socket bound to `source` with any port, connections of services that set
+/// a source address are made from it
+pub(crate) fn bound_socket(source: IpAddr, kind: Type) -> io::Result<Socket> {
+    let address = SocketAddr::new(source, 0);
+    let socket = Socket::new(Domain::for_address(address), kind, None)?;
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+
+    Ok(socket)
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub(crate) struct ServiceConfigFields {
     pub(crate) backends: Vec<BackendDefinition>,
     #[serde(default)]
     pub(crate) load_balancing_algorithm: LoadBalancingAlgorithm,
+    /// Local IP connections to the backends come from, e.g. to pick the interface they leave
+    /// through on hosts with several. Left to the system when not set.
+    pub(crate) source_address: Option<IpAddr>,
+    /// Reuse upstream connections of TCP services, see `ConnectionPoolConfig` for when it's
+    /// safe. Every client gets a new connection when not set.
+    pub(crate) connection_pool: Option<ConnectionPoolConfig>,
+    /// How long TCP services wait for a backend to accept a connection before trying the next
+    /// one, 10 seconds when not set
+    pub(crate) connect_timeout: Option<DurationString>,
+}
+
+/// When UDP services pass the client address to backends in a PROXY protocol v2 header
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SendProxyProtocol {
+    /// Only in the datagram that opens a session, cheaper but lost along with that datagram
+    FirstDatagram,
+    EveryDatagram,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct UdpServiceConfigFields {
+    #[serde(flatten)]
+    pub(crate) fields: ServiceConfigFields,
+    /// Backends have to expect the header, it's not sent when not set
+    pub(crate) send_proxy_protocol: Option<SendProxyProtocol>,
+    /// Ejects backends that errors are reported for, e.g. an ICMP port unreachable when
+    /// nothing listens on them. Backends aren't ejected when not set. Spelled the way HTTP
+    /// services spell it, same as its fields.
+    #[serde(rename = "passive_health_check")]
+    pub(crate) passive_health_check: Option<PassiveHealthCheckConfig>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(rename_all = "kebab-case", tag = "protocol")]
 pub(crate) enum StreamServiceConfig {
     Tcp(ServiceConfigFields),
-    Udp(ServiceConfigFields),
+    Udp(UdpServiceConfigFields),
+}
+
+impl StreamServiceConfig {
+    pub(crate) fn fields(&self) -> &ServiceConfigFields {
+        match self {
+            StreamServiceConfig::Tcp(fields) => fields,
+            StreamServiceConfig::Udp(config) => &config.fields,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn connects_to_backend_by_name() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let backend: BackendDefinition =
+            serde_yaml::from_str(&format!("{{ host: localhost, port: {} }}", port)).unwrap();
+
+        assert_eq!(backend.address(), format!("localhost:{}", port));
+
+        let (connected, accepted) =
+            tokio::join!(backend.get_connection(None, None, false), listener.accept());
+
+        connected.unwrap();
+        accepted.unwrap();
+    }
+
+    #[test]
+    fn ipv6_addresses_are_bracketed() {
+        for (host, address) in [("127.0.0.1", "127.0.0.1:80"), ("'::1'", "[::1]:80")] {
+            let backend: BackendDefinition =
+                serde_yaml::from_str(&format!("{{ host: {}, port: 80 }}", host)).unwrap();
+
+            assert_eq!(backend.address(), address);
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_from_source_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let backend: BackendDefinition =
+            serde_yaml::from_str(&format!("{{ host: 127.0.0.1, port: {} }}", port)).unwrap();
+        let source = "127.0.0.2".parse().unwrap();
+
+        let (connected, accepted) =
+            tokio::join!(backend.connect_tcp(Some(source)), listener.accept());
+
+        assert_eq!(connected.unwrap().local_addr().unwrap().ip(), source);
+        assert_eq!(accepted.unwrap().1.ip(), source);
+
+        // Backends of the other family can't be reached from it
+        let err = backend
+            .connect_tcp(Some("::1".parse().unwrap()))
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[test]
+    fn ip_is_still_accepted() {
+        let backend: BackendDefinition =
+            serde_yaml::from_str("{ ip: 10.0.0.1, port: 80 }").unwrap();
+
+        assert!(matches!(backend.host, BackendHost::Ip(_)));
+    }
 }
@@ -1,49 +1,228 @@
 pub(crate) mod config;
+pub(crate) mod passive_health;
+pub(crate) mod pool;
+pub(crate) mod resolver;
+pub(crate) mod tls;
 
 use std::{
-    net::{SocketAddr, SocketAddrV4},
-    str::FromStr,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
 use crate::protocol::StreamProtocol;
+use config::LoadBalancingAlgorithm;
+use passive_health::PassiveHealthCheck;
+use pool::ConnectionPool;
+use rand::Rng;
+use thiserror::Error;
 use tokio::net::TcpStream;
 
+/// Used when the service doesn't set a connect timeout
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub(crate) enum ConnectionError {
+    /// Validation rejects services without backends, but the set we can pick from may still
+    /// end up empty at runtime
+    #[error("service has no backends to connect to")]
+    NoBackends,
+    /// Every backend is out of rotation after failing its health checks. Also returned if the
+    /// picked backend doesn't exist, which is our fault and should never happen.
+    #[error("no healthy backend found")]
+    BackendNotFound,
+    #[error("IO error occured: {0}")]
+    IoError(std::io::Error),
+}
+
+/// Which backend a connection went to and why
+#[derive(Debug)]
+pub(crate) struct BackendSelection {
+    pub(crate) algorithm: LoadBalancingAlgorithm,
+    pub(crate) index: usize,
+    pub(crate) address: String,
+    /// Backends tried before this one that couldn't be connected to
+    pub(crate) failed_over: usize,
+    /// Taken from the connection pool of the service instead of connected to
+    pub(crate) reused: bool,
+}
+
 #[derive(Clone)]
 pub(crate) struct TcpService {
     pub(crate) config: config::ServiceConfigFields,
+    /// Shared by every server using the service, so round robin goes over all of their
+    /// connections
+    next: Arc<AtomicUsize>,
+    pool: Option<ConnectionPool>,
 }
 
 impl TcpService {
     pub(crate) fn new(config: config::ServiceConfigFields) -> Self {
-        Self { config }
+        Self {
+            pool: config.connection_pool.as_ref().map(ConnectionPool::new),
+            config,
+            next: Arc::default(),
+        }
+    }
+
+    /// Set when the service reuses upstream connections
+    pub(crate) fn pool(&self) -> Option<&ConnectionPool> {
+        self.pool.as_ref()
     }
 
-    pub(crate) async fn get_connection(&self) -> Result<TcpStream, tokio::io::Error> {
-        // TODO: load balancing
-        let ip = self.config.backends[0].ip;
-        let port = self.config.backends[0].port;
+    /// Takes an idle connection from the pool when there is one, otherwise connects to the
+    /// backend the algorithm picks, or to the ones after it in order when that one can't be
+    /// connected to
+    pub(crate) async fn get_connection(
+        &self,
+    ) -> Result<(TcpStream, BackendSelection), ConnectionError> {
+        if let Some(reused) = self.pool.as_ref().and_then(ConnectionPool::take) {
+            return Ok(reused);
+        }
+
+        let backends = &self.config.backends;
+
+        if backends.is_empty() {
+            return Err(ConnectionError::NoBackends);
+        }
+
+        let algorithm = self.config.load_balancing_algorithm;
+        let picked = match algorithm {
+            LoadBalancingAlgorithm::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            LoadBalancingAlgorithm::Random => rand::thread_rng().gen_range(0..backends.len()),
+        };
+
+        // A backend that drops connection attempts would otherwise hold up failover for as long
+        // as the system keeps trying
+        let connect_timeout = self
+            .config
+            .connect_timeout
+            .map_or(DEFAULT_CONNECT_TIMEOUT, Duration::from);
+
+        let mut last_err = None;
+
+        for failed_over in 0..backends.len() {
+            let index = (picked + failed_over) % backends.len();
+            let backend = &backends[index];
 
-        TcpStream::connect((ip, port)).await
+            let connected = tokio::time::timeout(
+                connect_timeout,
+                backend.connect_tcp(self.config.source_address),
+            )
+            .await
+            .unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "Timed out connecting to backend",
+                ))
+            });
+
+            match connected {
+                Ok(stream) => {
+                    let selection = BackendSelection {
+                        algorithm,
+                        index,
+                        address: backend.address(),
+                        failed_over,
+                        reused: false,
+                    };
+
+                    return Ok((stream, selection));
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        backend = %backend.address(),
+                        error = %err,
+                        "Failed to connect to backend"
+                    );
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        Err(ConnectionError::IoError(
+            last_err.expect("At least one backend was tried"),
+        ))
     }
 }
 
 #[derive(Clone)]
 pub(crate) struct UdpService {
     pub(crate) config: config::ServiceConfigFields,
+    pub(crate) send_proxy_protocol: Option<config::SendProxyProtocol>,
+    /// Shared by every server using the service, like the backends errors are reported for
+    next: Arc<AtomicUsize>,
+    /// Set when the service ejects backends errors are reported for
+    passive_health: Option<Arc<Mutex<PassiveHealthCheck>>>,
 }
 
 impl UdpService {
-    pub(crate) fn new(config: config::ServiceConfigFields) -> Self {
-        Self { config }
+    pub(crate) fn new(config: config::UdpServiceConfigFields) -> Self {
+        let backends = config.fields.backends.len();
+
+        Self {
+            passive_health: config
+                .passive_health_check
+                .map(|check| Arc::new(Mutex::new(PassiveHealthCheck::new(check, backends)))),
+            config: config.fields,
+            send_proxy_protocol: config.send_proxy_protocol,
+            next: Arc::default(),
+        }
+    }
+
+    /// Address of the backend a new session goes to along with its index, ejected backends
+    /// are skipped
+    pub(crate) async fn get_address(&self) -> Result<(usize, SocketAddr), ConnectionError> {
+        let backends = &self.config.backends;
+
+        if backends.is_empty() {
+            return Err(ConnectionError::NoBackends);
+        }
+
+        let picked = match self.config.load_balancing_algorithm {
+            LoadBalancingAlgorithm::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            LoadBalancingAlgorithm::Random => rand::thread_rng().gen_range(0..backends.len()),
+        };
+
+        let index = match &self.passive_health {
+            Some(passive_health) => {
+                let passive_health = passive_health.lock().expect("Passive health lock poisoned");
+
+                (0..backends.len())
+                    .map(|offset| (picked + offset) % backends.len())
+                    .find(|&index| !passive_health.is_ejected(index))
+                    .ok_or(ConnectionError::BackendNotFound)?
+            }
+            None => picked % backends.len(),
+        };
+
+        let addresses = backends[index]
+            .resolve()
+            .await
+            .map_err(ConnectionError::IoError)?;
+
+        Ok((index, addresses[0]))
     }
 
-    pub(crate) fn get_address(&self) -> SocketAddr {
-        // TODO: load balancing
-        let ip = self.config.backends[0].ip;
-        let port = self.config.backends[0].port;
+    /// Whether the backend answered or an error was reported for it, for passive health checks
+    pub(crate) fn record_outcome(&self, index: usize, succeeded: bool) {
+        let Some(passive_health) = &self.passive_health else {
+            return;
+        };
+
+        let mut passive_health = passive_health.lock().expect("Passive health lock poisoned");
 
-        // TODO : check on instantiation
-        SocketAddr::V4(SocketAddrV4::from_str(&format!("{}:{}", ip, port)).unwrap())
+        if let Some(ejection_time) = passive_health.record(index, succeeded) {
+            tracing::warn!(
+                backend = %self.config.backends[index].address(),
+                failures = passive_health.max_failures(),
+                ejection_time = ?ejection_time,
+                "UDP backend failed too many times in a row, ejecting it"
+            );
+        }
     }
 }
 
@@ -70,3 +249,116 @@ impl Service {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{TcpListener, TcpSocket};
+
+    use super::*;
+    use crate::test_helpers::closed_port;
+
+    #[tokio::test]
+    async fn selection_records_failover() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Nothing listens on the first one
+        let config = serde_yaml::from_str(&format!(
+            "backends: [{{ ip: 127.0.0.1, port: {} }}, {{ ip: 127.0.0.1, port: {} }}]",
+            closed_port().await,
+            port
+        ))
+        .unwrap();
+        let service = TcpService::new(config);
+
+        let (_, selection) = service.get_connection().await.unwrap();
+
+        assert!(matches!(
+            selection.algorithm,
+            LoadBalancingAlgorithm::RoundRobin
+        ));
+        assert_eq!(selection.index, 1);
+        assert_eq!(selection.address, format!("127.0.0.1:{}", port));
+        assert_eq!(selection.failed_over, 1);
+
+        // Round robin goes on from the backend after the one it picked
+        let (_, selection) = service.get_connection().await.unwrap();
+
+        assert_eq!(selection.index, 1);
+        assert_eq!(selection.failed_over, 0);
+    }
+
+    #[tokio::test]
+    async fn unresponsive_backend_times_out() {
+        // A listener with a full backlog drops the handshakes of further connections
+        let blackholed = TcpSocket::new_v4().unwrap();
+        blackholed.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let blackholed = blackholed.listen(0).unwrap();
+        let blackholed_port = blackholed.local_addr().unwrap().port();
+        let _backlog = TcpStream::connect(blackholed.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let config = serde_yaml::from_str(&format!(
+            "
+            backends: [{{ ip: 127.0.0.1, port: {} }}, {{ ip: 127.0.0.1, port: {} }}]
+            connect-timeout: 100ms
+            ",
+            blackholed_port, port
+        ))
+        .unwrap();
+        let service = TcpService::new(config);
+
+        let (_, selection) = tokio::time::timeout(Duration::from_secs(5), service.get_connection())
+            .await
+            .expect("Failover waited for the unresponsive backend")
+            .unwrap();
+
+        assert_eq!(selection.index, 1);
+        assert_eq!(selection.failed_over, 1);
+    }
+
+    #[tokio::test]
+    async fn udp_backends_with_errors_are_skipped() {
+        let config = serde_yaml::from_str(
+            "
+            backends: [{ ip: 127.0.0.1, port: 5000 }, { ip: 127.0.0.1, port: 5001 }]
+            passive_health_check: { max_failures: 2, ejection_time: 1m }
+            ",
+        )
+        .unwrap();
+        let service = UdpService::new(config);
+
+        service.record_outcome(0, false);
+        service.record_outcome(0, false);
+
+        for _ in 0..3 {
+            let (index, address) = service.get_address().await.unwrap();
+
+            assert_eq!(index, 1);
+            assert_eq!(address, "127.0.0.1:5001".parse().unwrap());
+        }
+
+        service.record_outcome(1, false);
+        service.record_outcome(1, false);
+
+        assert!(matches!(
+            service.get_address().await,
+            Err(ConnectionError::BackendNotFound)
+        ));
+    }
+    #[tokio::test]
+    async fn udp_backends_arent_ejected_unless_asked_to() {
+        let config = serde_yaml::from_str("backends: [{ ip: 127.0.0.1, port: 5000 }]").unwrap();
+        let service = UdpService::new(config);
+
+        for _ in 0..10 {
+            service.record_outcome(0, false);
+        }
+
+        assert_eq!(service.get_address().await.unwrap().0, 0);
+    }
+}
@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant};
+
+use duration_string::DurationString;
+use serde::{Deserialize, Serialize};
+
+/// Takes backends out of rotation by the requests that fail on them, no health endpoint needed.
+/// A backend that fails `max_failures` requests in a row is ejected for `ejection_time`. The
+/// first request after that is a trial: a failure ejects it again right away, a success puts it
+/// back for good.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub(crate) struct PassiveHealthCheckConfig {
+    pub(crate) max_failures: u32,
+    pub(crate) ejection_time: DurationString,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Outcomes {
+    /// Failures in a row, stays at the limit through an ejection
+    failures: u32,
+    ejected_until: Option<Instant>,
+}
+
+/// Outcomes of the requests to each backend of a service
+#[derive(Debug)]
+pub(crate) struct PassiveHealthCheck {
+    config: PassiveHealthCheckConfig,
+    backends: Vec<Outcomes>,
+}
+
+impl PassiveHealthCheck {
+    pub(crate) fn new(config: PassiveHealthCheckConfig, backends: usize) -> Self {
+        Self {
+            config,
+            backends: vec![Outcomes::default(); backends],
+        }
+    }
+
+    /// Failures in a row that eject a backend
+    pub(crate) fn max_failures(&self) -> u32 {
+        self.config.max_failures
+    }
+
+    pub(crate) fn is_ejected(&self, index: usize) -> bool {
+        self.backends[index]
+            .ejected_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Returns how long the backend is ejected for when this failure ejects it
+    pub(crate) fn record(&mut self, index: usize, succeeded: bool) -> Option<Duration> {
+        let outcomes = &mut self.backends[index];
+
+        if succeeded {
+            *outcomes = Outcomes::default();
+
+            return None;
+        }
+
+        outcomes.failures = (outcomes.failures + 1).min(self.config.max_failures);
+
+        if outcomes.failures < self.config.max_failures {
+            return None;
+        }
+
+        let ejection_time = self.config.ejection_time.into();
+
+        outcomes.ejected_until = Some(Instant::now() + ejection_time);
+
+        Some(ejection_time)
+    }
+
+    /// Keeps the outcomes of the backends that are still there once the backends are replaced,
+    /// `previous` has the index each of the new ones had before
+    pub(crate) fn carry_over(&mut self, previous: &[Option<usize>]) {
+        self.backends = previous
+            .iter()
+            .map(|index| index.map_or_else(Outcomes::default, |index| self.backends[index].clone()))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passive_health(max_failures: u32, ejection_time: &str) -> PassiveHealthCheck {
+        let config = serde_yaml::from_str(&format!(
+            "{{ max_failures: {}, ejection_time: {} }}",
+            max_failures, ejection_time
+        ))
+        .unwrap();
+
+        PassiveHealthCheck::new(config, 2)
+    }
+
+    #[test]
+    fn failures_in_a_row_eject() {
+        let mut check = passive_health(3, "1m");
+
+        check.record(0, false);
+        check.record(0, false);
+        check.record(0, true);
+        check.record(0, false);
+        check.record(0, false);
+
+        // A success in between started the count over
+        assert!(!check.is_ejected(0));
+
+        assert!(check.record(0, false).is_some());
+        assert!(check.is_ejected(0));
+        assert!(!check.is_ejected(1));
+    }
+
+    #[test]
+    fn failed_trial_ejects_again() {
+        let mut check = passive_health(3, "0s");
+
+        for _ in 0..3 {
+            check.record(0, false);
+        }
+
+        assert!(!check.is_ejected(0));
+        assert!(check.record(0, false).is_some());
+    }
+}
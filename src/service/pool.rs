@@ -0,0 +1,175 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use duration_string::DurationString;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+
+use super::BackendSelection;
+
+/// Upstream connections a TCP service keeps open once their client disconnected, so the next
+/// client doesn't wait for a new one.
+///
+/// Only safe for stateless request/response protocols, where an exchange is over once the
+/// client disconnects and nothing about it carries over to the next client on the same
+/// connection. Anything with a handshake, authentication or a session (databases, TLS, SSH...)
+/// would leak one client's state to another and must not use it. A connection only goes back
+/// when the backend answered the last bytes of the client and nothing more is waiting to be
+/// read, a client that disconnects in the middle of an exchange gets its connection shut down.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ConnectionPoolConfig {
+    /// Connections kept for the whole service
+    #[serde(default = "ConnectionPoolConfig::default_max_idle")]
+    pub(crate) max_idle: usize,
+    /// How long a connection can sit in the pool before it's closed
+    #[serde(default = "ConnectionPoolConfig::default_idle_timeout")]
+    pub(crate) idle_timeout: DurationString,
+}
+
+impl ConnectionPoolConfig {
+    fn default_max_idle() -> usize {
+        8
+    }
+
+    fn default_idle_timeout() -> DurationString {
+        Duration::from_secs(60).into()
+    }
+}
+
+struct Idle {
+    stream: TcpStream,
+    selection: BackendSelection,
+    since: Instant,
+}
+
+/// Idle upstream connections of a service, shared by every server using it
+#[derive(Clone)]
+pub(crate) struct ConnectionPool {
+    max_idle: usize,
+    idle_timeout: Duration,
+    idle: Arc<Mutex<Vec<Idle>>>,
+}
+
+impl ConnectionPool {
+    pub(crate) fn new(config: &ConnectionPoolConfig) -> Self {
+        Self {
+            max_idle: config.max_idle,
+            idle_timeout: config.idle_timeout.into(),
+            idle: Arc::default(),
+        }
+    }
+
+    /// Most recently released connection that's still open, connections that expired or that
+    /// the backend closed are dropped on the way
+    pub(crate) fn take(&self) -> Option<(TcpStream, BackendSelection)> {
+        let mut idle = self.idle.lock().expect("Pool lock poisoned");
+
+        while let Some(connection) = idle.pop() {
+            if connection.since.elapsed() >= self.idle_timeout {
+                continue;
+            }
+
+            // Nothing is expected from the backend between exchanges, data or EOF means the
+            // connection can't be used anymore
+            match connection.stream.try_read(&mut [0; 1]) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                _ => continue,
+            }
+
+            let mut selection = connection.selection;
+
+            selection.failed_over = 0;
+            selection.reused = true;
+
+            return Some((connection.stream, selection));
+        }
+
+        None
+    }
+
+    /// Keeps the connection for the next client unless the pool is full
+    pub(crate) fn release(&self, stream: TcpStream, selection: BackendSelection) {
+        let mut idle = self.idle.lock().expect("Pool lock poisoned");
+
+        if idle.len() >= self.max_idle {
+            return;
+        }
+
+        idle.push(Idle {
+            stream,
+            selection,
+            since: Instant::now(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+    use crate::service::config::LoadBalancingAlgorithm;
+
+    fn selection() -> BackendSelection {
+        BackendSelection {
+            algorithm: LoadBalancingAlgorithm::RoundRobin,
+            index: 0,
+            address: "127.0.0.1:1".to_owned(),
+            failed_over: 1,
+            reused: false,
+        }
+    }
+
+    fn pool(yaml: &str) -> ConnectionPool {
+        ConnectionPool::new(&serde_yaml::from_str(yaml).unwrap())
+    }
+
+    #[tokio::test]
+    async fn closed_connections_are_not_reused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let pool = pool("max-idle: 2");
+
+        let open = TcpStream::connect(address).await.unwrap();
+        let (_accepted_open, _) = listener.accept().await.unwrap();
+
+        let closed = TcpStream::connect(address).await.unwrap();
+        let (mut accepted_closed, _) = listener.accept().await.unwrap();
+
+        accepted_closed.shutdown().await.unwrap();
+        drop(accepted_closed);
+
+        // Over the limit, it's closed right away
+        let over = TcpStream::connect(address).await.unwrap();
+
+        pool.release(open, selection());
+        pool.release(closed, selection());
+        pool.release(over, selection());
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let (_, reused) = pool.take().unwrap();
+
+        assert!(reused.reused);
+        assert_eq!(reused.failed_over, 0);
+        assert!(pool.take().is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_connections_are_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = pool("idle-timeout: 0s");
+
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        pool.release(stream, selection());
+
+        assert!(pool.take().is_none());
+    }
+}
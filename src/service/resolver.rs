@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use duration_string::DurationString;
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::net::lookup_host;
+
+/// How backends with DNS names are resolved
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct DnsConfig {
+    /// How long resolved addresses are used before they're looked up again
+    #[serde(default = "DnsConfig::default_ttl")]
+    pub(crate) ttl: DurationString,
+}
+
+impl DnsConfig {
+    fn default_ttl() -> DurationString {
+        Duration::from_secs(30).into()
+    }
+}
+
+type Lookup = dyn Fn(String) -> BoxFuture<'static, io::Result<Vec<IpAddr>>> + Send + Sync;
+
+struct Entry {
+    ips: Vec<IpAddr>,
+    resolved_at: Instant,
+    /// Where the next caller starts in `ips`, so connections are spread over all of them
+    next: usize,
+    refreshing: bool,
+}
+
+/// Addresses of hostnames, shared by every backend. Expired names are still served while
+/// they're looked up again in the background, only the first lookup of a name is waited for.
+pub(crate) struct Resolver {
+    ttl: Duration,
+    lookup: Arc<Lookup>,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+static RESOLVER: OnceLock<Resolver> = OnceLock::new();
+
+/// Sets the TTL from the config, has to be called before any server starts
+pub(crate) fn init(config: Option<&DnsConfig>) {
+    if let Some(config) = config {
+        RESOLVER.get_or_init(|| Resolver::new(config.ttl.into(), Arc::new(system_lookup)));
+    }
+}
+
+/// Uses the default TTL when there's no DNS config
+pub(crate) fn resolver() -> &'static Resolver {
+    RESOLVER.get_or_init(|| Resolver::new(DnsConfig::default_ttl().into(), Arc::new(system_lookup)))
+}
+
+fn system_lookup(name: String) -> BoxFuture<'static, io::Result<Vec<IpAddr>>> {
+    Box::pin(async move {
+        let addresses = lookup_host((name.as_str(), 0)).await?;
+
+        Ok(addresses.map(|address| address.ip()).collect())
+    })
+}
+
+impl Resolver {
+    fn new(ttl: Duration, lookup: Arc<Lookup>) -> Self {
+        Self {
+            ttl,
+            lookup,
+            entries: Arc::default(),
+        }
+    }
+
+    /// Every address of `name`, starting from a different one on each call
+    pub(crate) async fn resolve(&self, name: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let cached = {
+            let mut entries = self.entries.lock().expect("Resolver lock poisoned");
+
+            entries.get_mut(name).map(|entry| {
+                if entry.resolved_at.elapsed() >= self.ttl && !entry.refreshing {
+                    entry.refreshing = true;
+                    self.refresh(name.to_owned());
+                }
+
+                rotate(entry, port)
+            })
+        };
+
+        if let Some(addresses) = cached {
+            return Ok(addresses);
+        }
+
+        let ips = (self.lookup)(name.to_owned()).await?;
+
+        if ips.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} doesn't resolve to any address", name),
+            ));
+        }
+
+        let mut entries = self.entries.lock().expect("Resolver lock poisoned");
+
+        // Concurrent first lookups of a name all end up here, the last one wins
+        let entry = entries.entry(name.to_owned()).or_insert(Entry {
+            ips: vec![],
+            resolved_at: Instant::now(),
+            next: 0,
+            refreshing: false,
+        });
+
+        entry.ips = ips;
+        entry.resolved_at = Instant::now();
+
+        Ok(rotate(entry, port))
+    }
+
+    fn refresh(&self, name: String) {
+        let lookup = self.lookup.clone();
+        let entries = self.entries.clone();
+
+        tokio::spawn(async move {
+            let resolved = lookup(name.clone()).await;
+
+            let mut entries = entries.lock().expect("Resolver lock poisoned");
+            let Some(entry) = entries.get_mut(&name) else {
+                return;
+            };
+
+            entry.refreshing = false;
+
+            update(entry, &name, resolved);
+        });
+    }
+
+    /// Looks `name` up right away instead of waiting for its addresses to expire, the ones it
+    /// had stay in use when the lookup fails. Returns the addresses it has for the name after
+    /// that, none when it never resolved.
+    pub(crate) async fn refresh_now(&self, name: &str) -> Vec<IpAddr> {
+        let resolved = (self.lookup)(name.to_owned()).await;
+
+        let mut entries = self.entries.lock().expect("Resolver lock poisoned");
+        let entry = entries.entry(name.to_owned()).or_insert(Entry {
+            ips: vec![],
+            resolved_at: Instant::now(),
+            next: 0,
+            refreshing: false,
+        });
+
+        update(entry, name, resolved);
+
+        // A name that never resolved isn't cached, its first caller looks it up
+        if entry.ips.is_empty() {
+            entries.remove(name);
+
+            return vec![];
+        }
+
+        entry.ips.clone()
+    }
+}
+
+fn update(entry: &mut Entry, name: &str, resolved: io::Result<Vec<IpAddr>>) {
+    match resolved {
+        Ok(ips) if !ips.is_empty() => {
+            entry.ips = ips;
+            entry.resolved_at = Instant::now();
+        }
+        // The old addresses are better than none, the next caller tries again
+        Ok(_) => tracing::warn!(name, "Name doesn't resolve to any address anymore"),
+        Err(err) => tracing::warn!(name, error = %err, "Failed to resolve name"),
+    }
+}
+
+fn rotate(entry: &mut Entry, port: u16) -> Vec<SocketAddr> {
+    let start = entry.next % entry.ips.len();
+
+    entry.next = entry.next.wrapping_add(1);
+
+    entry.ips[start..]
+        .iter()
+        .chain(&entry.ips[..start])
+        .map(|ip| SocketAddr::new(*ip, port))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Resolver that counts its lookups and resolves every name to `ips`
+    fn counting(ttl: Duration, ips: Vec<IpAddr>) -> (Resolver, Arc<AtomicUsize>) {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let counter = lookups.clone();
+
+        let lookup = move |_| -> BoxFuture<'static, io::Result<Vec<IpAddr>>> {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let ips = ips.clone();
+
+            Box::pin(async move { Ok(ips) })
+        };
+
+        (Resolver::new(ttl, Arc::new(lookup)), lookups)
+    }
+
+    #[tokio::test]
+    async fn lookup_is_cached_within_ttl() {
+        let ips = vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+        let (resolver, lookups) = counting(Duration::from_secs(60), ips);
+
+        let first = resolver.resolve("api.internal", 80).await.unwrap();
+        let second = resolver.resolve("api.internal", 80).await.unwrap();
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        // Every address is there, starting from the next one
+        assert_eq!(
+            first,
+            [
+                "10.0.0.1:80".parse().unwrap(),
+                "10.0.0.2:80".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            second,
+            [
+                "10.0.0.2:80".parse().unwrap(),
+                "10.0.0.1:80".parse().unwrap()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_keeps_the_last_addresses() {
+        let answers = Arc::new(Mutex::new(vec![
+            Err(io::Error::other("SERVFAIL")),
+            Ok(vec!["10.0.0.2".parse().unwrap()]),
+            Ok(vec!["10.0.0.1".parse().unwrap()]),
+        ]));
+
+        let lookup = move |_| -> BoxFuture<'static, io::Result<Vec<IpAddr>>> {
+            let answer = answers.lock().unwrap().pop().unwrap();
+
+            Box::pin(async move { answer })
+        };
+
+        let resolver = Resolver::new(Duration::from_secs(60), Arc::new(lookup));
+        let resolve = || resolver.resolve("api.internal", 80);
+
+        let refreshed = resolver.refresh_now("api.internal").await;
+        assert_eq!(refreshed, ["10.0.0.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(resolve().await.unwrap(), ["10.0.0.1:80".parse().unwrap()]);
+
+        // Within the TTL, but refreshed anyway
+        resolver.refresh_now("api.internal").await;
+        assert_eq!(resolve().await.unwrap(), ["10.0.0.2:80".parse().unwrap()]);
+
+        let refreshed = resolver.refresh_now("api.internal").await;
+        assert_eq!(refreshed, ["10.0.0.2".parse::<IpAddr>().unwrap()]);
+        assert_eq!(resolve().await.unwrap(), ["10.0.0.2:80".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn expired_name_is_refreshed_in_background() {
+        let (resolver, lookups) = counting(Duration::ZERO, vec!["10.0.0.1".parse().unwrap()]);
+
+        resolver.resolve("api.internal", 80).await.unwrap();
+
+        // Served from the cache while the refresh runs
+        let expired = resolver.resolve("api.internal", 80).await.unwrap();
+        assert_eq!(expired, ["10.0.0.1:80".parse().unwrap()]);
+
+        tokio::task::yield_now().await;
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+    }
+}
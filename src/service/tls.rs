@@ -0,0 +1,481 @@
+use std::{fmt, path::PathBuf, sync::Arc};
+
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use super::config::BackendHost;
+
+/// TLS settings for connections to a backend as they're written in the config
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct BackendTlsConfig {
+    /// Server name to send and verify the certificate against, defaults to the backend address
+    pub(crate) sni: Option<String>,
+    /// Name the certificate has to be issued for when it isn't the server name, e.g. backends
+    /// addressed by IP that share a certificate for a logical name. Verification is as strict
+    /// otherwise, only the name it's checked against changes.
+    pub(crate) verify_hostname: Option<String>,
+    /// PEM bundle with the CAs to trust instead of the built-in web PKI roots
+    pub(crate) ca: Option<PathBuf>,
+    /// Accept any certificate the backend presents. Only meant for testing.
+    #[serde(default)]
+    pub(crate) insecure_skip_verify: bool,
+}
+
+/// TLS client for a backend, built when the config is loaded so broken CA bundles are reported
+/// at startup rather than on the first request.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(try_from = "BackendTlsConfig", into = "BackendTlsConfig")]
+pub(crate) struct BackendTls {
+    config: BackendTlsConfig,
+    connector: TlsConnector,
+    /// Same as `connector`, but offers HTTP/2 with ALPN, which most servers need to speak it
+    /// over TLS
+    h2_connector: TlsConnector,
+}
+
+impl fmt::Debug for BackendTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.config.fmt(f)
+    }
+}
+
+impl TryFrom<BackendTlsConfig> for BackendTls {
+    type Error = String;
+
+    fn try_from(config: BackendTlsConfig) -> Result<Self, Self::Error> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| err.to_string())?;
+
+        let client_config = match (&config.verify_hostname, config.insecure_skip_verify) {
+            (Some(_), true) => {
+                return Err("verify-hostname can't be set when verification is skipped".to_owned());
+            }
+            (None, true) => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(SkipVerification(provider)))
+                .with_no_client_auth(),
+            (Some(hostname), false) => {
+                let name = ServerName::try_from(hostname.clone())
+                    .map_err(|err| format!("Invalid verify-hostname {}: {}", hostname, err))?;
+                let verifier = WebPkiServerVerifier::builder_with_provider(
+                    Arc::new(root_store(&config)?),
+                    provider,
+                )
+                .build()
+                .map_err(|err| err.to_string())?;
+
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(ExpectedHostname { verifier, name }))
+                    .with_no_client_auth()
+            }
+            (None, false) => builder
+                .with_root_certificates(root_store(&config)?)
+                .with_no_client_auth(),
+        };
+
+        if let Some(sni) = &config.sni {
+            ServerName::try_from(sni.as_str())
+                .map_err(|err| format!("Invalid backend SNI {}: {}", sni, err))?;
+        }
+
+        let mut h2_client_config = client_config.clone();
+        h2_client_config.alpn_protocols = vec![b"h2".to_vec()];
+
+        Ok(Self {
+            config,
+            connector: TlsConnector::from(Arc::new(client_config)),
+            h2_connector: TlsConnector::from(Arc::new(h2_client_config)),
+        })
+    }
+}
+
+impl From<BackendTls> for BackendTlsConfig {
+    fn from(value: BackendTls) -> Self {
+        value.config
+    }
+}
+
+fn root_store(config: &BackendTlsConfig) -> Result<RootCertStore, String> {
+    let Some(path) = &config.ca else {
+        return Ok(RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        });
+    };
+
+    let mut store = RootCertStore::empty();
+
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|err| format!("Failed to read CA file {}: {}", path.display(), err))?;
+
+    for cert in certs {
+        let cert =
+            cert.map_err(|err| format!("Failed to read CA file {}: {}", path.display(), err))?;
+
+        store
+            .add(cert)
+            .map_err(|err| format!("Invalid CA in {}: {}", path.display(), err))?;
+    }
+
+    if store.is_empty() {
+        return Err(format!(
+            "No certificates found in CA file {}",
+            path.display()
+        ));
+    }
+
+    Ok(store)
+}
+
+impl BackendTls {
+    /// Server name sent instead of the backend's host, when one is configured
+    pub(crate) fn sni(&self) -> Option<&str> {
+        self.config.sni.as_deref()
+    }
+
+    /// Nothing is offered with ALPN unless it's `http2`
+    pub(crate) async fn connect(
+        &self,
+        host: &BackendHost,
+        stream: TcpStream,
+        http2: bool,
+    ) -> std::io::Result<TlsStream<TcpStream>> {
+        let server_name = match (&self.config.sni, host) {
+            // Validated when the config was loaded
+            (Some(sni), _) => ServerName::try_from(sni.clone()).expect("SNI was validated"),
+            (None, BackendHost::Ip(ip)) => ServerName::IpAddress((*ip).into()),
+            (None, BackendHost::Name(name)) => ServerName::try_from(name.to_string())
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?,
+        };
+
+        let connector = if http2 {
+            &self.h2_connector
+        } else {
+            &self.connector
+        };
+
+        connector.connect(server_name, stream).await
+    }
+}
+
+/// Verifies certificates the usual way, but against a configured name instead of the server name
+/// of the connection
+#[derive(Debug)]
+struct ExpectedHostname {
+    verifier: Arc<WebPkiServerVerifier>,
+    name: ServerName<'static>,
+}
+
+impl ServerCertVerifier for ExpectedHostname {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verifier
+            .verify_server_cert(end_entity, intermediates, &self.name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+/// Still checks that the handshake is signed by the presented certificate, but doesn't care
+/// who issued it or which names it's for.
+#[derive(Debug)]
+struct SkipVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for SkipVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use rcgen::CertifiedKey;
+    use rustls::ServerConfig;
+    use rustls_pki_types::PrivateKeyDer;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::service::config::BackendDefinition;
+
+    use super::*;
+
+    /// Echo server behind TLS with a self-signed certificate for the given names
+    async fn tls_echo_server(names: &[&str]) -> (SocketAddr, CertifiedKey) {
+        let certified = rcgen::generate_simple_self_signed(
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certified.cert.der().clone()],
+                PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into()),
+            )
+            .unwrap();
+
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+
+                tokio::spawn(async move {
+                    let Ok(mut stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+
+                    let mut buffer = [0; 5];
+                    stream.read_exact(&mut buffer).await.unwrap();
+                    stream.write_all(&buffer).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                });
+            }
+        });
+
+        (addr, certified)
+    }
+
+    fn write_ca(certified: &CertifiedKey) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "bifrost-test-ca-{}-{}.pem",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+
+        std::fs::write(&path, certified.cert.pem()).unwrap();
+
+        path
+    }
+
+    fn backend(addr: SocketAddr, tls: &str) -> Result<BackendDefinition, serde_yaml::Error> {
+        serde_yaml::from_str(&format!(
+            "{{ ip: {}, port: {}, tls: {} }}",
+            addr.ip(),
+            addr.port(),
+            tls
+        ))
+    }
+
+    async fn echo(backend: &BackendDefinition) -> std::io::Result<Vec<u8>> {
+        let mut stream = backend.get_connection(None, None, false).await?;
+
+        stream.write_all(b"hello").await?;
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await?;
+
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn connects_with_custom_ca_and_sni() {
+        let (addr, certified) = tls_echo_server(&["backend.internal"]).await;
+        let ca = write_ca(&certified);
+
+        let backend = backend(
+            addr,
+            &format!("{{ sni: backend.internal, ca: {} }}", ca.display()),
+        )
+        .unwrap();
+
+        assert_eq!(echo(&backend).await.unwrap(), b"hello");
+
+        std::fs::remove_file(ca).unwrap();
+    }
+
+    #[tokio::test]
+    async fn verifies_backend_address_without_sni() {
+        let (addr, certified) = tls_echo_server(&["127.0.0.1"]).await;
+        let ca = write_ca(&certified);
+
+        let backend = backend(addr, &format!("{{ ca: {} }}", ca.display())).unwrap();
+
+        assert_eq!(echo(&backend).await.unwrap(), b"hello");
+
+        std::fs::remove_file(ca).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_sni() {
+        let (addr, certified) = tls_echo_server(&["backend.internal"]).await;
+        let ca = write_ca(&certified);
+
+        let backend = backend(
+            addr,
+            &format!("{{ sni: other.internal, ca: {} }}", ca.display()),
+        )
+        .unwrap();
+
+        assert!(echo(&backend).await.is_err());
+
+        std::fs::remove_file(ca).unwrap();
+    }
+
+    #[tokio::test]
+    async fn verifies_expected_hostname_instead_of_address() {
+        let (addr, certified) = tls_echo_server(&["backend.internal"]).await;
+        let ca = write_ca(&certified);
+
+        let strict = backend(addr, &format!("{{ ca: {} }}", ca.display())).unwrap();
+
+        assert!(echo(&strict).await.is_err());
+
+        let expected = backend(
+            addr,
+            &format!(
+                "{{ verify-hostname: backend.internal, ca: {} }}",
+                ca.display()
+            ),
+        )
+        .unwrap();
+
+        assert_eq!(echo(&expected).await.unwrap(), b"hello");
+
+        // The certificate still has to be trusted and issued for the expected name
+        let wrong = backend(
+            addr,
+            &format!(
+                "{{ verify-hostname: other.internal, ca: {} }}",
+                ca.display()
+            ),
+        )
+        .unwrap();
+        let untrusted = backend(addr, "{ verify-hostname: backend.internal }").unwrap();
+
+        assert!(echo(&wrong).await.is_err());
+        assert!(echo(&untrusted).await.is_err());
+
+        std::fs::remove_file(ca).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_untrusted_certificate() {
+        let (addr, _) = tls_echo_server(&["backend.internal"]).await;
+
+        let backend = backend(addr, "{ sni: backend.internal }").unwrap();
+
+        assert!(echo(&backend).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn skips_verification_when_asked() {
+        let (addr, _) = tls_echo_server(&["backend.internal"]).await;
+
+        let backend = backend(addr, "{ insecure-skip-verify: true }").unwrap();
+
+        assert_eq!(echo(&backend).await.unwrap(), b"hello");
+    }
+
+    #[test]
+    fn expected_hostname_needs_verification() {
+        let error = backend(
+            "127.0.0.1:443".parse().unwrap(),
+            "{ verify-hostname: backend.internal, insecure-skip-verify: true }",
+        )
+        .unwrap_err();
+
+        assert!(error.to_string().contains("verify-hostname can't be set"));
+    }
+
+    #[test]
+    fn missing_ca_fails_config_parsing() {
+        let error = backend(
+            "127.0.0.1:443".parse().unwrap(),
+            "{ ca: /definitely/not/here.pem }",
+        )
+        .unwrap_err();
+
+        assert!(error.to_string().contains("Failed to read CA file"));
+    }
+}
@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use duration_string::DurationString;
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+/// Completes once the proxy is asked to stop, cheap to clone so every listener can wait on it
+pub(crate) type Shutdown = Shared<BoxFuture<'static, ()>>;
+
+/// How the proxy stops on Ctrl-C or SIGTERM
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct ShutdownConfig {
+    /// How long the connections in flight get to finish once the listeners stop accepting, the
+    /// ones left after it are dropped
+    #[serde(default = "ShutdownConfig::default_grace_period")]
+    pub(crate) grace_period: DurationString,
+}
+
+impl ShutdownConfig {
+    fn default_grace_period() -> DurationString {
+        Duration::from_secs(30).into()
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            grace_period: Self::default_grace_period(),
+        }
+    }
+}
+
+/// Completes on Ctrl-C, or on SIGTERM where there are Unix signals, which is how containers
+/// are asked to stop
+pub(crate) fn on_signal() -> Shutdown {
+    async {
+        let ctrl_c = async {
+            if let Err(err) = tokio::signal::ctrl_c().await {
+                tracing::warn!(error = %err, "Failed to listen for Ctrl-C");
+
+                std::future::pending::<()>().await;
+            }
+        };
+
+        tokio::select! {
+            _ = ctrl_c => {}
+            _ = terminate() => {}
+        }
+
+        tracing::info!("Shutting down");
+    }
+    .boxed()
+    .shared()
+}
+
+#[cfg(unix)]
+async fn terminate() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            terminate.recv().await;
+        }
+        Err(err) => {
+            tracing::warn!(error = %err, "Failed to listen for SIGTERM");
+
+            std::future::pending().await
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate() {
+    std::future::pending().await
+}
+
+/// Connections a server spawned tasks for, so it can wait for them once it stops accepting
+pub(crate) struct ConnectionTracker {
+    open: mpsc::Sender<()>,
+    closed: mpsc::Receiver<()>,
+}
+
+/// Held by the task of a connection, the connection counts as open until it's dropped
+pub(crate) struct OpenConnection {
+    _open: mpsc::Sender<()>,
+}
+
+impl ConnectionTracker {
+    pub(crate) fn new() -> Self {
+        let (open, closed) = mpsc::channel(1);
+
+        Self { open, closed }
+    }
+
+    pub(crate) fn track(&self) -> OpenConnection {
+        OpenConnection {
+            _open: self.open.clone(),
+        }
+    }
+
+    /// Connections that haven't finished yet
+    pub(crate) fn open(&self) -> usize {
+        self.open.strong_count() - 1
+    }
+
+    /// Completes once every tracked connection has finished
+    pub(crate) async fn closed(self) {
+        let Self { open, mut closed } = self;
+
+        drop(open);
+
+        // Nothing is ever sent, it only completes once every sender is gone
+        let _ = closed.recv().await;
+    }
+}
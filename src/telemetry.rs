@@ -0,0 +1,156 @@
+use http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::TraceError,
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use serde::{Deserialize, Serialize};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+};
+
+/// Export of request spans to an OpenTelemetry collector
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct TracingConfig {
+    /// gRPC OTLP endpoint of the collector, e.g. `http://localhost:4317`
+    pub(crate) otlp_endpoint: String,
+    #[serde(default = "TracingConfig::default_service_name")]
+    pub(crate) service_name: String,
+}
+
+impl TracingConfig {
+    fn default_service_name() -> String {
+        "bifrost".to_owned()
+    }
+}
+
+/// Sets up logging, filtered by `RUST_LOG` and at `info` without it, and, when configured,
+/// exporting spans over OTLP
+pub(crate) fn init(config: Option<&TracingConfig>) -> Result<(), TraceError> {
+    let otel_layer = match config {
+        Some(config) => {
+            // W3C `traceparent`/`tracestate` are used both to continue client traces and to pass
+            // them on to backends
+            global::set_text_map_propagator(TraceContextPropagator::new());
+
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(&config.otlp_endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", config.service_name.clone()),
+                ])))
+                .install_batch(runtime::Tokio)?;
+
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(
+            EnvFilter::builder()
+                .with_default_directive(LevelFilter::INFO.into())
+                .from_env_lossy(),
+        )
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel_layer)
+        .init();
+
+    Ok(())
+}
+
+/// Flushes spans that are still waiting to be exported
+pub(crate) fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Makes the span a continuation of the trace the client sent in its headers
+pub(crate) fn continue_trace(span: &Span, headers: &HeaderMap) {
+    let parent =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+
+    span.set_parent(parent);
+}
+
+/// Puts the current trace into the headers of a request going to a backend
+pub(crate) fn propagate_trace(headers: &mut HeaderMap) {
+    let context = Span::current().context();
+
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn trace_is_continued_and_propagated() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        // Tracers only hold a weak reference to their provider
+        let provider = trace::TracerProvider::builder().build();
+        let tracer = provider.tracer("test");
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let mut client_headers = HeaderMap::new();
+            client_headers.insert("traceparent", HeaderValue::from_static(TRACEPARENT));
+
+            let span = tracing::info_span!("proxy_request");
+            continue_trace(&span, &client_headers);
+
+            let _entered = span.enter();
+
+            let mut backend_headers = HeaderMap::new();
+            propagate_trace(&mut backend_headers);
+
+            let traceparent = backend_headers["traceparent"].to_str().unwrap();
+
+            // Same trace, but the backend sees our span as its parent
+            assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+            assert!(!traceparent.contains("00f067aa0ba902b7"));
+            assert!(span.context().span().span_context().is_valid());
+        });
+    }
+}
@@ -0,0 +1,107 @@
+//! Helpers shared by the tests of several modules
+
+use std::{convert::Infallible, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{service::service_fn, Response};
+use hyper_util::rt::TokioIo;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+use crate::server::{
+    host::HostSpec,
+    http::{
+        cache::ResponseCache, error_pages::ErrorPages, path_index::PathIndex, route::HttpRoute,
+        route::HttpRule, service::HttpService, HttpServer,
+    },
+};
+
+/// Port on localhost nothing is listening on
+pub(crate) async fn closed_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    listener.local_addr().unwrap().port()
+}
+
+/// Backend that takes a while to answer, so there's a request in flight on shutdown
+pub(crate) async fn slow_backend() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+
+        let service = service_fn(|_| async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+
+            Ok::<_, Infallible>(Response::new(Full::new(Bytes::from("done"))))
+        });
+
+        hyper::server::conn::http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await
+            .unwrap();
+    });
+
+    port
+}
+
+/// Sends a `GET /` for `test.com` and reads the whole response
+pub(crate) async fn get(addr: SocketAddr) -> String {
+    let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+
+    client
+        .write_all(b"GET / HTTP/1.1\r\nHost: test.com\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+
+    response
+}
+
+/// HTTP server on `port` of localhost, where a listener and a route both called `name` send
+/// `test.com` to the backend on `backend_port`
+pub(crate) fn http_server(
+    name: &str,
+    port: u16,
+    backend_port: u16,
+    cache: Option<ResponseCache>,
+) -> HttpServer {
+    let config = serde_yaml::from_str(&format!(
+        "{{ port: {port}, address: 127.0.0.1, name: {name} }}"
+    ))
+    .unwrap();
+    let service: HttpService = serde_yaml::from_str(&format!(
+        "backends: [{{ ip: 127.0.0.1, port: {backend_port} }}]"
+    ))
+    .unwrap();
+
+    let rules = vec![HttpRule::new(
+        format!("{name}/0"),
+        vec![],
+        format!("{name}-service"),
+        Arc::new(service),
+        Default::default(),
+        None,
+        None,
+        vec![],
+    )];
+
+    let route = HttpRoute {
+        name: name.to_owned(),
+        hostnames: vec![HostSpec::from_str("test.com").unwrap()],
+        path_index: PathIndex::new(&rules),
+        rules,
+        cache,
+        grpc_web: false,
+        body_buffer: None,
+        synthesize: Default::default(),
+    };
+
+    HttpServer::new(config, vec![route], ErrorPages::default())
+}